@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS sessions (
+    id VARCHAR(64) NOT NULL PRIMARY KEY,
+    data TEXT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    INDEX sessions_expires_at (expires_at)
+);
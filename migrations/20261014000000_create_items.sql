@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS items (
+    id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    description TEXT NULL,
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
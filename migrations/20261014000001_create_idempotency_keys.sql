@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    scope VARCHAR(255) NOT NULL,
+    idempotency_key VARCHAR(255) NOT NULL,
+    fingerprint CHAR(64) NOT NULL,
+    status SMALLINT UNSIGNED NULL,
+    content_type VARCHAR(255) NULL,
+    body MEDIUMBLOB NULL,
+    trace_id CHAR(32) NULL,
+    span_id CHAR(16) NULL,
+    expires_at BIGINT NOT NULL,
+    PRIMARY KEY (scope, idempotency_key),
+    INDEX idempotency_keys_expires_at (expires_at)
+);
@@ -0,0 +1,116 @@
+// One span for the many iterations of a hot loop, where a span each would bury the trace
+// and the export in thousands of tiny ones: `aggregate.count`, and the iterations'
+// `aggregate.min_ms`, `aggregate.max_ms` and `aggregate.p99_ms` durations, recorded when the
+// aggregate is dropped. The span lasts from `new` to then, a child of the current span.
+//
+//     let mut rows = aggregate::Aggregate::new("parse row");
+//     for line in lines {
+//         rows.time(|| parse(line));
+//     }
+
+// Only the CSV export so far
+#![cfg_attr(not(feature = "mysql"), allow(dead_code))]
+
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+// Durations kept for the p99, sampled evenly from all iterations past this many
+const RESERVOIR: usize = 1024;
+
+pub struct Aggregate {
+    span: tracing::Span,
+    count: u64,
+    min: Duration,
+    max: Duration,
+    sample: Vec<Duration>,
+}
+
+impl Aggregate {
+    pub fn new(name: &'static str) -> Self {
+        let span = tracing::info_span!(
+            "aggregate",
+            otel.name = name,
+            aggregate.count = tracing::field::Empty,
+            aggregate.min_ms = tracing::field::Empty,
+            aggregate.max_ms = tracing::field::Empty,
+            aggregate.p99_ms = tracing::field::Empty,
+        );
+        Self { span, count: 0, min: Duration::MAX, max: Duration::ZERO, sample: Vec::new() }
+    }
+
+    // Runs one iteration, in the aggregate's span so its events land there
+    pub fn time<T>(&mut self, iteration: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let output = self.span.in_scope(iteration);
+        self.record(started.elapsed());
+        output
+    }
+
+    // For an iteration timed by the caller, an awaited one say
+    pub fn record(&mut self, duration: Duration) {
+        self.count += 1;
+        self.min = self.min.min(duration);
+        self.max = self.max.max(duration);
+
+        // Reservoir sampling: every iteration so far equally likely to be in the sample
+        if self.sample.len() < RESERVOIR {
+            self.sample.push(duration);
+        } else {
+            let index = rand::thread_rng().gen_range(0..self.count);
+            if let Some(slot) = self.sample.get_mut(index as usize) {
+                *slot = duration;
+            }
+        }
+    }
+
+    fn p99(&self) -> Duration {
+        let mut sorted = self.sample.clone();
+        sorted.sort();
+        let index = ((sorted.len() - 1) as f64 * 0.99).round() as usize;
+        sorted[index]
+    }
+}
+
+impl Drop for Aggregate {
+    fn drop(&mut self) {
+        self.span.record("aggregate.count", self.count as i64);
+        if self.count == 0 {
+            return;
+        }
+
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        self.span.record("aggregate.min_ms", ms(self.min));
+        self.span.record("aggregate.max_ms", ms(self.max));
+        self.span.record("aggregate.p99_ms", ms(self.p99()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_span_for_the_whole_loop() {
+        let telemetry = crate::test_support::init();
+
+        tracing::info_span!("request").in_scope(|| {
+            let mut rows = Aggregate::new("process row");
+            for row in 1..=5000u64 {
+                rows.time(|| std::hint::black_box(row * 2));
+            }
+            rows.record(Duration::from_millis(50));
+        });
+
+        let spans = telemetry.spans();
+        assert_eq!(spans.all().len(), 2);
+        let rows = spans.assert_span_exists("process row").child_of("request").with_attribute("aggregate.count", 5001i64);
+        let ms = |key: &str| match rows.attribute(key) {
+            Some(opentelemetry::Value::F64(ms)) => *ms,
+            other => panic!("{key} is {other:?}"),
+        };
+        let (min, p99, max) = (ms("aggregate.min_ms"), ms("aggregate.p99_ms"), ms("aggregate.max_ms"));
+        assert_eq!(max, 50.0);
+        assert!(min <= p99 && p99 < max, "min {min} p99 {p99} max {max}");
+    }
+}
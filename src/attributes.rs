@@ -0,0 +1,99 @@
+// Standard attributes for hot-path spans and metrics, built without allocating per request:
+// keys are static, and the few distinct values (methods, route patterns) are interned
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+use opentelemetry::{Key, KeyValue, StringValue};
+
+pub const HTTP_REQUEST_METHOD: Key = Key::from_static_str("http.request.method");
+pub const HTTP_ROUTE: Key = Key::from_static_str("http.route");
+pub const API_VERSION: Key = Key::from_static_str("api.version");
+pub const TENANT_ID: Key = Key::from_static_str("tenant.id");
+#[cfg(feature = "mysql")]
+pub const DB_SYSTEM: Key = Key::from_static_str("db.system");
+#[cfg(feature = "mysql")]
+pub const DB_OPERATION_NAME: Key = Key::from_static_str("db.operation.name");
+
+// Route patterns are few, anything past this is most likely not one and isn't kept
+const MAX_INTERNED: usize = 1024;
+
+// Enough for every attribute set below without spilling to the heap
+pub type Attributes = smallvec::SmallVec<[KeyValue; 4]>;
+
+// Shares one allocation between every use of the same string
+pub fn intern(value: &str) -> StringValue {
+    static INTERNED: OnceLock<RwLock<HashMap<Box<str>, StringValue>>> = OnceLock::new();
+    let interned = INTERNED.get_or_init(Default::default);
+
+    if let Some(found) = interned.read().unwrap().get(value) {
+        return found.clone();
+    }
+
+    let mut interned = interned.write().unwrap();
+    if interned.len() >= MAX_INTERNED {
+        return StringValue::from(value.to_string());
+    }
+    interned
+        .entry(value.into())
+        .or_insert_with(|| StringValue::from(std::sync::Arc::<str>::from(value)))
+        .clone()
+}
+
+// The known methods as they are; extension methods are client-controlled, so `_OTHER` as the
+// semantic conventions have it, to keep them out of the metric labels
+pub fn method(method: &axum::http::Method) -> StringValue {
+    let name = match *method {
+        axum::http::Method::GET => "GET",
+        axum::http::Method::POST => "POST",
+        axum::http::Method::PUT => "PUT",
+        axum::http::Method::DELETE => "DELETE",
+        axum::http::Method::PATCH => "PATCH",
+        axum::http::Method::HEAD => "HEAD",
+        axum::http::Method::OPTIONS => "OPTIONS",
+        axum::http::Method::CONNECT => "CONNECT",
+        axum::http::Method::TRACE => "TRACE",
+        _ => "_OTHER",
+    };
+    StringValue::from(name)
+}
+
+pub fn route(route: &str) -> KeyValue {
+    KeyValue::new(HTTP_ROUTE, intern(route))
+}
+
+// `v1` of `/v1/...`; the operational routes have none
+pub fn api_version(route: &str) -> Option<&str> {
+    let version = route.strip_prefix('/')?.split('/').next()?;
+    let number = version.strip_prefix('v')?;
+    (!number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())).then_some(version)
+}
+
+// For metrics by route, which then tell the API versions apart too
+pub fn route_labels(matched_route: &str) -> Attributes {
+    let mut labels = smallvec::smallvec![route(matched_route)];
+    if let Some(version) = api_version(matched_route) {
+        labels.push(KeyValue::new(API_VERSION, intern(version)));
+    }
+    labels
+}
+
+pub fn http_server(request_method: &axum::http::Method, matched_route: &str) -> Attributes {
+    let mut attributes = route_labels(matched_route);
+    attributes.insert(0, KeyValue::new(HTTP_REQUEST_METHOD, method(request_method)));
+    attributes
+}
+
+#[cfg(feature = "mysql")]
+pub fn db_client(operation: &'static str) -> Attributes {
+    smallvec::smallvec![KeyValue::new(DB_SYSTEM, "mysql"), KeyValue::new(DB_OPERATION_NAME, operation)]
+}
+
+// Sets them on the span's OpenTelemetry builder directly, skipping the field visitor
+// which copies every string value
+#[cfg(feature = "mysql")]
+pub fn set_all(span: &tracing::Span, attributes: Attributes) {
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    for KeyValue { key, value } in attributes {
+        span.set_attribute(key, value);
+    }
+}
@@ -0,0 +1,142 @@
+// Security-relevant events: auth failures, admin endpoint use, config changes. They are
+// tracing events with the `audit` target and an `audit = true` field, so they reach OTLP
+// as span events of the request like any other, and `AuditLog` also writes them, one
+// JSON object per line, to a file of their own. The log limits never drop them.
+
+use std::io::Write;
+use std::sync::Mutex;
+
+use opentelemetry::trace::TraceContextExt;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::AuditSettings;
+
+pub const TARGET: &str = "audit";
+
+// `tracing::info!` for audit events, with the same field and message syntax
+macro_rules! audit {
+    ($($arg:tt)+) => {
+        tracing::event!(target: $crate::audit::TARGET, tracing::Level::INFO, audit = true, $($arg)+)
+    };
+}
+pub(crate) use audit;
+
+pub struct AuditLog {
+    out: Mutex<std::io::LineWriter<std::fs::File>>,
+    secrets: crate::redact::Names,
+}
+
+impl AuditLog {
+    // None without a file to write to; the values of `redact_fields` are left out
+    pub fn new(settings: &AuditSettings, redact_fields: &[String]) -> Result<Option<Self>, String> {
+        let Some(path) = &settings.path else {
+            return Ok(None);
+        };
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("can't open audit log {path:?}: {e}"))?;
+
+        Ok(Some(Self {
+            out: Mutex::new(std::io::LineWriter::new(file)),
+            secrets: crate::redact::Names::new(redact_fields),
+        }))
+    }
+}
+
+// The event's fields as JSON values, secrets scrubbed as in the stdout log
+struct Fields<'a>(serde_json::Map<String, serde_json::Value>, &'a crate::redact::Names);
+
+impl Fields<'_> {
+    fn insert_text(&mut self, field: &tracing::field::Field, value: &str) {
+        let value = match self.1.is_secret(field.name()) {
+            true => crate::redact::REDACTED.into(),
+            false => crate::redact::scrub_urls(value),
+        };
+        self.0.insert(field.name().to_string(), value.into_owned().into());
+    }
+}
+
+impl tracing::field::Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.insert_text(field, &format!("{value:?}"));
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.insert_text(field, value);
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for AuditLog
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if event.metadata().target() != TARGET {
+            return;
+        }
+
+        let mut fields = Fields(serde_json::Map::new(), &self.secrets);
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        fields.0.insert("timestamp_unix_ms".to_string(), (now.as_millis() as u64).into());
+        event.record(&mut fields);
+
+        // Added by the OpenTelemetry layer, which sits below this one
+        let trace_id = ctx.event_span(event).and_then(|span| {
+            let extensions = span.extensions();
+            let data = extensions.get::<OtelData>()?;
+            // The caller's, for a trace continued from one
+            let remote = data.parent_cx.span().span_context().trace_id();
+            (remote != opentelemetry::trace::TraceId::INVALID).then_some(remote).or(data.builder.trace_id)
+        });
+        if let Some(trace_id) = trace_id {
+            fields.0.insert("trace_id".to_string(), trace_id.to_string().into());
+        }
+
+        let _ = writeln!(self.out.lock().unwrap(), "{}", serde_json::Value::Object(fields.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn writes_audit_events_only() {
+        let path = std::env::temp_dir().join(format!("audit-{}.log", uuid::Uuid::new_v4()));
+        let settings = AuditSettings { path: Some(path.to_string_lossy().into_owned()) };
+        let subscriber = tracing_subscriber::registry().with(AuditLog::new(&settings, &[]).unwrap());
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("Processing request");
+            audit!(auth.failure_reason = "token expired", "Rejected unauthenticated request");
+        });
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = written.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["audit"], true);
+        assert_eq!(lines[0]["auth.failure_reason"], "token expired");
+        assert_eq!(lines[0]["message"], "Rejected unauthenticated request");
+    }
+}
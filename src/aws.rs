@@ -0,0 +1,202 @@
+// AWS SDK clients, set up alike: rustls with ring, `[dependencies.*]`'s timeouts, and
+// `Tracing` for an interceptor. Every SDK call is a client span named like the operation,
+// "S3.PutObject", with `rpc.system` "aws-api", the service and the operation, the status
+// and the request ids AWS support asks for. The trace context goes out with the request
+// like on other outbound calls, and a throttled attempt shows as an event. The SDK's own
+// retries being off, `retry.count` is how many times the caller's policy retried before.
+
+// Only the S3 client so far
+#![cfg_attr(not(feature = "s3"), allow(dead_code))]
+
+use aws_smithy_runtime_api::box_error::BoxError;
+use aws_smithy_runtime_api::client::interceptors::context::{
+    BeforeSerializationInterceptorContextRef, BeforeTransmitInterceptorContextMut, FinalizerInterceptorContextRef,
+};
+use aws_smithy_runtime_api::client::interceptors::Intercept;
+use aws_smithy_runtime_api::client::runtime_components::RuntimeComponents;
+use aws_smithy_types::config_bag::{ConfigBag, Storable, StoreReplace};
+
+use crate::retry::Policy;
+
+// Error codes of the protocols which throttle with a 400
+const THROTTLING_CODES: &[&str] = &["Throttling", "ThrottlingException", "TooManyRequestsException", "RequestLimitExceeded", "SlowDown"];
+
+// The shared config of SDK clients. The SDK's own retries are off, `policy` is for the
+// caller to retry with; the default credential chain unless `credentials` are given.
+pub async fn load_config(region: &str, policy: &Policy, credentials: Option<aws_credential_types::Credentials>) -> aws_config::SdkConfig {
+    let http_client = aws_smithy_http_client::Builder::new()
+        .tls_provider(aws_smithy_http_client::tls::Provider::Rustls(
+            aws_smithy_http_client::tls::rustls_provider::CryptoMode::Ring,
+        ))
+        .build_https();
+    let timeouts = aws_config::timeout::TimeoutConfig::builder()
+        .connect_timeout(policy.connect_timeout())
+        .read_timeout(policy.read_timeout())
+        .build();
+
+    let loader = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .region(aws_config::Region::new(region.to_string()))
+        .http_client(http_client)
+        .timeout_config(timeouts)
+        .retry_config(aws_config::retry::RetryConfig::disabled());
+    match credentials {
+        Some(credentials) => loader.credentials_provider(credentials).load().await,
+        None => loader.load().await,
+    }
+}
+
+// The span of one SDK call, all its attempts
+#[derive(Debug)]
+struct CallSpan(tracing::Span);
+
+impl Storable for CallSpan {
+    type Storer = StoreReplace<Self>;
+}
+
+fn is_throttled(response: &aws_smithy_runtime_api::http::Response) -> bool {
+    let status = response.status().as_u16();
+    let error_type = response.headers().get("x-amzn-errortype").map(|value| value.split(':').next().unwrap_or(value));
+    status == 429 || status == 503 || error_type.is_some_and(|code| THROTTLING_CODES.contains(&code))
+}
+
+// Add with `.interceptor(aws::Tracing)` on a client's config builder
+#[derive(Debug)]
+pub struct Tracing;
+
+impl Intercept for Tracing {
+    fn name(&self) -> &'static str {
+        "Tracing"
+    }
+
+    // The first hook after the operation's config, its name included, is in the bag
+    fn read_before_serialization(
+        &self,
+        _context: &BeforeSerializationInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let (service, operation) = match cfg.load::<aws_smithy_runtime_api::client::orchestrator::Metadata>() {
+            Some(metadata) => (metadata.service().to_string(), metadata.name().to_string()),
+            None => ("unknown".to_string(), "unknown".to_string()),
+        };
+        let span = tracing::info_span!(
+            "aws",
+            otel.name = format!("{service}.{operation}"),
+            otel.kind = "client",
+            otel.status_code = tracing::field::Empty,
+            rpc.system = "aws-api",
+            rpc.service = service,
+            rpc.method = operation,
+            cloud.region = cfg.load::<aws_config::Region>().map(|region| region.to_string()),
+            http.response.status_code = tracing::field::Empty,
+            aws.request_id = tracing::field::Empty,
+            aws.extended_request_id = tracing::field::Empty,
+            aws.throttled = tracing::field::Empty,
+            retry.count = i64::from(crate::retry::retries()),
+        );
+        cfg.interceptor_state().store_put(CallSpan(span));
+        Ok(())
+    }
+
+    // Before signing, so the headers are signed with the rest
+    fn modify_before_signing(
+        &self,
+        context: &mut BeforeTransmitInterceptorContextMut<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if let Some(CallSpan(span)) = cfg.load::<CallSpan>() {
+            let headers = context.request_mut().headers_mut();
+            crate::propagation::inject_with(span, |key, value| {
+                let _ = headers.try_insert(key.to_string(), value);
+            });
+        }
+        Ok(())
+    }
+
+    fn read_after_attempt(&self, context: &FinalizerInterceptorContextRef<'_>, _runtime_components: &RuntimeComponents, cfg: &mut ConfigBag) -> Result<(), BoxError> {
+        if let (Some(CallSpan(span)), Some(response)) = (cfg.load::<CallSpan>(), context.response()) {
+            if is_throttled(response) {
+                let attempt = cfg.load::<aws_smithy_runtime_api::client::retries::RequestAttempts>().map(|attempts| attempts.attempts());
+                span.record("aws.throttled", true);
+                span.in_scope(|| tracing::warn!(http.response.status_code = response.status().as_u16(), retry.attempt = attempt, "Throttled"));
+            }
+        }
+        Ok(())
+    }
+
+    fn read_after_execution(&self, context: &FinalizerInterceptorContextRef<'_>, _runtime_components: &RuntimeComponents, cfg: &mut ConfigBag) -> Result<(), BoxError> {
+        let Some(CallSpan(span)) = cfg.load::<CallSpan>() else { return Ok(()) };
+
+        if let Some(response) = context.response() {
+            let headers = response.headers();
+            span.record("http.response.status_code", i64::from(response.status().as_u16()));
+            if let Some(id) = headers.get("x-amzn-requestid").or_else(|| headers.get("x-amz-request-id")) {
+                span.record("aws.request_id", id);
+            }
+            if let Some(id) = headers.get("x-amz-id-2") {
+                span.record("aws.extended_request_id", id);
+            }
+        }
+        if let Some(Err(e)) = context.output_or_error() {
+            span.record("otel.status_code", "error");
+            span.in_scope(|| tracing::error!("AWS call failed: {:?}", e));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "s3"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn calls_are_traced_with_the_request_ids() {
+        let telemetry = crate::test_support::init();
+        let app = axum::Router::new().route(
+            "/*path",
+            axum::routing::put(|| async { ([("x-amz-request-id", "4442587FB7D0A2F9"), ("x-amz-id-2", "vlR7PnpV2Ce81l0PRw6jlUpck7Jo5ZsQjryTjKlc5aLWGVHPZLj5NeC6qMa0emYBDXOo6QBU0Wo=")], "") }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let policy = Policy::new(Default::default());
+        let credentials = aws_credential_types::Credentials::new("test", "test", None, None, "test");
+        let config = aws_sdk_s3::config::Builder::from(&load_config("eu-west-1", &policy, Some(credentials)).await)
+            .endpoint_url(endpoint)
+            .force_path_style(true)
+            .interceptor(Tracing)
+            .build();
+        aws_sdk_s3::Client::from_conf(config).put_object().bucket("uploads").key("a.txt").send().await.unwrap();
+
+        let spans = telemetry.spans();
+        let call = spans
+            .all()
+            .iter()
+            .find(|span| span.name == "S3.PutObject" && span.attributes.iter().any(|kv| kv.key.as_str() == "aws.request_id"))
+            .expect("no traced S3.PutObject");
+        let attribute = |key: &str| call.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| kv.value.to_string());
+        assert_eq!(attribute("rpc.system").as_deref(), Some("aws-api"));
+        assert_eq!(attribute("rpc.method").as_deref(), Some("PutObject"));
+        assert_eq!(attribute("cloud.region").as_deref(), Some("eu-west-1"));
+        assert_eq!(attribute("aws.request_id").as_deref(), Some("4442587FB7D0A2F9"));
+        assert_eq!(attribute("http.response.status_code").as_deref(), Some("200"));
+        assert_eq!(attribute("retry.count").as_deref(), Some("0"));
+    }
+
+    #[test]
+    fn throttling() {
+        let response = |status: u16, error_type: Option<&'static str>| {
+            let mut response = aws_smithy_runtime_api::http::Response::new(status.try_into().unwrap(), aws_smithy_types::body::SdkBody::empty());
+            if let Some(error_type) = error_type {
+                response.headers_mut().insert("x-amzn-errortype", error_type);
+            }
+            response
+        };
+        assert!(is_throttled(&response(503, None)));
+        assert!(is_throttled(&response(400, Some("ThrottlingException:http://internal.amazon.com/coral/com.amazon.coral.availability/"))));
+        assert!(!is_throttled(&response(400, Some("ValidationException"))));
+        assert!(!is_throttled(&response(200, None)));
+    }
+}
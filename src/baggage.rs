@@ -0,0 +1,208 @@
+// W3C baggage from incoming requests: the propagator puts it in the request span's
+// context, spans below inherit it from there, and outbound calls hand it on
+
+use opentelemetry::baggage::BaggageExt;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::{Key, KeyValue};
+use tracing_opentelemetry::{OpenTelemetrySpanExt, OtelData};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::BaggageSettings;
+
+// Makes the request's context, baggage included, the current OTel context while it is
+// handled, for code which reads the baggage from there
+pub async fn layer(request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let cx = tracing::Span::current().context();
+    next.run(request).with_context(cx).await
+}
+
+// Marks spans whose baggage has been copied already
+struct Tagged;
+
+// Copies the allowlisted baggage entries onto every span as attributes. This runs when a
+// span is first entered rather than created, because the request span only gets the
+// caller's context (and its baggage) right after it is created.
+pub struct SpanLayer {
+    allowed: Vec<Key>,
+}
+
+impl SpanLayer {
+    pub fn new(settings: &BaggageSettings) -> Self {
+        Self {
+            allowed: settings
+                .span_attributes
+                .iter()
+                .map(|key| Key::from(std::sync::Arc::<str>::from(key.as_str())))
+                .collect(),
+        }
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for SpanLayer
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_enter(&self, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if extensions.get_mut::<Tagged>().is_some() {
+            return;
+        }
+        extensions.insert(Tagged);
+
+        // Added by the OpenTelemetry layer, which sits below this one
+        let Some(OtelData { parent_cx, builder }) = extensions.get_mut::<OtelData>() else {
+            return;
+        };
+        // The caller's entries, then those middleware added (the tenant) to the current
+        // context, which spans entered while the handler runs pick up from there
+        let current = opentelemetry::Context::current();
+        let entries = self.allowed.iter().filter_map(|key| {
+            let value = parent_cx.baggage().get(key.clone()).or_else(|| current.baggage().get(key.clone()))?;
+            Some(KeyValue::new(key.clone(), value.clone()))
+        });
+        builder.attributes.get_or_insert_with(Vec::new).extend(entries);
+    }
+}
+
+// Log line format which appends the allowlisted baggage entries of the current context,
+// as `key="value"` after the event fields, to whatever `inner` writes. The values are the
+// caller's, so they are quoted and escaped like the event's own fields, and redacted alike
+pub struct LogFields<F> {
+    inner: F,
+    allowed: Vec<Key>,
+    secrets: crate::redact::Names,
+}
+
+impl<F> LogFields<F> {
+    pub fn new(inner: F, settings: &BaggageSettings, redact_fields: &[String]) -> Self {
+        Self {
+            inner,
+            allowed: settings.log_fields.iter().map(|key| Key::from(std::sync::Arc::<str>::from(key.as_str()))).collect(),
+            secrets: crate::redact::Names::new(redact_fields),
+        }
+    }
+}
+
+impl<S, N, F> tracing_subscriber::fmt::FormatEvent<S, N> for LogFields<F>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'w> tracing_subscriber::fmt::FormatFields<'w> + 'static,
+    F: tracing_subscriber::fmt::FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+        mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        // The current OTel context, which is the request's while its handler runs (see
+        // `layer`); the span's own can't be looked up from here, subscriber calls don't nest
+        let cx = opentelemetry::Context::current();
+        let baggage = cx.baggage();
+        let entries: Vec<_> = self
+            .allowed
+            .iter()
+            .filter_map(|key| Some((key, baggage.get(key.clone())?)))
+            .collect();
+        if entries.is_empty() {
+            return self.inner.format_event(ctx, writer, event);
+        }
+
+        // The inner format ends the line, the entries go before the newline
+        let mut line = String::new();
+        self.inner.format_event(ctx, tracing_subscriber::fmt::format::Writer::new(&mut line), event)?;
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+
+        write!(writer, "{line}")?;
+        for (key, value) in entries {
+            if self.secrets.is_secret(key.as_str()) {
+                write!(writer, " {key}={}", crate::redact::REDACTED)?;
+            } else {
+                write!(writer, " {key}={:?}", crate::redact::scrub_urls(&value.as_str()))?;
+            }
+        }
+        writeln!(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn copies_allowlisted_entries_to_every_span() {
+        let telemetry = test_support::init();
+
+        let caller = opentelemetry::Context::new()
+            .with_baggage([KeyValue::new("session.id", "s-1"), KeyValue::new("card.number", "4111")]);
+        let request = tracing::info_span!("request");
+        request.set_parent(caller);
+        request.in_scope(|| tracing::info_span!("fetch row").in_scope(|| {}));
+        drop(request);
+
+        let spans = telemetry.spans();
+        for name in ["request", "fetch row"] {
+            spans.assert_span_exists(name).with_attribute("session.id", "s-1");
+        }
+        assert!(spans.find("request").unwrap().attributes.iter().all(|kv| kv.key.as_str() != "card.number"));
+    }
+
+    // Collects what the fmt layer writes
+    #[derive(Clone, Default)]
+    struct Output(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn appends_allowlisted_entries_to_log_lines() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::logging::fmt_layer(move || writer.clone(), &Default::default(), &BaggageSettings::default(), &Default::default()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _cx = opentelemetry::Context::new().with_baggage([KeyValue::new("session.id", "s-1")]).attach();
+            tracing::warn!("possible error");
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.trim_end().ends_with(r#"possible error session.id="s-1""#), "{output}");
+    }
+
+    #[test]
+    fn escapes_and_redacts_log_fields() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let output = Output::default();
+        let writer = output.clone();
+        let baggage = BaggageSettings { log_fields: vec!["session.id".into(), "api.token".into()], ..Default::default() };
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::logging::fmt_layer(move || writer.clone(), &Default::default(), &baggage, &Default::default()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _cx = opentelemetry::Context::new()
+                .with_baggage([KeyValue::new("session.id", "s-1\nERROR forged=1"), KeyValue::new("api.token", "t0k3n")])
+                .attach();
+            tracing::warn!("possible error");
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.lines().count(), 1, "{output}");
+        assert!(output.contains(r#"session.id="s-1\nERROR forged=1""#), "{output}");
+        assert!(output.contains("api.token=[redacted]") && !output.contains("t0k3n"), "{output}");
+    }
+}
@@ -0,0 +1,190 @@
+use std::time::{Duration, Instant};
+
+use tower::ServiceExt;
+use tracing::instrument::WithSubscriber;
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::{config, http_client, AppState};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Route to request; the default needs neither the database nor a downstream
+    #[arg(long, default_value = "/v1/buildinfo")]
+    pub path: String,
+    /// Timed requests per mode, at least one
+    #[arg(long, default_value_t = 5000, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub requests: usize,
+    /// Untimed requests sent before each mode's timed ones
+    #[arg(long, default_value_t = 500)]
+    pub warmup: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Off,
+    Fmt,
+    Otlp,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Off => "off",
+            Mode::Fmt => "fmt",
+            Mode::Otlp => "otlp",
+        }
+    }
+
+    // The same layers as `main` installs, with the stdout log written nowhere
+    fn dispatch(self, tracer: Option<&opentelemetry_sdk::trace::Tracer>) -> tracing::Dispatch {
+        let registry = || tracing_subscriber::registry()
+            .with(crate::logging::global_filter(&Default::default()))
+            .with(crate::logging::fmt_layer(std::io::sink, &Default::default(), &Default::default(), &Default::default()));
+
+        match self {
+            Mode::Off => tracing::Dispatch::none(),
+            Mode::Fmt => tracing::Dispatch::new(registry()),
+            Mode::Otlp => tracing::Dispatch::new(
+                registry().with(tracer.map(|tracer| tracing_opentelemetry::OpenTelemetryLayer::new(tracer.clone()))),
+            ),
+        }
+    }
+}
+
+struct Run {
+    latencies: Vec<Duration>,
+    cpu: Option<Duration>,
+}
+
+impl Run {
+    fn quantile(&self, q: f64) -> f64 {
+        let at = ((self.latencies.len() - 1) as f64 * q).round() as usize;
+        self.latencies[at].as_secs_f64() * 1e6
+    }
+
+    fn cpu_per_request(&self) -> Option<f64> {
+        self.cpu.map(|cpu| cpu.as_secs_f64() * 1e6 / self.latencies.len() as f64)
+    }
+}
+
+// User plus system time of the whole process, from /proc; None off Linux
+fn process_cpu() -> Option<Duration> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // Fields after the parenthesised command name, which may itself contain spaces
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks: u64 = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+    // USER_HZ, which is 100 on every Linux platform we run on
+    Some(Duration::from_millis(ticks * 10))
+}
+
+async fn send(app: &axum::Router, path: &str) -> Duration {
+    let request = axum::http::Request::get(path).body(axum::body::Body::empty()).unwrap();
+    let started = Instant::now();
+    let response = app.clone().oneshot(request).await.unwrap();
+    // The request span only ends with the body
+    let _ = http_body_util::BodyExt::collect(response.into_body()).await;
+    started.elapsed()
+}
+
+async fn run_mode(app: &axum::Router, args: &Args) -> Run {
+    for _ in 0..args.warmup {
+        send(app, &args.path).await;
+    }
+
+    let cpu_before = process_cpu();
+    let mut latencies = Vec::with_capacity(args.requests);
+    for _ in 0..args.requests {
+        latencies.push(send(app, &args.path).await);
+    }
+    let cpu = cpu_before.zip(process_cpu()).map(|(before, after)| after.saturating_sub(before));
+
+    latencies.sort();
+    Run { latencies, cpu }
+}
+
+pub async fn run(args: Args, settings: &config::Settings, tracer: Option<opentelemetry_sdk::trace::Tracer>) -> Result<(), crate::startup::StartupError> {
+    // The API alone, as served next to the admin listener
+    let app = crate::router(settings, state(settings)?, None)?;
+
+    let modes = match &tracer {
+        Some(_) => vec![Mode::Off, Mode::Fmt, Mode::Otlp],
+        None => {
+            println!("telemetry is disabled, skipping the otlp mode");
+            vec![Mode::Off, Mode::Fmt]
+        }
+    };
+
+    println!("{} requests to {} per mode, latencies in microseconds", args.requests, args.path);
+    println!("{:<6} {:>10} {:>10} {:>12} {:>12}", "mode", "p50", "p99", "cpu/request", "p50 vs off");
+
+    let mut baseline = None;
+    for mode in modes {
+        // Requests run on this task, so the dispatcher is the one in use throughout
+        let run = run_mode(&app, &args).with_subscriber(mode.dispatch(tracer.as_ref())).await;
+
+        let p50 = run.quantile(0.5);
+        let baseline = *baseline.get_or_insert(p50);
+        let cpu = run.cpu_per_request().map_or("n/a".to_string(), |cpu| format!("{cpu:.1}"));
+        println!("{:<6} {:>10.1} {:>10.1} {:>12} {:>+12.1}", mode.name(), p50, run.quantile(0.99), cpu, p50 - baseline);
+    }
+    Ok(())
+}
+
+// Only connects to the database when the benchmarked route queries it
+fn state(settings: &config::Settings) -> Result<AppState, crate::startup::StartupError> {
+    #[cfg(feature = "mysql")]
+    let pool = crate::db::connect_lazy(&settings.database, &settings.dependencies.db)
+        .map_err(|e| e.to_string())
+        .map_err(crate::startup::StartupError::config("database"))?;
+
+    Ok(AppState {
+        #[cfg(feature = "mysql")]
+        pool: pool.clone(),
+        #[cfg(feature = "mysql")]
+        db_breaker: std::sync::Arc::new(crate::circuit_breaker::CircuitBreaker::new("mysql", settings.circuit_breaker.clone())),
+        #[cfg(feature = "mysql")]
+        db_policy: std::sync::Arc::new(crate::retry::Policy::new(settings.dependencies.db.clone())),
+        #[cfg(feature = "mysql")]
+        explainer: std::sync::Arc::new(crate::explain::Explainer::new(pool.clone(), &settings.database)),
+        #[cfg(feature = "mysql")]
+        hedger: std::sync::Arc::new(crate::hedging::Hedger::new(settings.hedging.clone())),
+        #[cfg(feature = "mysql")]
+        transactions: std::sync::Arc::new(crate::transaction_retry::TransactionRetry::new(settings.transaction_retry.clone())),
+        #[cfg(feature = "mysql")]
+        hasher: std::sync::Arc::new(crate::hashing::Hasher::new(settings.hashing.clone()).map_err(crate::startup::StartupError::config("hashing"))?),
+        #[cfg(feature = "mysql")]
+        sessions: std::sync::Arc::new(crate::session::SessionStore::new(pool.clone(), settings.session.clone())),
+        #[cfg(feature = "mysql")]
+        idempotency: std::sync::Arc::new(crate::idempotency::IdempotencyStore::new(pool, settings.idempotency.clone())),
+        #[cfg(feature = "mysql")]
+        copies: std::sync::Arc::new(crate::items::Copies::new(&settings.degraded)),
+        http: http_client::HttpClient::new(&settings.dependencies.http).map_err(crate::startup::StartupError::config("dependencies.http"))?,
+        chain_url: format!("{}{}", settings.downstream.base_url, settings.downstream.chain_path),
+        jobs: std::sync::Arc::new(crate::jobs::Jobs::new(settings.jobs.clone())),
+        connections: settings.connections.clone(),
+        flags: crate::flags::Flags::new(&settings.flags).map_err(crate::startup::StartupError::config("flags"))?,
+        chaos: crate::chaos::Chaos::new(&settings.chaos).map_err(crate::startup::StartupError::config("chaos"))?,
+        #[cfg(feature = "email")]
+        mailer: std::sync::Arc::new(crate::email::Mailer::new(&settings.email, &settings.dependencies.smtp).map_err(crate::startup::StartupError::config("email"))?),
+        #[cfg(feature = "s3")]
+        storage: std::sync::Arc::new(crate::storage::Storage::new(&settings.storage, &settings.dependencies.s3)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: Args,
+    }
+
+    #[test]
+    fn takes_at_least_one_request() {
+        assert!(Cli::try_parse_from(["bench-overhead", "--requests", "0"]).is_err());
+        assert_eq!(Cli::try_parse_from(["bench-overhead", "--requests", "1"]).unwrap().args.requests, 1);
+    }
+}
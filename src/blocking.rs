@@ -0,0 +1,65 @@
+// CPU-bound or blocking work moved off the async workers, traced like the code around it,
+// in its context and with its baggage
+
+// Only the database routes use it so far
+#![cfg_attr(not(feature = "mysql"), allow(dead_code))]
+
+use std::time::Instant;
+
+// Runs `work` on the blocking pool inside a span named `name`, a child of the current
+// span. The span covers the time spent queued for a blocking thread too, which is
+// recorded apart from the time spent running.
+pub async fn run_blocking<T, F>(name: &'static str, work: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let span = tracing::info_span!(
+        "blocking",
+        otel.name = name,
+        blocking.queue_wait_ms = tracing::field::Empty,
+        blocking.run_ms = tracing::field::Empty,
+    );
+    let queued_at = Instant::now();
+
+    // The caller's subscriber too, which may be scoped to its thread (as in tests)
+    let context = span.in_scope(crate::threads::ThreadContext::capture);
+    let task = crate::tasks::spawn_blocking(context.wrap(move || {
+        let span = tracing::Span::current();
+        span.record("blocking.queue_wait_ms", queued_at.elapsed().as_secs_f64() * 1000.0);
+
+        let started = Instant::now();
+        let result = work();
+        span.record("blocking.run_ms", started.elapsed().as_secs_f64() * 1000.0);
+        result
+    }));
+
+    match crate::middleware::time_breakdown::timed(crate::middleware::time_breakdown::Part::Blocking, task).await {
+        Ok(result) => result,
+        // Same as if `work` had run here
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::baggage::BaggageExt;
+    use tracing::Instrument;
+
+    #[tokio::test]
+    async fn the_work_runs_in_the_callers_context() {
+        let telemetry = crate::test_support::init();
+        let _cx = opentelemetry::Context::current_with_baggage([opentelemetry::KeyValue::new("tenant.id", "acme")]).attach();
+
+        let tenant = super::run_blocking("hash", || {
+            tracing::info_span!("bcrypt").in_scope(|| opentelemetry::Context::current().baggage().get("tenant.id").map(|tenant| tenant.to_string()))
+        })
+        .instrument(tracing::info_span!("request"))
+        .await;
+
+        assert_eq!(tenant.as_deref(), Some("acme"));
+        let spans = telemetry.spans();
+        spans.assert_span_exists("hash").child_of("request").with_attribute_present("blocking.run_ms");
+        spans.assert_span_exists("bcrypt").child_of("hash");
+    }
+}
@@ -0,0 +1,165 @@
+// Events from before the subscriber is installed, while the config is parsed and secrets
+// are fetched, which would otherwise go nowhere. `Bootstrap` keeps those of INFO and above
+// sent on this thread, a task's awaited here included; `replay` sends them again through the
+// subscriber, each with the target and time it had, its fields written into the message with
+// those of `logging.redact_fields` redacted. A startup which fails before then prints them
+// to stderr instead, redacting those of the default names.
+
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tracing::Level;
+use tracing_subscriber::layer::SubscriberExt;
+
+// Past this many, events are counted rather than kept
+const CAPACITY: usize = 1000;
+
+struct Early {
+    level: Level,
+    target: String,
+    message: String,
+    // By name, formatted
+    fields: Vec<(String, String)>,
+    at: SystemTime,
+}
+
+impl Early {
+    // The message, then the fields as `name=value`, secrets redacted
+    fn text(&self, secrets: &crate::redact::Names) -> String {
+        let fields = self.fields.iter().map(|(name, value)| match secrets.is_secret(name) {
+            true => format!("{name}={}", crate::redact::REDACTED),
+            false => format!("{name}={}", crate::redact::scrub_urls(value)),
+        });
+        std::iter::once(crate::redact::scrub_urls(&self.message).into_owned()).chain(fields).collect::<Vec<_>>().join(" ")
+    }
+}
+
+#[derive(Default)]
+struct Events {
+    kept: Vec<Early>,
+    dropped: usize,
+}
+
+// The message, then the other fields by name
+#[derive(Default)]
+struct Visitor(String, Vec<(String, String)>);
+
+impl tracing::field::Visit for Visitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => self.0 = format!("{value:?}"),
+            name => self.1.push((name.to_string(), format!("{value:?}"))),
+        }
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        match field.name() {
+            "message" => self.0 = value.to_string(),
+            name => self.1.push((name.to_string(), format!("{value:?}"))),
+        }
+    }
+}
+
+struct Capture(Arc<Mutex<Events>>);
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Capture {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let mut events = self.0.lock().unwrap();
+        if events.kept.len() >= CAPACITY {
+            events.dropped += 1;
+            return;
+        }
+
+        let mut visitor = Visitor::default();
+        event.record(&mut visitor);
+        let Visitor(message, fields) = visitor;
+        let metadata = event.metadata();
+        events.kept.push(Early { level: *metadata.level(), target: metadata.target().to_string(), message, fields, at: SystemTime::now() });
+    }
+}
+
+pub struct Bootstrap {
+    events: Arc<Mutex<Events>>,
+    guard: Option<tracing::subscriber::DefaultGuard>,
+}
+
+impl Bootstrap {
+    // Keeps events until `replay`, as this thread's default subscriber
+    pub fn start() -> Self {
+        let events = Arc::new(Mutex::new(Events::default()));
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_subscriber::filter::LevelFilter::INFO)
+            .with(Capture(events.clone()));
+        Self { events, guard: Some(tracing::subscriber::set_default(subscriber)) }
+    }
+
+    // Once the subscriber is installed, the values of the fields named like `redact_fields`
+    // redacted
+    pub fn replay(mut self, redact_fields: &[String]) {
+        drop(self.guard.take());
+        let Events { kept, dropped } = std::mem::take(&mut *self.events.lock().unwrap());
+
+        let secrets = crate::redact::Names::new(redact_fields);
+        for early in kept {
+            let at = humantime::format_rfc3339_micros(early.at).to_string();
+            let message = early.text(&secrets);
+            macro_rules! replay {
+                ($level:expr) => {
+                    tracing::event!($level, early.target = early.target, early.timestamp = at, "{}", message)
+                };
+            }
+            match early.level {
+                Level::ERROR => replay!(Level::ERROR),
+                Level::WARN => replay!(Level::WARN),
+                Level::INFO => replay!(Level::INFO),
+                Level::DEBUG => replay!(Level::DEBUG),
+                Level::TRACE => replay!(Level::TRACE),
+            }
+        }
+        if dropped > 0 {
+            tracing::warn!(dropped, "Dropped events sent before the subscriber was installed, past the first {}", CAPACITY);
+        }
+    }
+}
+
+// Never replayed, startup failed first
+impl Drop for Bootstrap {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        let events = std::mem::take(&mut *self.events.lock().unwrap());
+        let secrets = crate::redact::Names::new(&crate::config::LoggingSettings::default().redact_fields);
+        for early in &events.kept {
+            eprintln!("{} {:>5} {}: {}", humantime::format_rfc3339_micros(early.at), early.level, early.target, early.text(&secrets));
+        }
+        if events.dropped > 0 {
+            eprintln!("... and {} more", events.dropped);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn early_events_are_replayed() {
+        let mut bootstrap = Bootstrap::start();
+        tracing::warn!(path = "settings.toml", "Config has an unknown key");
+        tracing::debug!("Not kept");
+        tracing::warn!(db.password = "hunter2", url = "mysql://app:hunter2@db/orders", "Retrying the database");
+
+        // The test subscriber being this thread's too, it comes after capturing stops
+        drop(bootstrap.guard.take());
+        let telemetry = crate::test_support::init();
+        tracing::info_span!("startup").in_scope(|| bootstrap.replay(&["password".to_string()]));
+
+        let spans = telemetry.spans();
+        let startup = spans.assert_span_exists("startup").span();
+        let names: Vec<_> = startup.events.iter().map(|event| event.name.to_string()).collect();
+        assert_eq!(names[0], "Config has an unknown key path=\"settings.toml\"");
+        assert!(!names[1].contains("hunter2"), "{}", names[1]);
+        assert!(names[1].starts_with("Retrying the database db.password=[redacted] url="));
+        let event = &startup.events[0];
+        assert!(event.attributes.iter().any(|kv| kv.key.as_str() == "early.target" && kv.value.as_str().contains("bootstrap")));
+    }
+}
@@ -0,0 +1,32 @@
+// Build metadata embedded by build.rs
+pub const VCS_REVISION: &str = env!("BUILD_VCS_REVISION");
+pub const VCS_DIRTY: &str = env!("BUILD_VCS_DIRTY");
+pub const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+pub const RUSTC_VERSION: &str = env!("BUILD_RUSTC_VERSION");
+// Comma-separated
+pub const FEATURES: &str = env!("BUILD_FEATURES");
+
+// Added to the resource next to `service.version`, so every span names its exact build
+pub fn resource_attributes() -> [opentelemetry::KeyValue; 4] {
+    [
+        opentelemetry::KeyValue::new("vcs.revision", VCS_REVISION),
+        opentelemetry::KeyValue::new("vcs.dirty", VCS_DIRTY == "true"),
+        opentelemetry::KeyValue::new("build.timestamp", BUILD_TIMESTAMP),
+        opentelemetry::KeyValue::new("build.rustc_version", RUSTC_VERSION),
+    ]
+}
+
+#[utoipa::path(get, path = "/buildinfo", summary = "Version and build metadata", responses(
+    (status = 200, body = Object, description = "Build metadata"),
+))]
+pub async fn handler() -> crate::response::Traced<axum::Json<serde_json::Value>> {
+    crate::response::Traced::new(axum::Json(serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "vcs_revision": VCS_REVISION,
+        "vcs_dirty": VCS_DIRTY == "true",
+        "build_timestamp": BUILD_TIMESTAMP,
+        "rustc_version": RUSTC_VERSION,
+        "features": FEATURES.split(',').filter(|feature| !feature.is_empty()).collect::<Vec<_>>(),
+    })))
+}
@@ -0,0 +1,196 @@
+// Fault injection for game days: with `[chaos] enabled`, each fault of the list is injected
+// into `percent` of the requests of its route and tenant, any when unset. `latency` waits
+// before the handler, `error` answers with `status` in its place, `drop_db_connection`
+// hard-closes the idle connection the request's first query would get, which the pool then
+// replaces. Every injected fault is a "Fault injected" event on the request span. The list
+// starts out as configured and is swapped through `PUT /admin/chaos`, until the restart.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use axum::response::IntoResponse;
+use rand::Rng;
+
+use crate::config::{ChaosSettings, FaultKind, FaultSettings};
+
+tokio::task_local! {
+    // Whether a connection is still to be dropped for the request
+    static DROP_DB_CONNECTION: AtomicBool;
+}
+
+#[derive(Clone)]
+pub struct Chaos {
+    enabled: bool,
+    faults: Arc<RwLock<Vec<FaultSettings>>>,
+}
+
+fn validate(faults: &[FaultSettings]) -> Result<(), String> {
+    for fault in faults {
+        if !(0.0..=100.0).contains(&fault.percent) {
+            return Err(format!("percent {} is not between 0 and 100", fault.percent));
+        }
+        if fault.kind == FaultKind::Error && !(400..=599).contains(&fault.status) {
+            return Err(format!("status {} is not an error status", fault.status));
+        }
+        if let Some(route) = fault.route.as_ref().filter(|route| !route.starts_with('/')) {
+            return Err(format!("route {route:?} does not start with /"));
+        }
+    }
+    Ok(())
+}
+
+impl Chaos {
+    pub fn new(settings: &ChaosSettings) -> Result<Self, String> {
+        validate(&settings.faults)?;
+        Ok(Self { enabled: settings.enabled, faults: Arc::new(RwLock::new(settings.faults.clone())) })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn get(&self) -> Vec<FaultSettings> {
+        self.faults.read().unwrap().clone()
+    }
+
+    pub fn set(&self, faults: Vec<FaultSettings>) -> Result<(), String> {
+        validate(&faults)?;
+        *self.faults.write().unwrap() = faults;
+        Ok(())
+    }
+
+    // Those drawn for a request of `route` and `tenant`
+    fn draw(&self, route: &str, tenant: Option<&str>) -> Vec<FaultSettings> {
+        let mut rng = rand::thread_rng();
+        self.faults
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|fault| fault.route.as_deref().is_none_or(|scoped| scoped == route))
+            .filter(|fault| fault.tenant.as_deref().is_none_or(|scoped| Some(scoped) == tenant))
+            .filter(|fault| rng.gen_bool(fault.percent / 100.0))
+            .cloned()
+            .collect()
+    }
+}
+
+// Inside the tenant, which faults can be scoped to
+pub async fn layer(
+    axum::extract::State(chaos): axum::extract::State<Chaos>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let route = crate::middleware::matched_route(&request);
+    let tenant = request.extensions().get::<crate::middleware::tenant::Tenant>().map(|tenant| tenant.0.as_str());
+    let faults = chaos.draw(&route, tenant);
+    if faults.is_empty() {
+        return next.run(request).await;
+    }
+
+    let mut drop_connection = false;
+    for fault in faults {
+        match fault.kind {
+            FaultKind::Latency => {
+                tracing::warn!(chaos.fault = "latency", chaos.latency_ms = fault.latency_ms as i64, "Fault injected");
+                tokio::time::sleep(Duration::from_millis(fault.latency_ms)).await;
+            }
+            FaultKind::Error => {
+                tracing::warn!(chaos.fault = "error", chaos.status = i64::from(fault.status), "Fault injected");
+                let status = axum::http::StatusCode::from_u16(fault.status).unwrap_or(axum::http::StatusCode::SERVICE_UNAVAILABLE);
+                return (status, "injected fault").into_response();
+            }
+            FaultKind::DropDbConnection => {
+                tracing::warn!(chaos.fault = "drop_db_connection", "Fault injected");
+                drop_connection = true;
+            }
+        }
+    }
+    DROP_DB_CONNECTION.scope(AtomicBool::new(drop_connection), next.run(request)).await
+}
+
+// The pool's check of an idle connection before handing it out: an error closes it hard,
+// as a connection the server dropped would be
+#[cfg(feature = "mysql")]
+pub fn drop_db_connection() -> Result<bool, sqlx::Error> {
+    if DROP_DB_CONNECTION.try_with(|pending| pending.swap(false, std::sync::atomic::Ordering::Relaxed)).unwrap_or(false) {
+        tracing::warn!("Dropped a database connection");
+        return Err(sqlx::Error::Io(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "dropped by fault injection")));
+    }
+    Ok(true)
+}
+
+#[utoipa::path(get, path = "/admin/chaos", summary = "Faults being injected", responses(
+    (status = 200, body = Vec<FaultSettings>, description = "The faults"),
+    (status = 401, body = String, description = "No token"),
+    (status = 403, body = String, description = "The caller is not an admin"),
+))]
+pub async fn get_handler(axum::extract::State(chaos): axum::extract::State<Chaos>) -> crate::response::Traced<axum::Json<Vec<FaultSettings>>> {
+    crate::response::Traced::new(axum::Json(chaos.get()))
+}
+
+// Replaces the faults with those of the body, `[]` to stop; with `[chaos] enabled` only.
+// Admins only.
+#[utoipa::path(put, path = "/admin/chaos", summary = "Replaces the faults injected until the restart, [] to stop", request_body = Vec<FaultSettings>, responses(
+    (status = 200, body = Vec<FaultSettings>, description = "The new faults"),
+    (status = 400, body = String, description = "A percent or status is out of range, or a route doesn't start with /"),
+    (status = 401, body = String, description = "No token"),
+    (status = 403, body = String, description = "The caller is not an admin"),
+    (status = 409, body = String, description = "Fault injection is off"),
+))]
+pub async fn put_handler(
+    axum::extract::State(chaos): axum::extract::State<Chaos>,
+    crate::middleware::auth::RequireAdmin(admin): crate::middleware::auth::RequireAdmin,
+    axum::Json(faults): axum::Json<Vec<FaultSettings>>,
+) -> axum::response::Response {
+    if !chaos.enabled() {
+        return (axum::http::StatusCode::CONFLICT, "fault injection is off, see [chaos] enabled").into_response();
+    }
+    if let Err(e) = chaos.set(faults) {
+        return (axum::http::StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    crate::audit::audit!(chaos.faults = ?chaos.get(), enduser.id = admin.id, "Fault injection changed at runtime");
+    axum::Json(chaos.get()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+
+    #[tokio::test]
+    async fn injects_the_faults_of_the_route() {
+        let telemetry = crate::test_support::init();
+
+        let chaos = Chaos::new(&ChaosSettings {
+            enabled: true,
+            faults: vec![
+                FaultSettings { kind: FaultKind::Error, route: Some("/items".to_string()), ..Default::default() },
+                FaultSettings { kind: FaultKind::Latency, tenant: Some("acme".to_string()), ..Default::default() },
+            ],
+        })
+        .unwrap();
+        let app = axum::Router::new()
+            .route("/items", axum::routing::get(|| async {}))
+            .route("/jobs", axum::routing::get(|| async {}))
+            .layer(axum::middleware::from_fn_with_state(chaos.clone(), layer));
+        let get = |uri: &'static str| {
+            let request = axum::http::Request::get(uri).body(axum::body::Body::empty()).unwrap();
+            tower::ServiceExt::oneshot(app.clone(), request).instrument(tracing::info_span!("request", otel.name = uri))
+        };
+
+        assert_eq!(get("/items").await.unwrap().status(), axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        // Neither the route nor the tenant of a fault
+        assert_eq!(get("/jobs").await.unwrap().status(), axum::http::StatusCode::OK);
+
+        let spans = telemetry.spans();
+        let injected = spans.assert_span_exists("/items").span();
+        assert_eq!(injected.events.iter().map(|event| event.name.to_string()).collect::<Vec<_>>(), ["Fault injected"]);
+        assert!(spans.assert_span_exists("/jobs").span().events.is_empty());
+
+        assert!(chaos.set(vec![FaultSettings { percent: 150.0, ..Default::default() }]).is_err());
+        chaos.set(Vec::new()).unwrap();
+        assert_eq!(get("/items").await.unwrap().status(), axum::http::StatusCode::OK);
+    }
+}
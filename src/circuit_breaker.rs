@@ -0,0 +1,264 @@
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use opentelemetry::KeyValue;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::CircuitBreakerSettings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl State {
+    fn as_str(self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half_open",
+        }
+    }
+
+    // Gauge value, higher is worse
+    fn as_gauge(self) -> i64 {
+        match self {
+            State::Closed => 0,
+            State::HalfOpen => 1,
+            State::Open => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    // The call was not attempted because the circuit is open
+    Open,
+    Inner(E),
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Open => write!(f, "circuit breaker is open"),
+            Error::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for Error<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Open => None,
+            Error::Inner(e) => Some(e),
+        }
+    }
+}
+
+struct Inner {
+    state: State,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    // Only one request probes a half-open circuit
+    probing: bool,
+    // Of the state, bumped by every transition; a call's result only counts in the state it
+    // was let through in
+    generation: u64,
+}
+
+// A call let through, by the state it was in
+#[derive(Clone, Copy)]
+struct Ticket {
+    generation: u64,
+    probe: bool,
+}
+
+// Stops calling a dependency after consecutive failures, and lets a single probe through
+// once the open period has passed to find out whether it recovered
+pub struct CircuitBreaker {
+    name: &'static str,
+    settings: CircuitBreakerSettings,
+    inner: Mutex<Inner>,
+    state_gauge: opentelemetry::metrics::Gauge<i64>,
+    short_circuits: opentelemetry::metrics::Counter<u64>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, settings: CircuitBreakerSettings) -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let state_gauge = meter
+            .i64_gauge("circuit_breaker.state")
+            .with_description("Circuit breaker state: 0 closed, 1 half open, 2 open")
+            .init();
+        let short_circuits = meter
+            .u64_counter("circuit_breaker.short_circuits")
+            .with_description("Calls rejected without being attempted because the circuit was open")
+            .init();
+        state_gauge.record(State::Closed.as_gauge(), &[KeyValue::new("dependency", name)]);
+
+        Self {
+            name,
+            settings,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                probing: false,
+                generation: 0,
+            }),
+            state_gauge,
+            short_circuits,
+        }
+    }
+
+    // Current state as reported by the health details endpoint
+    pub fn state_name(&self) -> &'static str {
+        self.inner.lock().unwrap().state.as_str()
+    }
+
+    fn transition(&self, inner: &mut Inner, to: State) {
+        if inner.state == to {
+            return;
+        }
+        tracing::warn!(
+            circuit_breaker.dependency = self.name,
+            circuit_breaker.from = inner.state.as_str(),
+            circuit_breaker.to = to.as_str(),
+            circuit_breaker.consecutive_failures = inner.consecutive_failures,
+            "Circuit breaker state changed"
+        );
+        inner.state = to;
+        inner.generation += 1;
+        inner.opened_at = (to == State::Open).then(Instant::now);
+        self.state_gauge.record(to.as_gauge(), &[KeyValue::new("dependency", self.name)]);
+    }
+
+    // Whether a call may go ahead right now
+    fn try_acquire(&self) -> Option<Ticket> {
+        let mut inner = self.inner.lock().unwrap();
+        let probe = match inner.state {
+            State::Closed => false,
+            State::Open => {
+                let open_for = Duration::from_secs(self.settings.open_secs);
+                if inner.opened_at.is_none_or(|at| at.elapsed() < open_for) {
+                    return None;
+                }
+                self.transition(&mut inner, State::HalfOpen);
+                true
+            }
+            State::HalfOpen if inner.probing => return None,
+            State::HalfOpen => true,
+        };
+        inner.probing |= probe;
+        Some(Ticket { generation: inner.generation, probe })
+    }
+
+    // The probe of the current state being over, another may go
+    fn release(inner: &mut Inner, ticket: Ticket) {
+        if ticket.probe && ticket.generation == inner.generation {
+            inner.probing = false;
+        }
+    }
+
+    fn on_result(&self, ticket: Ticket, failed: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        Self::release(&mut inner, ticket);
+        // Let through before the state last changed, a late success can't close an open
+        // circuit nor a late failure count against a recovered one
+        if ticket.generation != inner.generation {
+            return;
+        }
+
+        if failed {
+            inner.consecutive_failures += 1;
+            if inner.state == State::HalfOpen || inner.consecutive_failures >= self.settings.failure_threshold {
+                self.transition(&mut inner, State::Open);
+            }
+        } else {
+            inner.consecutive_failures = 0;
+            self.transition(&mut inner, State::Closed);
+        }
+    }
+
+    // Runs `call` unless the circuit is open; `is_failure` decides which errors count
+    // against the dependency (a bad query is not an outage)
+    pub async fn call<T, E, F>(&self, is_failure: impl Fn(&E) -> bool, call: F) -> Result<T, Error<E>>
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        let span = tracing::Span::current();
+
+        let Some(ticket) = self.try_acquire() else {
+            span.set_attribute("circuit_breaker.short_circuited", true);
+            self.short_circuits.add(1, &[KeyValue::new("dependency", self.name)]);
+            return Err(Error::Open);
+        };
+
+        let mut attempt = Attempt { breaker: self, ticket, finished: false };
+        let result = call.await;
+        attempt.finished = true;
+
+        self.on_result(ticket, matches!(&result, Err(e) if is_failure(e)));
+        result.map_err(Error::Inner)
+    }
+}
+
+// Releases a half-open probe whose caller was cancelled, so the circuit can't get stuck
+struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    ticket: Ticket,
+    finished: bool,
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            CircuitBreaker::release(&mut self.breaker.inner.lock().unwrap(), self.ticket);
+        }
+    }
+}
+
+// Errors which say the database is unavailable, rather than that the query was wrong
+pub fn is_db_unavailable(e: &sqlx::Error) -> bool {
+    matches!(
+        e,
+        sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::Protocol(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_late_success_leaves_the_circuit_open() {
+        let breaker = CircuitBreaker::new("test", CircuitBreakerSettings { failure_threshold: 1, open_secs: 60 });
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+
+        // Let through while closed, it succeeds once another call has opened the circuit
+        let slow = breaker.call(|_: &()| true, async {
+            released.await.unwrap();
+            Ok(())
+        });
+        let failing = async {
+            tokio::task::yield_now().await;
+            let failed = breaker.call(|_: &()| true, async { Err::<(), _>(()) }).await;
+            release.send(()).unwrap();
+            failed
+        };
+        let (slow, failed) = tokio::join!(slow, failing);
+        assert!(slow.is_ok());
+        assert!(matches!(failed, Err(Error::Inner(()))));
+
+        assert_eq!(breaker.state_name(), "open");
+        assert!(matches!(breaker.call(|_: &()| true, async { Ok(()) }).await, Err(Error::Open)));
+    }
+}
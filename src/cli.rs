@@ -0,0 +1,41 @@
+#[derive(Debug, clap::Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Run the HTTP server (the default)
+    Serve,
+    /// Apply pending database migrations and exit
+    #[cfg(feature = "mysql")]
+    Migrate,
+    /// Apply migrations and insert demo sessions
+    #[cfg(feature = "mysql")]
+    Seed {
+        /// Number of sessions to insert
+        #[arg(long, default_value_t = 100)]
+        sessions: u32,
+    },
+    /// Check the config, the OTLP endpoint, MySQL and its migrations, then exit without serving
+    Preflight,
+    /// Send synthetic traffic to a running server, or emit synthetic spans directly
+    Loadgen(crate::loadgen::Args),
+    /// Time requests through the middleware stack with telemetry off, logging only and fully exported
+    BenchOverhead(crate::bench::Args),
+}
+
+impl Command {
+    // Short commands recording a handful of spans, which they export as the spans end
+    pub fn is_one_shot(&self) -> bool {
+        match self {
+            Command::Serve | Command::Loadgen(_) | Command::BenchOverhead(_) => false,
+            // Batched and flushed at exit, the collector being what may well be down
+            Command::Preflight => false,
+            #[cfg(feature = "mysql")]
+            Command::Migrate | Command::Seed { .. } => true,
+        }
+    }
+}
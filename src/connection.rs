@@ -0,0 +1,202 @@
+// Connections open for hours, server-sent event streams say, whose request span lasts as
+// long: a backend capping span duration drops or cuts it, and nothing shows until it ends.
+// `Connection` puts a "Connection heartbeat" event on it every `heartbeat_secs` with what the
+// interval carried, messages, bytes and the worst lag behind their source, and with
+// `interval_spans` also a child span per interval holding the same, exported as the
+// interval ends. A last event sums the connection up when it is dropped.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::ConnectionSettings;
+
+#[derive(Default)]
+struct Counts {
+    messages: u64,
+    bytes: u64,
+    // Lost before they were sent, by a slow client say
+    dropped: u64,
+    max_lag: Duration,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+        self.dropped += other.dropped;
+        self.max_lag = self.max_lag.max(other.max_lag);
+    }
+}
+
+#[derive(Default)]
+struct Interval {
+    index: u64,
+    counts: Counts,
+    span: Option<tracing::Span>,
+}
+
+pub struct Connection {
+    kind: &'static str,
+    span: tracing::Span,
+    started: Instant,
+    interval_spans: bool,
+    interval: Mutex<Interval>,
+    // Of the intervals before the current one
+    totals: Mutex<Counts>,
+}
+
+impl Connection {
+    // On the current span, which should be the request's: a handler's ends with the handler
+    pub fn start(kind: &'static str, settings: &ConnectionSettings) -> Arc<Self> {
+        let span = tracing::Span::current();
+        let connection = Arc::new(Self {
+            kind,
+            interval_spans: settings.interval_spans,
+            interval: Mutex::default(),
+            totals: Mutex::default(),
+            started: Instant::now(),
+            span,
+        });
+        connection.interval.lock().unwrap().span = connection.interval_span(0);
+
+        let heartbeat = Duration::from_secs(settings.heartbeat_secs.max(1));
+        let beating = Arc::downgrade(&connection);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + heartbeat, heartbeat);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(connection) = beating.upgrade() else {
+                    return;
+                };
+                connection.beat();
+            }
+        });
+        connection
+    }
+
+    fn interval_span(&self, index: u64) -> Option<tracing::Span> {
+        self.interval_spans.then(|| {
+            tracing::info_span!(
+                parent: &self.span,
+                "connection interval",
+                otel.name = format!("{} interval", self.kind),
+                connection.interval = index as i64,
+                connection.messages = tracing::field::Empty,
+                connection.bytes = tracing::field::Empty,
+                connection.dropped = tracing::field::Empty,
+                connection.max_lag_ms = tracing::field::Empty,
+            )
+        })
+    }
+
+    // A message sent, `lag` after its source produced it
+    pub fn sent(&self, bytes: usize, lag: Duration) {
+        let mut interval = self.interval.lock().unwrap();
+        interval.counts.messages += 1;
+        interval.counts.bytes += bytes as u64;
+        interval.counts.max_lag = interval.counts.max_lag.max(lag);
+    }
+
+    pub fn dropped(&self, messages: u64) {
+        self.interval.lock().unwrap().counts.dropped += messages;
+    }
+
+    // The span of the interval, to put the work of a message under; the connection's without
+    // `interval_spans`
+    pub fn interval(&self) -> tracing::Span {
+        self.interval.lock().unwrap().span.clone().unwrap_or_else(|| self.span.clone())
+    }
+
+    // Ends the interval, its span included, and starts the next
+    fn beat(&self) {
+        let (index, counts, span) = {
+            let mut interval = self.interval.lock().unwrap();
+            let index = interval.index + 1;
+            let next = Interval { index, counts: Counts::default(), span: self.interval_span(index) };
+            let ended = std::mem::replace(&mut *interval, next);
+            (ended.index, ended.counts, ended.span)
+        };
+        self.totals.lock().unwrap().add(&counts);
+
+        if let Some(span) = span {
+            record(&span, &counts);
+        }
+        tracing::info!(
+            parent: &self.span,
+            connection.interval = index as i64,
+            connection.messages = counts.messages as i64,
+            connection.bytes = counts.bytes as i64,
+            connection.dropped = counts.dropped as i64,
+            connection.max_lag_ms = counts.max_lag.as_millis() as i64,
+            connection.age_s = self.started.elapsed().as_secs() as i64,
+            "Connection heartbeat"
+        );
+    }
+}
+
+fn record(span: &tracing::Span, counts: &Counts) {
+    span.record("connection.messages", counts.messages as i64);
+    span.record("connection.bytes", counts.bytes as i64);
+    span.record("connection.dropped", counts.dropped as i64);
+    span.record("connection.max_lag_ms", counts.max_lag.as_millis() as i64);
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let interval = std::mem::take(&mut *self.interval.lock().unwrap());
+        if let Some(span) = interval.span {
+            record(&span, &interval.counts);
+        }
+        let mut totals = std::mem::take(&mut *self.totals.lock().unwrap());
+        totals.add(&interval.counts);
+        tracing::info!(
+            parent: &self.span,
+            connection.messages = totals.messages as i64,
+            connection.bytes = totals.bytes as i64,
+            connection.dropped = totals.dropped as i64,
+            connection.max_lag_ms = totals.max_lag.as_millis() as i64,
+            connection.age_s = self.started.elapsed().as_secs() as i64,
+            "Connection closed"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn heartbeats_split_the_connection() {
+        let telemetry = crate::test_support::init();
+        // Beaten by hand rather than waited for
+        let settings = ConnectionSettings { heartbeat_secs: 3600, interval_spans: true };
+
+        tracing::Instrument::instrument(
+            async {
+                let connection = Connection::start("events", &settings);
+                connection.sent(100, Duration::from_millis(5));
+                connection.sent(50, Duration::from_millis(20));
+                connection.beat();
+                connection.dropped(3);
+            },
+            tracing::info_span!("request"),
+        )
+        .await;
+
+        let spans = telemetry.spans();
+        spans
+            .assert_span_exists("events interval")
+            .child_of("request")
+            .with_attribute("connection.interval", 0i64)
+            .with_attribute("connection.messages", 2i64)
+            .with_attribute("connection.bytes", 150i64)
+            .with_attribute("connection.max_lag_ms", 20i64);
+        let request = spans.assert_span_exists("request").span();
+        let events: Vec<_> = request.events.iter().map(|event| event.name.to_string()).collect();
+        assert_eq!(events, ["Connection heartbeat", "Connection closed"]);
+        let closed = &request.events[1];
+        let dropped = closed.attributes.iter().find(|kv| kv.key.as_str() == "connection.dropped").map(|kv| kv.value.clone());
+        assert_eq!(dropped, Some(3i64.into()));
+    }
+}
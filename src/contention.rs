@@ -0,0 +1,127 @@
+// tokio locks which report how long callers waited for them, so contention shows up in
+// traces as an event where the time went rather than as an unexplained gap
+
+use std::time::{Duration, Instant};
+
+use opentelemetry::KeyValue;
+
+struct Contention {
+    name: &'static str,
+    threshold: Duration,
+    wait: opentelemetry::metrics::Histogram<f64>,
+}
+
+impl Contention {
+    fn new(name: &'static str, threshold: Duration) -> Self {
+        let wait = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .f64_histogram("sync.wait")
+            .with_unit("ms")
+            .with_description("Time spent waiting to acquire a lock or semaphore, by name")
+            .init();
+
+        Self { name, threshold, wait }
+    }
+
+    // Every acquisition goes into the histogram, only slow ones become span events
+    fn record(&self, waited: Duration) {
+        let waited_ms = waited.as_secs_f64() * 1000.0;
+        self.wait.record(waited_ms, &[KeyValue::new("sync.name", self.name)]);
+
+        if waited >= self.threshold {
+            tracing::info!(sync.name = self.name, sync.wait_ms = waited_ms, "Waited for {}", self.name);
+        }
+    }
+}
+
+// Only the database health check uses it so far
+#[cfg_attr(not(feature = "mysql"), allow(dead_code))]
+pub struct TracedMutex<T> {
+    inner: tokio::sync::Mutex<T>,
+    contention: Contention,
+}
+
+#[cfg_attr(not(feature = "mysql"), allow(dead_code))]
+impl<T> TracedMutex<T> {
+    pub fn new(name: &'static str, threshold: Duration, value: T) -> Self {
+        Self {
+            inner: tokio::sync::Mutex::new(value),
+            contention: Contention::new(name, threshold),
+        }
+    }
+
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, T> {
+        if let Ok(guard) = self.inner.try_lock() {
+            self.contention.record(Duration::ZERO);
+            return guard;
+        }
+
+        let started = Instant::now();
+        let guard = self.inner.lock().await;
+        self.contention.record(started.elapsed());
+        guard
+    }
+}
+
+pub struct TracedSemaphore {
+    inner: tokio::sync::Semaphore,
+    contention: Contention,
+}
+
+impl TracedSemaphore {
+    pub fn new(name: &'static str, threshold: Duration, permits: usize) -> Self {
+        Self {
+            inner: tokio::sync::Semaphore::new(permits),
+            contention: Contention::new(name, threshold),
+        }
+    }
+
+    // Not recorded, there is no wait to speak of
+    pub fn try_acquire(&self) -> Result<tokio::sync::SemaphorePermit<'_>, tokio::sync::TryAcquireError> {
+        self.inner.try_acquire()
+    }
+
+    pub async fn acquire(&self) -> Result<tokio::sync::SemaphorePermit<'_>, tokio::sync::AcquireError> {
+        let started = Instant::now();
+        let permit = self.inner.acquire().await;
+        self.contention.record(started.elapsed());
+        permit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+
+    #[tokio::test]
+    async fn slow_acquisitions_are_span_events() {
+        let telemetry = crate::test_support::init();
+        let mutex = std::sync::Arc::new(TracedMutex::new("test mutex", Duration::from_millis(20), ()));
+        let semaphore = TracedSemaphore::new("test semaphore", Duration::from_millis(20), 1);
+
+        async { drop(mutex.lock().await) }.instrument(tracing::info_span!("uncontended")).await;
+
+        let guard = mutex.lock().await;
+        let permit = semaphore.try_acquire().unwrap();
+        let waiting = {
+            let mutex = mutex.clone();
+            tokio::spawn(async move { drop(mutex.lock().await) }.instrument(tracing::info_span!("contended")))
+        };
+        let acquiring = async { drop(semaphore.acquire().await.unwrap()) }.instrument(tracing::info_span!("queued"));
+        let releasing = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop((guard, permit));
+        };
+        tokio::join!(acquiring, releasing);
+        waiting.await.unwrap();
+
+        let spans = telemetry.spans();
+        assert!(spans.assert_span_exists("uncontended").span().events.is_empty());
+        for (span, name) in [("contended", "test mutex"), ("queued", "test semaphore")] {
+            let events = &spans.assert_span_exists(span).span().events;
+            assert_eq!(events.len(), 1, "{span}");
+            assert_eq!(events[0].name, format!("Waited for {name}"));
+            assert!(events[0].attributes.iter().any(|kv| kv.key.as_str() == "sync.wait_ms" && kv.value.to_string().parse::<f64>().unwrap() >= 20.0));
+        }
+    }
+}
@@ -0,0 +1,286 @@
+// Request-scoped batching of lookups by key, the fix for N+1 queries: a handler makes one
+// `Loader` and calls `load` for every key it needs, concurrently (`join_all`, say), and the
+// keys asked for within the same tick go to `Batch::load` together, one `WHERE id IN (…)`
+// query rather than one per key. A key is loaded once per loader, repeats wait for or reuse
+// the first. Each batch has a span with how many loads its keys stood for.
+
+// Only the items so far
+#![cfg_attr(not(feature = "mysql"), allow(dead_code))]
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use tracing::Instrument;
+
+// Keys past this many go in further batches, run one after the other so a request holds
+// no more than one connection of the pool for them
+pub const MAX_BATCH: usize = 100;
+
+#[axum::async_trait]
+pub trait Batch: Send + Sync {
+    type Key: Eq + Hash + Clone + Send + Sync;
+    type Value: Clone + Send;
+    // Given to every load of the batch's keys
+    type Error: Clone + Send;
+
+    // What is loaded, for the span name
+    fn name(&self) -> &'static str;
+
+    // The values of those of `keys` which exist
+    async fn load(&self, keys: &[Self::Key]) -> Result<HashMap<Self::Key, Self::Value>, Self::Error>;
+}
+
+type Loaded<B> = Result<Option<<B as Batch>::Value>, <B as Batch>::Error>;
+
+struct State<B: Batch> {
+    // Waiting for the next batch
+    queued: Vec<B::Key>,
+    // Of the queued keys and those being loaded
+    waiters: HashMap<B::Key, Vec<tokio::sync::oneshot::Sender<Loaded<B>>>>,
+    loaded: HashMap<B::Key, Option<B::Value>>,
+    // `load` calls since the last batch started, for its dedup ratio
+    loads: usize,
+    // Whether a call is about to start the next batch
+    leading: bool,
+}
+
+pub struct Loader<B: Batch> {
+    batch: B,
+    state: Mutex<State<B>>,
+}
+
+impl<B: Batch> Loader<B> {
+    pub fn new(batch: B) -> Self {
+        Self {
+            batch,
+            state: Mutex::new(State { queued: Vec::new(), waiters: HashMap::new(), loaded: HashMap::new(), loads: 0, leading: false }),
+        }
+    }
+
+    // None when the key doesn't exist; every load of a key in a failed batch fails alike
+    pub async fn load(&self, key: B::Key) -> Loaded<B> {
+        loop {
+            let (sender, receiver) = tokio::sync::oneshot::channel();
+            let lead = {
+                let mut state = self.state.lock().unwrap();
+                if let Some(value) = state.loaded.get(&key) {
+                    return Ok(value.clone());
+                }
+                state.loads += 1;
+                match state.waiters.entry(key.clone()) {
+                    std::collections::hash_map::Entry::Occupied(mut waiting) => waiting.get_mut().push(sender),
+                    std::collections::hash_map::Entry::Vacant(entry) => {
+                        entry.insert(vec![sender]);
+                        state.queued.push(key.clone());
+                    }
+                }
+                !std::mem::replace(&mut state.leading, true)
+            };
+
+            // The first caller of the tick runs the batch for the others, in its task rather
+            // than a spawned one, so the query counts against the request and its span is the
+            // handler's child
+            if lead {
+                let leading = Leading { state: &self.state, taken: false };
+                tokio::task::yield_now().await;
+                self.dispatch(leading).await;
+            }
+            // Without a result the batch was dropped unfinished, with the load running it
+            if let Ok(loaded) = receiver.await {
+                return loaded;
+            }
+        }
+    }
+
+    async fn dispatch(&self, mut leading: Leading<'_, B>) {
+        let (keys, loads) = leading.take();
+        if keys.is_empty() {
+            return;
+        }
+        let mut abandoned = Abandoned { state: &self.state, keys: &keys, finished: false };
+
+        let span = tracing::info_span!(
+            "dataloader batch",
+            otel.name = format!("load {}", self.batch.name()),
+            dataloader.keys = keys.len() as i64,
+            dataloader.loads = loads as i64,
+            // Of the loads, those another load's key saved a lookup for
+            dataloader.dedup_ratio = (loads - keys.len()) as f64 / loads.max(1) as f64,
+            dataloader.batches = keys.len().div_ceil(MAX_BATCH) as i64,
+        );
+        let results = async {
+            let mut results = Vec::new();
+            for chunk in keys.chunks(MAX_BATCH) {
+                results.push((chunk, self.batch.load(chunk).await));
+            }
+            results
+        }
+        .instrument(span)
+        .await;
+
+        let mut state = self.state.lock().unwrap();
+        for (chunk, result) in results {
+            for key in chunk {
+                let loaded = match &result {
+                    Ok(values) => Ok(values.get(key).cloned()),
+                    Err(e) => Err(e.clone()),
+                };
+                if let Ok(value) = &loaded {
+                    state.loaded.insert(key.clone(), value.clone());
+                }
+                for waiter in state.waiters.remove(key).unwrap_or_default() {
+                    let _ = waiter.send(loaded.clone());
+                }
+            }
+        }
+        abandoned.finished = true;
+    }
+}
+
+// The call which is to start the next batch; dropped before it took the queued keys, the
+// next call leads instead, and the keys' waiters load them again
+struct Leading<'a, B: Batch> {
+    state: &'a Mutex<State<B>>,
+    taken: bool,
+}
+
+impl<B: Batch> Leading<'_, B> {
+    // The keys of the next batch, and the loads they stand for
+    fn take(&mut self) -> (Vec<B::Key>, usize) {
+        let mut state = self.state.lock().unwrap();
+        state.leading = false;
+        self.taken = true;
+        (std::mem::take(&mut state.queued), std::mem::take(&mut state.loads))
+    }
+}
+
+impl<B: Batch> Drop for Leading<'_, B> {
+    fn drop(&mut self) {
+        if self.taken {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        state.leading = false;
+        state.loads = 0;
+        for key in std::mem::take(&mut state.queued) {
+            state.waiters.remove(&key);
+        }
+    }
+}
+
+// Drops the waiters of a batch which was itself dropped, so they load their keys again
+struct Abandoned<'a, B: Batch> {
+    state: &'a Mutex<State<B>>,
+    keys: &'a [B::Key],
+    finished: bool,
+}
+
+impl<B: Batch> Drop for Abandoned<'_, B> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        for key in self.keys {
+            state.waiters.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Squares, remembering the batches asked for
+    #[derive(Default)]
+    struct Squares(Mutex<Vec<Vec<u32>>>);
+
+    #[axum::async_trait]
+    impl Batch for Squares {
+        type Key = u32;
+        type Value = u32;
+        type Error = String;
+
+        fn name(&self) -> &'static str {
+            "squares"
+        }
+
+        async fn load(&self, keys: &[u32]) -> Result<HashMap<u32, u32>, String> {
+            self.0.lock().unwrap().push(keys.to_vec());
+            Ok(keys.iter().filter(|key| **key != 0).map(|key| (*key, key * key)).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesces_loads_into_one_batch() {
+        let telemetry = crate::test_support::init();
+        let loader = Loader::new(Squares::default());
+
+        let loaded = tracing::Instrument::instrument(
+            futures_util::future::join_all([3, 1, 3, 0, 2].map(|key| loader.load(key))),
+            tracing::info_span!("handler"),
+        )
+        .await;
+        let loaded: Vec<_> = loaded.into_iter().map(Result::unwrap).collect();
+        assert_eq!(loaded, [Some(9), Some(1), Some(9), None, Some(4)]);
+        // Loaded before, not batched again
+        assert_eq!(loader.load(2).await, Ok(Some(4)));
+        assert_eq!(*loader.batch.0.lock().unwrap(), [vec![3, 1, 0, 2]]);
+
+        let spans = telemetry.spans();
+        let batch = spans.assert_span_exists("load squares").with_attribute("dataloader.keys", 4i64).with_attribute("dataloader.loads", 5i64).child_of("handler");
+        assert_eq!(batch.attribute("dataloader.dedup_ratio"), Some(&opentelemetry::Value::F64(0.2)));
+    }
+
+    #[tokio::test]
+    async fn a_load_dropped_before_its_batch_leaves_it_to_the_others() {
+        use futures_util::FutureExt;
+
+        let loader = Loader::new(Squares::default());
+        let mut first = Box::pin(loader.load(1));
+        let mut second = Box::pin(loader.load(2));
+        // The first leads, and is dropped yielding to the second
+        assert!((&mut first).now_or_never().is_none());
+        assert!((&mut second).now_or_never().is_none());
+        drop(first);
+
+        let loaded = tokio::time::timeout(std::time::Duration::from_secs(1), second).await;
+        assert_eq!(loaded, Ok(Ok(Some(4))));
+        assert_eq!(*loader.batch.0.lock().unwrap(), [vec![2]]);
+    }
+
+    #[tokio::test]
+    async fn runs_the_batches_of_many_keys_one_after_the_other() {
+        // Keys in flight, and the most there were at once
+        #[derive(Default)]
+        struct Slow(Mutex<(usize, usize)>);
+
+        #[axum::async_trait]
+        impl Batch for Slow {
+            type Key = usize;
+            type Value = usize;
+            type Error = String;
+
+            fn name(&self) -> &'static str {
+                "slow"
+            }
+
+            async fn load(&self, keys: &[usize]) -> Result<HashMap<usize, usize>, String> {
+                {
+                    let mut in_flight = self.0.lock().unwrap();
+                    in_flight.0 += 1;
+                    in_flight.1 = in_flight.1.max(in_flight.0);
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                self.0.lock().unwrap().0 -= 1;
+                Ok(keys.iter().map(|key| (*key, *key)).collect())
+            }
+        }
+
+        let loader = Loader::new(Slow::default());
+        let loaded = futures_util::future::join_all((0..MAX_BATCH * 3).map(|key| loader.load(key))).await;
+        assert!(loaded.iter().all(Result::is_ok));
+        assert_eq!(loader.batch.0.lock().unwrap().1, 1);
+    }
+}
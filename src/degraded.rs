@@ -0,0 +1,125 @@
+// Degraded mode, `[degraded]`: while the database is down, reads answer with the copy of
+// their last successful result rather than a 503. Each read keeps its copy in a `LastKnown`
+// and falls back to it when the circuit is open or the database unavailable; a response
+// served that way is `degraded=true` on the handler's span and the request span, carries a
+// `Degraded: true` header with the copy's `Age`, and `Cache-Control: no-store` so the response
+// cache doesn't replay it once the database is back, and is counted in
+// `http.server.degraded_responses`. Writes have nothing to fall back to and fail as before.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use opentelemetry::KeyValue;
+
+use crate::config::DegradedSettings;
+
+pub const HEADER: &str = "degraded";
+
+fn served() -> &'static opentelemetry::metrics::Counter<u64> {
+    static SERVED: std::sync::OnceLock<opentelemetry::metrics::Counter<u64>> = std::sync::OnceLock::new();
+    SERVED.get_or_init(|| {
+        opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .u64_counter("http.server.degraded_responses")
+            .with_description("Reads answered from their last known copy while the database was down, by kind")
+            .init()
+    })
+}
+
+// The last successful result of one kind of read, by key
+pub struct LastKnown<T> {
+    kind: &'static str,
+    enabled: bool,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, (Instant, T)>>,
+}
+
+impl<T: Clone> LastKnown<T> {
+    pub fn new(kind: &'static str, settings: &DegradedSettings) -> Self {
+        Self {
+            kind,
+            enabled: settings.enabled,
+            max_entries: settings.max_entries.max(1),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Whether there's a copy to fall back to at all
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().unwrap().is_empty()
+    }
+
+    pub fn keep(&self, key: String, value: &T) {
+        if !self.enabled {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let oldest = entries.iter().min_by_key(|(_, (at, _))| *at).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, (Instant::now(), value.clone()));
+    }
+
+    // The copy to answer with, marked on the current span, when there is one
+    pub fn fallback(&self, key: &str) -> Option<(T, Stale)> {
+        if !self.enabled {
+            return None;
+        }
+        let (at, value) = self.entries.lock().unwrap().get(key).cloned()?;
+        let stale = Stale { age: at.elapsed() };
+        let span = tracing::Span::current();
+        span.record("degraded", true);
+        span.record("degraded.age_ms", stale.age.as_secs_f64() * 1000.0);
+        tracing::warn!(degraded.kind = self.kind, "Database unavailable, serving the last known copy");
+        served().add(1, &[KeyValue::new("degraded.kind", self.kind)]);
+        Some((value, stale))
+    }
+}
+
+// A response part: the response is a copy, this old
+#[derive(Debug, Clone, Copy)]
+pub struct Stale {
+    age: Duration,
+}
+
+impl axum::response::IntoResponseParts for Stale {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts(self, mut parts: axum::response::ResponseParts) -> Result<axum::response::ResponseParts, Self::Error> {
+        parts.headers_mut().insert(HEADER, axum::http::HeaderValue::from_static("true"));
+        parts.headers_mut().insert(axum::http::header::AGE, axum::http::HeaderValue::from(self.age.as_secs()));
+        parts.headers_mut().insert(axum::http::header::CACHE_CONTROL, axum::http::HeaderValue::from_static("no-store"));
+        Ok(parts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_the_last_copy_kept() {
+        let telemetry = crate::test_support::init();
+        let settings = DegradedSettings { enabled: true, max_entries: 1 };
+        let items = LastKnown::new("item", &settings);
+        items.keep("1".to_string(), &"first");
+        items.keep("2".to_string(), &"second");
+
+        let span = tracing::info_span!("handler", degraded = tracing::field::Empty, degraded.age_ms = tracing::field::Empty);
+        let (first, second) = span.in_scope(|| (items.fallback("1"), items.fallback("2")));
+        assert!(first.is_none(), "evicted");
+        assert_eq!(second.map(|(value, _)| value), Some("second"));
+        drop(span);
+
+        let spans = telemetry.spans();
+        spans.assert_span_exists("handler").with_attribute("degraded", true).with_attribute_present("degraded.age_ms");
+        assert!(LastKnown::<&str>::new("item", &DegradedSettings::default()).fallback("1").is_none());
+    }
+}
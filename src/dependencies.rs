@@ -0,0 +1,163 @@
+// Whether what the service depends on is up, so a dashboard tells their outage from our
+// bug at a glance. Probes run every `probe_interval_secs`, untraced: `SELECT 1` on MySQL
+// and a GET of the downstream's `probe_path`; the collector is up while span exports
+// succeed. Exported as the `dependency.up` gauge, 1 or 0, and `dependency.last_error`,
+// seconds since the epoch of the last failure, both by `dependency`.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use opentelemetry::KeyValue;
+
+use crate::config::{DependencySettings, Settings};
+use crate::telemetry::ExporterHealth;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct State {
+    up: bool,
+    last_error_unix_secs: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct Dependencies {
+    states: Mutex<BTreeMap<&'static str, State>>,
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl Dependencies {
+    // `failed_at` defaults to now for a dependency found down
+    fn set(&self, dependency: &'static str, up: bool, failed_at: Option<u64>) {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(dependency).or_insert(State { up, last_error_unix_secs: None });
+        if state.up != up {
+            match up {
+                true => tracing::info!(dependency, "Dependency is up again"),
+                false => tracing::warn!(dependency, "Dependency is down"),
+            }
+        }
+        state.up = up;
+        if !up || failed_at.is_some() {
+            state.last_error_unix_secs = failed_at.or_else(|| Some(unix_secs()));
+        }
+    }
+
+    // Observable gauges reading the states; call after the meter provider is installed
+    pub fn register_metrics(self: &Arc<Self>) {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+
+        let dependencies = self.clone();
+        meter
+            .i64_observable_gauge("dependency.up")
+            .with_description("1 while the dependency answers its probe, 0 otherwise")
+            .with_callback(move |observer| {
+                for (name, state) in dependencies.states.lock().unwrap().iter() {
+                    observer.observe(i64::from(state.up), &[KeyValue::new("dependency", *name)]);
+                }
+            })
+            .init();
+
+        let dependencies = self.clone();
+        meter
+            .u64_observable_gauge("dependency.last_error")
+            .with_description("When the dependency last failed, in seconds since the epoch")
+            .with_unit("s")
+            .with_callback(move |observer| {
+                for (name, state) in dependencies.states.lock().unwrap().iter() {
+                    if let Some(at) = state.last_error_unix_secs {
+                        observer.observe(at, &[KeyValue::new("dependency", *name)]);
+                    }
+                }
+            })
+            .init();
+    }
+}
+
+// What to probe, the database added with `with_database`
+pub struct Probes {
+    interval: Duration,
+    exporter: Arc<ExporterHealth>,
+    downstream: reqwest::Client,
+    downstream_url: String,
+    #[cfg(feature = "mysql")]
+    database: Option<(sqlx::MySqlPool, Duration)>,
+}
+
+impl Probes {
+    pub fn new(settings: &Settings, exporter: Arc<ExporterHealth>) -> Result<Self, String> {
+        let DependencySettings { connect_timeout_ms, read_timeout_ms, .. } = settings.dependencies.http;
+        Ok(Self {
+            interval: Duration::from_secs(settings.dependencies.probe_interval_secs),
+            exporter,
+            downstream: reqwest::Client::builder()
+                .connect_timeout(Duration::from_millis(connect_timeout_ms))
+                .timeout(Duration::from_millis(read_timeout_ms))
+                .dns_resolver(Arc::new(crate::dns::Resolver(crate::dns::Origin::Traced)))
+                .build()
+                .map_err(|e| format!("can't build the probes' HTTP client: {e}"))?,
+            downstream_url: format!("{}{}", settings.downstream.base_url, settings.downstream.probe_path),
+            #[cfg(feature = "mysql")]
+            database: None,
+        })
+    }
+
+    #[cfg(feature = "mysql")]
+    pub fn with_database(mut self, pool: sqlx::MySqlPool, settings: &DependencySettings) -> Self {
+        self.database = Some((pool, Duration::from_millis(settings.read_timeout_ms)));
+        self
+    }
+
+    async fn probe(&self, dependencies: &Dependencies) {
+        #[cfg(feature = "mysql")]
+        if let Some((pool, timeout)) = &self.database {
+            let ping = tokio::time::timeout(*timeout, sqlx::query("SELECT 1").execute(pool)).await;
+            dependencies.set("mysql", matches!(ping, Ok(Ok(_))), None);
+        }
+
+        let response = self.downstream.get(&self.downstream_url).send().await;
+        dependencies.set("downstream", response.is_ok_and(|response| !response.status().is_server_error()), None);
+
+        // Span exports are probe enough
+        let failed_at = self.exporter.last_failure_ms().map(|ms| ms / 1000);
+        dependencies.set("collector", self.exporter.is_healthy(), failed_at);
+    }
+
+    // Probes until the process exits; not at all with `probe_interval_secs = 0`
+    pub fn spawn(self, dependencies: Arc<Dependencies>) {
+        if self.interval.is_zero() {
+            return;
+        }
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                self.probe(&dependencies).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn downstream_down_is_recorded() {
+        let mut settings = Settings::default();
+        settings.downstream.base_url = "http://127.0.0.1:1".to_string();
+        let dependencies = Dependencies::default();
+
+        Probes::new(&settings, Arc::default()).unwrap().probe(&dependencies).await;
+
+        let states = dependencies.states.lock().unwrap();
+        assert!(!states["downstream"].up);
+        assert!(states["downstream"].last_error_unix_secs.is_some());
+        assert!(states["collector"].up);
+        assert!(states["collector"].last_error_unix_secs.is_none());
+    }
+}
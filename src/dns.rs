@@ -0,0 +1,133 @@
+// DNS lookups of outbound connections, timed: the HTTP clients and the OTLP exporters
+// resolve through here rather than on their own. A lookup made for a traced call is a "DNS
+// lookup" event on its span, with the name, how long it took and the addresses it got, or
+// why it failed; one made for an exporter, which has no span to put it on, is logged, as a
+// warning when it failed or was slow. Both go into the `dns.lookup.duration` histogram. An
+// address given as an IP isn't looked up.
+
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use opentelemetry::KeyValue;
+
+// A lookup taking longer is logged as a warning, for the exporters
+const SLOW: Duration = Duration::from_millis(200);
+
+// Who a lookup is for, and where it goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    // An event on the current span
+    Traced,
+    // The log only: a span of its own would be exported through the lookup it records
+    #[cfg_attr(not(any(feature = "otlp-grpc", feature = "otlp-http")), allow(dead_code))]
+    Exporter,
+}
+
+fn duration() -> &'static opentelemetry::metrics::Histogram<f64> {
+    static DURATION: OnceLock<opentelemetry::metrics::Histogram<f64>> = OnceLock::new();
+    DURATION.get_or_init(|| {
+        opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .f64_histogram("dns.lookup.duration")
+            .with_unit("ms")
+            .with_description("Time resolving the hosts of outbound connections")
+            .init()
+    })
+}
+
+pub async fn lookup(host: &str, port: u16, origin: Origin) -> std::io::Result<Vec<SocketAddr>> {
+    let started = Instant::now();
+    let result = tokio::net::lookup_host((host, port)).await.map(Iterator::collect::<Vec<_>>);
+    let elapsed = started.elapsed();
+    let result = result.and_then(|addresses| match addresses.is_empty() {
+        true => Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("no addresses for {host}"))),
+        false => Ok(addresses),
+    });
+    record(host, elapsed, &result, origin);
+    result
+}
+
+fn record(host: &str, elapsed: Duration, result: &std::io::Result<Vec<SocketAddr>>, origin: Origin) {
+    let duration_ms = elapsed.as_secs_f64() * 1000.0;
+    let mut attributes = vec![KeyValue::new("dns.question.name", host.to_string())];
+    if let Err(e) = result {
+        attributes.push(KeyValue::new("error.type", format!("{:?}", e.kind())));
+    }
+    duration().record(duration_ms, &attributes);
+
+    let answers = result.as_ref().map(|addresses| addresses.iter().map(|address| address.ip().to_string()).collect::<Vec<_>>().join(","));
+    match (origin, answers) {
+        (Origin::Traced, Ok(answers)) => {
+            tracing::info!(dns.question.name = host, dns.lookup.duration_ms = duration_ms, dns.answers = answers, "DNS lookup")
+        }
+        (Origin::Traced, Err(e)) => tracing::warn!(dns.question.name = host, dns.lookup.duration_ms = duration_ms, error = %e, "DNS lookup failed"),
+        (Origin::Exporter, Ok(answers)) if elapsed >= SLOW => {
+            tracing::warn!(dns.question.name = host, dns.lookup.duration_ms = duration_ms, dns.answers = answers, "Slow DNS lookup for the exporter")
+        }
+        (Origin::Exporter, Ok(answers)) => {
+            tracing::debug!(dns.question.name = host, dns.lookup.duration_ms = duration_ms, dns.answers = answers, "DNS lookup for the exporter")
+        }
+        (Origin::Exporter, Err(e)) => {
+            tracing::warn!(dns.question.name = host, dns.lookup.duration_ms = duration_ms, error = %e, "DNS lookup for the exporter failed")
+        }
+    }
+}
+
+// reqwest's resolver hook; it doesn't ask for IP addresses
+#[derive(Debug, Clone, Copy)]
+pub struct Resolver(pub Origin);
+
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let origin = self.0;
+        Box::pin(async move {
+            // reqwest puts the port in itself
+            let addresses = lookup(name.as_str(), 0, origin).await?;
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+// A TCP connection to the host and port of `uri`, resolved here, for connectors of our
+// own; the first address which takes it
+#[cfg(feature = "otlp-grpc")]
+pub async fn connect(uri: &axum::http::Uri, origin: Origin) -> std::io::Result<tokio::net::TcpStream> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("no host in {uri}"));
+    let host = uri.host().ok_or_else(invalid)?.trim_start_matches('[').trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+    let addresses = match host.parse::<std::net::IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => lookup(host, port, origin).await?,
+    };
+
+    let mut last = None;
+    for address in addresses {
+        match tokio::net::TcpStream::connect(address).await {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.unwrap_or_else(invalid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lookups_are_events_on_the_calls_span() {
+        let telemetry = crate::test_support::init();
+
+        tracing::Instrument::instrument(lookup("localhost", 80, Origin::Traced), tracing::info_span!("call")).await.unwrap();
+
+        let spans = telemetry.spans();
+        let call = spans.assert_span_exists("call").span();
+        let lookup = call.events.iter().find(|event| event.name == "DNS lookup").expect("no DNS lookup event");
+        let answers = lookup.attributes.iter().find(|kv| kv.key.as_str() == "dns.answers").map(|kv| kv.value.to_string()).unwrap();
+        assert!(answers.contains("127.0.0.1") || answers.contains("::1"), "{answers}");
+        assert!(lookup.attributes.iter().any(|kv| kv.key.as_str() == "dns.lookup.duration_ms"));
+    }
+}
@@ -0,0 +1,119 @@
+// `GET /admin/config`: the settings this process runs with, as JSON: the file with the
+// defaults filled in, the fetched secrets and the environment's sampling ratio applied; those
+// read when they're used, DATABASE_URL say, are as configured. What can change at
+// runtime is as it is now: `sampling` has the ratios a PUT to /admin/sampling made, and
+// `telemetry.endpoints` the endpoints exported to, a vendor's included. Secrets never leave:
+// fields named like a password, secret, token or key are masked, and so are those named in
+// `logging.redact_fields` and the password of every URL.
+
+use std::sync::Arc;
+
+use crate::config::Settings;
+use crate::redact::{Names, REDACTED};
+
+// Masked whatever `logging.redact_fields` says, which only adds to them
+const SECRETS: [&str; 8] = ["password", "secret", "token", "authorization", "dsn", "api_key", "access_key", "secret_access_key"];
+
+#[derive(Clone)]
+pub struct EffectiveConfig {
+    // Masked already
+    settings: Arc<serde_json::Value>,
+    sampling: crate::sampling::TenantRates,
+}
+
+impl EffectiveConfig {
+    pub fn new(settings: &Settings, sampling: crate::sampling::TenantRates) -> Self {
+        let mut names = SECRETS.map(str::to_string).to_vec();
+        names.extend(settings.logging.redact_fields.iter().cloned());
+        let mut value = serde_json::to_value(settings).unwrap_or_default();
+        mask(&mut value, &Names::new(&names));
+        if let Some(telemetry) = value.get_mut("telemetry").and_then(serde_json::Value::as_object_mut) {
+            let endpoints = crate::telemetry::endpoints(&settings.telemetry).iter().map(|endpoint| crate::redact::scrub_urls(endpoint).into_owned()).collect();
+            telemetry.insert("endpoints".to_string(), endpoints);
+        }
+        Self { settings: Arc::new(value), sampling }
+    }
+
+    fn current(&self) -> serde_json::Value {
+        let mut value = (*self.settings).clone();
+        if let Some(settings) = value.as_object_mut() {
+            settings.insert("sampling".to_string(), serde_json::to_value(self.sampling.get()).unwrap_or_default());
+        }
+        value
+    }
+}
+
+// Secret fields, set ones only so it still shows which are, and URLs' passwords anywhere
+fn mask(value: &mut serde_json::Value, names: &Names) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                match value {
+                    serde_json::Value::Null => {}
+                    _ if names.is_secret(name) => *value = REDACTED.into(),
+                    value => mask(value, names),
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(|value| mask(value, names)),
+        serde_json::Value::String(text) => {
+            if let std::borrow::Cow::Owned(scrubbed) = crate::redact::scrub_urls(text) {
+                *text = scrubbed;
+            }
+        }
+        _ => {}
+    }
+}
+
+// Admins only; each read is audited since it shows where secrets live
+#[utoipa::path(get, path = "/admin/config", summary = "The settings in effect, secrets masked, with the sampling ratios in use", responses(
+    (status = 200, body = Object, description = "The settings by section"),
+    (status = 401, body = String, description = "No token"),
+    (status = 403, body = String, description = "The caller is not an admin"),
+))]
+pub async fn handler(
+    axum::extract::State(config): axum::extract::State<EffectiveConfig>,
+    crate::middleware::auth::RequireAdmin(admin): crate::middleware::auth::RequireAdmin,
+) -> crate::response::Traced<axum::Json<serde_json::Value>> {
+    crate::audit::audit!(enduser.id = admin.id, "Effective config read");
+    crate::response::Traced::new(axum::Json(config.current()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_secrets_and_shows_the_sampling_in_use() {
+        let mut settings = Settings::default();
+        settings.auth.jwt_secret = "hunter2".to_string();
+        settings.telemetry.api_key = Some("ingest-key".to_string());
+        settings.database.url = Some("mysql://app:hunter2@db:3306/app".to_string());
+        let rates = crate::sampling::TenantRates::new(&settings.sampling).unwrap();
+        let config = EffectiveConfig::new(&settings, rates.clone());
+        rates.set(&crate::config::SamplingSettings { ratio: 0.25, ..Default::default() }).unwrap();
+
+        let value = config.current();
+        assert_eq!(value["auth"]["jwt_secret"], REDACTED);
+        assert_eq!(value["telemetry"]["api_key"], REDACTED);
+        assert_eq!(value["database"]["url"], format!("mysql://app:{REDACTED}@db:3306/app"));
+        assert_eq!(value["database"]["password_file"], serde_json::Value::Null);
+        assert_eq!(value["sampling"]["ratio"], 0.25);
+        assert!(value["database"]["min_connections"].is_number());
+        assert!(!value.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn masks_secrets_whatever_the_log_redacts() {
+        let mut settings = Settings::default();
+        settings.auth.jwt_secret = "hunter2".to_string();
+        settings.database.password = "hunter2".to_string();
+        settings.logging.redact_fields = vec!["ssn".to_string()];
+        let config = EffectiveConfig::new(&settings, crate::sampling::TenantRates::new(&settings.sampling).unwrap());
+
+        let value = config.current();
+        assert_eq!(value["auth"]["jwt_secret"], REDACTED);
+        assert_eq!(value["database"]["password"], REDACTED);
+        assert!(!value.to_string().contains("hunter2"));
+    }
+}
@@ -0,0 +1,305 @@
+// Email over SMTP, the pattern for side effects a request sets off without waiting for
+// them. `POST /v1/notify` answers 202 straight away and sends in a task with a trace of
+// its own, linked to the request; the "email send" span there has a child per attempt's
+// step: "smtp connect" (the greeting and EHLO included), "smtp starttls", "smtp auth",
+// "smtp send" and "smtp quit". A 4xx reply, a refused connection or a timeout is retried
+// from the start after `[dependencies.smtp]`'s backoff. It sends only to the addresses of
+// `email.recipients` and to the caller's own, and `email.max_in_flight` messages at a time.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::response::IntoResponse;
+
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::client::{AsyncSmtpConnection, TlsParameters};
+use lettre::transport::smtp::extension::ClientId;
+use tracing::Instrument;
+
+use crate::config::{DependencySettings, EmailSettings, SmtpTls};
+use crate::middleware::auth::AuthUser;
+use crate::result_ext::ResultExt;
+use crate::retry::Policy;
+use crate::validated_json::ValidatedJson;
+use crate::AppState;
+
+#[derive(Debug)]
+pub enum SendError {
+    // The message couldn't be made, a bad address say; not retried
+    Message(String),
+    Smtp(lettre::transport::smtp::Error),
+    TimedOut(Duration),
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Message(e) => write!(f, "invalid message: {e}"),
+            Self::Smtp(e) => write!(f, "{e}"),
+            Self::TimedOut(timeout) => write!(f, "no reply within {}ms", timeout.as_millis()),
+        }
+    }
+}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Smtp(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl crate::retry::TimedOut for SendError {
+    fn timed_out(timeout: Duration) -> Self {
+        Self::TimedOut(timeout)
+    }
+}
+
+impl SendError {
+    // 5xx replies, TLS failures and what the client itself refused would only fail again
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::Message(_) => false,
+            Self::Smtp(e) => !(e.is_permanent() || e.is_tls() || e.is_client() || e.is_response()),
+            Self::TimedOut(_) => true,
+        }
+    }
+}
+
+pub struct Mailer {
+    settings: EmailSettings,
+    from: lettre::message::Mailbox,
+    credentials: Option<Credentials>,
+    hello: ClientId,
+    policy: Policy,
+    in_flight: Arc<tokio::sync::Semaphore>,
+}
+
+// One step of an attempt in a span of its own, with the reply code when the server said no
+async fn step<T>(name: &'static str, future: impl Future<Output = Result<T, lettre::transport::smtp::Error>>) -> Result<T, SendError> {
+    let span = tracing::info_span!(
+        "smtp",
+        otel.name = name,
+        otel.status_code = tracing::field::Empty,
+        smtp.response.code = tracing::field::Empty,
+    );
+    let result = future.instrument(span.clone()).await;
+    if let Err(e) = &result {
+        span.record("otel.status_code", "error");
+        if let Some(code) = e.status() {
+            span.record("smtp.response.code", i64::from(u16::from(code)));
+        }
+        span.in_scope(|| tracing::warn!("SMTP step failed: {}", e));
+    }
+    result.map_err(SendError::Smtp)
+}
+
+impl Mailer {
+    pub fn new(settings: &EmailSettings, dependency: &DependencySettings) -> Result<Self, String> {
+        let from = settings.from.parse().map_err(|e| format!("invalid email.from {:?}: {e}", settings.from))?;
+        let credentials = match (&settings.username, &settings.password) {
+            (Some(username), Some(password)) => Some(Credentials::new(username.clone(), password.clone())),
+            _ => None,
+        };
+        Ok(Self {
+            settings: settings.clone(),
+            from,
+            credentials,
+            hello: ClientId::default(),
+            policy: Policy::new(dependency.clone()),
+            in_flight: Arc::new(tokio::sync::Semaphore::new(settings.max_in_flight)),
+        })
+    }
+
+    // One of `email.recipients`, or the caller's own address
+    fn may_send_to(&self, to: &str, user: Option<&AuthUser>) -> bool {
+        let own = user.and_then(|user| user.claims.get("email")).and_then(|email| email.as_str());
+        self.settings.recipients.iter().map(String::as_str).chain(own).any(|allowed| allowed.eq_ignore_ascii_case(to))
+    }
+
+    fn tls(&self) -> Result<TlsParameters, SendError> {
+        TlsParameters::new(self.settings.host.clone()).map_err(SendError::Smtp)
+    }
+
+    // Sends `text` to `to`, attempts and all within an "email send" span
+    pub async fn send(&self, to: &str, subject: &str, text: String) -> Result<(), SendError> {
+        let span = tracing::info_span!(
+            "email send",
+            otel.kind = "client",
+            otel.status_code = tracing::field::Empty,
+            server.address = self.settings.host.as_str(),
+            server.port = self.settings.port,
+            email.message.size = tracing::field::Empty,
+        );
+
+        async {
+            let to = to.parse().map_err(|e| SendError::Message(format!("{e}")))?;
+            let message = lettre::Message::builder()
+                .from(self.from.clone())
+                .to(to)
+                .subject(subject)
+                .body(text)
+                .map_err(|e| SendError::Message(e.to_string()))?;
+            let raw = message.formatted();
+            tracing::Span::current().record("email.message.size", raw.len() as i64);
+
+            self.policy.run_timed(SendError::is_retryable, || self.attempt(message.envelope(), &raw)).await
+        }
+        .instrument(span.clone())
+        .await
+        .inspect_err(|_| {
+            span.record("otel.status_code", "error");
+        })
+        .trace_err()
+    }
+
+    async fn attempt(&self, envelope: &lettre::address::Envelope, raw: &[u8]) -> Result<(), SendError> {
+        let EmailSettings { host, port, tls, .. } = &self.settings;
+        let implicit = match tls {
+            SmtpTls::Implicit => Some(self.tls()?),
+            SmtpTls::StartTls | SmtpTls::None => None,
+        };
+        let connect = AsyncSmtpConnection::connect_tokio1((host.as_str(), *port), Some(self.policy.connect_timeout()), &self.hello, implicit, None);
+        let mut connection = step("smtp connect", connect).await?;
+
+        if *tls == SmtpTls::StartTls {
+            let parameters = self.tls()?;
+            step("smtp starttls", connection.starttls(parameters, &self.hello)).await?;
+        }
+        if let Some(credentials) = &self.credentials {
+            step("smtp auth", connection.auth(&[Mechanism::Plain, Mechanism::Login], credentials)).await?;
+        }
+
+        let response = step("smtp send", connection.send(envelope, raw)).await?;
+        tracing::info!(smtp.response.code = i64::from(u16::from(response.code())), "Message accepted");
+
+        // The message is in, a failed QUIT only shows on its span
+        let _ = step("smtp quit", connection.quit()).await;
+        Ok(())
+    }
+}
+
+#[derive(serde::Deserialize, validator::Validate, utoipa::ToSchema)]
+pub struct Notification {
+    #[validate(email)]
+    #[schema(format = Email)]
+    to: String,
+    #[validate(length(min = 1, max = 255))]
+    #[schema(min_length = 1, max_length = 255)]
+    subject: String,
+    text: String,
+}
+
+// Accepted once it's valid, to an allowed recipient, and there's room for it; whether it went
+// out is in the task's trace, not the response
+#[utoipa::path(post, path = "/notify", summary = "Sends an email in the background, in a trace linked to the request's", request_body = Notification, responses(
+    (status = 202, description = "Accepted, to be sent"),
+    (status = 403, body = String, description = "Not one of email.recipients, nor the caller's own address"),
+    (status = 422, body = crate::validated_json::Invalid, description = "The address or subject is invalid"),
+    (status = 503, body = String, description = "email.max_in_flight messages are being sent already"),
+))]
+#[traced_handler::traced_handler]
+pub async fn notify(
+    axum::extract::State(AppState { mailer, .. }): axum::extract::State<AppState>,
+    user: Option<axum::Extension<AuthUser>>,
+    ValidatedJson(notification): ValidatedJson<Notification>,
+) -> axum::response::Response {
+    if !mailer.may_send_to(&notification.to, user.as_deref()) {
+        return (axum::http::StatusCode::FORBIDDEN, "not an allowed recipient").into_response();
+    }
+    let Ok(permit) = mailer.in_flight.clone().try_acquire_owned() else {
+        return (axum::http::StatusCode::SERVICE_UNAVAILABLE, "too many notifications being sent").into_response();
+    };
+    crate::tasks::spawn_detached("email notification", async move {
+        let _permit = permit;
+        let _ = mailer.send(&notification.to, &notification.subject, notification.text).await;
+    });
+    axum::http::StatusCode::ACCEPTED.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    // A relay turning the first connection away with a 421, then taking the message
+    async fn relay() -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut busy, _) = listener.accept().await.unwrap();
+            busy.write_all(b"421 busy, try later\r\n").await.unwrap();
+            drop(busy);
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = tokio::io::BufReader::new(read).lines();
+            write.write_all(b"220 relay ready\r\n").await.unwrap();
+            let mut data = false;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let reply: &[u8] = match line.as_str() {
+                    "." if data => {
+                        data = false;
+                        b"250 queued\r\n"
+                    }
+                    _ if data => continue,
+                    "DATA" => {
+                        data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    "QUIT" => b"221 bye\r\n",
+                    ehlo if ehlo.starts_with("EHLO") => b"250-relay\r\n250 8BITMIME\r\n",
+                    _ => b"250 ok\r\n",
+                };
+                write.write_all(reply).await.unwrap();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn sends_only_to_allowed_recipients() {
+        let settings = EmailSettings { recipients: vec!["ops@example.com".to_string()], ..Default::default() };
+        let mailer = Mailer::new(&settings, &DependencySettings::default()).unwrap();
+        let user = AuthUser {
+            id: "user-1".to_string(),
+            roles: Vec::new(),
+            claims: [("email".to_string(), serde_json::json!("me@example.com"))].into(),
+        };
+
+        assert!(mailer.may_send_to("OPS@example.com", None));
+        assert!(mailer.may_send_to("me@example.com", Some(&user)));
+        assert!(!mailer.may_send_to("me@example.com", None));
+        assert!(!mailer.may_send_to("victim@example.com", Some(&user)));
+    }
+
+    #[tokio::test]
+    async fn attempts_are_retried_and_traced() {
+        let telemetry = crate::test_support::init();
+        let settings = EmailSettings { host: "127.0.0.1".to_string(), port: relay().await, tls: SmtpTls::None, ..Default::default() };
+        let dependency = DependencySettings { connect_timeout_ms: 1_000, read_timeout_ms: 5_000, retries: 2, retry_backoff_ms: 1 };
+        let mailer = Mailer::new(&settings, &dependency).unwrap();
+
+        mailer.send("someone@example.com", "Hello", "Hi there".to_string()).await.unwrap();
+
+        let spans = telemetry.spans();
+        let send = spans
+            .assert_span_exists("email send")
+            .with_attribute("retry.count", 1)
+            .with_attribute("server.address", "127.0.0.1")
+            .with_attribute_present("email.message.size")
+            .span();
+        let connects: Vec<_> = spans.all().iter().filter(|span| span.name == "smtp connect").collect();
+        assert_eq!(connects.len(), 2);
+        assert!(connects.iter().all(|span| span.parent_span_id == send.span_context.span_id()));
+        let codes: Vec<_> = connects
+            .iter()
+            .flat_map(|span| span.attributes.iter().filter(|kv| kv.key.as_str() == "smtp.response.code").map(|kv| kv.value.to_string()))
+            .collect();
+        assert_eq!(codes, ["421"]);
+        spans.assert_span_exists("smtp send").child_of("email send");
+        spans.assert_no_span("smtp starttls");
+    }
+}
@@ -0,0 +1,210 @@
+// Errors the service answers with, whatever made them: handlers, extractors and the
+// rejection layer. An API client gets JSON, `error` and the trace id to look the request up
+// by, with when it happened and whatever `with` added; `page_layer` shows a browser, which
+// asks for HTML, the same as a page from templates/error.html, as it does the errors of the
+// layers answering before a handler runs.
+
+use askama::Template;
+use axum::response::IntoResponse;
+use opentelemetry::trace::TraceContextExt;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+#[derive(Debug, Clone)]
+pub struct AppError {
+    status: axum::http::StatusCode,
+    message: String,
+    // Of the span current when the error was made
+    trace_id: Option<String>,
+    timestamp: String,
+    // More for JSON clients, the fields which failed validation say
+    details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl AppError {
+    pub fn new(status: axum::http::StatusCode, message: impl Into<String>) -> Self {
+        let span_context = tracing::Span::current().context().span().span_context().clone();
+        Self {
+            status,
+            message: message.into(),
+            trace_id: span_context.is_valid().then(|| span_context.trace_id().to_string()),
+            timestamp: humantime::format_rfc3339_seconds(std::time::SystemTime::now()).to_string(),
+            details: serde_json::Map::new(),
+        }
+    }
+
+    pub fn with(mut self, key: &str, value: impl serde::Serialize) -> Self {
+        self.details.insert(key.to_string(), serde_json::to_value(value).unwrap_or_default());
+        self
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        let mut body = serde_json::Map::new();
+        body.insert("error".to_string(), self.message.clone().into());
+        body.extend(self.details.clone());
+        body.insert("trace_id".to_string(), self.trace_id.clone().into());
+        body.insert("timestamp".to_string(), self.timestamp.clone().into());
+
+        // For `page_layer`, should the client want a page instead
+        let mut response = (self.status, axum::Json(body)).into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+struct Page<'a> {
+    status: u16,
+    reason: &'a str,
+    message: &'a str,
+    trace_id: Option<&'a str>,
+    timestamp: &'a str,
+}
+
+// Whether the client would rather have HTML than JSON, by the Accept header's weights; a
+// wildcard is no preference, so curl and API clients get JSON
+fn wants_html(headers: &axum::http::HeaderMap) -> bool {
+    let (mut html, mut json) = (0.0, 0.0);
+    for accept in headers.get_all(axum::http::header::ACCEPT).iter().filter_map(|value| value.to_str().ok()) {
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let q = params.find_map(|param| param.strip_prefix("q=")).and_then(|q| q.parse::<f32>().ok()).unwrap_or(1.0);
+            match media_type.as_str() {
+                "text/html" | "application/xhtml+xml" => html = f32::max(html, q),
+                "application/json" => json = f32::max(json, q),
+                _ => (),
+            }
+        }
+    }
+    html > 0.0 && html >= json
+}
+
+// Outside every layer which answers with an error; a client asking for HTML gets it as a
+// page, with the status and the headers, `Retry-After` and the like, kept. What isn't an
+// `AppError`, a timeout or a busy server say, is told by its status, and its text if it's
+// short plain text.
+pub async fn page_layer(request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let html = wants_html(request.headers());
+    let response = next.run(request).await;
+    let status = response.status();
+    if !html || !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let error = match parts.extensions.get::<AppError>().cloned() {
+        Some(error) => error,
+        None => match plain_text(&parts, body).await {
+            Ok(message) => AppError::new(status, message.unwrap_or_else(|| status.canonical_reason().unwrap_or("Error").to_string())),
+            // gone with the failed read
+            Err(body) => {
+                parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+                return axum::response::Response::from_parts(parts, body);
+            }
+        },
+    };
+    let page = Page {
+        status: error.status.as_u16(),
+        reason: error.status.canonical_reason().unwrap_or("Error"),
+        message: &error.message,
+        trace_id: error.trace_id.as_deref(),
+        timestamp: &error.timestamp,
+    };
+    let page = match crate::templates::render("error.html", &page) {
+        Ok(page) => page,
+        Err(e) => {
+            tracing::error!("Failed to render the error page: {}", e);
+            return (status, error.message).into_response();
+        }
+    };
+
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+    parts.headers.insert(axum::http::header::CONTENT_TYPE, axum::http::HeaderValue::from_static("text/html; charset=utf-8"));
+    axum::response::Response::from_parts(parts, axum::body::Body::from(page))
+}
+
+// Longest plain text answer shown on the page
+const MAX_MESSAGE_BYTES: u64 = 1024;
+
+// The body's text when it's short plain text, None when it isn't; the body back if it
+// couldn't be read
+async fn plain_text(parts: &axum::http::response::Parts, body: axum::body::Body) -> Result<Option<String>, axum::body::Body> {
+    let plain = parts.headers.get(axum::http::header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).is_some_and(|value| value.starts_with("text/plain"));
+    let short = http_body::Body::size_hint(&body).exact().is_some_and(|len| len <= MAX_MESSAGE_BYTES);
+    if !(plain && short) {
+        return Ok(None);
+    }
+    match http_body_util::BodyExt::collect(body).await {
+        Ok(body) => Ok(Some(String::from_utf8_lossy(&body.to_bytes()).into_owned()).filter(|text| !text.is_empty())),
+        Err(_) => Err(axum::body::Body::empty()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+
+    async fn get(accept: &str) -> (axum::http::StatusCode, String, String) {
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async { AppError::new(axum::http::StatusCode::CONFLICT, "<b>taken</b>").with("field", "name") }))
+            .layer(axum::middleware::from_fn(page_layer));
+        let request = axum::http::Request::get("/").header(axum::http::header::ACCEPT, accept).body(axum::body::Body::empty()).unwrap();
+        let response = tower::ServiceExt::oneshot(app, request).instrument(tracing::info_span!("request")).await.unwrap();
+
+        let status = response.status();
+        let content_type = response.headers()[axum::http::header::CONTENT_TYPE].to_str().unwrap().to_string();
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        (status, content_type, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn whatever_answered_with_an_error_gets_a_page() {
+        let telemetry = crate::test_support::init();
+
+        // As the concurrency limit answers, before any handler
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn(|_: axum::extract::Request, _: axum::middleware::Next| async {
+                (axum::http::StatusCode::SERVICE_UNAVAILABLE, [(axum::http::header::RETRY_AFTER, "1")], "server busy").into_response()
+            }))
+            .layer(axum::middleware::from_fn(page_layer));
+        let request = axum::http::Request::get("/").header(axum::http::header::ACCEPT, "text/html").body(axum::body::Body::empty()).unwrap();
+        let response = tower::ServiceExt::oneshot(app, request).instrument(tracing::info_span!("request")).await.unwrap();
+
+        assert_eq!(response.status(), axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[axum::http::header::RETRY_AFTER], "1");
+        assert!(response.headers()[axum::http::header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+        let page = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        let trace_id = telemetry.spans().assert_span_exists("request").span().span_context.trace_id().to_string();
+        assert!(page.contains("503 Service Unavailable"), "{page}");
+        assert!(page.contains("server busy"), "{page}");
+        assert!(page.contains(&trace_id), "{page}");
+    }
+
+    #[tokio::test]
+    async fn browsers_get_a_page_and_api_clients_json() {
+        let telemetry = crate::test_support::init();
+
+        let (status, content_type, page) = get("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8").await;
+        let trace_id = telemetry.spans().assert_span_exists("request").span().span_context.trace_id().to_string();
+        assert_eq!(status, axum::http::StatusCode::CONFLICT);
+        assert!(content_type.starts_with("text/html"));
+        assert!(page.contains(&trace_id));
+        assert!(page.contains("&lt;b&gt;taken&lt;/b&gt;"));
+
+        for accept in ["application/json", "*/*", "text/html;q=0.5, application/json"] {
+            let (status, content_type, body) = get(accept).await;
+            assert_eq!(status, axum::http::StatusCode::CONFLICT);
+            assert_eq!(content_type, "application/json");
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"], "<b>taken</b>");
+            assert_eq!(body["field"], "name");
+            assert!(body["timestamp"].is_string());
+        }
+    }
+}
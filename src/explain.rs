@@ -0,0 +1,164 @@
+// The plan of a slow query, right on its span: once a statement has taken
+// `[database] slow_query_ms` or longer, a spawned task runs `EXPLAIN FORMAT=TREE` for it in
+// a "query plan" span of its own, linked to the query's, with a "Query plan" event holding
+// the plan as `db.query.plan`. The query span ends when the query does, its duration is
+// what's being looked into. The placeholders are bound to 1, so it's the plan for the
+// statement's shape rather than for the values the query had. At most
+// `explain_per_minute` statements a minute are explained, each once for as long as the
+// process runs, whatever the tenant comment and deadline hint `sqlcommenter::tag` added;
+// INSERTs never are.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use opentelemetry::trace::TraceContextExt;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::DatabaseSettings;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// Plans longer than this are cut
+const MAX_PLAN: usize = 4096;
+
+// Statements remembered as explained; past this many, new ones aren't explained
+const MAX_STATEMENTS: usize = 1024;
+
+// Statements explained so far, and how many since the start of the current minute
+struct Window {
+    started: Instant,
+    explained: usize,
+    statements: HashSet<String>,
+}
+
+pub struct Explainer {
+    pool: sqlx::MySqlPool,
+    threshold: Duration,
+    per_minute: usize,
+    window: Mutex<Window>,
+}
+
+impl Explainer {
+    pub fn new(pool: sqlx::MySqlPool, settings: &DatabaseSettings) -> Self {
+        Self {
+            pool,
+            threshold: Duration::from_millis(settings.slow_query_ms),
+            per_minute: settings.explain_per_minute as usize,
+            window: Mutex::new(Window { started: Instant::now(), explained: 0, statements: HashSet::new() }),
+        }
+    }
+
+    // Whether `sql` is to be explained now, counting it against the minute when it is
+    fn admit(&self, sql: &str) -> bool {
+        let mut window = self.window.lock().unwrap();
+        if window.started.elapsed() >= Duration::from_secs(60) {
+            window.started = Instant::now();
+            window.explained = 0;
+        }
+        if window.explained >= self.per_minute || window.statements.len() >= MAX_STATEMENTS {
+            return false;
+        }
+        let admitted = window.statements.insert(crate::sqlcommenter::untag(sql));
+        window.explained += usize::from(admitted);
+        admitted
+    }
+
+    // Runs `query`, the statement `sql` of `span`, explaining it in the background when slow
+    pub async fn timed<T>(self: &Arc<Self>, span: &tracing::Span, sql: &str, query: impl Future<Output = T>) -> T {
+        let started = Instant::now();
+        let output = query.await;
+        let elapsed = started.elapsed();
+        if self.per_minute > 0 && elapsed >= self.threshold && explainable(sql) && self.admit(sql) {
+            // Only the query's context goes along, a handle would keep its span open
+            let plan = tracing::info_span!(parent: None, "query plan", otel.name = "EXPLAIN", db.system = "mysql");
+            plan.add_link(span.context().span().span_context().clone());
+            let (explainer, sql) = (self.clone(), sql.to_string());
+            tokio::spawn(async move { explainer.explain(plan, sql, elapsed).await });
+        }
+        output
+    }
+
+    async fn explain(&self, span: tracing::Span, sql: String, elapsed: Duration) {
+        let explain = format!("EXPLAIN FORMAT=TREE {sql}");
+        let query = (0..sql.matches('?').count()).fold(sqlx::query_scalar::<_, String>(&explain), |query, _| query.bind(1i64));
+        match tokio::time::timeout(TIMEOUT, query.fetch_one(&self.pool)).await {
+            Ok(Ok(plan)) => tracing::info!(
+                parent: &span,
+                db.query.plan = truncate(&plan, MAX_PLAN),
+                db.query.duration_ms = elapsed.as_secs_f64() * 1000.0,
+                "Query plan"
+            ),
+            Ok(Err(e)) => tracing::warn!(parent: &span, error = %e, "Query plan unavailable"),
+            Err(_) => tracing::warn!(parent: &span, error = "timed out", "Query plan unavailable"),
+        }
+    }
+}
+
+// What MySQL can explain: reads, updates and deletes
+fn explainable(sql: &str) -> bool {
+    let verb = sql.split_whitespace().next().unwrap_or_default();
+    ["SELECT", "UPDATE", "DELETE", "WITH"].iter().any(|explained| verb.eq_ignore_ascii_case(explained))
+}
+
+fn truncate(plan: &str, max: usize) -> &str {
+    if plan.len() <= max {
+        return plan;
+    }
+    let end = (0..=max).rev().find(|&end| plan.is_char_boundary(end)).unwrap_or(0);
+    &plan[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn explains_slow_queries_once_within_the_rate() {
+        let telemetry = crate::test_support::init();
+
+        // A closed port, so the EXPLAIN fails fast
+        let pool = sqlx::mysql::MySqlPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy_with(sqlx::mysql::MySqlConnectOptions::new().host("127.0.0.1").port(1));
+        let settings = DatabaseSettings { slow_query_ms: 10, explain_per_minute: 1, ..Default::default() };
+        let explainer = Arc::new(Explainer::new(pool, &settings));
+
+        let sql = "SELECT id FROM items WHERE id = ?";
+        let span = tracing::info_span!("db query", otel.name = "slow");
+        explainer.timed(&span, sql, tokio::time::sleep(Duration::from_millis(20))).await;
+        let query = span.context().span().span_context().clone();
+        drop(span);
+        let fast = tracing::info_span!("db query", otel.name = "fast");
+        explainer.timed(&fast, "SELECT 1", async {}).await;
+        drop(fast);
+
+        // Once a minute, and never an INSERT
+        assert!(!explainer.admit(sql));
+        assert!(!explainer.admit("SELECT name FROM items"));
+        // Nor again in a later minute, or for another tenant
+        explainer.window.lock().unwrap().started -= Duration::from_secs(60);
+        assert!(!explainer.admit("SELECT /*+ MAX_EXECUTION_TIME(256) */ id FROM items WHERE id = ? /*tenant_id='acme'*/"));
+        assert!(explainer.admit("SELECT name FROM items"));
+        assert!(!explainable("INSERT INTO items (name) VALUES (?)"));
+
+        // The query span is over before its EXPLAIN, which waits out the pool's 100ms
+        let slow = telemetry.spans().assert_span_exists("slow").span().clone();
+        assert!(slow.end_time.duration_since(slow.start_time).unwrap() < Duration::from_millis(100));
+        assert!(slow.events.is_empty());
+
+        let spans = loop {
+            let spans = telemetry.spans();
+            if spans.find("EXPLAIN").is_some() {
+                break spans;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let plan = spans.assert_span_exists("EXPLAIN").without_parent().span();
+        assert_eq!(plan.events.iter().map(|event| event.name.to_string()).collect::<Vec<_>>(), ["Query plan unavailable"]);
+        assert!(plan.links.iter().any(|link| link.span_context == query));
+        assert!(plan.end_time > slow.end_time);
+        assert!(spans.assert_span_exists("fast").span().events.is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures_util::{Stream, TryStreamExt};
+use sqlx::Row;
+
+use crate::AppState;
+
+// Emit a progress event every N rows
+const PROGRESS_EVERY_ROWS: u64 = 10_000;
+
+pub const DEFAULT_ROWS: u64 = 100_000;
+pub const MAX_ROWS: u64 = 1_000_000;
+
+// A recursive CTE is enough to produce a large result set without any table
+const EXPORT_QUERY: &str = "
+    WITH RECURSIVE seq (n) AS (
+        SELECT 1
+        UNION ALL
+        SELECT n + 1 FROM seq WHERE n < ?
+    )
+    SELECT /*+ SET_VAR(cte_max_recursion_depth = 1000000) */
+        n, CONCAT('item-', n) AS label, MD5(n) AS digest
+    FROM seq";
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+pub struct ExportParams {
+    #[param(minimum = 0, maximum = 1_000_000, default = 100_000)]
+    rows: Option<u64>,
+}
+
+#[utoipa::path(get, path = "/export.csv", summary = "Streams generated rows as CSV", params(ExportParams), responses(
+    (status = 200, body = String, content_type = "text/csv", description = "The rows"),
+))]
+#[traced_handler::traced_handler]
+pub async fn export_csv(
+    axum::extract::State(AppState { pool, .. }): axum::extract::State<AppState>,
+    axum::extract::Query(params): axum::extract::Query<ExportParams>,
+) -> impl axum::response::IntoResponse {
+    let rows = params.rows.unwrap_or(DEFAULT_ROWS).min(MAX_ROWS);
+
+    // The handler span closes as soon as the response head is returned, so the body
+    // gets its own span which lives until the last chunk is written (or the client leaves)
+    let span = tracing::info_span!(
+        "stream csv",
+        export.rows_requested = rows,
+        db.query.text = tracing::field::Empty,
+        export.rows_sent = tracing::field::Empty,
+        export.bytes_sent = tracing::field::Empty,
+        export.downstream_wait_ms = tracing::field::Empty,
+        http.request.aborted = tracing::field::Empty,
+        otel.status_code = tracing::field::Empty,
+    );
+    crate::span_fields::record_lazy(&span, "db.query.text", || crate::span_fields::query_text(EXPORT_QUERY));
+
+    // The body is streamed after the handler returns, outside the request's context
+    let query = crate::sqlcommenter::tag(EXPORT_QUERY);
+    let lines = async_stream::try_stream! {
+        yield bytes::Bytes::from_static(b"n,label,digest\n");
+
+        let mut rs = sqlx::query(&query).bind(rows).fetch(&pool);
+        // A span for all rows rather than one each
+        let mut formatting = crate::aggregate::Aggregate::new("format row");
+        while let Some(row) = rs.try_next().await? {
+            let line = formatting.time(|| -> Result<String, sqlx::Error> {
+                let n: i64 = row.try_get("n")?;
+                let label: String = row.try_get("label")?;
+                let digest: String = row.try_get("digest")?;
+                Ok(format!("{n},{label},{digest}\n"))
+            })?;
+            yield bytes::Bytes::from(line);
+        }
+    };
+
+    (
+        [
+            (axum::http::header::CONTENT_TYPE, "text/csv"),
+            (axum::http::header::CONTENT_DISPOSITION, "attachment; filename=\"export.csv\""),
+        ],
+        axum::body::Body::from_stream(InstrumentedCsv::new(lines, span)),
+    )
+}
+
+type CsvStream = Pin<Box<dyn Stream<Item = Result<bytes::Bytes, sqlx::Error>> + Send>>;
+
+// The CSV body, recording its progress on the export span. hyper only polls the body when
+// the socket takes more, so the time from yielding a chunk to the next poll is the client's
+struct InstrumentedCsv {
+    inner: CsvStream,
+    span: tracing::Span,
+    rows_sent: u64,
+    bytes_sent: u64,
+    downstream_wait: Duration,
+    last_yield: Option<Instant>,
+    finished: bool,
+}
+
+impl InstrumentedCsv {
+    fn new(inner: impl Stream<Item = Result<bytes::Bytes, sqlx::Error>> + Send + 'static, span: tracing::Span) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            span,
+            rows_sent: 0,
+            bytes_sent: 0,
+            downstream_wait: Duration::ZERO,
+            last_yield: None,
+            finished: false,
+        }
+    }
+
+    fn record_totals(&self) {
+        self.span.record("export.rows_sent", self.rows_sent);
+        self.span.record("export.bytes_sent", self.bytes_sent);
+        self.span.record("export.downstream_wait_ms", self.downstream_wait.as_millis() as u64);
+    }
+}
+
+impl Stream for InstrumentedCsv {
+    type Item = Result<bytes::Bytes, sqlx::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let _enter = this.span.enter();
+
+        if let Some(last_yield) = this.last_yield.take() {
+            this.downstream_wait += last_yield.elapsed();
+        }
+
+        let polled = this.inner.as_mut().poll_next(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => {
+                // The header line is not a row
+                if this.bytes_sent > 0 {
+                    this.rows_sent += 1;
+                }
+                this.bytes_sent += chunk.len() as u64;
+                this.last_yield = Some(Instant::now());
+
+                if this.rows_sent > 0 && this.rows_sent.is_multiple_of(PROGRESS_EVERY_ROWS) {
+                    tracing::info!(
+                        export.rows_sent = this.rows_sent,
+                        export.bytes_sent = this.bytes_sent,
+                        export.downstream_wait_ms = this.downstream_wait.as_millis() as u64,
+                        "export progress"
+                    );
+                }
+            }
+            Poll::Ready(Some(Err(e))) => {
+                this.finished = true;
+                this.record_totals();
+                this.span.record("otel.status_code", "error");
+                tracing::error!("Export failed: {:?}", e);
+            }
+            Poll::Ready(None) => {
+                this.finished = true;
+                this.record_totals();
+                tracing::info!("export completed");
+            }
+            Poll::Pending => (),
+        }
+
+        polled
+    }
+}
+
+impl Drop for InstrumentedCsv {
+    fn drop(&mut self) {
+        // hyper drops the body without draining it when the client goes away
+        if !self.finished {
+            let _enter = self.span.enter();
+            self.record_totals();
+            self.span.record("http.request.aborted", true);
+            tracing::warn!(export.rows_sent = self.rows_sent, "client aborted the download");
+        }
+    }
+}
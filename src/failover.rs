@@ -0,0 +1,195 @@
+// Exporting across the collectors of `telemetry.endpoints`, in order: after `failover_after`
+// failed exports in a row, the next one takes over, the first following the last. Meanwhile
+// every `failback_probe_secs` an export goes to the first one before the one in use, and
+// stays with it when it takes it. Each switch is logged, a warning when failing over.
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::future::BoxFuture;
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+
+use crate::config::TelemetrySettings;
+
+// Which endpoint a signal is exported to, and how it's been going
+pub struct Failover {
+    signal: &'static str,
+    endpoints: Vec<String>,
+    after: u32,
+    probe_every: Duration,
+    active: AtomicUsize,
+    failures: AtomicU32,
+    probed: Mutex<Instant>,
+}
+
+impl Failover {
+    // `endpoints` being those of the export targets, in order
+    pub fn new(signal: &'static str, endpoints: Vec<String>, settings: &TelemetrySettings) -> Self {
+        Self {
+            signal,
+            endpoints,
+            after: settings.failover_after.max(1),
+            probe_every: Duration::from_secs(settings.failback_probe_secs),
+            active: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            probed: Mutex::new(Instant::now()),
+        }
+    }
+
+    // The endpoints an export goes to in turn until one takes it: the one in use, after the
+    // first when it's time to probe it again
+    pub fn order(&self) -> Vec<usize> {
+        let active = self.active.load(Ordering::Relaxed);
+        if active == 0 {
+            return vec![0];
+        }
+        let mut probed = self.probed.lock().unwrap();
+        if probed.elapsed() < self.probe_every {
+            return vec![active];
+        }
+        *probed = Instant::now();
+        vec![0, active]
+    }
+
+    pub fn record(&self, index: usize, ok: bool) {
+        let active = self.active.load(Ordering::Relaxed);
+        match (ok, index == active) {
+            (true, true) => self.failures.store(0, Ordering::Relaxed),
+            // The probe of the first endpoint
+            (true, false) => {
+                self.active.store(index, Ordering::Relaxed);
+                self.failures.store(0, Ordering::Relaxed);
+                tracing::info!(signal = self.signal, endpoint = self.endpoints[index], "Exporting to the first OTLP endpoint again");
+            }
+            (false, true) => {
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures < self.after || self.endpoints.len() < 2 {
+                    return;
+                }
+                let next = (active + 1) % self.endpoints.len();
+                self.active.store(next, Ordering::Relaxed);
+                self.failures.store(0, Ordering::Relaxed);
+                *self.probed.lock().unwrap() = Instant::now();
+                tracing::warn!(
+                    signal = self.signal,
+                    failures,
+                    from = self.endpoints[active],
+                    to = self.endpoints[next],
+                    "Failing over to the next OTLP endpoint"
+                );
+            }
+            (false, false) => {}
+        }
+    }
+}
+
+// A span exporter per endpoint, exported to as the `Failover` says
+pub struct FailoverExporter<E> {
+    exporters: Arc<Vec<Mutex<E>>>,
+    failover: Arc<Failover>,
+}
+
+impl<E> FailoverExporter<E> {
+    pub fn new(exporters: Vec<E>, failover: Failover) -> Self {
+        Self { exporters: Arc::new(exporters.into_iter().map(Mutex::new).collect()), failover: Arc::new(failover) }
+    }
+}
+
+impl<E> std::fmt::Debug for FailoverExporter<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FailoverExporter").field("endpoints", &self.failover.endpoints).finish()
+    }
+}
+
+impl<E: SpanExporter + 'static> SpanExporter for FailoverExporter<E> {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let (exporters, failover) = (self.exporters.clone(), self.failover.clone());
+        Box::pin(async move {
+            let order = failover.order();
+            let mut batch = Some(batch);
+            let mut result = Ok(());
+            for (n, &index) in order.iter().enumerate() {
+                // A copy for each endpoint but the last tried
+                let spans = if n + 1 < order.len() { batch.clone() } else { batch.take() }.unwrap_or_default();
+                let export = exporters[index].lock().unwrap().export(spans);
+                result = export.await;
+                failover.record(index, result.is_ok());
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result
+        })
+    }
+
+    fn shutdown(&mut self) {
+        for exporter in self.exporters.iter() {
+            exporter.lock().unwrap().shutdown();
+        }
+    }
+
+    fn force_flush(&mut self) -> BoxFuture<'static, ExportResult> {
+        let active = self.failover.active.load(Ordering::Relaxed);
+        self.exporters[active].lock().unwrap().force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &opentelemetry_sdk::Resource) {
+        for exporter in self.exporters.iter() {
+            exporter.lock().unwrap().set_resource(resource);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{Tracer as _, TracerProvider as _};
+    use std::sync::atomic::AtomicBool;
+
+    // A collector which can be taken down
+    #[derive(Debug)]
+    struct Collector {
+        exporter: opentelemetry_sdk::testing::trace::InMemorySpanExporter,
+        down: Arc<AtomicBool>,
+    }
+
+    impl SpanExporter for Collector {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            if self.down.load(Ordering::Relaxed) {
+                return Box::pin(std::future::ready(Err(opentelemetry::trace::TraceError::from("unavailable"))));
+            }
+            self.exporter.export(batch)
+        }
+    }
+
+    #[test]
+    fn fails_over_and_back() {
+        let collector = |down: bool| Collector { exporter: Default::default(), down: Arc::new(AtomicBool::new(down)) };
+        let (primary, secondary) = (collector(true), collector(false));
+        let (primary_spans, primary_down, secondary_spans) = (primary.exporter.clone(), primary.down.clone(), secondary.exporter.clone());
+
+        let settings = TelemetrySettings { failover_after: 2, failback_probe_secs: 0, ..Default::default() };
+        let failover = Failover::new("span", vec!["http://primary:4317".to_string(), "http://secondary:4317".to_string()], &settings);
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(FailoverExporter::new(vec![primary, secondary], failover))
+            .build();
+        let tracer = provider.tracer("test");
+        let names = |exporter: &opentelemetry_sdk::testing::trace::InMemorySpanExporter| {
+            exporter.get_finished_spans().unwrap().into_iter().map(|span| span.name.into_owned()).collect::<Vec<_>>()
+        };
+
+        // Two failures, then the secondary takes over
+        for name in ["lost", "also lost", "to the secondary"] {
+            tracer.in_span(name, |_| ());
+        }
+        assert_eq!(names(&secondary_spans), ["to the secondary"]);
+
+        // The probe finds the primary back
+        primary_down.store(false, Ordering::Relaxed);
+        tracer.in_span("to the primary", |_| ());
+        tracer.in_span("still the primary", |_| ());
+        assert_eq!(names(&primary_spans), ["to the primary", "still the primary"]);
+        assert_eq!(names(&secondary_spans), ["to the secondary"]);
+    }
+}
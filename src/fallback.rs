@@ -0,0 +1,120 @@
+// Requests no route answers: unknown paths, and known ones with the wrong method. Both are
+// counted by a bucket of the path rather than the path itself, which is whatever the
+// client made up, and an unknown path gets a span saying so below the request span.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use opentelemetry::KeyValue;
+
+// Distinct buckets before the rest all go to `OTHER`
+const MAX_BUCKETS: usize = 64;
+const OTHER: &str = "/*";
+
+pub struct Unmatched {
+    requests: opentelemetry::metrics::Counter<u64>,
+    buckets: Mutex<HashSet<String>>,
+}
+
+impl Unmatched {
+    pub fn new() -> Self {
+        let requests = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .u64_counter("http.server.unmatched_requests")
+            .with_description("Requests for unknown paths, or with a method the route doesn't take")
+            .init();
+
+        Self { requests, buckets: Mutex::default() }
+    }
+
+    // The first segment of the path, `/admin/*` for `/admin/x/y`, up to `MAX_BUCKETS` of them
+    fn bucket(&self, path: &str) -> String {
+        let mut segments = path.trim_start_matches('/').splitn(2, '/');
+        let first = segments.next().unwrap_or_default();
+        let valid = !first.is_empty()
+            && first.len() <= 32
+            && first.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return OTHER.to_string();
+        }
+
+        let bucket = match segments.next() {
+            Some(_) => format!("/{first}/*"),
+            None => format!("/{first}"),
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.contains(&bucket) || buckets.len() < MAX_BUCKETS {
+            buckets.insert(bucket.clone());
+            bucket
+        } else {
+            OTHER.to_string()
+        }
+    }
+
+    fn count(&self, method: &axum::http::Method, bucket: &str, status: axum::http::StatusCode) {
+        self.requests.add(
+            1,
+            &[
+                KeyValue::new("http.request.method", crate::attributes::method(method)),
+                KeyValue::new("url.path_bucket", bucket.to_string()),
+                KeyValue::new("http.response.status_code", i64::from(status.as_u16())),
+            ],
+        );
+    }
+}
+
+// The router's fallback
+pub async fn not_found(
+    axum::extract::State(unmatched): axum::extract::State<Arc<Unmatched>>,
+    method: axum::http::Method,
+    uri: axum::http::Uri,
+) -> crate::error::AppError {
+    let bucket = unmatched.bucket(uri.path());
+    let _span = tracing::info_span!("route not found", url.path_bucket = bucket.as_str()).entered();
+    unmatched.count(&method, &bucket, axum::http::StatusCode::NOT_FOUND);
+    crate::error::AppError::new(axum::http::StatusCode::NOT_FOUND, "there is nothing at this address")
+}
+
+// Route layer, so the route is matched; a 405 comes from the route's method router, which
+// keeps the `Allow` header it adds. The route is the bucket, there are only so many.
+pub async fn method_not_allowed(
+    axum::extract::State(unmatched): axum::extract::State<Arc<Unmatched>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let method = request.method().clone();
+    let route = crate::middleware::matched_route(&request);
+
+    let response = next.run(request).await;
+    if response.status() == axum::http::StatusCode::METHOD_NOT_ALLOWED {
+        let allowed = response.headers().get(axum::http::header::ALLOW).and_then(|value| value.to_str().ok());
+        tracing::info!(http.request.method = %method, http.route = route, allowed, "Method not allowed");
+        unmatched.count(&method, &route, response.status());
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_by_first_segment() {
+        let unmatched = Unmatched::new();
+        assert_eq!(unmatched.bucket("/admin/users/42"), "/admin/*");
+        assert_eq!(unmatched.bucket("/wp-login.php"), "/wp-login.php");
+        assert_eq!(unmatched.bucket("/%2e%2e/etc"), OTHER);
+
+        for n in 0..MAX_BUCKETS {
+            unmatched.bucket(&format!("/probe{n}"));
+        }
+        assert_eq!(unmatched.bucket("/one-too-many"), OTHER);
+        assert_eq!(unmatched.bucket("/admin/settings"), "/admin/*");
+    }
+
+    #[test]
+    fn extension_methods_are_one_label() {
+        let brew = axum::http::Method::from_bytes(b"BREW").unwrap();
+        assert_eq!(crate::attributes::method(&brew).as_str(), "_OTHER");
+        assert_eq!(crate::attributes::method(&axum::http::Method::GET).as_str(), "GET");
+    }
+}
@@ -0,0 +1,225 @@
+// Feature flags, on or off, from `[flags.defaults]` and the file at `flags.file` over them.
+// Every evaluation goes on the span it happens in: a `feature_flag` event with the flag and
+// its variant, and `feature_flag.<name>` as an attribute, so a regression can be lined up with
+// the flags it ran with. PUT /admin/flags/:name flips one until the next SIGHUP, which goes
+// back to the config.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::FlagSettings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Config,
+    File,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+pub struct Flag {
+    pub enabled: bool,
+    // Where the value came from
+    pub source: Source,
+}
+
+// The flags in use, shared with the admin API and the SIGHUP reload
+#[derive(Clone, Default)]
+pub struct Flags(Arc<RwLock<BTreeMap<String, Flag>>>);
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+fn load(settings: &FlagSettings) -> Result<BTreeMap<String, Flag>, String> {
+    let file = match &settings.file {
+        Some(path) => {
+            let raw = std::fs::read_to_string(path).map_err(|e| format!("failed to read {path}: {e}"))?;
+            toml::from_str::<HashMap<String, bool>>(&raw).map_err(|e| format!("failed to parse {path}: {e}"))?
+        }
+        None => HashMap::new(),
+    };
+
+    let defaults = settings.defaults.iter().map(|(name, enabled)| (name, Flag { enabled: *enabled, source: Source::Config }));
+    let overrides = file.iter().map(|(name, enabled)| (name, Flag { enabled: *enabled, source: Source::File }));
+    let flags: BTreeMap<String, Flag> = defaults.chain(overrides).map(|(name, flag)| (name.clone(), flag)).collect();
+    if let Some(name) = flags.keys().find(|name| !valid_name(name)) {
+        return Err(format!("flag name {name:?} is not lowercase letters, digits, '-', '_' and '.'"));
+    }
+    Ok(flags)
+}
+
+impl Flags {
+    pub fn new(settings: &FlagSettings) -> Result<Self, String> {
+        Ok(Self(Arc::new(RwLock::new(load(settings)?))))
+    }
+
+    // Replaces every flag, those set at runtime too; invalid settings leave the current ones
+    pub fn reload(&self, settings: &FlagSettings) -> Result<(), String> {
+        *self.0.write().unwrap() = load(settings)?;
+        Ok(())
+    }
+
+    pub fn set(&self, name: &str, enabled: bool) -> Result<(), String> {
+        if !valid_name(name) {
+            return Err(format!("flag name {name:?} is not lowercase letters, digits, '-', '_' and '.'"));
+        }
+        self.0.write().unwrap().insert(name.to_string(), Flag { enabled, source: Source::Admin });
+        Ok(())
+    }
+
+    pub fn get(&self) -> BTreeMap<String, Flag> {
+        self.0.read().unwrap().clone()
+    }
+
+    // Whether `name` is on, recorded on the current span; off when there is no such flag
+    pub fn is_enabled(&self, name: &str) -> bool {
+        let enabled = self.0.read().unwrap().get(name).is_some_and(|flag| flag.enabled);
+
+        let span = tracing::Span::current();
+        span.set_attribute(format!("feature_flag.{name}"), enabled);
+        tracing::info!(
+            feature_flag.key = name,
+            feature_flag.variant = if enabled { "on" } else { "off" },
+            "feature_flag"
+        );
+        enabled
+    }
+}
+
+impl std::fmt::Debug for Flags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.0.read().unwrap().iter().map(|(name, flag)| (name, flag.enabled))).finish()
+    }
+}
+
+// The flags for handlers, taken with the `Flags` extractor
+pub async fn layer(
+    axum::extract::State(flags): axum::extract::State<Flags>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    request.extensions_mut().insert(flags);
+    next.run(request).await
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for Flags {
+    type Rejection = (axum::http::StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Flags>()
+            .cloned()
+            .ok_or((axum::http::StatusCode::INTERNAL_SERVER_ERROR, "flags layer is not installed"))
+    }
+}
+
+#[utoipa::path(get, path = "/admin/flags", summary = "Feature flags, whether they are on and where that comes from", responses(
+    (status = 200, body = BTreeMap<String, Flag>, description = "The flags by name"),
+    (status = 401, body = String, description = "No token"),
+    (status = 403, body = String, description = "The caller is not an admin"),
+))]
+pub async fn get_handler(axum::extract::State(flags): axum::extract::State<Flags>) -> crate::response::Traced<axum::Json<BTreeMap<String, Flag>>> {
+    crate::response::Traced::new(axum::Json(flags.get()))
+}
+
+// Turns a flag on or off, with `true` or `false` as the body, until the next SIGHUP. Admins
+// only.
+#[utoipa::path(put, path = "/admin/flags/{name}", summary = "Turns a flag on or off until the next SIGHUP",
+    params(("name" = String, Path)),
+    request_body = bool,
+    responses(
+        (status = 200, body = BTreeMap<String, Flag>, description = "The flags by name"),
+        (status = 400, body = String, description = "The name is not lowercase letters, digits, '-', '_' and '.'"),
+        (status = 401, body = String, description = "No token"),
+        (status = 403, body = String, description = "The caller is not an admin"),
+    ),
+)]
+pub async fn put_handler(
+    axum::extract::State(flags): axum::extract::State<Flags>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    crate::middleware::auth::RequireAdmin(admin): crate::middleware::auth::RequireAdmin,
+    axum::Json(enabled): axum::Json<bool>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if let Err(e) = flags.set(&name, enabled) {
+        return (axum::http::StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    crate::audit::audit!(feature_flag.key = name, feature_flag.enabled = enabled, enduser.id = admin.id, "Feature flag changed at runtime");
+    axum::Json(flags.get()).into_response()
+}
+
+// Re-reads the flags from the config file, and theirs, on SIGHUP
+pub fn spawn_reload_on_sighup(flags: Flags) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("Failed to install the SIGHUP handler, the feature flags won't be reloaded: {}", e);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match crate::config::Settings::load().and_then(|settings| flags.reload(&settings.flags)) {
+                Ok(()) => crate::audit::audit!(flags = ?flags, "Reloaded feature flags"),
+                Err(e) => tracing::error!("Failed to reload feature flags, keeping the current ones: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_overrides_the_defaults() {
+        let path = std::env::temp_dir().join(format!("flags-{}.toml", std::process::id()));
+        std::fs::write(&path, "new-checkout = true\n").unwrap();
+        let settings = FlagSettings {
+            defaults: HashMap::from([("new-checkout".to_string(), false), ("dark-mode".to_string(), true)]),
+            file: Some(path.display().to_string()),
+        };
+        let flags = Flags::new(&settings).unwrap().get();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(flags["new-checkout"], Flag { enabled: true, source: Source::File });
+        assert_eq!(flags["dark-mode"], Flag { enabled: true, source: Source::Config });
+        let invalid = FlagSettings { defaults: HashMap::from([("New Checkout".to_string(), true)]), file: None };
+        assert!(Flags::new(&invalid).is_err());
+    }
+
+    #[test]
+    fn evaluations_are_recorded_on_the_span() {
+        let telemetry = crate::test_support::init();
+        let flags = Flags::default();
+        flags.set("new-checkout", true).unwrap();
+
+        tracing::info_span!("request").in_scope(|| {
+            assert!(flags.is_enabled("new-checkout"));
+            assert!(!flags.is_enabled("missing"));
+        });
+
+        let spans = telemetry.spans();
+        let span = spans
+            .assert_span_exists("request")
+            .with_attribute("feature_flag.new-checkout", true)
+            .with_attribute("feature_flag.missing", false)
+            .span();
+        let variants: Vec<_> = span
+            .events
+            .iter()
+            .filter(|event| event.name == "feature_flag")
+            .flat_map(|event| event.attributes.iter().filter(|kv| kv.key.as_str() == "feature_flag.variant").map(|kv| kv.value.to_string()))
+            .collect();
+        assert_eq!(variants, ["on", "off"]);
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Instant;
+
+use opentelemetry::KeyValue;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::HashingSettings;
+
+// Password hashing at the configured cost, timed by cost so the effect of a new work
+// factor can be measured before it is rolled out
+pub struct Hasher {
+    settings: HashingSettings,
+    duration: opentelemetry::metrics::Histogram<f64>,
+}
+
+impl Hasher {
+    // Fails on a cost bcrypt doesn't take, which would fail every hash
+    pub fn new(settings: HashingSettings) -> Result<Self, String> {
+        if !(4..=31).contains(&settings.bcrypt_cost) {
+            return Err(format!("bcrypt_cost {} is not between 4 and 31", settings.bcrypt_cost));
+        }
+        let duration = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .f64_histogram("password.hash.duration")
+            .with_unit("ms")
+            .with_description("Time spent computing a bcrypt hash, by cost")
+            .init();
+
+        Ok(Self { settings, duration })
+    }
+
+    // Runs on the blocking pool, the span and the histogram only count the hashing itself
+    pub async fn hash(&self, name: &'static str, password: String) -> Result<String, bcrypt::BcryptError> {
+        let cost = self.settings.bcrypt_cost;
+        let duration = self.duration.clone();
+
+        crate::blocking::run_blocking(name, move || {
+            tracing::Span::current().set_attribute("bcrypt.cost", cost as i64);
+
+            let started = Instant::now();
+            let hashed = bcrypt::hash(password, cost);
+            duration.record(started.elapsed().as_secs_f64() * 1000.0, &[KeyValue::new("bcrypt.cost", cost as i64)]);
+            hashed
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_cost_bcrypt_refuses_is_a_config_error() {
+        assert!(Hasher::new(HashingSettings { bcrypt_cost: 4 }).is_ok());
+        assert!(Hasher::new(HashingSettings { bcrypt_cost: 3 }).is_err());
+        assert!(Hasher::new(HashingSettings { bcrypt_cost: 32 }).is_err());
+    }
+}
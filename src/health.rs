@@ -0,0 +1,386 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "mysql")]
+use std::sync::Mutex;
+#[cfg(feature = "mysql")]
+use std::time::{Duration, Instant};
+
+use axum::response::IntoResponse;
+
+#[cfg(feature = "mysql")]
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::HealthSettings;
+#[cfg(feature = "mysql")]
+use crate::contention::TracedMutex;
+#[cfg(feature = "mysql")]
+use crate::session::SessionStore;
+use crate::sampling::TenantRates;
+use crate::telemetry::ExporterHealth;
+
+// Upper bound on a readiness ping, probes have short timeouts of their own
+#[cfg(feature = "mysql")]
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Health {
+    settings: HealthSettings,
+    exporter: Arc<ExporterHealth>,
+    // The ratios in use, reported by `/health/details`
+    sampling: Option<TenantRates>,
+    started: AtomicBool,
+    #[cfg(feature = "mysql")]
+    database: Option<Database>,
+}
+
+impl Health {
+    pub fn new(settings: HealthSettings, exporter: Arc<ExporterHealth>) -> Self {
+        Self {
+            settings,
+            exporter,
+            sampling: None,
+            started: AtomicBool::new(false),
+            #[cfg(feature = "mysql")]
+            database: None,
+        }
+    }
+
+    pub fn with_sampling(mut self, sampling: TenantRates) -> Self {
+        self.sampling = Some(sampling);
+        self
+    }
+
+    #[cfg(feature = "mysql")]
+    pub fn with_database(mut self, database: Database) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    // Called once the listener is about to accept connections
+    pub fn mark_started(&self) {
+        self.started.store(true, Ordering::Relaxed);
+    }
+
+    // Cached readiness of the database, None when there is none; degraded when it is down
+    // but reads are served from the copies kept of them
+    #[cfg(feature = "mysql")]
+    async fn database_ready(&self) -> Option<(Status, serde_json::Value)> {
+        let database = self.database.as_ref()?;
+        let ok = database.ok(Duration::from_secs(self.settings.db_ping_cache_secs)).await;
+        let serving_stale = database.copies.as_ref().is_some_and(|copies| copies.any());
+        let status = match (ok, serving_stale) {
+            (true, _) => Status::Ok,
+            (false, true) => Status::Degraded,
+            (false, false) => Status::Down,
+        };
+        let body = serde_json::json!({
+            "ok": ok,
+            "degraded": status == Status::Degraded,
+            "error": *database.last_ping_error.lock().unwrap(),
+        });
+        Some((status, body))
+    }
+
+    #[cfg(not(feature = "mysql"))]
+    async fn database_ready(&self) -> Option<(Status, serde_json::Value)> {
+        None
+    }
+
+    // Fresh checks of the database and the session cache in front of it
+    #[cfg(feature = "mysql")]
+    async fn database_details(&self) -> Vec<(&'static str, Status, serde_json::Value)> {
+        let Some(database) = &self.database else {
+            return Vec::new();
+        };
+        let (mysql_status, mysql) = database.check_mysql().await;
+        let (cache_status, cache) = database.check_session_cache();
+        vec![("mysql", mysql_status, mysql), ("session_cache", cache_status, cache)]
+    }
+
+    #[cfg(not(feature = "mysql"))]
+    async fn database_details(&self) -> Vec<(&'static str, Status, serde_json::Value)> {
+        Vec::new()
+    }
+}
+
+// The MySQL pool and what sits on top of it
+#[cfg(feature = "mysql")]
+pub struct Database {
+    pool: sqlx::MySqlPool,
+    sessions: Arc<SessionStore>,
+    breaker: Arc<CircuitBreaker>,
+    // With `[degraded]` on, the copies reads go on from while the database is down
+    copies: Option<Arc<crate::items::Copies>>,
+    last_ping: TracedMutex<Option<(Instant, bool)>>,
+    last_ping_error: Mutex<Option<String>>,
+}
+
+#[cfg(feature = "mysql")]
+impl Database {
+    pub fn new(pool: sqlx::MySqlPool, sessions: Arc<SessionStore>, breaker: Arc<CircuitBreaker>) -> Self {
+        Self {
+            pool,
+            sessions,
+            breaker,
+            copies: None,
+            // Probes queue here while one of them pings
+            last_ping: TracedMutex::new("health.last_ping", Duration::from_millis(10), None),
+            last_ping_error: Mutex::new(None),
+        }
+    }
+
+    pub fn serving_stale(mut self, copies: Arc<crate::items::Copies>) -> Self {
+        self.copies = Some(copies);
+        self
+    }
+
+    // Pings the database at most once per cache period, concurrent probes share the result
+    async fn ok(&self, max_age: Duration) -> bool {
+        let mut last_ping = self.last_ping.lock().await;
+        if let Some((at, ok)) = *last_ping {
+            if at.elapsed() < max_age {
+                return ok;
+            }
+        }
+
+        let result = self.ping().await;
+        let ok = result.is_ok();
+        *self.last_ping_error.lock().unwrap() = result.err();
+        *last_ping = Some((Instant::now(), ok));
+        ok
+    }
+
+    async fn ping(&self) -> Result<(), String> {
+        match tokio::time::timeout(PING_TIMEOUT, sqlx::query("SELECT 1").execute(&self.pool)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("ping timed out".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Ok,
+    Degraded,
+    Down,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Degraded => "degraded",
+            Status::Down => "down",
+        }
+    }
+}
+
+// Each check gets its own span and reports its status on it
+fn record_status(status: Status) -> Status {
+    let span = tracing::Span::current();
+    span.record("health.status", status.as_str());
+    if status != Status::Ok {
+        span.record("otel.status_code", "error");
+    }
+    status
+}
+
+impl Health {
+    #[tracing::instrument(name = "health check", skip(self), fields(health.dependency = "otlp_exporter", health.status, otel.status_code))]
+    fn check_exporter(&self) -> (Status, serde_json::Value) {
+        let status = match (self.exporter.is_healthy(), self.exporter.last_success_ms()) {
+            (true, _) => Status::Ok,
+            // Spans got through before, the collector may be coming back
+            (false, Some(_)) => Status::Degraded,
+            (false, None) => Status::Down,
+        };
+
+        let body = serde_json::json!({
+            "status": record_status(status).as_str(),
+            "last_success_unix_ms": self.exporter.last_success_ms(),
+            "last_failure_unix_ms": self.exporter.last_failure_ms(),
+            "last_error": self.exporter.last_error(),
+        });
+        (status, body)
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl Database {
+    #[tracing::instrument(
+        name = "health check",
+        skip(self),
+        fields(health.dependency = "mysql", health.status, otel.status_code, db.ping_ms)
+    )]
+    async fn check_mysql(&self) -> (Status, serde_json::Value) {
+        let started = Instant::now();
+        let result = self.ping().await;
+        let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+        tracing::Span::current().record("db.ping_ms", latency_ms);
+
+        let breaker = self.breaker.state_name();
+        let status = match (&result, breaker) {
+            (Err(_), _) => Status::Down,
+            (Ok(()), "closed") => Status::Ok,
+            (Ok(()), _) => Status::Degraded,
+        };
+
+        let body = serde_json::json!({
+            "status": record_status(status).as_str(),
+            "latency_ms": latency_ms,
+            "circuit_breaker": breaker,
+            "pool_size": self.pool.size(),
+            "pool_idle": self.pool.num_idle(),
+            "error": result.err(),
+        });
+        (status, body)
+    }
+
+    #[tracing::instrument(name = "health check", skip(self), fields(health.dependency = "session_cache", health.status, otel.status_code))]
+    fn check_session_cache(&self) -> (Status, serde_json::Value) {
+        let stats = self.sessions.cache_stats();
+        // A full cache is cleared on the next insert, so lookups keep missing
+        let status = if stats.entries >= stats.capacity { Status::Degraded } else { Status::Ok };
+
+        let body = serde_json::json!({
+            "status": record_status(status).as_str(),
+            "entries": stats.entries,
+            "capacity": stats.capacity,
+            "hits": stats.hits,
+            "misses": stats.misses,
+        });
+        (status, body)
+    }
+}
+
+fn status(ok: bool) -> axum::http::StatusCode {
+    if ok {
+        axum::http::StatusCode::OK
+    } else {
+        axum::http::StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+#[utoipa::path(get, path = "/healthz/live", summary = "Liveness probe", responses((status = 200, body = String, description = "Alive")))]
+async fn live() -> &'static str {
+    "ok"
+}
+
+#[utoipa::path(get, path = "/healthz/startup", summary = "Startup probe", responses(
+    (status = 200, body = String, description = "Started"),
+    (status = 503, body = String, description = "Starting"),
+))]
+async fn startup(axum::extract::State(health): axum::extract::State<Arc<Health>>) -> impl IntoResponse {
+    let started = health.started.load(Ordering::Relaxed);
+    (status(started), if started { "ok" } else { "starting" })
+}
+
+#[utoipa::path(get, path = "/healthz/ready", summary = "Readiness probe", responses(
+    (status = 200, body = Object, description = "Ready"),
+    (status = 503, body = Object, description = "Not ready"),
+))]
+async fn ready(axum::extract::State(health): axum::extract::State<Arc<Health>>) -> impl IntoResponse {
+    let database = health.database_ready().await;
+    let exporter = health.exporter.is_healthy();
+    let ready = health.started.load(Ordering::Relaxed)
+        && database.as_ref().is_none_or(|(status, _)| *status != Status::Down)
+        && (exporter || !health.settings.require_exporter);
+    // Still ready, only partly
+    let degraded = database.as_ref().is_some_and(|(status, _)| *status == Status::Degraded);
+
+    let body = serde_json::json!({
+        "status": match (ready, degraded) {
+            (false, _) => "unavailable",
+            (true, true) => "degraded",
+            (true, false) => "ok",
+        },
+        "database": database.map(|(_, body)| body),
+        "exporter": {
+            "ok": exporter,
+            "required": health.settings.require_exporter,
+            "error": if exporter { None } else { health.exporter.last_error() },
+        },
+    });
+    (status(ready), axum::Json(body))
+}
+
+// Every dependency is checked afresh; only a dependency that is down makes this a 503
+#[utoipa::path(get, path = "/health/details", summary = "Status of every dependency", responses(
+    (status = 200, body = Object, description = "All up"),
+    (status = 503, body = Object, description = "Something is down"),
+))]
+#[tracing::instrument(name = "health details", skip_all, fields(health.status))]
+async fn details(axum::extract::State(health): axum::extract::State<Arc<Health>>) -> impl IntoResponse {
+    let (exporter_status, exporter) = health.check_exporter();
+    let checks = health.database_details().await;
+
+    let overall = checks.iter().map(|(_, status, _)| *status).fold(exporter_status, Status::max);
+    let mut dependencies = serde_json::Map::new();
+    dependencies.insert("otlp_exporter".to_string(), exporter);
+    for (name, _, body) in checks {
+        dependencies.insert(name.to_string(), body);
+    }
+
+    tracing::Span::current().record("health.status", overall.as_str());
+
+    // The ratio in effect, whichever of the file, TRACE_SAMPLE_RATE and the admin API set it
+    let body = serde_json::json!({
+        "status": overall.as_str(),
+        "dependencies": dependencies,
+        "sampling": health.sampling.as_ref().map(TenantRates::get),
+    });
+    (status(overall != Status::Down), axum::Json(body))
+}
+
+// Probe routes, merged outside the middleware stack so probe traffic gets no request
+// span, no access log and no auth or limits; `/health/details` traces its own checks
+pub fn router(health: Arc<Health>) -> utoipa_axum::router::OpenApiRouter {
+    crate::openapi::router()
+        .routes(utoipa_axum::routes!(live))
+        .routes(utoipa_axum::routes!(ready))
+        .routes(utoipa_axum::routes!(startup))
+        .routes(utoipa_axum::routes!(details))
+        .with_state(health)
+}
+
+#[cfg(all(test, feature = "mysql"))]
+mod tests {
+    use super::*;
+    use tower::ServiceExt;
+
+    async fn ready_status(app: &axum::Router) -> (axum::http::StatusCode, serde_json::Value) {
+        let request = axum::http::Request::get("/healthz/ready").body(axum::body::Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn degraded_only_with_copies_to_serve() {
+        // The database is a closed port
+        let pool = sqlx::mysql::MySqlPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy_with(sqlx::mysql::MySqlConnectOptions::new().host("127.0.0.1").port(1));
+        let settings = crate::config::Settings::default();
+        let copies = Arc::new(crate::items::Copies::new(&crate::config::DegradedSettings { enabled: true, ..Default::default() }));
+        let database = Database::new(
+            pool.clone(),
+            Arc::new(SessionStore::new(pool, settings.session.clone())),
+            Arc::new(CircuitBreaker::new("mysql", settings.circuit_breaker.clone())),
+        );
+        let health = Health::new(HealthSettings { db_ping_cache_secs: 0, ..settings.health.clone() }, Default::default())
+            .with_database(database.serving_stale(copies.clone()));
+        health.mark_started();
+        let app = axum::Router::from(router(Arc::new(health)));
+
+        let (status, body) = ready_status(&app).await;
+        assert_eq!(status, axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "unavailable");
+
+        copies.keep_read(1);
+        let (status, body) = ready_status(&app).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["database"]["degraded"], true);
+    }
+}
@@ -0,0 +1,100 @@
+// jemalloc as the global allocator, with the `jemalloc` feature, and its statistics: the
+// `allocator.memory` gauges by `allocator.state`, and `/debug/heap` for a look now. A leak
+// shows as `allocated` growing; `resident` well above `active` is memory jemalloc keeps
+// for reuse rather than a leak, and `metadata` is its own bookkeeping.
+
+use opentelemetry::KeyValue;
+use tikv_jemalloc_ctl::{epoch, stats};
+
+// With `resource-usage` its counting allocator goes around this one, see `usage`
+#[cfg(not(feature = "resource-usage"))]
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Stats {
+    // Bytes the application asked for and hasn't freed
+    pub allocated: usize,
+    // In pages holding allocations, fragmentation included
+    pub active: usize,
+    pub metadata: usize,
+    // Physically resident pages jemalloc has mapped
+    pub resident: usize,
+    pub mapped: usize,
+    // Unmapped but kept for reuse, virtual only
+    pub retained: usize,
+}
+
+impl Stats {
+    // jemalloc caches its stats, an epoch refreshes them
+    pub fn read() -> Result<Self, String> {
+        epoch::advance().map_err(|e| format!("failed to refresh jemalloc stats: {e}"))?;
+        let read = |stat: tikv_jemalloc_ctl::Result<usize>| stat.map_err(|e| format!("failed to read jemalloc stats: {e}"));
+        Ok(Self {
+            allocated: read(stats::allocated::read())?,
+            active: read(stats::active::read())?,
+            metadata: read(stats::metadata::read())?,
+            resident: read(stats::resident::read())?,
+            mapped: read(stats::mapped::read())?,
+            retained: read(stats::retained::read())?,
+        })
+    }
+
+    fn states(&self) -> [(&'static str, usize); 6] {
+        [
+            ("allocated", self.allocated),
+            ("active", self.active),
+            ("metadata", self.metadata),
+            ("resident", self.resident),
+            ("mapped", self.mapped),
+            ("retained", self.retained),
+        ]
+    }
+}
+
+// Observable gauges read at every metric export; call after the meter provider is installed
+pub fn register_metrics() {
+    opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+        .u64_observable_gauge("allocator.memory")
+        .with_description("jemalloc's memory by allocator.state")
+        .with_unit("By")
+        .with_callback(|observer| match Stats::read() {
+            Ok(stats) => {
+                for (state, bytes) in stats.states() {
+                    observer.observe(bytes as u64, &[KeyValue::new("allocator.state", state)]);
+                }
+            }
+            Err(e) => tracing::warn!("{}", e),
+        })
+        .init();
+}
+
+#[utoipa::path(get, path = "/debug/heap", summary = "The allocator's statistics, in bytes", responses(
+    (status = 200, body = Object, description = "Allocated, active, metadata, resident, mapped and retained bytes"),
+    (status = 401, body = String, description = "No token"),
+    (status = 403, body = String, description = "The caller is not an admin"),
+))]
+pub async fn debug_handler() -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    match Stats::read() {
+        Ok(stats) => crate::response::Traced::new(axum::Json(stats)).into_response(),
+        Err(e) => crate::error::AppError::new(axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_follow_allocations() {
+        let before = Stats::read().unwrap();
+        let buffer = std::hint::black_box(vec![1u8; 64 << 20]);
+        let after = Stats::read().unwrap();
+        drop(buffer);
+
+        assert!(after.allocated >= before.allocated + (64 << 20) - (1 << 20), "{before:?} then {after:?}");
+        assert!(after.active >= after.allocated);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use opentelemetry::trace::TraceContextExt;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::HedgingSettings;
+
+// Latencies kept for the percentile estimate
+const WINDOW: usize = 256;
+// Below this many samples the configured initial delay is used
+const MIN_SAMPLES: usize = 20;
+
+// Fires a second attempt of an idempotent call when the first one is slower than
+// the observed latency percentile, and keeps whichever finishes first
+pub struct Hedger {
+    settings: HedgingSettings,
+    latencies: Mutex<VecDeque<Duration>>,
+    hedges: opentelemetry::metrics::Counter<u64>,
+}
+
+impl Hedger {
+    pub fn new(settings: HedgingSettings) -> Self {
+        let hedges = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .u64_counter("hedging.attempts")
+            .with_description("Hedged second attempts, by which attempt won")
+            .init();
+
+        Self {
+            settings,
+            latencies: Mutex::new(VecDeque::with_capacity(WINDOW)),
+            hedges,
+        }
+    }
+
+    fn delay(&self) -> Duration {
+        let latencies = self.latencies.lock().unwrap();
+        if latencies.len() < MIN_SAMPLES {
+            return Duration::from_millis(self.settings.initial_delay_ms);
+        }
+
+        let mut sorted: Vec<Duration> = latencies.iter().copied().collect();
+        sorted.sort();
+        // a percentile past 1 is the slowest sample
+        let index = ((sorted.len() - 1) as f64 * self.settings.percentile).round() as usize;
+        sorted[index.min(sorted.len() - 1)]
+    }
+
+    fn observe(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().unwrap();
+        if latencies.len() == WINDOW {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+
+    // `attempt` must be safe to run twice, only use this for reads
+    pub async fn run<T, E, F, Fut>(&self, attempt: F) -> Result<T, E>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        if !self.settings.enabled {
+            return attempt().await;
+        }
+
+        let span = tracing::Span::current();
+        let delay = self.delay();
+        let started = Instant::now();
+
+        let first_span = tracing::info_span!("hedge attempt", hedge.attempt = 1);
+        let first = attempt().instrument(first_span.clone());
+        tokio::pin!(first);
+
+        tokio::select! {
+            result = &mut first => {
+                self.observe(started.elapsed());
+                span.set_attribute("hedge.fired", false);
+                return result;
+            }
+            _ = tokio::time::sleep(delay) => (),
+        }
+
+        // Both attempts link to each other, so either one leads to the other in the trace view
+        let second_span = tracing::info_span!("hedge attempt", hedge.attempt = 2, hedge.delay_ms = delay.as_millis() as u64);
+        second_span.add_link(first_span.context().span().span_context().clone());
+        first_span.add_link(second_span.context().span().span_context().clone());
+
+        let second = attempt().instrument(second_span.clone());
+        tokio::pin!(second);
+
+        // A failed attempt doesn't win while the other one may still succeed; the loser is
+        // cancelled when it's dropped still running, not when it had failed already
+        let (winner, result, cancelled) = tokio::select! {
+            result = &mut first => match result {
+                Ok(value) => (1, Ok(value), true),
+                Err(_) => (2, (&mut second).await, false),
+            },
+            result = &mut second => match result {
+                Ok(value) => (2, Ok(value), true),
+                Err(_) => (1, (&mut first).await, false),
+            },
+        };
+
+        // Whichever won, for the window to hold the latencies the callers got
+        self.observe(started.elapsed());
+        if cancelled {
+            let loser = if winner == 1 { &second_span } else { &first_span };
+            loser.set_attribute("hedge.cancelled", true);
+        }
+
+        span.set_attribute("hedge.fired", true);
+        span.set_attribute("hedge.winner", winner as i64);
+        self.hedges.add(1, &[opentelemetry::KeyValue::new("winner", winner as i64)]);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn hedger(percentile: f64) -> Hedger {
+        Hedger::new(HedgingSettings { enabled: true, percentile, initial_delay_ms: 10 })
+    }
+
+    #[test]
+    fn a_percentile_past_one_is_the_slowest_sample() {
+        let hedger = hedger(1.5);
+        for ms in 1..=MIN_SAMPLES as u64 {
+            hedger.observe(Duration::from_millis(ms));
+        }
+        assert_eq!(hedger.delay(), Duration::from_millis(MIN_SAMPLES as u64));
+    }
+
+    #[tokio::test]
+    async fn a_hedged_win_is_observed_and_a_failed_loser_not_cancelled() {
+        let telemetry = crate::test_support::init();
+        let hedger = hedger(0.95);
+        let attempts = AtomicU64::new(0);
+
+        // The first attempt fails after the second has started, then the second wins
+        let result: Result<u64, &str> = hedger
+            .run(|| {
+                let attempt = attempts.fetch_add(1, Ordering::Relaxed) + 1;
+                async move {
+                    match attempt {
+                        1 => {
+                            tokio::time::sleep(Duration::from_millis(20)).await;
+                            Err("refused")
+                        }
+                        _ => {
+                            tokio::time::sleep(Duration::from_millis(40)).await;
+                            Ok(attempt)
+                        }
+                    }
+                }
+            })
+            .instrument(tracing::info_span!("read"))
+            .await;
+        assert_eq!(result, Ok(2));
+        assert_eq!(hedger.latencies.lock().unwrap().len(), 1);
+
+        let spans = telemetry.spans();
+        spans.assert_span_exists("read").with_attribute("hedge.winner", 2_i64);
+        assert!(spans.all().iter().filter(|span| span.name == "hedge attempt").all(|span| !span.attributes.iter().any(|kv| kv.key.as_str() == "hedge.cancelled")));
+    }
+}
@@ -0,0 +1,106 @@
+use crate::config::DependencySettings;
+use crate::middleware::deadline::{Deadline, DEADLINE_HEADER};
+use crate::retry::Policy;
+
+// Set by the client or for a single hop, not passed on with a forwarded request
+const HOP_BY_HOP: [axum::http::HeaderName; 4] = [
+    axum::http::header::HOST,
+    axum::http::header::CONNECTION,
+    axum::http::header::CONTENT_LENGTH,
+    axum::http::header::TRANSFER_ENCODING,
+];
+
+// An incoming request to send on to `base_url`, the path and query appended
+pub fn forward(base_url: &str, parts: &axum::http::request::Parts, body: bytes::Bytes) -> Result<reqwest::Request, String> {
+    let target = parts.uri.path_and_query().map_or(parts.uri.path(), |target| target.as_str());
+    let url = reqwest::Url::parse(&format!("{}{target}", base_url.trim_end_matches('/'))).map_err(|e| e.to_string())?;
+    let mut request = reqwest::Request::new(parts.method.clone(), url);
+    for (name, value) in &parts.headers {
+        if !HOP_BY_HOP.contains(name) {
+            request.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    *request.body_mut() = Some(body.into());
+    Ok(request)
+}
+
+// Outbound HTTP client which traces every call and hands the trace context
+// and the remaining request deadline on to the downstream service
+#[derive(Clone)]
+pub struct HttpClient {
+    inner: reqwest::Client,
+    policy: std::sync::Arc<Policy>,
+}
+
+impl HttpClient {
+    pub fn new(settings: &DependencySettings) -> Result<Self, String> {
+        let policy = Policy::new(settings.clone());
+        Ok(Self {
+            inner: reqwest::Client::builder()
+                .connect_timeout(policy.connect_timeout())
+                .read_timeout(policy.read_timeout())
+                .dns_resolver(std::sync::Arc::new(crate::dns::Resolver(crate::dns::Origin::Traced)))
+                .build()
+                .map_err(|e| format!("can't build the HTTP client: {e}"))?,
+            policy: std::sync::Arc::new(policy),
+        })
+    }
+
+    // A GET is sent again if it didn't get through or timed out, a span for each attempt
+    pub async fn get(&self, url: &str) -> Result<reqwest::Response, reqwest::Error> {
+        self.policy
+            .run(
+                |e: &reqwest::Error| e.is_connect() || e.is_timeout(),
+                || async {
+                    let request = self.inner.get(url).build()?;
+                    self.execute(request).await
+                },
+            )
+            .await
+    }
+
+    pub async fn execute(&self, mut request: reqwest::Request) -> Result<reqwest::Response, reqwest::Error> {
+        let span = tracing::info_span!(
+            "http client",
+            otel.name = request.method().as_str(),
+            otel.kind = "client",
+            otel.status_code = tracing::field::Empty,
+            http.request.method = request.method().as_str(),
+            url.full = request.url().as_str(),
+            server.address = request.url().host_str().unwrap_or(""),
+            http.response.status_code = tracing::field::Empty,
+            deadline.budget_ms = tracing::field::Empty,
+        );
+
+        crate::propagation::inject(&span, request.headers_mut());
+
+        if let Some(deadline) = Deadline::current() {
+            span.record("deadline.budget_ms", deadline.remaining().as_millis() as u64);
+            *request.timeout_mut() = Some(deadline.remaining());
+            if let Ok(value) = deadline.to_header_value().parse() {
+                request.headers_mut().insert(DEADLINE_HEADER, value);
+            }
+        }
+
+        let sent = tracing::Instrument::instrument(self.inner.execute(request), span.clone());
+        let result = crate::middleware::time_breakdown::timed(crate::middleware::time_breakdown::Part::Downstream, sent).await;
+        match &result {
+            Ok(response) => {
+                span.record("http.response.status_code", response.status().as_u16());
+                if response.status().is_server_error() {
+                    span.record("otel.status_code", "error");
+                }
+            }
+            Err(e) => {
+                span.record("otel.status_code", "error");
+                span.in_scope(|| {
+                    if e.is_timeout() {
+                        tracing::warn!("Request deadline exhausted waiting for downstream");
+                    }
+                    tracing::error!("Downstream request failed: {:?}", e);
+                });
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,341 @@
+// Retries of mutating requests which don't happen twice. A POST, PUT, PATCH or DELETE with an
+// `Idempotency-Key` header runs once per key and caller; the same request again gets the first
+// response back from the `idempotency_keys` table, with `Idempotent-Replayed: true`, and the
+// handler doesn't run. The request span records `idempotency.replayed`, and a replay links to
+// the span of the request it replays. A retry while the first is still running is a 409, the
+// key with another request a 422. Server errors aren't kept, so those can be retried.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use opentelemetry::trace::TraceContextExt;
+use sha2::Digest;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::IdempotencySettings;
+use crate::middleware::db_calls::Counted;
+
+pub const HEADER: &str = "idempotency-key";
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+// The header, as a parameter of the routes taking it in the OpenAPI description
+#[derive(utoipa::IntoParams)]
+#[into_params(parameter_in = Header)]
+#[allow(dead_code)]
+pub struct KeyHeader {
+    /// Runs the request once; the same request with the key again gets the first response, with `Idempotent-Replayed: true`
+    #[param(rename = "Idempotency-Key", min_length = 1, max_length = 255)]
+    key: Option<String>,
+}
+
+// Expired keys are deleted this often, besides when they are used again
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// A response as it was first sent, with the span of the request which sent it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stored {
+    status: u16,
+    content_type: Option<String>,
+    body: Vec<u8>,
+    trace_id: Option<String>,
+    span_id: Option<String>,
+}
+
+enum Claim {
+    // This request holds the key now
+    New,
+    InProgress,
+    Mismatch,
+    Replay(Stored),
+}
+
+type Row = (String, Option<u16>, Option<String>, Option<Vec<u8>>, Option<String>, Option<String>);
+
+pub struct IdempotencyStore {
+    pool: sqlx::MySqlPool,
+    settings: IdempotencySettings,
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+// Of what the request does, so the key can't be reused for something else
+fn fingerprint(method: &axum::http::Method, uri: &axum::http::Uri, body: &[u8]) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(method.as_str());
+    hasher.update(b" ");
+    hasher.update(uri.path_and_query().map(|path| path.as_str()).unwrap_or("/"));
+    hasher.update(b"\n");
+    hasher.update(body);
+    hasher.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+impl IdempotencyStore {
+    pub fn new(pool: sqlx::MySqlPool, settings: IdempotencySettings) -> Self {
+        Self { pool, settings }
+    }
+
+    // Takes the key unless another request holds it or held it, an expired hold aside
+    #[tracing::instrument(name = "idempotency claim", skip_all, fields(idempotency.claimed = tracing::field::Empty))]
+    async fn claim(&self, scope: &str, key: &str, fingerprint: &str) -> Result<Claim, sqlx::Error> {
+        let now = unix_now();
+        sqlx::query(&crate::sqlcommenter::tag("DELETE FROM idempotency_keys WHERE scope = ? AND idempotency_key = ? AND expires_at <= ?"))
+            .bind(scope)
+            .bind(key)
+            .bind(now)
+            .execute(&self.pool)
+            .counted()
+            .await?;
+
+        let inserted = sqlx::query(&crate::sqlcommenter::tag(
+            "INSERT IGNORE INTO idempotency_keys (scope, idempotency_key, fingerprint, expires_at) VALUES (?, ?, ?, ?)",
+        ))
+        .bind(scope)
+        .bind(key)
+        .bind(fingerprint)
+        .bind(now + self.settings.lock_secs as i64)
+        .execute(&self.pool)
+        .counted()
+        .await?;
+        tracing::Span::current().record("idempotency.claimed", inserted.rows_affected() == 1);
+        if inserted.rows_affected() == 1 {
+            return Ok(Claim::New);
+        }
+
+        let row: Option<Row> = sqlx::query_as(&crate::sqlcommenter::tag(
+            "SELECT fingerprint, status, content_type, body, trace_id, span_id FROM idempotency_keys WHERE scope = ? AND idempotency_key = ?",
+        ))
+        .bind(scope)
+        .bind(key)
+        .fetch_optional(&self.pool)
+        .counted()
+        .await?;
+
+        Ok(match row {
+            Some((stored, ..)) if stored != fingerprint => Claim::Mismatch,
+            Some((_, Some(status), content_type, body, trace_id, span_id)) => Claim::Replay(Stored {
+                status,
+                content_type,
+                body: body.unwrap_or_default(),
+                trace_id,
+                span_id,
+            }),
+            // Still running, or released a moment ago
+            _ => Claim::InProgress,
+        })
+    }
+
+    #[tracing::instrument(name = "idempotency save", skip_all, fields(http.response.status_code = stored.status))]
+    async fn save(&self, scope: &str, key: &str, stored: &Stored) -> Result<(), sqlx::Error> {
+        sqlx::query(&crate::sqlcommenter::tag(
+            "UPDATE idempotency_keys SET status = ?, content_type = ?, body = ?, trace_id = ?, span_id = ?, expires_at = ?
+             WHERE scope = ? AND idempotency_key = ?",
+        ))
+        .bind(stored.status)
+        .bind(&stored.content_type)
+        .bind(&stored.body)
+        .bind(&stored.trace_id)
+        .bind(&stored.span_id)
+        .bind(unix_now() + self.settings.ttl_secs as i64)
+        .bind(scope)
+        .bind(key)
+        .execute(&self.pool)
+        .counted()
+        .await?;
+        Ok(())
+    }
+
+    // Lets the key be used again, for a response which isn't kept
+    #[tracing::instrument(name = "idempotency release", skip_all)]
+    async fn release(&self, scope: &str, key: &str) {
+        let deleted = sqlx::query(&crate::sqlcommenter::tag("DELETE FROM idempotency_keys WHERE scope = ? AND idempotency_key = ?"))
+            .bind(scope)
+            .bind(key)
+            .execute(&self.pool)
+            .counted()
+            .await;
+        if let Err(e) = deleted {
+            tracing::error!("Failed to release idempotency key: {:?}", e);
+        }
+    }
+
+    // Deletes expired keys every hour until the process exits
+    pub fn spawn_purge(self: &Arc<Self>) {
+        if !self.settings.enabled {
+            return;
+        }
+
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PURGE_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let purged = sqlx::query(&crate::sqlcommenter::tag("DELETE FROM idempotency_keys WHERE expires_at <= ?"))
+                    .bind(unix_now())
+                    .execute(&store.pool)
+                    .await;
+                if let Err(e) = purged {
+                    tracing::warn!("Failed to purge expired idempotency keys: {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+// The first response again, linked from the current span to the one which sent it
+fn replay(stored: Stored) -> axum::response::Response {
+    let span = tracing::Span::current();
+    span.set_attribute("idempotency.replayed", true);
+    let trace_id = stored.trace_id.as_deref().and_then(|id| opentelemetry::trace::TraceId::from_hex(id).ok());
+    let span_id = stored.span_id.as_deref().and_then(|id| opentelemetry::trace::SpanId::from_hex(id).ok());
+    if let (Some(trace_id), Some(span_id)) = (trace_id, span_id) {
+        span.add_link(opentelemetry::trace::SpanContext::new(
+            trace_id,
+            span_id,
+            opentelemetry::trace::TraceFlags::SAMPLED,
+            true,
+            opentelemetry::trace::TraceState::default(),
+        ));
+    }
+
+    let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    let mut response = (status, stored.body).into_response();
+    let headers = response.headers_mut();
+    headers.remove(axum::http::header::CONTENT_TYPE);
+    if let Some(content_type) = stored.content_type.and_then(|value| value.parse().ok()) {
+        headers.insert(axum::http::header::CONTENT_TYPE, content_type);
+    }
+    headers.insert(REPLAYED_HEADER, axum::http::HeaderValue::from_static("true"));
+    response
+}
+
+fn too_large(e: &axum::Error) -> bool {
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        if e.is::<http_body_util::LengthLimitError>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+// Inside auth: a key is the caller's own, anonymous callers sharing theirs
+pub async fn layer(
+    axum::extract::State(store): axum::extract::State<Arc<IdempotencyStore>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let mutating = matches!(
+        *request.method(),
+        axum::http::Method::POST | axum::http::Method::PUT | axum::http::Method::PATCH | axum::http::Method::DELETE
+    );
+    let Some(key) = request.headers().get(HEADER).filter(|_| store.settings.enabled && mutating) else {
+        return next.run(request).await;
+    };
+    let Some(key) = key.to_str().ok().filter(|key| (1..=255).contains(&key.len())).map(str::to_string) else {
+        return (StatusCode::BAD_REQUEST, "Idempotency-Key must be 1 to 255 visible characters").into_response();
+    };
+    let scope = request
+        .extensions()
+        .get::<crate::middleware::auth::AuthUser>()
+        .map(|user| user.id.clone())
+        .unwrap_or_default();
+
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) if too_large(&e) => return (StatusCode::PAYLOAD_TOO_LARGE, "request body too large").into_response(),
+        Err(_) => return (StatusCode::BAD_REQUEST, "failed to read the request body").into_response(),
+    };
+
+    let span = tracing::Span::current();
+    match store.claim(&scope, &key, &fingerprint(&parts.method, &parts.uri, &body)).await {
+        Ok(Claim::New) => span.set_attribute("idempotency.replayed", false),
+        Ok(Claim::Replay(stored)) => return replay(stored),
+        Ok(Claim::InProgress) => return (StatusCode::CONFLICT, "a request with this Idempotency-Key is in progress").into_response(),
+        Ok(Claim::Mismatch) => {
+            return (StatusCode::UNPROCESSABLE_ENTITY, "this Idempotency-Key was used for a different request").into_response()
+        }
+        // Running it unguarded could do it twice
+        Err(e) => {
+            tracing::error!("Failed to claim idempotency key: {:?}", e);
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    }
+
+    let response = next.run(axum::extract::Request::from_parts(parts, axum::body::Body::from(body))).await;
+    let size = http_body::Body::size_hint(response.body()).upper();
+    if response.status().is_server_error() || size.is_none_or(|size| size > store.settings.max_response_bytes as u64) {
+        store.release(&scope, &key).await;
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to read the response to keep: {:?}", e);
+            store.release(&scope, &key).await;
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let span_context = span.context().span().span_context().clone();
+    let stored = Stored {
+        status: parts.status.as_u16(),
+        content_type: parts.headers.get(axum::http::header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(str::to_string),
+        body: body.to_vec(),
+        trace_id: span_context.is_valid().then(|| span_context.trace_id().to_string()),
+        span_id: span_context.is_valid().then(|| span_context.span_id().to_string()),
+    };
+    // The request is done either way; a retry would run it again
+    if let Err(e) = store.save(&scope, &key, &stored).await {
+        tracing::error!("Failed to save idempotent response: {:?}", e);
+        store.release(&scope, &key).await;
+    }
+    axum::response::Response::from_parts(parts, axum::body::Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+
+    #[test]
+    fn fingerprint_covers_the_request() {
+        let uri: axum::http::Uri = "/v1/items".parse().unwrap();
+        let post = fingerprint(&axum::http::Method::POST, &uri, br#"{"name": "a"}"#);
+        assert_eq!(post.len(), 64);
+        assert_eq!(post, fingerprint(&axum::http::Method::POST, &uri, br#"{"name": "a"}"#));
+        assert_ne!(post, fingerprint(&axum::http::Method::POST, &uri, br#"{"name": "b"}"#));
+        assert_ne!(post, fingerprint(&axum::http::Method::PUT, &uri, br#"{"name": "a"}"#));
+    }
+
+    #[tokio::test]
+    async fn replays_link_to_the_original_request() {
+        let telemetry = crate::test_support::init();
+        let stored = Stored {
+            status: 201,
+            content_type: Some("application/json".to_string()),
+            body: br#"{"id": 1}"#.to_vec(),
+            trace_id: Some("0af7651916cd43dd8448eb211c80319c".to_string()),
+            span_id: Some("b7ad6b7169203331".to_string()),
+        };
+
+        let response = async { replay(stored) }.instrument(tracing::info_span!("request")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[REPLAYED_HEADER], "true");
+        assert_eq!(response.headers()[axum::http::header::CONTENT_TYPE], "application/json");
+
+        let spans = telemetry.spans();
+        let span = spans.assert_span_exists("request").with_attribute("idempotency.replayed", true).span();
+        let link = &span.links.links[0];
+        assert_eq!(link.span_context.trace_id().to_string(), "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(link.span_context.span_id().to_string(), "b7ad6b7169203331");
+    }
+}
@@ -0,0 +1,47 @@
+// Trace and span ids from a seeded generator, with `telemetry.id_seed`: a run doing the same
+// things in the same order gets the same ids, for span-tree snapshots and screenshots which
+// don't change from one run to the next. Ids handed out from several threads at once come in
+// whichever order the threads do. Not for production, instances with the same seed collide.
+
+use std::sync::Mutex;
+
+use opentelemetry::trace::{SpanId, TraceId};
+use rand::{Rng, SeedableRng};
+
+#[derive(Debug)]
+pub struct SeededIdGenerator(Mutex<rand::rngs::StdRng>);
+
+impl SeededIdGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self(Mutex::new(rand::rngs::StdRng::seed_from_u64(seed)))
+    }
+}
+
+impl opentelemetry_sdk::trace::IdGenerator for SeededIdGenerator {
+    // All zeroes is invalid, for both
+    fn new_trace_id(&self) -> TraceId {
+        let mut rng = self.0.lock().unwrap();
+        TraceId::from_bytes(rng.gen_range(1..=u128::MAX).to_be_bytes())
+    }
+
+    fn new_span_id(&self) -> SpanId {
+        let mut rng = self.0.lock().unwrap();
+        SpanId::from_bytes(rng.gen_range(1..=u64::MAX).to_be_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_sdk::trace::IdGenerator;
+
+    #[test]
+    fn the_same_seed_gives_the_same_ids() {
+        let ids = |seed| {
+            let generator = SeededIdGenerator::new(seed);
+            (generator.new_trace_id(), generator.new_span_id(), generator.new_span_id())
+        };
+        assert_eq!(ids(42), ids(42));
+        assert_ne!(ids(42), ids(43));
+    }
+}
@@ -0,0 +1,613 @@
+// The `items` resource: list with keyset pagination, get, create, update and delete. Each
+// statement has a span of its own below the handler's, named after what it does to the
+// table, and goes through the circuit breaker like every other database call. Reads are
+// retried per `[dependencies.db]`; writes are only timed out, one may have happened.
+// `POST /items:batch` inserts many at once, a multi-row statement per chunk, and
+// `GET /items:batch?ids=…` reads many through a `dataloader`, 100 to a `WHERE id IN (…)`.
+// `GET /pages/items` and `/pages/items/:id` are the list and an item as HTML pages. With
+// `[degraded]` on, the pages and items read are kept, to answer with while the database is down.
+// An update or a delete invalidates the item's and the lists' cached responses. A client
+// leaving during a write is told apart, `database write` being the request's aborted stage.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::http::StatusCode;
+use tracing::Instrument;
+
+use crate::circuit_breaker;
+use crate::degraded::Stale;
+use crate::middleware::cache::Cache;
+use crate::middleware::db_calls::Counted;
+use crate::middleware::disconnect::Progress;
+use crate::result_ext::ResultExt;
+use crate::validated_json::ValidatedJson;
+use crate::AppState;
+
+pub const DEFAULT_PAGE: u32 = 50;
+pub const MAX_PAGE: u32 = 500;
+
+// Rows per INSERT of a batch, and items per batch
+pub const BATCH_CHUNK: usize = 100;
+pub const MAX_BATCH: usize = 5000;
+
+const COLUMNS: &str = "id, name, description, created_at, updated_at";
+
+type Row = (i64, String, Option<String>, i64, i64);
+
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct Item {
+    id: i64,
+    name: String,
+    description: Option<String>,
+    created_at_ms: i64,
+    updated_at_ms: i64,
+}
+
+impl From<Row> for Item {
+    fn from((id, name, description, created_at_ms, updated_at_ms): Row) -> Self {
+        Self { id, name, description, created_at_ms, updated_at_ms }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, validator::Validate, utoipa::ToSchema)]
+pub struct ItemInput {
+    #[validate(custom(function = "name_length"))]
+    #[schema(min_length = 1, max_length = 255)]
+    name: String,
+    description: Option<String>,
+}
+
+// In bytes, which `length` doesn't count
+fn name_length(name: &str) -> Result<(), validator::ValidationError> {
+    match name.len() {
+        1..=255 => Ok(()),
+        _ => Err(validator::ValidationError::new("length").with_message("name must be 1 to 255 bytes".into())),
+    }
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+pub struct Page {
+    /// `next_after` of the previous page
+    after: Option<i64>,
+    #[param(minimum = 1, maximum = 500, default = 50)]
+    limit: Option<u32>,
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+pub struct Ids {
+    /// Comma-separated item ids
+    ids: String,
+}
+
+// In the order asked for, those which exist
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct Items {
+    items: Vec<Item>,
+    missing: Vec<i64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct ItemsPage {
+    items: Vec<Item>,
+    /// `after` of the next page, none on the last one
+    next_after: Option<i64>,
+}
+
+// Body of a batch which wasn't inserted whole. A chunk is one statement, so it is in or
+// out as a whole; `first` is the index of its first item in the request.
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct BatchResult {
+    inserted: usize,
+    failed: Vec<FailedChunk>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct FailedChunk {
+    first: usize,
+    count: usize,
+    status: u16,
+    error: &'static str,
+}
+
+// `SELECT items` and the like, with the statement as `db.query.text` when it's exported, and
+// the rows it read or wrote once it's done
+fn query_span(operation: &'static str, sql: &str) -> tracing::Span {
+    let span = tracing::info_span!(
+        "db query",
+        otel.name = format!("{operation} items"),
+        db.collection.name = "items",
+        db.query.text = tracing::field::Empty,
+        db.response.returned_rows = tracing::field::Empty,
+        db.response.affected_rows = tracing::field::Empty,
+        db.operation.batch.size = tracing::field::Empty,
+    );
+    crate::attributes::set_all(&span, crate::attributes::db_client(operation));
+    crate::span_fields::record_lazy(&span, "db.query.text", || crate::span_fields::query_text(sql));
+    span
+}
+
+fn returned_rows(span: &tracing::Span, rows: usize) {
+    span.record("db.response.returned_rows", rows as i64);
+}
+
+fn affected_rows(span: &tracing::Span, done: &sqlx::mysql::MySqlQueryResult) {
+    span.record("db.response.affected_rows", done.rows_affected() as i64);
+}
+
+// The last pages and items read, by `after` and limit and by id
+pub struct Copies {
+    pages: crate::degraded::LastKnown<ItemsPage>,
+    items: crate::degraded::LastKnown<Item>,
+}
+
+impl Copies {
+    pub fn new(settings: &crate::config::DegradedSettings) -> Self {
+        Self {
+            pages: crate::degraded::LastKnown::new("items_page", settings),
+            items: crate::degraded::LastKnown::new("item", settings),
+        }
+    }
+
+    // Whether a read could be answered from a copy, for readiness
+    pub fn any(&self) -> bool {
+        !(self.pages.is_empty() && self.items.is_empty())
+    }
+}
+
+#[cfg(test)]
+impl Copies {
+    // What reading item `id`, and the first page with it alone, keeps
+    pub fn keep_read(&self, id: i64) {
+        let item = Item { id, name: format!("item {id}"), description: None, created_at_ms: 0, updated_at_ms: 0 };
+        self.pages.keep(format!("0:{DEFAULT_PAGE}"), &ItemsPage { items: vec![item.clone()], next_after: None });
+        self.items.keep(id.to_string(), &item);
+    }
+}
+
+// Unavailable while the database is, a server error otherwise
+fn status(e: circuit_breaker::Error<sqlx::Error>) -> StatusCode {
+    match &e {
+        circuit_breaker::Error::Open => StatusCode::SERVICE_UNAVAILABLE,
+        circuit_breaker::Error::Inner(e) if circuit_breaker::is_db_unavailable(e) => StatusCode::SERVICE_UNAVAILABLE,
+        circuit_breaker::Error::Inner(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn unix_now_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+#[utoipa::path(get, path = "/items", summary = "A page of items, by id", params(Page), responses(
+    (status = 200, body = ItemsPage, description = "The items, and where the next page starts"),
+))]
+#[traced_handler::traced_handler(degraded = tracing::field::Empty, degraded.age_ms = tracing::field::Empty)]
+pub async fn list(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    axum::extract::Query(page): axum::extract::Query<Page>,
+) -> Result<(Option<Stale>, axum::Json<ItemsPage>), StatusCode> {
+    let (page, stale) = read_page(&state, &page).await?;
+    Ok((stale, axum::Json(page)))
+}
+
+// A copy when the database is unavailable and one was kept
+async fn read_page(AppState { pool, db_breaker, db_policy, explainer, copies, .. }: &AppState, page: &Page) -> Result<(ItemsPage, Option<Stale>), StatusCode> {
+    let limit = page.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let key = format!("{}:{limit}", page.after.unwrap_or(0));
+
+    // One more than the page, to know whether there is a next one
+    let sql = crate::sqlcommenter::tag(&format!("SELECT {COLUMNS} FROM items WHERE id > ? ORDER BY id LIMIT ?"));
+    let span = query_span("SELECT", &sql);
+    let query = db_policy.run_timed(circuit_breaker::is_db_unavailable, || {
+        sqlx::query_as::<_, Row>(&sql).bind(page.after.unwrap_or(0)).bind(limit + 1).fetch_all(pool).counted()
+    });
+    let rows = explainer
+        .timed(&span, &sql, db_breaker.call(circuit_breaker::is_db_unavailable, query))
+        .instrument(span.clone())
+        .await
+        .trace_err()
+        .map_err(status);
+    let mut rows = match rows {
+        Ok(rows) => rows,
+        Err(StatusCode::SERVICE_UNAVAILABLE) if copies.pages.enabled() => {
+            return copies.pages.fallback(&key).map(|(page, stale)| (page, Some(stale))).ok_or(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        Err(status) => return Err(status),
+    };
+    returned_rows(&span, rows.len());
+
+    let next_after = (rows.len() > limit as usize).then(|| {
+        rows.truncate(limit as usize);
+        rows.last().map(|row| row.0)
+    });
+    let page = ItemsPage {
+        items: rows.into_iter().map(Item::from).collect(),
+        next_after: next_after.flatten(),
+    };
+    copies.pages.keep(key, &page);
+    Ok((page, None))
+}
+
+#[utoipa::path(get, path = "/items/{id}", summary = "One item", params(("id" = i64, Path)), responses(
+    (status = 200, body = Item, description = "The item"),
+    (status = 404, description = "No such item"),
+))]
+#[traced_handler::traced_handler(item.id = id, degraded = tracing::field::Empty, degraded.age_ms = tracing::field::Empty)]
+pub async fn get(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<i64>,
+) -> Result<(Option<Stale>, axum::Json<Item>), StatusCode> {
+    let (item, stale) = read(&state, id).await?;
+    Ok((stale, axum::Json(item.ok_or(StatusCode::NOT_FOUND)?)))
+}
+
+async fn read(AppState { pool, db_breaker, db_policy, explainer, copies, .. }: &AppState, id: i64) -> Result<(Option<Item>, Option<Stale>), StatusCode> {
+    let sql = crate::sqlcommenter::tag(&format!("SELECT {COLUMNS} FROM items WHERE id = ?"));
+    let span = query_span("SELECT", &sql);
+    let query = db_policy.run_timed(circuit_breaker::is_db_unavailable, || sqlx::query_as::<_, Row>(&sql).bind(id).fetch_optional(pool).counted());
+    let row = explainer
+        .timed(&span, &sql, db_breaker.call(circuit_breaker::is_db_unavailable, query))
+        .instrument(span.clone())
+        .await
+        .trace_err()
+        .map_err(status);
+    let row = match row {
+        Ok(row) => row,
+        Err(StatusCode::SERVICE_UNAVAILABLE) if copies.items.enabled() => {
+            return copies.items.fallback(&id.to_string()).map(|(item, stale)| (Some(item), Some(stale))).ok_or(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        Err(status) => return Err(status),
+    };
+    returned_rows(&span, row.iter().len());
+    let item = row.map(Item::from);
+    if let Some(item) = &item {
+        copies.items.keep(id.to_string(), item);
+    }
+    Ok((item, None))
+}
+
+#[derive(askama::Template)]
+#[template(path = "items.html")]
+struct ItemsHtml {
+    page: ItemsPage,
+    limit: u32,
+}
+
+#[derive(askama::Template)]
+#[template(path = "item.html")]
+struct ItemHtml {
+    item: Item,
+}
+
+// The same pages and items as HTML, the queries under the handler's span and the render
+// next to them
+#[utoipa::path(get, path = "/pages/items", summary = "A page of items as HTML", params(Page), responses(
+    (status = 200, body = String, content_type = "text/html", description = "The items, linking to the next page"),
+))]
+#[traced_handler::traced_handler(degraded = tracing::field::Empty, degraded.age_ms = tracing::field::Empty)]
+pub async fn list_page(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    axum::extract::Query(page): axum::extract::Query<Page>,
+) -> Result<(Option<Stale>, axum::response::Html<String>), StatusCode> {
+    let limit = page.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let (page, stale) = read_page(&state, &page).await?;
+    Ok((stale, crate::templates::page("items.html", &ItemsHtml { page, limit })?))
+}
+
+#[utoipa::path(get, path = "/pages/items/{id}", summary = "One item as HTML", params(("id" = i64, Path)), responses(
+    (status = 200, body = String, content_type = "text/html", description = "The item"),
+    (status = 404, description = "No such item"),
+))]
+#[traced_handler::traced_handler(item.id = id, degraded = tracing::field::Empty, degraded.age_ms = tracing::field::Empty)]
+pub async fn get_page(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<i64>,
+) -> Result<(Option<Stale>, axum::response::Html<String>), StatusCode> {
+    let (item, stale) = read(&state, id).await?;
+    let item = item.ok_or(StatusCode::NOT_FOUND)?;
+    Ok((stale, crate::templates::page("item.html", &ItemHtml { item })?))
+}
+
+#[utoipa::path(post, path = "/items", summary = "Creates an item", params(crate::idempotency::KeyHeader), request_body = ItemInput, responses(
+    (status = 201, body = Item, description = "The item"),
+    (status = 422, body = crate::validated_json::Invalid, description = "The name is empty or too long"),
+))]
+#[traced_handler::traced_handler(item.id = tracing::field::Empty)]
+pub async fn create(
+    axum::extract::State(AppState { pool, db_breaker, db_policy, .. }): axum::extract::State<AppState>,
+    progress: Option<axum::Extension<Progress>>,
+    ValidatedJson(input): ValidatedJson<ItemInput>,
+) -> Result<(StatusCode, axum::Json<Item>), (StatusCode, &'static str)> {
+    let now = unix_now_ms();
+
+    let sql = crate::sqlcommenter::tag("INSERT INTO items (name, description, created_at, updated_at) VALUES (?, ?, ?, ?)");
+    let query = db_policy.run_timed(|_| false, || sqlx::query(&sql).bind(&input.name).bind(&input.description).bind(now).bind(now).execute(&pool).counted());
+    let span = query_span("INSERT", &sql);
+    mark(&progress, "database write");
+    let done = db_breaker
+        .call(circuit_breaker::is_db_unavailable, query)
+        .instrument(span.clone())
+        .await
+        .trace_err()
+        .map_err(|e| (status(e), "failed to create the item"))?;
+    mark(&progress, "handler");
+    affected_rows(&span, &done);
+
+    let id = done.last_insert_id() as i64;
+    tracing::Span::current().record("item.id", id);
+    let item = Item {
+        id,
+        name: input.name,
+        description: input.description,
+        created_at_ms: now,
+        updated_at_ms: now,
+    };
+    Ok((StatusCode::CREATED, axum::Json(item)))
+}
+
+// The update and its read back in one transaction, retried on a deadlock
+#[utoipa::path(put, path = "/items/{id}", summary = "Replaces an item's name and description",
+    params(("id" = i64, Path), crate::idempotency::KeyHeader),
+    request_body = ItemInput,
+    responses(
+        (status = 200, body = Item, description = "The item"),
+        (status = 404, body = String, description = "No such item"),
+        (status = 422, body = crate::validated_json::Invalid, description = "The name is empty or too long"),
+    ),
+)]
+#[traced_handler::traced_handler(item.id = id)]
+pub async fn update(
+    axum::extract::State(AppState { pool, db_breaker, db_policy, explainer, transactions, .. }): axum::extract::State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<i64>,
+    axum::extract::OriginalUri(uri): axum::extract::OriginalUri,
+    cache: Option<axum::Extension<std::sync::Arc<Cache>>>,
+    progress: Option<axum::Extension<Progress>>,
+    ValidatedJson(input): ValidatedJson<ItemInput>,
+) -> Result<axum::Json<Item>, (StatusCode, &'static str)> {
+    let update = crate::sqlcommenter::tag("UPDATE items SET name = ?, description = ?, updated_at = ? WHERE id = ?");
+    // For the creation time, and the row as stored
+    let select = crate::sqlcommenter::tag(&format!("SELECT {COLUMNS} FROM items WHERE id = ?"));
+    let updated_at = unix_now_ms();
+    let input = std::sync::Arc::new(input);
+    let transaction = transactions.run(&pool, |connection| {
+        let (update, select, input, db_policy, explainer) = (update.clone(), select.clone(), input.clone(), db_policy.clone(), explainer.clone());
+        Box::pin(async move {
+            let span = query_span("UPDATE", &update);
+            let query = sqlx::query(&update).bind(&input.name).bind(&input.description).bind(updated_at).bind(id).execute(&mut *connection);
+            let done = explainer.timed(&span, &update, db_policy.timed(query.counted())).instrument(span.clone()).await?;
+            affected_rows(&span, &done);
+            if done.rows_affected() == 0 {
+                return Ok(None);
+            }
+
+            let span = query_span("SELECT", &select);
+            let query = sqlx::query_as::<_, Row>(&select).bind(id).fetch_one(&mut *connection);
+            let row = explainer.timed(&span, &select, db_policy.timed(query.counted())).instrument(span.clone()).await?;
+            returned_rows(&span, 1);
+            Ok(Some(row))
+        })
+    });
+    mark(&progress, "database write");
+    let row = db_breaker
+        .call(circuit_breaker::is_db_unavailable, transaction)
+        .await
+        .trace_err()
+        .map_err(|e| (status(e), "failed to update the item"))?;
+    mark(&progress, "handler");
+    let row = row.ok_or((StatusCode::NOT_FOUND, "no such item"))?;
+    invalidate(cache, uri.path()).await;
+    Ok(axum::Json(Item::from(row)))
+}
+
+#[utoipa::path(delete, path = "/items/{id}", summary = "Deletes an item", params(("id" = i64, Path), crate::idempotency::KeyHeader), responses(
+    (status = 204, description = "Deleted"),
+    (status = 404, description = "No such item"),
+))]
+#[traced_handler::traced_handler(item.id = id)]
+pub async fn delete(
+    axum::extract::State(AppState { pool, db_breaker, db_policy, explainer, .. }): axum::extract::State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<i64>,
+    axum::extract::OriginalUri(uri): axum::extract::OriginalUri,
+    cache: Option<axum::Extension<std::sync::Arc<Cache>>>,
+    progress: Option<axum::Extension<Progress>>,
+) -> StatusCode {
+    let sql = crate::sqlcommenter::tag("DELETE FROM items WHERE id = ?");
+    let query = db_policy.run_timed(|_| false, || sqlx::query(&sql).bind(id).execute(&pool).counted());
+    let span = query_span("DELETE", &sql);
+    mark(&progress, "database write");
+    let done = explainer
+        .timed(&span, &sql, db_breaker.call(circuit_breaker::is_db_unavailable, query))
+        .instrument(span.clone())
+        .await
+        .trace_err();
+    mark(&progress, "handler");
+    if let Ok(done) = &done {
+        affected_rows(&span, done);
+    }
+    match done {
+        Ok(done) if done.rows_affected() == 0 => StatusCode::NOT_FOUND,
+        Ok(_) => {
+            invalidate(cache, uri.path()).await;
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => status(e),
+    }
+}
+
+// Where a client leaving would find the request, the disconnect layer's stage
+fn mark(progress: &Option<axum::Extension<Progress>>, stage: &'static str) {
+    if let Some(axum::Extension(progress)) = progress {
+        progress.mark(stage);
+    }
+}
+
+// The cached responses a change of the item at `path`, `/v1/items/7` say, makes stale: the
+// item's and the list's, as JSON and as pages
+async fn invalidate(cache: Option<axum::Extension<std::sync::Arc<Cache>>>, path: &str) {
+    let (Some(axum::Extension(cache)), Some((base, id))) = (cache, path.rsplit_once('/')) else {
+        return;
+    };
+    let pages = base.strip_suffix("/items").map(|prefix| format!("{prefix}/pages/items"));
+    for stale in [Some(base.to_string()), Some(path.to_string()), pages.clone(), pages.map(|pages| format!("{pages}/{id}"))].into_iter().flatten() {
+        cache.invalidate(&stale).await;
+    }
+}
+
+// Items by id, for a `dataloader::Loader`
+struct ById {
+    pool: sqlx::MySqlPool,
+    db_breaker: std::sync::Arc<circuit_breaker::CircuitBreaker>,
+    db_policy: std::sync::Arc<crate::retry::Policy>,
+    explainer: std::sync::Arc<crate::explain::Explainer>,
+}
+
+#[axum::async_trait]
+impl crate::dataloader::Batch for ById {
+    type Key = i64;
+    type Value = Item;
+    type Error = StatusCode;
+
+    fn name(&self) -> &'static str {
+        "items"
+    }
+
+    async fn load(&self, ids: &[i64]) -> Result<std::collections::HashMap<i64, Item>, StatusCode> {
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let sql = crate::sqlcommenter::tag(&format!("SELECT {COLUMNS} FROM items WHERE id IN ({placeholders})"));
+        let span = query_span("SELECT", &sql);
+        span.record("db.operation.batch.size", ids.len() as i64);
+        let query = self.db_policy.run_timed(circuit_breaker::is_db_unavailable, || {
+            ids.iter().fold(sqlx::query_as::<_, Row>(&sql), |query, id| query.bind(id)).fetch_all(&self.pool).counted()
+        });
+        let rows = self
+            .explainer
+            .timed(&span, &sql, self.db_breaker.call(circuit_breaker::is_db_unavailable, query))
+            .instrument(span.clone())
+            .await
+            .trace_err()
+            .map_err(status)?;
+        returned_rows(&span, rows.len());
+        Ok(rows.into_iter().map(|row| (row.0, Item::from(row))).collect())
+    }
+}
+
+// Each id loaded on its own, as a handler resolving references would, and batched for it
+#[utoipa::path(get, path = "/items:batch", summary = "Up to 500 items by id, 100 to a statement", params(Ids), responses(
+    (status = 200, body = Items, description = "The items in the order asked for, and the ids of those that don't exist"),
+    (status = 400, body = String, description = "The ids aren't integers, or there are too many"),
+))]
+#[traced_handler::traced_handler(batch.size = tracing::field::Empty)]
+pub async fn get_batch(
+    axum::extract::State(AppState { pool, db_breaker, db_policy, explainer, .. }): axum::extract::State<AppState>,
+    axum::extract::Query(Ids { ids }): axum::extract::Query<Ids>,
+) -> Result<axum::Json<Items>, (StatusCode, &'static str)> {
+    let ids = ids
+        .split(',')
+        .map(|id| id.trim().parse::<i64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "ids are comma-separated integers"))?;
+    if ids.len() > MAX_PAGE as usize {
+        return Err((StatusCode::BAD_REQUEST, "at most 500 ids"));
+    }
+    tracing::Span::current().record("batch.size", ids.len());
+
+    let loader = crate::dataloader::Loader::new(ById { pool, db_breaker, db_policy, explainer });
+    let loaded = futures_util::future::join_all(ids.iter().map(|id| loader.load(*id))).await;
+    let mut items = Items { items: Vec::new(), missing: Vec::new() };
+    for (id, loaded) in ids.into_iter().zip(loaded) {
+        match loaded.map_err(|status| (status, "failed to read the items"))? {
+            Some(item) => items.items.push(item),
+            None => items.missing.push(id),
+        }
+    }
+    Ok(axum::Json(items))
+}
+
+fn rows_per_second(rows: usize, elapsed: std::time::Duration) -> f64 {
+    rows as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+// 201 when every chunk went in, 207 when only some did; with none in, the status of the
+// first failure. Either way the body says which items are missing.
+#[utoipa::path(post, path = "/items:batch", summary = "Creates up to 5000 items, 100 to a statement",
+    params(crate::idempotency::KeyHeader),
+    request_body = Vec<ItemInput>,
+    responses(
+        (status = 201, body = BatchResult, description = "Every item was inserted"),
+        (status = 207, body = BatchResult, description = "Some chunks were not inserted"),
+        (status = 422, body = crate::validated_json::Invalid, description = "An item is invalid; as text, the batch is empty or too big"),
+    ),
+)]
+#[traced_handler::traced_handler(
+    batch.size = items.len(),
+    batch.chunks = items.len().div_ceil(BATCH_CHUNK),
+    batch.inserted = tracing::field::Empty,
+    batch.failed_chunks = tracing::field::Empty,
+    batch.rows_per_second = tracing::field::Empty,
+)]
+pub async fn create_batch(
+    axum::extract::State(AppState { pool, db_breaker, db_policy, .. }): axum::extract::State<AppState>,
+    ValidatedJson(items): ValidatedJson<Vec<ItemInput>>,
+) -> Result<(StatusCode, axum::Json<BatchResult>), (StatusCode, &'static str)> {
+    if items.is_empty() || items.len() > MAX_BATCH {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "a batch is 1 to 5000 items"));
+    }
+
+    let started = std::time::Instant::now();
+    let now = unix_now_ms();
+    let mut result = BatchResult { inserted: 0, failed: Vec::new() };
+    for (index, chunk) in items.chunks(BATCH_CHUNK).enumerate() {
+        let span = tracing::info_span!(
+            "items chunk",
+            otel.name = format!("chunk {index}"),
+            batch.chunk.index = index,
+            db.operation.batch.size = chunk.len() as i64,
+            batch.rows_per_second = tracing::field::Empty,
+        );
+        let chunk_started = std::time::Instant::now();
+
+        let values = vec!["(?, ?, ?, ?)"; chunk.len()].join(", ");
+        let sql = crate::sqlcommenter::tag(&format!("INSERT INTO items (name, description, created_at, updated_at) VALUES {values}"));
+        let query = db_policy.run_timed(|_| false, || {
+            let query = chunk.iter().fold(sqlx::query(&sql), |query, item| query.bind(&item.name).bind(&item.description).bind(now).bind(now));
+            query.execute(&pool).counted()
+        });
+        let insert = span.in_scope(|| query_span("INSERT", &sql));
+        let done = async {
+            db_breaker
+                .call(circuit_breaker::is_db_unavailable, query)
+                .instrument(insert.clone())
+                .await
+                .trace_err()
+        }
+        .instrument(span.clone())
+        .await;
+
+        match done {
+            Ok(done) => {
+                affected_rows(&insert, &done);
+                result.inserted += chunk.len();
+                span.record("batch.rows_per_second", rows_per_second(chunk.len(), chunk_started.elapsed()));
+            }
+            Err(e) => {
+                let status = status(e);
+                result.failed.push(FailedChunk {
+                    first: index * BATCH_CHUNK,
+                    count: chunk.len(),
+                    status: status.as_u16(),
+                    error: status.canonical_reason().unwrap_or("failed"),
+                });
+            }
+        }
+    }
+
+    let handler = tracing::Span::current();
+    handler.record("batch.inserted", result.inserted);
+    handler.record("batch.failed_chunks", result.failed.len());
+    handler.record("batch.rows_per_second", rows_per_second(result.inserted, started.elapsed()));
+    let status = match result.failed.first() {
+        None => StatusCode::CREATED,
+        Some(_) if result.inserted > 0 => StatusCode::MULTI_STATUS,
+        Some(failed) => StatusCode::from_u16(failed.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    Ok((status, axum::Json(result)))
+}
@@ -0,0 +1,339 @@
+// Span export to a classic Jaeger agent, `telemetry.traces_exporter = "jaeger"`: each batch
+// goes to `telemetry.jaeger_agent` as `emitBatch` calls in Thrift's compact protocol over UDP,
+// as many datagrams as it takes. The resource is the Jaeger process, `service.name` its name
+// and the rest its tags; attributes, the kind and the status are span tags, events are logs
+// and links `FOLLOWS_FROM` references. The sampler is the same as with OTLP, and metrics are
+// still exported over OTLP.
+
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::future::BoxFuture;
+use opentelemetry::trace::{SpanKind, Status};
+use opentelemetry::{KeyValue, Value};
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::Resource;
+
+// What an agent takes in one datagram
+const MAX_PACKET: usize = 65_000;
+
+// Compact protocol types
+const BOOL_TRUE: u8 = 1;
+const BOOL_FALSE: u8 = 2;
+const I32: u8 = 5;
+const I64: u8 = 6;
+const DOUBLE: u8 = 7;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+// Thrift compact protocol, just what `emitBatch` needs
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+    // The previous field id of each struct being written
+    fields: Vec<i16>,
+}
+
+impl Writer {
+    fn varint(&mut self, mut n: u64) {
+        while n >= 0x80 {
+            self.buf.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.buf.push(n as u8);
+    }
+
+    fn zigzag(&mut self, n: i64) {
+        self.varint(((n << 1) ^ (n >> 63)) as u64);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn begin(&mut self) {
+        self.fields.push(0);
+    }
+
+    fn end(&mut self) {
+        self.buf.push(0);
+        self.fields.pop();
+    }
+
+    fn field(&mut self, id: i16, kind: u8) {
+        let last = self.fields.last_mut().expect("a field outside a struct");
+        let delta = id - std::mem::replace(last, id);
+        if (1..=15).contains(&delta) {
+            self.buf.push((delta as u8) << 4 | kind);
+        } else {
+            self.buf.push(kind);
+            self.zigzag(i64::from(id));
+        }
+    }
+
+    fn i32(&mut self, id: i16, n: i32) {
+        self.field(id, I32);
+        self.zigzag(i64::from(n));
+    }
+
+    fn i64(&mut self, id: i16, n: i64) {
+        self.field(id, I64);
+        self.zigzag(n);
+    }
+
+    fn string(&mut self, id: i16, s: &str) {
+        self.field(id, BINARY);
+        self.bytes(s.as_bytes());
+    }
+
+    fn list(&mut self, id: i16, kind: u8, len: usize) {
+        self.field(id, LIST);
+        if len < 15 {
+            self.buf.push((len as u8) << 4 | kind);
+        } else {
+            self.buf.push(0xf0 | kind);
+            self.varint(len as u64);
+        }
+    }
+
+    fn structure(&mut self, id: i16) {
+        self.field(id, STRUCT);
+        self.begin();
+    }
+}
+
+fn tag(writer: &mut Writer, key: &str, value: &Value) {
+    writer.begin();
+    writer.string(1, key);
+    match value {
+        Value::Bool(value) => {
+            writer.i32(2, 2);
+            writer.field(5, if *value { BOOL_TRUE } else { BOOL_FALSE });
+        }
+        Value::I64(value) => {
+            writer.i32(2, 3);
+            writer.i64(6, *value);
+        }
+        Value::F64(value) => {
+            writer.i32(2, 1);
+            writer.field(4, DOUBLE);
+            writer.buf.extend_from_slice(&value.to_le_bytes());
+        }
+        // Strings, and arrays written out as one
+        value => {
+            writer.i32(2, 0);
+            writer.string(3, &value.as_str());
+        }
+    }
+    writer.end();
+}
+
+fn tags<'a>(writer: &mut Writer, id: i16, tags: impl ExactSizeIterator<Item = (&'a str, Value)>) {
+    writer.list(id, STRUCT, tags.len());
+    for (key, value) in tags {
+        tag(writer, key, &value);
+    }
+}
+
+fn micros(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as i64
+}
+
+fn trace_id(id: opentelemetry::trace::TraceId) -> (i64, i64) {
+    let id = u128::from_be_bytes(id.to_bytes());
+    (id as i64, (id >> 64) as i64)
+}
+
+fn span_id(id: opentelemetry::trace::SpanId) -> i64 {
+    i64::from_be_bytes(id.to_bytes())
+}
+
+// One span, the struct on its own so batches can be cut to the datagram size
+fn encode_span(span: &SpanData) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.begin();
+    let (low, high) = trace_id(span.span_context.trace_id());
+    writer.i64(1, low);
+    writer.i64(2, high);
+    writer.i64(3, span_id(span.span_context.span_id()));
+    writer.i64(4, span_id(span.parent_span_id));
+    writer.string(5, &span.name);
+
+    let links = span.links.iter();
+    writer.list(6, STRUCT, links.len());
+    for link in links {
+        let (low, high) = trace_id(link.span_context.trace_id());
+        writer.begin();
+        writer.i32(1, 1);
+        writer.i64(2, low);
+        writer.i64(3, high);
+        writer.i64(4, span_id(link.span_context.span_id()));
+        writer.end();
+    }
+
+    writer.i32(7, i32::from(span.span_context.trace_flags().is_sampled()));
+    writer.i64(8, micros(span.start_time));
+    writer.i64(9, micros(span.end_time) - micros(span.start_time));
+
+    let kind = match span.span_kind {
+        SpanKind::Client => Some("client"),
+        SpanKind::Server => Some("server"),
+        SpanKind::Producer => Some("producer"),
+        SpanKind::Consumer => Some("consumer"),
+        SpanKind::Internal => None,
+    };
+    let status = match &span.status {
+        Status::Error { description } => vec![
+            ("error", Value::Bool(true)),
+            ("otel.status_code", Value::from("ERROR")),
+            ("otel.status_description", Value::from(description.to_string())),
+        ],
+        Status::Ok => vec![("otel.status_code", Value::from("OK"))],
+        Status::Unset => Vec::new(),
+    };
+    let attributes = span.attributes.iter().map(|KeyValue { key, value }| (key.as_str(), value.clone()));
+    let all: Vec<_> = attributes
+        .chain(kind.map(|kind| ("span.kind", Value::from(kind))))
+        .chain(status)
+        .chain([("otel.scope.name", Value::from(span.instrumentation_lib.name.to_string()))])
+        .collect();
+    tags(&mut writer, 10, all.into_iter());
+
+    writer.list(11, STRUCT, span.events.len());
+    for event in span.events.iter() {
+        writer.begin();
+        writer.i64(1, micros(event.timestamp));
+        let fields = [("event", Value::from(event.name.to_string()))].into_iter();
+        let fields: Vec<_> = fields.chain(event.attributes.iter().map(|KeyValue { key, value }| (key.as_str(), value.clone()))).collect();
+        tags(&mut writer, 2, fields.into_iter());
+        writer.end();
+    }
+    writer.end();
+    writer.buf
+}
+
+// `Agent.emitBatch` of the process and the encoded spans, a oneway call
+fn encode_batch(process: &[u8], spans: &[Vec<u8>]) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.buf.extend_from_slice(&[0x82, 0x81]);
+    writer.varint(0);
+    writer.bytes(b"emitBatch");
+    writer.begin();
+    writer.structure(1);
+    writer.field(1, STRUCT);
+    writer.buf.extend_from_slice(process);
+    writer.list(2, STRUCT, spans.len());
+    for span in spans {
+        writer.buf.extend_from_slice(span);
+    }
+    writer.end();
+    writer.end();
+    writer.buf
+}
+
+fn encode_process(resource: &Resource) -> Vec<u8> {
+    let service = resource.get(opentelemetry::Key::from_static_str("service.name"));
+    let mut writer = Writer::default();
+    writer.begin();
+    writer.string(1, &service.map_or("unknown_service".into(), |service| service.as_str().into_owned()));
+    let others: Vec<_> = resource.iter().filter(|(key, _)| key.as_str() != "service.name").map(|(key, value)| (key.as_str(), value.clone())).collect();
+    tags(&mut writer, 2, others.into_iter());
+    writer.end();
+    writer.buf
+}
+
+pub struct JaegerExporter {
+    socket: Arc<UdpSocket>,
+    agent: String,
+    process: Vec<u8>,
+}
+
+impl JaegerExporter {
+    pub fn new(agent: &str) -> Result<Self, String> {
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+        socket.connect(agent).map_err(|e| format!("can't reach the Jaeger agent at {agent}: {e}"))?;
+        Ok(Self { socket: Arc::new(socket), agent: agent.to_string(), process: encode_process(&Resource::empty()) })
+    }
+}
+
+impl std::fmt::Debug for JaegerExporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JaegerExporter").field("agent", &self.agent).finish()
+    }
+}
+
+impl SpanExporter for JaegerExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        // Filled up to the datagram size, less room for the call around them
+        let room = MAX_PACKET - self.process.len() - 64;
+        let mut packets: Vec<Vec<Vec<u8>>> = vec![Vec::new()];
+        let mut size = 0;
+        let mut oversized = 0;
+        for span in batch.iter().map(encode_span) {
+            if span.len() > room {
+                oversized += 1;
+                continue;
+            }
+            if size + span.len() > room {
+                packets.push(Vec::new());
+                size = 0;
+            }
+            size += span.len();
+            packets.last_mut().unwrap().push(span);
+        }
+
+        let sent = packets
+            .iter()
+            .filter(|spans| !spans.is_empty())
+            .try_for_each(|spans| self.socket.send(&encode_batch(&self.process, spans)).map(|_| ()))
+            .map_err(|e| opentelemetry::trace::TraceError::from(format!("sending to the Jaeger agent at {}: {e}", self.agent)));
+        let result = sent.and_then(|()| match oversized {
+            0 => Ok(()),
+            n => Err(format!("{n} spans too large for a datagram were dropped").into()),
+        });
+        Box::pin(std::future::ready(result))
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.process = encode_process(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{Span as _, Tracer as _, TracerProvider as _};
+
+    #[test]
+    fn emits_batches_to_the_agent() {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let exporter = JaegerExporter::new(&agent.local_addr().unwrap().to_string()).unwrap();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_config(opentelemetry_sdk::trace::Config::default().with_resource(Resource::new([KeyValue::new("service.name", "checkout")])))
+            .with_simple_exporter(exporter)
+            .build();
+        let mut span = provider.tracer("test").start("GET /items");
+        span.set_attribute(KeyValue::new("http.response.status_code", 200));
+        span.end();
+
+        let mut packet = vec![0; MAX_PACKET];
+        let len = agent.recv(&mut packet).unwrap();
+        let packet = &packet[..len];
+        // A oneway `emitBatch` call
+        assert_eq!(&packet[..3], [0x82, 0x81, 0]);
+        assert_eq!(&packet[3..13], b"\x09emitBatch");
+        let contains = |needle: &[u8]| packet.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"checkout"));
+        assert!(contains(b"GET /items"));
+        assert!(contains(b"http.response.status_code"));
+
+        // Field ids past a gap of 15 take the long form
+        let mut writer = Writer::default();
+        writer.begin();
+        writer.i32(20, 1);
+        assert_eq!(writer.buf, [I32, 40, 2]);
+    }
+}
@@ -0,0 +1,251 @@
+// Work too long for a request: `POST /reports` starts a job and answers 202 with its id
+// straight away. The job runs in a task with a trace of its own, linked to the request,
+// with an event per step done, and `GET /jobs/:id` tells how far it got, or `GET /jobs:events`
+// streams every job's changes as they happen. Jobs are kept in memory only, the oldest
+// finished ones forgotten past `retain`, and no more start while `retain` are running. A job
+// whose task panicked or was aborted is failed.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use opentelemetry::trace::TraceContextExt;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::JobSettings;
+use crate::AppState;
+
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Status {
+    Running { progress: f64 },
+    Succeeded { result: serde_json::Value },
+    Failed { error: String },
+}
+
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct Job {
+    id: u64,
+    kind: &'static str,
+    #[serde(flatten)]
+    status: Status,
+    started_at_ms: i64,
+    finished_at_ms: Option<i64>,
+    /// Of the job's own trace, once it has started
+    trace_id: Option<String>,
+}
+
+// Changes waiting for a slow `GET /jobs:events` client; past this many it misses some
+const EVENTS_QUEUED: usize = 256;
+
+pub struct Jobs {
+    settings: JobSettings,
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    // Each job as it changes, and when
+    changes: tokio::sync::broadcast::Sender<(Job, Instant)>,
+}
+
+fn unix_now_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+impl Jobs {
+    pub fn new(settings: JobSettings) -> Self {
+        Self {
+            settings,
+            next_id: AtomicU64::new(1),
+            jobs: Mutex::new(BTreeMap::new()),
+            changes: tokio::sync::broadcast::channel(EVENTS_QUEUED).0,
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    fn update(&self, id: u64, update: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            update(job);
+            let _ = self.changes.send((job.clone(), Instant::now()));
+        }
+    }
+
+    // None when `retain` jobs are running already
+    fn insert(&self, kind: &'static str) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        // The oldest finished ones make room
+        while jobs.len() >= self.settings.retain.max(1) {
+            let oldest = jobs.values().find(|job| job.finished_at_ms.is_some()).map(|job| job.id)?;
+            jobs.remove(&oldest);
+        }
+
+        let job = Job {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            kind,
+            status: Status::Running { progress: 0.0 },
+            started_at_ms: unix_now_ms(),
+            finished_at_ms: None,
+            trace_id: None,
+        };
+        jobs.insert(job.id, job.clone());
+        let _ = self.changes.send((job.clone(), Instant::now()));
+        Some(job)
+    }
+
+    // Starts aggregating and exporting a report, a step every `step_ms`; None when too many
+    // jobs are running
+    pub fn start_report(self: &Arc<Self>) -> Option<(Job, tokio::task::JoinHandle<()>)> {
+        let job = self.insert("report")?;
+        let (id, jobs) = (job.id, Arc::clone(self));
+        // Moved into the task, which may be aborted before it first runs
+        let running = Running { jobs: Arc::clone(self), id };
+
+        let task = crate::tasks::spawn_detached("report job", async move {
+            let _running = running;
+            let span = tracing::Span::current();
+            span.set_attribute("job.id", id as i64);
+            let trace_id = span.context().span().span_context().trace_id().to_string();
+            jobs.update(id, |job| job.trace_id = Some(trace_id));
+
+            let steps = jobs.settings.steps.max(1);
+            let mut rows = 0u64;
+            for step in 1..=steps {
+                tokio::time::sleep(Duration::from_millis(jobs.settings.step_ms)).await;
+                rows += 1000;
+
+                let progress = f64::from(step) / f64::from(steps);
+                tracing::info!(job.id = id, job.step = step, job.progress = progress, "Report progress");
+                jobs.update(id, |job| job.status = Status::Running { progress });
+            }
+
+            span.set_attribute("job.rows", rows as i64);
+            tracing::info!(job.id = id, job.rows = rows, "Report exported");
+            jobs.update(id, |job| {
+                job.status = Status::Succeeded { result: serde_json::json!({ "rows": rows }) };
+                job.finished_at_ms = Some(unix_now_ms());
+            });
+        });
+        Some((job, task))
+    }
+}
+
+// Fails its job if it goes before the job finished, its task having panicked or been aborted
+struct Running {
+    jobs: Arc<Jobs>,
+    id: u64,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.jobs.update(self.id, |job| {
+            if job.finished_at_ms.is_none() {
+                job.status = Status::Failed { error: "the job stopped before finishing".to_string() };
+                job.finished_at_ms = Some(unix_now_ms());
+            }
+        });
+    }
+}
+
+#[utoipa::path(post, path = "/reports", summary = "Starts a report job, which runs for a few seconds", responses(
+    (status = 202, body = Job, description = "The job; the Location header is where to follow it"),
+    (status = 503, body = String, description = "As many jobs as `jobs.retain` are running"),
+))]
+#[traced_handler::traced_handler(job.id = tracing::field::Empty)]
+pub async fn start_report(
+    axum::extract::State(AppState { jobs, .. }): axum::extract::State<AppState>,
+) -> axum::response::Response {
+    let Some((job, _)) = jobs.start_report() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "too many jobs running").into_response();
+    };
+    tracing::Span::current().record("job.id", job.id);
+
+    let location = format!("/v1/jobs/{}", job.id);
+    (StatusCode::ACCEPTED, [(axum::http::header::LOCATION, location)], axum::Json(job)).into_response()
+}
+
+#[utoipa::path(get, path = "/jobs/{id}", summary = "A job's progress, or its result once it finished",
+    params(("id" = u64, Path)),
+    responses((status = 200, body = Job, description = "The job"), (status = 404, description = "No such job, or forgotten")),
+)]
+#[traced_handler::traced_handler(job.id = id)]
+pub async fn get(
+    axum::extract::State(AppState { jobs, .. }): axum::extract::State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<u64>,
+) -> Result<axum::Json<Job>, StatusCode> {
+    jobs.get(id).map(axum::Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+// Not a traced handler: the stream outlives the handler, and its heartbeats go on the
+// request span, which lasts as long as the stream does
+#[utoipa::path(get, path = "/jobs:events", summary = "Every job as it starts and progresses, as server-sent `job` events, until the client leaves", responses(
+    (status = 200, body = Job, content_type = "text/event-stream", description = "The events, each a job"),
+))]
+pub async fn events(
+    axum::extract::State(AppState { jobs, connections, .. }): axum::extract::State<AppState>,
+) -> axum::response::sse::Sse<impl futures_util::Stream<Item = Result<axum::response::sse::Event, std::convert::Infallible>>> {
+    let connection = crate::connection::Connection::start("job events", &connections);
+    let changes = jobs.changes.subscribe();
+    let events = futures_util::stream::unfold((changes, connection), |(mut changes, connection)| async move {
+        loop {
+            match changes.recv().await {
+                Ok((job, changed)) => {
+                    let data = connection.interval().in_scope(|| serde_json::to_string(&job).unwrap_or_default());
+                    connection.sent(data.len(), changed.elapsed());
+                    let event = axum::response::sse::Event::default().event("job").data(data);
+                    return Some((Ok(event), (changes, connection)));
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => connection.dropped(missed),
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    axum::response::sse::Sse::new(events).keep_alive(axum::response::sse::KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn report_runs_in_a_trace_of_its_own() {
+        let telemetry = crate::test_support::init();
+        let jobs = Arc::new(Jobs::new(JobSettings { steps: 3, step_ms: 1, retain: 10 }));
+
+        let (job, task) = tracing::info_span!("request").in_scope(|| jobs.start_report()).unwrap();
+        task.await.unwrap();
+
+        let done = jobs.get(job.id).unwrap();
+        assert!(matches!(done.status, Status::Succeeded { .. }));
+        assert!(done.finished_at_ms.is_some());
+
+        let spans = telemetry.spans();
+        let task = spans.assert_span_exists("report job").without_parent().span();
+        assert_eq!(task.links.links.len(), 1);
+        assert_eq!(Some(task.span_context.trace_id().to_string()), done.trace_id);
+        let progress = task.events.iter().filter(|event| event.name == "Report progress").count();
+        assert_eq!(progress, 3);
+    }
+
+    #[tokio::test]
+    async fn no_more_start_while_retain_are_running() {
+        let jobs = Arc::new(Jobs::new(JobSettings { steps: 1, step_ms: 60_000, retain: 1 }));
+
+        let (job, task) = jobs.start_report().unwrap();
+        assert!(jobs.start_report().is_none());
+
+        // An aborted job is failed, which makes room
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        let failed = jobs.get(job.id).unwrap();
+        assert!(matches!(failed.status, Status::Failed { .. }));
+        assert!(failed.finished_at_ms.is_some());
+
+        let (next, _) = jobs.start_report().unwrap();
+        assert!(jobs.get(job.id).is_none());
+        assert!(matches!(jobs.get(next.id).unwrap().status, Status::Running { .. }));
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use opentelemetry::trace::{Span as _, SpanKind, Status, TraceContextExt, Tracer as _};
+use opentelemetry::KeyValue;
+use rand::Rng;
+
+use crate::middleware::deadline::DEADLINE_HEADER;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Server to send requests to
+    #[arg(long, default_value = "http://127.0.0.1:3000")]
+    pub target: String,
+    /// Requests (or synthetic traces) per second
+    #[arg(long, default_value_t = 10.0, value_parser = parse_rps)]
+    pub rps: f64,
+    /// How long to run for, a week at most
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..=MAX_DURATION_SECS))]
+    pub duration_secs: u64,
+    /// Weighted paths to request, as `path=weight,...`
+    #[arg(long, default_value = "/v1=8,/v1/chain=1,/v1/cause_error=1", value_parser = parse_mix)]
+    pub mix: Mix,
+    /// Share of requests made to fail; they carry an expired deadline and get a 504
+    #[arg(long, default_value_t = 0.0, value_parser = parse_error_rate)]
+    pub error_rate: f64,
+    /// Requests in flight at most, ticks beyond it are skipped
+    #[arg(long, default_value_t = 64)]
+    pub concurrency: usize,
+    /// Emit synthetic server spans through the tracer instead of sending requests
+    #[arg(long)]
+    pub synthetic: bool,
+}
+
+const MAX_DURATION_SECS: u64 = 7 * 24 * 60 * 60;
+// Rates which make a tick interval tokio takes, neither zero nor past a Duration
+const RPS: std::ops::RangeInclusive<f64> = 0.001..=1_000_000.0;
+
+fn parse_rps(value: &str) -> Result<f64, String> {
+    let rps = value.parse::<f64>().map_err(|e| e.to_string())?;
+    match RPS.contains(&rps) {
+        true => Ok(rps),
+        false => Err(format!("{rps} is not in {}..={}", RPS.start(), RPS.end())),
+    }
+}
+
+fn parse_error_rate(value: &str) -> Result<f64, String> {
+    let rate = value.parse::<f64>().map_err(|e| e.to_string())?;
+    match (0.0..=1.0).contains(&rate) {
+        true => Ok(rate),
+        false => Err(format!("{rate} is not a share, in 0..=1")),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mix(Vec<(String, u32)>);
+
+fn parse_mix(value: &str) -> Result<Mix, String> {
+    let entries = value
+        .split(',')
+        .map(|entry| {
+            let (path, weight) = entry.split_once('=').ok_or_else(|| format!("expected path=weight, got {entry:?}"))?;
+            let weight = weight.parse().map_err(|e| format!("bad weight in {entry:?}: {e}"))?;
+            Ok((path.trim().to_string(), weight))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if entries.iter().all(|(_, weight)| *weight == 0) {
+        return Err("the mix needs at least one path with a positive weight".to_string());
+    }
+    Ok(Mix(entries))
+}
+
+impl Mix {
+    fn pick(&self, rng: &mut impl Rng) -> &str {
+        let total: u32 = self.0.iter().map(|(_, weight)| weight).sum();
+        let mut n = rng.gen_range(0..total);
+        for (path, weight) in &self.0 {
+            if n < *weight {
+                return path;
+            }
+            n -= weight;
+        }
+        unreachable!("n is below the total weight")
+    }
+}
+
+// Outcomes by path and status, and the latencies of every request
+#[derive(Default)]
+struct Report {
+    outcomes: BTreeMap<(String, String), u64>,
+    latencies_ms: Vec<f64>,
+    skipped: u64,
+}
+
+impl Report {
+    fn record(&mut self, path: &str, outcome: String, latency: Duration) {
+        *self.outcomes.entry((path.to_string(), outcome)).or_default() += 1;
+        self.latencies_ms.push(latency.as_secs_f64() * 1000.0);
+    }
+
+    fn print(&mut self, elapsed: Duration) {
+        let sent: u64 = self.outcomes.values().sum();
+        println!("sent {sent} in {:.1}s ({:.1}/s), skipped {} over the concurrency limit", elapsed.as_secs_f64(), sent as f64 / elapsed.as_secs_f64(), self.skipped);
+        for ((path, outcome), count) in &self.outcomes {
+            println!("  {path:<24} {outcome:<8} {count}");
+        }
+
+        self.latencies_ms.sort_by(f64::total_cmp);
+        if !self.latencies_ms.is_empty() {
+            let at = |q: f64| self.latencies_ms[((self.latencies_ms.len() - 1) as f64 * q).round() as usize];
+            println!("latency p50 {:.1}ms p90 {:.1}ms p99 {:.1}ms", at(0.5), at(0.9), at(0.99));
+        }
+    }
+}
+
+pub async fn run(args: Args) {
+    let report = Arc::new(Mutex::new(Report::default()));
+    let permits = Arc::new(tokio::sync::Semaphore::new(args.concurrency));
+    let client = reqwest::Client::new();
+
+    let started = Instant::now();
+    let until = started + Duration::from_secs(args.duration_secs);
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / args.rps));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    while Instant::now() < until {
+        ticker.tick().await;
+
+        let (path, fail) = {
+            let mut rng = rand::thread_rng();
+            (args.mix.pick(&mut rng).to_string(), rng.gen_bool(args.error_rate))
+        };
+
+        if args.synthetic {
+            let outcome = emit_synthetic(&path, fail);
+            report.lock().unwrap().record(&path, outcome.to_string(), Duration::ZERO);
+            continue;
+        }
+
+        let Ok(permit) = permits.clone().try_acquire_owned() else {
+            report.lock().unwrap().skipped += 1;
+            continue;
+        };
+        let (client, report, url) = (client.clone(), report.clone(), format!("{}{path}", args.target));
+        tokio::spawn(async move {
+            let _permit = permit;
+            let mut request = client.get(url);
+            if fail {
+                // Already expired, the server answers 504 without running the handler
+                request = request.header(DEADLINE_HEADER, "1");
+            }
+
+            let sent_at = Instant::now();
+            let outcome = match request.send().await {
+                Ok(response) => response.status().as_u16().to_string(),
+                Err(_) => "error".to_string(),
+            };
+            report.lock().unwrap().record(&path, outcome, sent_at.elapsed());
+        });
+    }
+
+    // Let the requests in flight finish
+    let _ = permits.acquire_many(args.concurrency as u32).await;
+    report.lock().unwrap().print(started.elapsed());
+}
+
+// A server span with a database child, timestamped as if they had just happened; the
+// spans go through the installed tracer provider, so sampling applies as usual
+fn emit_synthetic(path: &str, fail: bool) -> &'static str {
+    let tracer = opentelemetry::global::tracer("loadgen");
+    let mut rng = rand::thread_rng();
+    let end = SystemTime::now();
+    let duration = Duration::from_micros(rng.gen_range(1_000..50_000));
+    let start = end - duration;
+
+    let mut server = tracer
+        .span_builder(format!("GET {path}"))
+        .with_kind(SpanKind::Server)
+        .with_start_time(start)
+        .with_attributes([
+            KeyValue::new("http.request.method", "GET"),
+            KeyValue::new("http.route", path.to_string()),
+            KeyValue::new("http.response.status_code", if fail { 500 } else { 200 }),
+            KeyValue::new("loadgen.synthetic", true),
+        ])
+        .start(&tracer);
+
+    let cx = opentelemetry::Context::current().with_remote_span_context(server.span_context().clone());
+    let mut db = tracer
+        .span_builder("fetch row")
+        .with_kind(SpanKind::Client)
+        .with_start_time(start + duration / 4)
+        .start_with_context(&tracer, &cx);
+    if fail {
+        db.set_status(Status::error("synthetic failure"));
+        server.set_status(Status::error("synthetic failure"));
+    }
+    db.end_with_timestamp(end - duration / 4);
+    server.end_with_timestamp(end);
+
+    if fail {
+        "500"
+    } else {
+        "200"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: Args,
+    }
+
+    #[test]
+    fn arguments_it_cant_run_with_are_usage_errors() {
+        let parse = |flags: &[&str]| Cli::try_parse_from(["loadgen"].iter().chain(flags)).map(|cli| cli.args);
+        for flags in [["--rps", "0"], ["--rps", "-1"], ["--rps", "NaN"], ["--error-rate", "NaN"], ["--error-rate", "1.5"], ["--duration-secs", "18446744073709551615"]] {
+            assert!(parse(&flags).is_err(), "{flags:?}");
+        }
+        let args = parse(&["--rps", "0.5", "--error-rate", "1", "--duration-secs", "5"]).unwrap();
+        assert_eq!((args.rps, args.error_rate, args.duration_secs), (0.5, 1.0, 5));
+    }
+}
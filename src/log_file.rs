@@ -0,0 +1,151 @@
+// `[logging.file]`: the stdout log's lines appended to a file as well, for hosts where stdout
+// isn't collected. tracing_appender's non-blocking writer writes them on a thread of its own,
+// so a slow or full disk costs lines rather than request latency, and the guard held until
+// exit writes out what is queued. Daily rotation is tracing_appender's, into dated files;
+// it has no rotation by size, which is done here into `<path>.1` … `<path>.<keep>`.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+
+use crate::config::{LogFileSettings, Rotation};
+
+// None without a path; the file is opened here so a bad path fails startup
+pub fn open(settings: &LogFileSettings) -> Result<Option<(NonBlocking, WorkerGuard)>, String> {
+    let Some(path) = &settings.path else {
+        return Ok(None);
+    };
+    let opened: std::io::Result<Box<dyn Write + Send>> = match settings.rotation {
+        Rotation::Daily => daily(Path::new(path), settings.keep).map(|file| Box::new(file) as _),
+        Rotation::Size => {
+            let max_bytes = settings.max_size_mb.saturating_mul(1024 * 1024);
+            SizeRotated::open(PathBuf::from(path), max_bytes, settings.keep).map(|file| Box::new(file) as _)
+        }
+        Rotation::Never => append(Path::new(path)).map(|file| Box::new(file) as _),
+    };
+    let file = opened.map_err(|e| format!("can't open log file {path:?}: {e}"))?;
+
+    Ok(Some(
+        NonBlockingBuilder::default()
+            .buffered_lines_limit(settings.queue_lines.max(1))
+            .lossy(true)
+            .thread_name("log file")
+            .finish(file),
+    ))
+}
+
+fn append(path: &Path) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+// `<path>.<UTC date>`, today's and the `keep` days before it
+fn daily(path: &Path, keep: usize) -> std::io::Result<tracing_appender::rolling::RollingFileAppender> {
+    let name = path.file_name().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file"))?;
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    tracing_appender::rolling::Builder::new()
+        .rotation(tracing_appender::rolling::Rotation::DAILY)
+        .filename_prefix(name.to_string_lossy())
+        .max_log_files(keep + 1)
+        .build(dir)
+        .map_err(std::io::Error::other)
+}
+
+// Rotated before the line which would take it past `max_bytes`; the non-blocking writer
+// hands it one line per write
+struct SizeRotated {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: std::fs::File,
+    size: u64,
+}
+
+impl SizeRotated {
+    fn open(path: PathBuf, max_bytes: u64, keep: usize) -> std::io::Result<Self> {
+        let file = append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, max_bytes, keep, file, size })
+    }
+
+    // `<path>.1` becomes `<path>.2` and so on, the oldest past `keep` is removed
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let numbered = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+        for n in (1..self.keep).rev() {
+            match std::fs::rename(numbered(n), numbered(n + 1)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        match self.keep {
+            0 => std::fs::remove_file(&self.path)?,
+            _ => std::fs::rename(&self.path, numbered(1))?,
+        }
+
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotated {
+    fn write(&mut self, line: &[u8]) -> std::io::Result<usize> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(line.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_written_and_rotated() {
+        let dir = std::env::temp_dir().join(format!("log-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("service.log");
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap_or_default();
+
+        // Queued, then written out by the guard
+        let settings = LogFileSettings { path: Some(path.to_string_lossy().into_owned()), rotation: Rotation::Never, ..Default::default() };
+        let (mut file, guard) = open(&settings).unwrap().unwrap();
+        for n in 0..3 {
+            file.write_all(format!("line {n}\n").as_bytes()).unwrap();
+        }
+        drop(guard);
+        assert_eq!(read("service.log"), "line 0\nline 1\nline 2\n");
+
+        // Dated by tracing_appender
+        let settings = LogFileSettings { rotation: Rotation::Daily, ..settings };
+        let (mut file, guard) = open(&settings).unwrap().unwrap();
+        file.write_all(b"today\n").unwrap();
+        drop(guard);
+        let dated = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).find(|name| name.starts_with("service.log."));
+        assert_eq!(read(&dated.unwrap()), "today\n");
+
+        // Three lines of 40 bytes fit in 100 by two
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut writer = SizeRotated::open(path.clone(), 100, 2).unwrap();
+        for n in 0..5 {
+            writer.write_all(format!("{n:>39}\n").as_bytes()).unwrap();
+        }
+        let lines = |name: &str| read(name).lines().map(|line| line.trim().to_string()).collect::<Vec<_>>();
+        assert_eq!(lines("service.log"), ["4"]);
+        assert_eq!(lines("service.log.1"), ["2", "3"]);
+        assert_eq!(lines("service.log.2"), ["0", "1"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,636 @@
+use tracing_core::Level;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+// Crates whose info and debug events are noise here, only their warnings get through
+const NOISY_TARGETS: &[&str] = &["h2", "hyper", "hyper_util", "tonic", "tower"];
+
+// Runs before every layer and answers once per callsite: a callsite it disables is
+// cached as never interesting, so its events are dropped before any layer sees them,
+// at the cost of one atomic load
+pub fn global_filter(routes: &RouteLevels) -> Verbosity {
+    let targets = NOISY_TARGETS
+        .iter()
+        .fold(Targets::new().with_default(Level::INFO), |targets, target| targets.with_target(*target, Level::WARN))
+        // extractor rejections, for `middleware::rejection`
+        .with_target(crate::middleware::rejection::TARGET, Level::TRACE);
+    Verbosity::new(targets, routes)
+}
+
+// Stdout log (severity >= WARN, and the startup banner) with the request's allowlisted baggage on every line and
+// credentials scrubbed; the level filter is static too, so its interest is cached per
+// callsite alongside the global one
+pub fn fmt_layer<S, W>(
+    writer: W,
+    settings: &crate::config::LoggingSettings,
+    baggage: &crate::config::BaggageSettings,
+    routes: &RouteLevels,
+) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    text_layer(writer, true, settings, baggage, routes)
+}
+
+// The same lines in `logging.file`, without colours
+pub fn file_layer<S>(
+    file: tracing_appender::non_blocking::NonBlocking,
+    settings: &crate::config::LoggingSettings,
+    baggage: &crate::config::BaggageSettings,
+    routes: &RouteLevels,
+) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    text_layer(file, false, settings, baggage, routes)
+}
+
+fn text_layer<S, W>(
+    writer: W,
+    ansi: bool,
+    settings: &crate::config::LoggingSettings,
+    baggage: &crate::config::BaggageSettings,
+    routes: &RouteLevels,
+) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> tracing_subscriber::fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .fmt_fields(crate::redact::Redact::new(tracing_subscriber::fmt::format::DefaultFields::new(), &settings.redact_fields))
+        .event_format(crate::baggage::LogFields::new(tracing_subscriber::fmt::format(), baggage, &settings.redact_fields))
+        .with_ansi(ansi)
+        .with_writer(writer)
+        .with_filter(Verbosity::new(
+            Targets::new().with_default(Level::WARN).with_target(crate::startup::TARGET, Level::INFO),
+            routes,
+        ))
+}
+
+tokio::task_local! {
+    // Level of the route being handled, when it has one of its own
+    static ROUTE_LEVEL: LevelFilter;
+}
+
+// `log_level` of the routes which have one
+#[derive(Debug, Default)]
+pub struct RouteLevels(std::collections::HashMap<String, LevelFilter>);
+
+impl RouteLevels {
+    pub fn new(routes: &std::collections::HashMap<String, crate::config::RouteSettings>) -> Result<Self, String> {
+        routes
+            .iter()
+            .filter_map(|(route, settings)| Some((route, settings.log_level.as_ref()?)))
+            .map(|(route, level)| match level.parse() {
+                Ok(level) => Ok((route.clone(), level)),
+                Err(_) => Err(format!("log level {level:?} of route {route:?} is not one of off, error, warn, info, debug, trace")),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.0.values().copied().max().unwrap_or(LevelFilter::OFF)
+    }
+
+    // Runs the request for `route` at its level, if it has one
+    pub async fn scope<F: std::future::Future>(&self, route: &str, future: F) -> F::Output {
+        match self.0.get(route) {
+            Some(level) => ROUTE_LEVEL.scope(*level, future).await,
+            None => future.await,
+        }
+    }
+}
+
+// Static levels by target, raised while a route with a level of its own is being handled.
+// Only callsites more verbose than the static level but within the highest route level
+// are checked per event; with no route levels this is just the static filter.
+pub struct Verbosity {
+    targets: Targets,
+    max_route_level: LevelFilter,
+}
+
+impl Verbosity {
+    fn new(targets: Targets, routes: &RouteLevels) -> Self {
+        Self { targets, max_route_level: routes.max() }
+    }
+
+    fn interest(&self, metadata: &tracing::Metadata<'_>) -> tracing_core::Interest {
+        if self.targets.would_enable(metadata.target(), metadata.level()) {
+            tracing_core::Interest::always()
+        } else if *metadata.level() <= self.max_route_level {
+            tracing_core::Interest::sometimes()
+        } else {
+            tracing_core::Interest::never()
+        }
+    }
+
+    fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+        self.targets.would_enable(metadata.target(), metadata.level())
+            || ROUTE_LEVEL.try_with(|level| metadata.level() <= level).unwrap_or(false)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(Layer::<tracing_subscriber::Registry>::max_level_hint(&self.targets)?.max(self.max_route_level))
+    }
+}
+
+impl<S: tracing::Subscriber> Layer<S> for Verbosity {
+    fn register_callsite(&self, metadata: &'static tracing::Metadata<'static>) -> tracing_core::Interest {
+        self.interest(metadata)
+    }
+
+    fn enabled(&self, metadata: &tracing::Metadata<'_>, _: tracing_subscriber::layer::Context<'_, S>) -> bool {
+        Verbosity::enabled(self, metadata)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Verbosity::max_level_hint(self)
+    }
+}
+
+impl<S> tracing_subscriber::layer::Filter<S> for Verbosity {
+    fn callsite_enabled(&self, metadata: &'static tracing::Metadata<'static>) -> tracing_core::Interest {
+        self.interest(metadata)
+    }
+
+    fn enabled(&self, metadata: &tracing::Metadata<'_>, _: &tracing_subscriber::layer::Context<'_, S>) -> bool {
+        Verbosity::enabled(self, metadata)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Verbosity::max_level_hint(self)
+    }
+}
+
+// Target of the summaries, which are never limited themselves
+const SUMMARY_TARGET: &str = "log_rate_limit";
+
+struct Window {
+    metadata: &'static tracing::Metadata<'static>,
+    started: std::time::Instant,
+    kept: u32,
+    // Dropped since the last report
+    suppressed: u64,
+}
+
+// Keeps at most `max_per_sec` events per callsite and second, for every layer at once: a
+// storm of identical errors (say, the database is down) costs one counter increment per
+// event past the limit. What was dropped is reported by `report`.
+#[derive(Clone)]
+pub struct RateLimit {
+    max_per_sec: u32,
+    windows: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<tracing_core::callsite::Identifier, Window>>>,
+}
+
+pub fn rate_limit(settings: &crate::config::LoggingSettings) -> Option<RateLimit> {
+    (settings.max_events_per_sec > 0).then(|| RateLimit {
+        max_per_sec: settings.max_events_per_sec,
+        windows: Default::default(),
+    })
+}
+
+impl RateLimit {
+    fn admit(&self, metadata: &'static tracing::Metadata<'static>) -> bool {
+        let now = std::time::Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(metadata.callsite()).or_insert(Window {
+            metadata,
+            started: now,
+            kept: 0,
+            suppressed: 0,
+        });
+
+        if now.duration_since(window.started) >= std::time::Duration::from_secs(1) {
+            window.started = now;
+            window.kept = 0;
+        }
+
+        if window.kept < self.max_per_sec {
+            window.kept += 1;
+            true
+        } else {
+            window.suppressed += 1;
+            false
+        }
+    }
+
+    // Logs one summary per callsite which had events dropped since the last call, and
+    // forgets the callsites which have been quiet for a second; whether there was any
+    pub fn report(&self) -> bool {
+        let mut suppressed = Vec::new();
+        self.windows.lock().unwrap().retain(|_, window| {
+            if window.suppressed > 0 {
+                suppressed.push((window.metadata, std::mem::take(&mut window.suppressed)));
+            }
+            window.started.elapsed() < std::time::Duration::from_secs(1)
+        });
+
+        if suppressed.is_empty() {
+            return false;
+        }
+        let _span = summary_span().entered();
+        for (metadata, count) in suppressed {
+            summarise(metadata, count);
+        }
+        true
+    }
+}
+
+fn summarise(metadata: &tracing::Metadata<'_>, suppressed: u64) {
+    macro_rules! summary {
+        ($level:expr) => {
+            tracing::event!(
+                target: SUMMARY_TARGET,
+                $level,
+                log.suppressed = suppressed,
+                log.suppressed_target = metadata.target(),
+                code.filepath = metadata.file(),
+                code.lineno = metadata.line(),
+                "Suppressed {suppressed} similar events"
+            )
+        };
+    }
+
+    // At the level of what was suppressed, so it reaches the same outputs
+    match *metadata.level() {
+        Level::ERROR => summary!(Level::ERROR),
+        Level::WARN => summary!(Level::WARN),
+        Level::INFO => summary!(Level::INFO),
+        Level::DEBUG => summary!(Level::DEBUG),
+        Level::TRACE => summary!(Level::TRACE),
+    }
+}
+
+impl<S: tracing::Subscriber> Layer<S> for RateLimit {
+    fn event_enabled(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) -> bool {
+        let metadata = event.metadata();
+        metadata.target() == SUMMARY_TARGET || metadata.target() == crate::audit::TARGET || self.admit(metadata)
+    }
+}
+
+// Target of the aggregated error events
+const DEDUP_TARGET: &str = "log_dedup";
+
+// Groups past this size are pruned of the ones whose window has ended
+const MAX_ERROR_GROUPS: usize = 1000;
+
+struct Occurrences {
+    metadata: &'static tracing::Metadata<'static>,
+    first_seen: std::time::SystemTime,
+    last_seen: std::time::SystemTime,
+    // Repeats dropped since the one that was logged
+    repeats: u64,
+}
+
+type Groups = std::collections::HashMap<(tracing_core::callsite::Identifier, String), Occurrences>;
+
+// Logs an ERROR event once per callsite and message within the window, counting the
+// repeats. Once the window has ended `report` logs the count and the first and last time
+// it was seen, so a trace taken during a sustained failure shows one error and a count
+// rather than pages of them.
+#[derive(Clone)]
+pub struct ErrorDedup {
+    window: std::time::Duration,
+    groups: std::sync::Arc<std::sync::Mutex<Groups>>,
+    // Groups with repeats whose window ended before `report` got to them
+    ended: std::sync::Arc<std::sync::Mutex<Vec<(String, Occurrences)>>>,
+}
+
+pub fn error_dedup(settings: &crate::config::LoggingSettings) -> Option<ErrorDedup> {
+    (settings.error_dedup_window_secs > 0).then(|| ErrorDedup {
+        window: std::time::Duration::from_secs(settings.error_dedup_window_secs),
+        groups: Default::default(),
+        ended: Default::default(),
+    })
+}
+
+// The formatted `message` field, which is what makes two errors identical
+#[derive(Default)]
+struct Message(String);
+
+impl tracing::field::Visit for Message {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = value.to_string();
+        }
+    }
+}
+
+fn unix_ms(at: std::time::SystemTime) -> u64 {
+    at.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+impl ErrorDedup {
+    fn expired(&self, group: &Occurrences, now: std::time::SystemTime) -> bool {
+        now.duration_since(group.first_seen).unwrap_or_default() >= self.window
+    }
+
+    fn admit(&self, event: &tracing::Event<'_>) -> bool {
+        let mut message = Message::default();
+        event.record(&mut message);
+
+        let now = std::time::SystemTime::now();
+        let mut groups = self.groups.lock().unwrap();
+        let key = (event.metadata().callsite(), message.0);
+        if groups.len() >= MAX_ERROR_GROUPS && !groups.contains_key(&key) {
+            groups.retain(|_, group| !self.expired(group, now) || group.repeats > 0);
+            // Still full: logged, untracked, rather than growing past the cap
+            if groups.len() >= MAX_ERROR_GROUPS {
+                return true;
+            }
+        }
+
+        let fresh = Occurrences { metadata: event.metadata(), first_seen: now, last_seen: now, repeats: 0 };
+        match groups.entry(key) {
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(fresh);
+                true
+            }
+            std::collections::hash_map::Entry::Occupied(mut entry) if self.expired(entry.get(), now) => {
+                let closed = std::mem::replace(entry.get_mut(), fresh);
+                if closed.repeats > 0 {
+                    self.ended.lock().unwrap().push((entry.key().1.clone(), closed));
+                }
+                true
+            }
+            std::collections::hash_map::Entry::Occupied(mut entry) => {
+                let group = entry.get_mut();
+                group.repeats += 1;
+                group.last_seen = now;
+                false
+            }
+        }
+    }
+
+    // Logs the count of every group whose window has ended with repeats in it; whether
+    // there was any
+    pub fn report(&self) -> bool {
+        let now = std::time::SystemTime::now();
+        let mut ended = std::mem::take(&mut *self.ended.lock().unwrap());
+        self.groups.lock().unwrap().retain(|(_, message), group| {
+            if !self.expired(group, now) {
+                return true;
+            }
+            if group.repeats > 0 {
+                let group = std::mem::replace(group, Occurrences { repeats: 0, ..*group });
+                ended.push((message.clone(), group));
+            }
+            false
+        });
+
+        if ended.is_empty() {
+            return false;
+        }
+        let _span = summary_span().entered();
+        for (message, group) in ended {
+            let count = group.repeats + 1;
+            tracing::event!(
+                target: DEDUP_TARGET,
+                Level::ERROR,
+                log.occurrences = count,
+                log.first_seen_unix_ms = unix_ms(group.first_seen),
+                log.last_seen_unix_ms = unix_ms(group.last_seen),
+                code.filepath = group.metadata.file(),
+                code.lineno = group.metadata.line(),
+                "\"{message}\" occurred {count} times in {}s",
+                self.window.as_secs()
+            );
+        }
+        true
+    }
+}
+
+impl<S: tracing::Subscriber> Layer<S> for ErrorDedup {
+    fn event_enabled(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) -> bool {
+        let metadata = event.metadata();
+        *metadata.level() != Level::ERROR || [DEDUP_TARGET, crate::audit::TARGET].contains(&metadata.target()) || self.admit(event)
+    }
+}
+
+// Attribute of the span events kept by `LogSampling`, the N of 1 in N
+const SAMPLE_RATE: opentelemetry::Key = opentelemetry::Key::from_static_str("log.sample_rate");
+
+struct SampleRule {
+    target: String,
+    // This level and the more verbose ones are sampled
+    level: Level,
+    one_in: u32,
+}
+
+// Keeps 1 in `one_in` of the INFO and more verbose events of the targets in
+// `[logging.sample]`, chosen at random, so per-row logging of a busy route doesn't swamp the
+// logs pipeline. Warnings and errors are all kept, as is everything in a route whose
+// `log_level` is raised; `rates` tags the kept ones with their rate for the backend.
+#[derive(Clone)]
+pub struct LogSampling {
+    // The longest target first, so the most specific rule wins
+    rules: std::sync::Arc<Vec<SampleRule>>,
+}
+
+pub fn sampling(settings: &crate::config::LoggingSettings) -> Result<Option<LogSampling>, String> {
+    let mut rules = settings
+        .sample
+        .iter()
+        .map(|(target, sample)| {
+            let level: Level = sample.level.parse().map_err(|_| format!("sample level {:?} of {target:?} is not one of info, debug, trace", sample.level))?;
+            if level < Level::INFO {
+                return Err(format!("sample level of {target:?} is {level}, warnings and errors are never sampled"));
+            }
+            if sample.one_in == 0 {
+                return Err(format!("sample one_in of {target:?} is 0, use 1 to keep every event"));
+            }
+            Ok(SampleRule { target: target.clone(), level, one_in: sample.one_in })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    rules.sort_by_key(|rule| std::cmp::Reverse(rule.target.len()));
+    Ok((!rules.is_empty()).then(|| LogSampling { rules: std::sync::Arc::new(rules) }))
+}
+
+impl LogSampling {
+    // Of a sampled event, matching targets as `Targets` does, by module path prefix
+    fn one_in(&self, metadata: &tracing::Metadata<'_>) -> Option<u32> {
+        let target = metadata.target();
+        let rule = self.rules.iter().find(|rule| {
+            target.strip_prefix(rule.target.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })?;
+        let raised = ROUTE_LEVEL.try_with(|level| *level > LevelFilter::INFO).unwrap_or(false);
+        (*metadata.level() >= rule.level && rule.one_in > 1 && !raised && target != crate::audit::TARGET).then_some(rule.one_in)
+    }
+
+    // For below the OpenTelemetry layer
+    pub fn rates(&self) -> SampleRates {
+        SampleRates(self.clone())
+    }
+}
+
+impl<S: tracing::Subscriber> Layer<S> for LogSampling {
+    fn event_enabled(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) -> bool {
+        self.one_in(event.metadata()).is_none_or(|one_in| rand::Rng::gen_ratio(&mut rand::thread_rng(), 1, one_in))
+    }
+}
+
+pub struct SampleRates(LogSampling);
+
+impl<S> Layer<S> for SampleRates
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let (Some(one_in), Some(span)) = (self.0.one_in(event.metadata()), ctx.event_span(event)) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        // The event the OpenTelemetry layer has just added, as in `severity`
+        if let Some(otel_event) = extensions
+            .get_mut::<tracing_opentelemetry::OtelData>()
+            .and_then(|data| data.builder.events.as_mut()?.last_mut())
+        {
+            otel_event.attributes.push(opentelemetry::KeyValue::new(SAMPLE_RATE, one_in as i64));
+        }
+    }
+}
+
+// Opened by a report with something in it, so the summaries reach OTLP too; a quiet round
+// opens none, which would be an empty trace a second
+fn summary_span() -> tracing::Span {
+    tracing::info_span!(parent: None, "log summary")
+}
+
+// Logs what the limits above dropped once a second. Summaries can't be logged from the
+// layers themselves: events raised inside a subscriber callback go nowhere, tracing
+// doesn't nest them.
+pub fn spawn_reports(rate_limit: Option<RateLimit>, error_dedup: Option<ErrorDedup>) {
+    if rate_limit.is_none() && error_dedup.is_none() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            if let Some(error_dedup) = &error_dedup {
+                error_dedup.report();
+            }
+            if let Some(rate_limit) = &rate_limit {
+                rate_limit.report();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // Targets of the events which got past the rate limit
+    #[derive(Clone, Default)]
+    struct Recorded(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> Layer<S> for Recorded {
+        fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+            self.0.lock().unwrap().push(event.metadata().target().to_string());
+        }
+    }
+
+    #[test]
+    fn limits_each_callsite() {
+        let recorded = Recorded::default();
+        let limit = rate_limit(&crate::config::LoggingSettings { max_events_per_sec: 3, error_dedup_window_secs: 0, ..Default::default() }).unwrap();
+        let subscriber = tracing_subscriber::registry().with(limit.clone()).with(recorded.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..10 {
+                tracing::error!("Failed to fetch row");
+            }
+            tracing::error!("Another callsite");
+            assert!(limit.report());
+            assert!(!limit.report(), "nothing new to report");
+        });
+
+        let recorded = recorded.0.lock().unwrap();
+        assert_eq!(recorded.len(), 5);
+        assert_eq!(recorded[4], SUMMARY_TARGET);
+    }
+
+    #[test]
+    fn samples_chatty_targets() {
+        let recorded = Recorded::default();
+        let settings = crate::config::LoggingSettings {
+            sample: std::collections::HashMap::from([(
+                "rows".to_string(),
+                crate::config::LogSampleSettings { one_in: 4, level: "info".to_string() },
+            )]),
+            ..Default::default()
+        };
+        let sample = sampling(&settings).unwrap().unwrap();
+        let subscriber = tracing_subscriber::registry().with(sample).with(recorded.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..1000 {
+                tracing::info!(target: "rows::fetch", "Fetched row");
+                tracing::warn!(target: "rows::fetch", "Slow row");
+                tracing::info!(target: "rowset", "Not sampled");
+            }
+        });
+
+        let recorded = recorded.0.lock().unwrap();
+        let sampled = recorded.iter().filter(|target| *target == "rows::fetch").count() - 1000;
+        assert!((150..350).contains(&sampled), "{sampled}");
+        assert_eq!(recorded.iter().filter(|target| *target == "rowset").count(), 1000);
+
+        let warn = crate::config::LogSampleSettings { one_in: 4, level: "warn".to_string() };
+        let settings = crate::config::LoggingSettings { sample: [("rows".to_string(), warn)].into(), ..Default::default() };
+        assert!(sampling(&settings).is_err());
+    }
+
+    #[tokio::test]
+    async fn raises_the_level_of_configured_routes() {
+        let routes = std::collections::HashMap::from([(
+            "/cause_error".to_string(),
+            crate::config::RouteSettings { log_level: Some("debug".to_string()), ..Default::default() },
+        )]);
+        let levels = RouteLevels::new(&routes).unwrap();
+        let recorded = Recorded::default();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(global_filter(&levels)).with(recorded.clone()),
+        );
+
+        levels.scope("/cause_error", async { tracing::debug!("Kept") }).await;
+        levels.scope("/", async { tracing::debug!("Dropped") }).await;
+        tracing::debug!("Dropped");
+
+        assert_eq!(recorded.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn folds_repeated_errors() {
+        let recorded = Recorded::default();
+        let dedup = error_dedup(&crate::config::LoggingSettings { max_events_per_sec: 0, error_dedup_window_secs: 60, ..Default::default() }).unwrap();
+        let subscriber = tracing_subscriber::registry().with(dedup.clone()).with(recorded.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            for row in [1, 1, 1, 2] {
+                tracing::error!("Failed to fetch row {row}");
+            }
+            assert_eq!(recorded.0.lock().unwrap().len(), 2);
+
+            // Past the cap, still logged but no longer tracked
+            for item in 0..MAX_ERROR_GROUPS {
+                tracing::error!("Failed to load item {item}");
+            }
+        });
+
+        assert_eq!(recorded.0.lock().unwrap().len(), 2 + MAX_ERROR_GROUPS);
+        assert_eq!(dedup.groups.lock().unwrap().len(), MAX_ERROR_GROUPS);
+    }
+}
@@ -1,108 +1,800 @@
 use tracing::Instrument;
-use tracing_core::Level;
-use tracing_subscriber::{util::SubscriberInitExt, layer::{Layer, SubscriberExt}};
-use opentelemetry::trace::TracerProvider;
-use opentelemetry_otlp::WithExportConfig;
-use opentelemetry_sdk::{Resource, trace::{Sampler, RandomIdGenerator}};
-use opentelemetry_semantic_conventions::{
-    attribute::{SERVICE_NAME, SERVICE_VERSION},
-    SCHEMA_URL,
-};
+#[cfg(feature = "mysql")]
+use middleware::db_calls::Counted;
+#[cfg(feature = "mysql")]
+use result_ext::ResultExt;
+use tracing_subscriber::{util::SubscriberInitExt, layer::SubscriberExt};
+
+mod aggregate;
+mod attributes;
+mod audit;
+#[cfg(feature = "aws")]
+mod aws;
+mod baggage;
+mod bench;
+mod blocking;
+mod bootstrap;
+mod build_info;
+mod chaos;
+#[cfg(feature = "mysql")]
+mod circuit_breaker;
+mod cli;
+mod config;
+mod connection;
+mod contention;
+mod dataloader;
+#[cfg(feature = "mysql")]
+mod db;
+#[cfg(feature = "mysql")]
+mod degraded;
+mod dependencies;
+mod dns;
+mod effective_config;
+#[cfg(feature = "email")]
+mod email;
+mod error;
+#[cfg(feature = "mysql")]
+mod explain;
+#[cfg(feature = "mysql")]
+mod export;
+mod failover;
+mod fallback;
+mod flags;
+mod health;
+#[cfg(feature = "jemalloc")]
+mod heap;
+#[cfg(feature = "mysql")]
+mod hashing;
+#[cfg(feature = "mysql")]
+mod hedging;
+mod http_client;
+#[cfg(feature = "mysql")]
+mod idempotency;
+mod ids;
+#[cfg(feature = "jaeger")]
+mod jaeger;
+mod jobs;
+#[cfg(feature = "mysql")]
+mod items;
+mod loadgen;
+mod log_file;
+mod logging;
+mod middleware;
+mod openapi;
+mod panics;
+mod preflight;
+mod propagation;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod proxy;
+mod pubsub;
+mod redact;
+mod redis;
+mod resource;
+mod response;
+mod result_ext;
+mod retry;
+mod secrets;
+mod self_test;
+mod sampling;
+mod server;
+#[cfg(feature = "mysql")]
+mod session;
+mod severity;
+mod slo;
+mod span_fields;
+mod span_metrics;
+mod startup;
+#[cfg(feature = "s3")]
+mod storage;
+#[cfg(feature = "mysql")]
+mod sqlcommenter;
+#[cfg(feature = "systemd")]
+mod systemd;
+mod tasks;
+mod telemetry;
+mod templates;
+#[cfg(test)]
+mod test_support;
+mod threads;
+mod tls;
+#[cfg(feature = "mysql")]
+mod transaction_retry;
+#[cfg(feature = "resource-usage")]
+mod usage;
+mod validated_json;
+mod vendor;
 
 #[derive(Clone)]
 struct AppState {
+    #[cfg(feature = "mysql")]
     pool: sqlx::MySqlPool,
+    #[cfg(feature = "mysql")]
+    db_breaker: std::sync::Arc<circuit_breaker::CircuitBreaker>,
+    #[cfg(feature = "mysql")]
+    db_policy: std::sync::Arc<retry::Policy>,
+    #[cfg(feature = "mysql")]
+    explainer: std::sync::Arc<explain::Explainer>,
+    #[cfg(feature = "mysql")]
+    hedger: std::sync::Arc<hedging::Hedger>,
+    #[cfg(feature = "mysql")]
+    transactions: std::sync::Arc<transaction_retry::TransactionRetry>,
+    #[cfg(feature = "mysql")]
+    hasher: std::sync::Arc<hashing::Hasher>,
+    #[cfg(feature = "mysql")]
+    sessions: std::sync::Arc<session::SessionStore>,
+    #[cfg(feature = "mysql")]
+    idempotency: std::sync::Arc<idempotency::IdempotencyStore>,
+    #[cfg(feature = "mysql")]
+    copies: std::sync::Arc<items::Copies>,
+    http: http_client::HttpClient,
+    // What `/chain` calls
+    chain_url: String,
+    jobs: std::sync::Arc<jobs::Jobs>,
+    connections: config::ConnectionSettings,
+    flags: flags::Flags,
+    chaos: chaos::Chaos,
+    #[cfg(feature = "email")]
+    mailer: std::sync::Arc<email::Mailer>,
+    #[cfg(feature = "s3")]
+    storage: std::sync::Arc<storage::Storage>,
+}
+
+// What the operational routes need, served by `admin_router` or along with the API
+struct Admin {
+    health: std::sync::Arc<health::Health>,
+    pipeline_stats: std::sync::Arc<telemetry::PipelineStats>,
+    sampling: sampling::TenantRates,
+    flags: flags::Flags,
+    chaos: chaos::Chaos,
+    config: effective_config::EffectiveConfig,
+}
+
+impl Admin {
+    // `/debug` and `/admin`, the probes being merged in apart
+    fn routes<S: Clone + Send + Sync + 'static>(self) -> utoipa_axum::router::OpenApiRouter<S> {
+        use utoipa_axum::router::UtoipaMethodRouterExt;
+
+        let routes = openapi::router()
+            .routes(utoipa_axum::routes!(telemetry::debug_handler).with_state(self.pipeline_stats))
+            .routes(utoipa_axum::routes!(sampling::get_handler, sampling::put_handler).with_state(self.sampling))
+            .routes(utoipa_axum::routes!(flags::get_handler).with_state(self.flags.clone()))
+            .routes(utoipa_axum::routes!(flags::put_handler).with_state(self.flags))
+            .routes(utoipa_axum::routes!(chaos::get_handler, chaos::put_handler).with_state(self.chaos))
+            .routes(utoipa_axum::routes!(effective_config::handler).with_state(self.config));
+        #[cfg(feature = "pprof")]
+        let routes = routes.routes(utoipa_axum::routes!(profiling::profile_handler));
+        #[cfg(feature = "jemalloc")]
+        let routes = routes.routes(utoipa_axum::routes!(heap::debug_handler));
+        routes
+    }
 }
 
 #[tokio::main]
-async fn main() {
-    // Tracer setup
-    let resource = Resource::from_schema_url(
-        [
-            opentelemetry::KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")),
-            opentelemetry::KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")),
-        ],
-        SCHEMA_URL,
-    );
-
-    let provider = opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint("http://localhost:4317"))
-        .with_trace_config(
-            opentelemetry_sdk::trace::Config::default()
-                // sampling rate
-                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(1.0))))
-
-                // id generator
-                .with_id_generator(RandomIdGenerator::default())
-
-                // resource
-                .with_resource(resource)
-        )
-        .install_batch(opentelemetry_sdk::runtime::Tokio)
-        .unwrap();
+async fn main() -> std::process::ExitCode {
+    match run().await {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        // On stderr, the log may not be up yet
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+async fn run() -> Result<(), startup::StartupError> {
+    use startup::StartupError;
+
+    let mut startup = startup::Startup::begin();
+    let command = <cli::Cli as clap::Parser>::parse().command.unwrap_or(cli::Command::Serve);
+    // Events from before the subscriber is installed, replayed once it is
+    let bootstrap = bootstrap::Bootstrap::start();
+    let mut settings = startup.phase("config load", config::Settings::load).map_err(StartupError::ConfigFile)?;
+    // The password and API key `[secrets]` names, before the exporter needs the key
+    let fetch_secrets = secrets::apply(&mut settings);
+    startup.phase_async("secrets fetch", fetch_secrets).await?;
+    if let Err(e) = sampling::ratio_from_env(&mut settings.sampling) {
+        tracing::warn!("Ignoring {}: {}, keeping the configured ratio {}", sampling::RATIO_ENV, e, settings.sampling.ratio);
+    }
+
+    // One-shot commands export every span as it ends, so none are lost when they exit
+    if command.is_one_shot() {
+        settings.telemetry.span_processor = config::SpanProcessor::Simple;
+    }
 
-    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    // Telemetry setup; with `telemetry.enabled = false` nothing is exported and no
+    // collector connection is made, only the stdout log remains
+    let exporter_health = std::sync::Arc::new(telemetry::ExporterHealth::default());
+    let pipeline_stats = std::sync::Arc::new(telemetry::PipelineStats::new());
+    let sampling = sampling::TenantRates::new(&settings.sampling).map_err(StartupError::config("sampling"))?;
+    let pipeline = if settings.telemetry.enabled {
+        telemetry::check(&settings.telemetry).map_err(StartupError::config("telemetry"))?;
+        let sampler = sampling::sampler(sampling.clone());
+        // Where a server listens goes into the resource
+        let listeners = match command {
+            cli::Command::Serve => server::resource_attributes(&settings.server),
+            _ => Vec::new(),
+        };
+        // and the database it connects to, without the password
+        #[cfg(feature = "mysql")]
+        let listeners = [listeners, db::resource_attributes(&settings.database, &settings.dependencies.db)].concat();
+        let pipeline = telemetry::Pipeline::builder(&settings.telemetry, sampler, exporter_health.clone(), pipeline_stats.clone())
+            .with_resource_attributes(listeners);
+        // tags spans started while a CPU profile is taken
+        #[cfg(feature = "pprof")]
+        let pipeline = pipeline.with_span_processor(profiling::WindowProcessor);
+        // counts the spans, for span-derived request rates
+        let pipeline = if settings.telemetry.span_metrics {
+            pipeline.with_span_processor(span_metrics::SpanMetricsProcessor::default())
+        } else {
+            pipeline
+        };
+        let install = pipeline.install();
+        Some(startup.phase_async("telemetry init", install).await)
+    } else {
+        None
+    };
+
+    // W3C trace context and baggage, used to continue incoming traces and to hand them on to downstream calls
+    opentelemetry::global::set_text_map_propagator(opentelemetry::propagation::TextMapCompositePropagator::new(vec![
+        Box::new(opentelemetry_sdk::propagation::TraceContextPropagator::new()),
+        Box::new(opentelemetry_sdk::propagation::BaggagePropagator::new()),
+    ]));
+
+    let error_dedup = logging::error_dedup(&settings.logging);
+    let log_rate_limit = logging::rate_limit(&settings.logging);
+    let log_sampling = logging::sampling(&settings.logging).map_err(StartupError::config("logging"))?;
+
+    let route_levels = logging::RouteLevels::new(&settings.routes).map_err(StartupError::config("routes"))?;
+    let audit = audit::AuditLog::new(&settings.audit, &settings.logging.redact_fields).map_err(StartupError::config("audit"))?;
+    let (log_file, log_file_flush) = log_file::open(&settings.logging.file).map_err(StartupError::config("logging"))?.unzip();
 
     tracing_subscriber::registry()
-        // global filter to hide h2 traces
-        .with(tracing_subscriber::filter::LevelFilter::from_level(Level::INFO))
+        // shared filter, rejects h2 and hyper debug events once for every layer below
+        // unless a route's level lets them through
+        .with(logging::global_filter(&route_levels))
+        // chatty targets keep only some of their INFO and more verbose events
+        .with(log_sampling.clone())
+        // repeated errors are counted rather than logged, then identical events past the
+        // per-second limit are dropped, both for every layer below
+        .with(error_dedup.clone())
+        .with(log_rate_limit.clone())
 
         // stdout log (severity >= WARN)
-        .with(tracing_subscriber::fmt::layer().with_filter(tracing_subscriber::filter::LevelFilter::WARN))
+        .with(logging::fmt_layer(std::io::stdout, &settings.logging, &settings.baggage, &route_levels))
+        // and the same lines in the log file, if there is one
+        .with(log_file.map(|file| logging::file_layer(file, &settings.logging, &settings.baggage, &route_levels)))
 
         // opentelemetry log (severity >= INFO)
-        .with(tracing_opentelemetry::OpenTelemetryLayer::new(tracer))
+        .with(pipeline.as_ref().map(|pipeline| tracing_opentelemetry::OpenTelemetryLayer::new(pipeline.tracer())))
+
+        // severity numbers on the span events above
+        .with(pipeline.as_ref().map(|_| severity::SeverityLayer))
+        // and the sample rate on the sampled ones
+        .with(pipeline.as_ref().and(log_sampling.as_ref()).map(logging::LogSampling::rates))
+
+        // allowlisted baggage onto the spans above, once they have their context
+        .with(pipeline.as_ref().map(|_| baggage::SpanLayer::new(&settings.baggage)))
+
+        // extractor rejections, kept on their request span for `middleware::rejection`
+        .with(middleware::rejection::RejectionLayer)
+
+        // audit events to a file of their own, with the trace id of their span
+        .with(audit)
         .init();
+    logging::spawn_reports(log_rate_limit, error_dedup);
+    // panics as error events on their span, with the trace id
+    panics::install_hook();
+    // what is running, ahead of anything else in the log
+    let fingerprint = startup::Fingerprint::new(&settings);
+    fingerprint.log();
+    bootstrap.replay(&settings.logging.redact_fields);
+    // telemetry which couldn't be set up doesn't stop the service, it's retried
+    for warning in pipeline.iter().flat_map(telemetry::Pipeline::warnings) {
+        tracing::warn!("{}", warning);
+    }
 
-    // DB setup
-    let options = sqlx::mysql::MySqlConnectOptions::new()
-        .host("127.0.0.1")
-        .port(3306)
-        .username("user")
-        .password("password")
-        .database("mydb");
-
-    let pool = sqlx::mysql::MySqlPoolOptions::new()
-        .connect_with(options)
+    let result = match command {
+        cli::Command::Serve => {
+            let startup = startup.into_span();
+            fingerprint.record(&startup);
+            serve(settings, startup, sampling, exporter_health, pipeline_stats).await
+        }
+        cli::Command::Preflight => preflight::run(&settings).await,
+        cli::Command::Loadgen(args) => {
+            loadgen::run(args).await;
+            Ok(())
+        }
+        cli::Command::BenchOverhead(args) => bench::run(args, &settings, pipeline.as_ref().map(|pipeline| pipeline.tracer())).await,
+        #[cfg(feature = "mysql")]
+        cli::Command::Migrate => async {
+            let pool = connect_database(&settings).await?;
+            migrate_database(&settings, &pool).await
+        }
+        .await,
+        #[cfg(feature = "mysql")]
+        cli::Command::Seed { sessions } => async {
+            let pool = connect_database(&settings).await?;
+            migrate_database(&settings, &pool).await?;
+            db::seed(session::SessionStore::new(pool, settings.session.clone()), sessions).await;
+            Ok(())
+        }
+        .await,
+    };
+    if let Err(e) = &result {
+        tracing::error!(exit_code = e.exit_code(), "{}", e);
+    }
+
+    // Flush what was recorded before exiting, the error above included
+    if let Some(pipeline) = pipeline {
+        pipeline.shutdown();
+    }
+    drop(log_file_flush);
+    result
+}
+
+#[cfg(feature = "mysql")]
+async fn connect_database(settings: &config::Settings) -> Result<sqlx::MySqlPool, startup::StartupError> {
+    db::connect(&settings.database, &settings.dependencies.db)
+        .await
+        .map_err(startup::StartupError::dependency("MySQL", db::target(&settings.database)))
+}
+
+#[cfg(feature = "mysql")]
+async fn migrate_database(settings: &config::Settings, pool: &sqlx::MySqlPool) -> Result<(), startup::StartupError> {
+    db::migrate(pool)
         .await
-        .expect("Failed to connect to MySQL");
-    
+        .map_err(|e| format!("migrations failed: {e}"))
+        .map_err(startup::StartupError::dependency("MySQL", db::target(&settings.database)))
+}
+
+// Runs the server until it is told to stop and has drained; `startup` ends once it is ready
+async fn serve(
+    settings: config::Settings,
+    startup: tracing::Span,
+    sampling: sampling::TenantRates,
+    exporter_health: std::sync::Arc<telemetry::ExporterHealth>,
+    pipeline_stats: std::sync::Arc<telemetry::PipelineStats>,
+) -> Result<(), startup::StartupError> {
+    use startup::StartupError;
+
+    // DB setup
+    #[cfg(feature = "mysql")]
+    let pool = connect_database(&settings)
+        .instrument(tracing::info_span!(parent: &startup, "db connect"))
+        .await?;
+    #[cfg(feature = "mysql")]
+    db::record_server(&pool).instrument(startup.clone()).await;
+    #[cfg(feature = "mysql")]
+    migrate_database(&settings, &pool).instrument(startup.clone()).await?;
+    #[cfg(feature = "mysql")]
+    if settings.database.warm_up {
+        db::warm_up(&pool, settings.database.min_connections).instrument(startup.clone()).await;
+    }
+
+    #[cfg(feature = "mysql")]
+    let db_breaker = std::sync::Arc::new(circuit_breaker::CircuitBreaker::new("mysql", settings.circuit_breaker.clone()));
+    #[cfg(feature = "mysql")]
+    let sessions = std::sync::Arc::new(session::SessionStore::new(pool.clone(), settings.session.clone()));
+    #[cfg(feature = "mysql")]
+    let idempotency = std::sync::Arc::new(idempotency::IdempotencyStore::new(pool.clone(), settings.idempotency.clone()));
+    #[cfg(feature = "mysql")]
+    idempotency.spawn_purge();
+    #[cfg(feature = "mysql")]
+    db::spawn_credential_rotation(pool.clone(), &settings.database);
+
+    // Availability gauges, from probes of their own
+    let probes = dependencies::Probes::new(&settings, exporter_health.clone()).map_err(StartupError::config("dependencies.http"))?;
+    #[cfg(feature = "mysql")]
+    let probes = probes.with_database(pool.clone(), &settings.dependencies.db);
+    let availability = std::sync::Arc::new(dependencies::Dependencies::default());
+    availability.register_metrics();
+    probes.spawn(availability);
+    // a trace of its own now and then, checked to have gone out
+    if settings.telemetry.enabled {
+        self_test::spawn(&settings.telemetry, exporter_health.clone());
+    }
+    #[cfg(feature = "jemalloc")]
+    heap::register_metrics();
+    #[cfg(feature = "pprof")]
+    profiling::spawn_continuous(&settings.profiling, &settings.telemetry.environment()).map_err(StartupError::config("profiling"))?;
+
+    #[cfg(feature = "mysql")]
+    let hasher = hashing::Hasher::new(settings.hashing.clone()).map_err(StartupError::config("hashing"))?;
+    #[cfg(feature = "mysql")]
+    let copies = std::sync::Arc::new(items::Copies::new(&settings.degraded));
+    let health = health::Health::new(settings.health.clone(), exporter_health).with_sampling(sampling.clone());
+    #[cfg(feature = "mysql")]
+    let health = health.with_database(health::Database::new(pool.clone(), sessions.clone(), db_breaker.clone()).serving_stale(copies.clone()));
+    let health = std::sync::Arc::new(health);
+    let flags = flags::Flags::new(&settings.flags).map_err(StartupError::config("flags"))?;
+    let chaos = chaos::Chaos::new(&settings.chaos).map_err(StartupError::config("chaos"))?;
+
     // Server setup
-    let app = axum::Router::new()
-        .route("/", axum::routing::get(root))
-        .route("/cause_error", axum::routing::get(cause_error))
-        .with_state(AppState{ pool });
+    let state = AppState {
+        #[cfg(feature = "mysql")]
+        pool: pool.clone(),
+        #[cfg(feature = "mysql")]
+        db_breaker,
+        #[cfg(feature = "mysql")]
+        db_policy: std::sync::Arc::new(retry::Policy::new(settings.dependencies.db.clone())),
+        #[cfg(feature = "mysql")]
+        explainer: std::sync::Arc::new(explain::Explainer::new(pool.clone(), &settings.database)),
+        #[cfg(feature = "mysql")]
+        hedger: std::sync::Arc::new(hedging::Hedger::new(settings.hedging.clone())),
+        #[cfg(feature = "mysql")]
+        transactions: std::sync::Arc::new(transaction_retry::TransactionRetry::new(settings.transaction_retry.clone())),
+        #[cfg(feature = "mysql")]
+        hasher: std::sync::Arc::new(hasher),
+        #[cfg(feature = "mysql")]
+        sessions,
+        #[cfg(feature = "mysql")]
+        idempotency,
+        #[cfg(feature = "mysql")]
+        copies,
+        http: http_client::HttpClient::new(&settings.dependencies.http).map_err(StartupError::config("dependencies.http"))?,
+        chain_url: format!("{}{}", settings.downstream.base_url, settings.downstream.chain_path),
+        jobs: std::sync::Arc::new(jobs::Jobs::new(settings.jobs.clone())),
+        connections: settings.connections.clone(),
+        flags: flags.clone(),
+        chaos: chaos.clone(),
+        #[cfg(feature = "email")]
+        mailer: std::sync::Arc::new(email::Mailer::new(&settings.email, &settings.dependencies.smtp).map_err(StartupError::config("email"))?),
+        #[cfg(feature = "s3")]
+        storage: std::sync::Arc::new(storage::Storage::new(&settings.storage, &settings.dependencies.s3)),
+    };
+    let config = effective_config::EffectiveConfig::new(&settings, sampling.clone());
+    let admin = Admin { health: health.clone(), pipeline_stats, sampling: sampling.clone(), flags: flags.clone(), chaos, config };
+    let (app, admin) = match settings.admin.separate {
+        true => (router(&settings, state, None)?, Some(admin_router(&settings, admin)?)),
+        false => (router(&settings, state, Some(admin))?, None),
+    };
+
+    let listeners = server::bind(&settings.server)
+        .instrument(tracing::info_span!(parent: &startup, "listener bind"))
+        .await
+        .map_err(|e| match e.kind() {
+            // Nothing to listen on, or the certificates
+            std::io::ErrorKind::InvalidInput => StartupError::Config { section: "server", reason: e.to_string() },
+            _ => StartupError::Bind { listener: "server", address: server::address(&settings.server), reason: e.to_string() },
+        })?;
+    // Up until the main listener has drained, so probes still answer meanwhile
+    let admin = match admin {
+        Some(admin) => {
+            let listener = server::bind_admin(&settings.admin)
+                .instrument(tracing::info_span!(parent: &startup, "admin listener bind"))
+                .await
+                .map_err(|e| StartupError::Bind { listener: "admin", address: settings.admin.bind.clone(), reason: e.to_string() })?;
+            Some(server::spawn_admin(listener, admin))
+        }
+        None => None,
+    };
+
+    // Telemetry and the database are up and the listener is bound
+    #[cfg(feature = "systemd")]
+    {
+        systemd::notify_ready();
+        #[cfg(feature = "mysql")]
+        systemd::spawn_watchdog(pool.clone());
+    }
+
+    health.mark_started();
+    drop(startup);
+    sampling::spawn_reload_on_sighup(sampling);
+    flags::spawn_reload_on_sighup(flags);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app)
+    server::serve(listeners, app, &settings.server)
         .await
-        .unwrap();
+        .map_err(StartupError::Serve)?;
+    if let Some(admin) = admin {
+        admin.abort();
+    }
+
+    // The drain is over, what still holds a connection is cut off after the timeout
+    #[cfg(feature = "mysql")]
+    db::close(&pool, std::time::Duration::from_secs(settings.database.close_timeout_secs)).await;
+    Ok(())
 }
 
-#[tracing::instrument]
-async fn root(axum::extract::State(AppState { pool }): axum::extract::State<AppState>) -> &'static str {
+// Version 1 of the API. A breaking change goes into a `v2` router nested next to it, with
+// the handlers that didn't change shared; spans and metrics tell them apart by
+// `api.version`, taken from the route.
+fn v1(state: &AppState) -> utoipa_axum::router::OpenApiRouter<AppState> {
+    let v1 = openapi::router()
+        .routes(utoipa_axum::routes!(chain))
+        .routes(utoipa_axum::routes!(build_info::handler))
+        .routes(utoipa_axum::routes!(jobs::start_report))
+        .routes(utoipa_axum::routes!(jobs::get))
+        .routes(utoipa_axum::routes!(jobs::events));
+    #[cfg(feature = "email")]
+    let v1 = v1.routes(utoipa_axum::routes!(email::notify));
+    #[cfg(feature = "s3")]
+    let v1 = v1.merge(openapi::wildcard("/files/*key", utoipa_axum::routes!(storage::get, storage::put)));
+
+    // Routes reading from the database, the only ones with a session; and with
+    // `Idempotency-Key`, outside it so a replay doesn't load the session
+    #[cfg(feature = "mysql")]
+    let v1 = v1
+        .routes(utoipa_axum::routes!(root))
+        .routes(utoipa_axum::routes!(cause_error))
+        .routes(utoipa_axum::routes!(export::export_csv))
+        .routes(utoipa_axum::routes!(visit_counter))
+        .routes(utoipa_axum::routes!(items::list, items::create))
+        .routes(utoipa_axum::routes!(items::create_batch, items::get_batch))
+        .routes(utoipa_axum::routes!(items::get, items::update, items::delete))
+        .routes(utoipa_axum::routes!(items::list_page))
+        .routes(utoipa_axum::routes!(items::get_page))
+        .layer(axum::middleware::from_fn_with_state(state.sessions.clone(), session::layer))
+        .layer(axum::middleware::from_fn_with_state(state.idempotency.clone(), idempotency::layer));
+
+    // Every route can take the feature flags
+    v1.layer(axum::middleware::from_fn_with_state(state.flags.clone(), flags::layer))
+}
+
+// The operational routes on a listener of their own, without the request span and the
+// limits of the API's stack; `/debug` and `/admin` take an admin's token, auth on or off,
+// and `/docs` describes them and the probes
+fn admin_router(settings: &config::Settings, admin: Admin) -> Result<axum::Router, startup::StartupError> {
+    let verifier = middleware::auth::JwtVerifier::new(&settings.auth).map_err(startup::StartupError::config("auth"))?;
+    let (probes, probes_api) = health::router(admin.health.clone()).split_for_parts();
+    let (routes, mut api) = admin.routes().split_for_parts();
+    api.merge(probes_api);
+    Ok(routes
+        .layer(axum::middleware::from_fn(middleware::auth::require_admin))
+        .layer(axum::middleware::from_fn_with_state(std::sync::Arc::new(verifier.required()), middleware::auth::require_auth))
+        .merge(probes)
+        .merge(openapi::routes(api)))
+}
+
+// Routes and the middleware stack, shared by the server and the route tests; the
+// operational routes come along unless `admin.separate` puts them on `admin_router`
+fn router(settings: &config::Settings, state: AppState, admin: Option<Admin>) -> Result<axum::Router, startup::StartupError> {
+    use startup::StartupError;
+
+    let chaos = state.chaos.clone();
+    // for auth, and for the tenant of the request span before it
+    let verifier = std::sync::Arc::new(middleware::auth::JwtVerifier::new(&settings.auth).map_err(StartupError::config("auth"))?);
+    let tenants = std::sync::Arc::new(middleware::tenant::TenantResolver::new(&settings.tenant));
+    // Operational routes stay unversioned, the API is under `/v1`
+    let app = openapi::router().nest("/v1", v1(&state));
+    let health = admin.as_ref().map(|admin| admin.health.clone());
+    let app = match admin {
+        Some(admin) => app.merge(admin.routes()),
+        None => app,
+    };
+    // `/docs` describes the routes so far and the probes, which are merged in last
+    let probes = health.map(health::router);
+    let (app, mut api) = app.split_for_parts();
+    if let Some(probes) = &probes {
+        api.merge(probes.get_openapi().clone());
+    }
+    let app = app.merge(openapi::routes(api));
+
+    let unmatched = std::sync::Arc::new(fallback::Unmatched::new());
+    let app = app
+        // from the server receiving the request to here, right before the handler
+        .route_layer(axum::middleware::from_fn_with_state(
+            std::sync::Arc::new(middleware::queue_time::QueueTimeMetrics::new()),
+            middleware::queue_time::layer,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(unmatched.clone(), fallback::method_not_allowed))
+        // spelled out so 404s pass through the layers below, which the default fallback
+        // doesn't once the probe routes are merged in
+        .fallback_service(axum::handler::Handler::with_state(fallback::not_found, unmatched))
+        // the responses of the routes with `cache_ttl_secs`, inside auth and the tenant it keys by
+        .layer(tower::util::option_layer(
+            middleware::cache::Cache::new(&settings.cache, &settings.routes)
+                .map_err(StartupError::config("cache"))?
+                .map(|cache| {
+                    cache.subscribe();
+                    axum::middleware::from_fn_with_state(cache, middleware::cache::layer)
+                }),
+        ))
+        // outside the cache, so hits are answered with a 304 alike
+        .layer(tower::util::option_layer(
+            middleware::etag::Etags::new(&settings.etag).map(|etags| axum::middleware::from_fn_with_state(std::sync::Arc::new(etags), middleware::etag::layer)),
+        ))
+        // outside the cache, which keeps the variants apart
+        .layer(tower::util::option_layer(
+            middleware::canary::Canary::new(&settings.canary, &settings.dependencies.http)
+                .map_err(StartupError::config("canary"))?
+                .map(|canary| axum::middleware::from_fn_with_state(std::sync::Arc::new(canary), middleware::canary::layer)),
+        ))
+        // the faults of `[chaos]`, inside the tenant they can be scoped to
+        .layer(tower::util::option_layer(
+            chaos.enabled().then(|| axum::middleware::from_fn_with_state(chaos.clone(), chaos::layer)),
+        ))
+        // what the layers below put in the request's extensions, on the request span
+        .layer(axum::middleware::from_fn_with_state(
+            std::sync::Arc::new(middleware::extension_fields::ExtensionFields::default().with::<middleware::auth::AuthUser>()),
+            middleware::extension_fields::layer,
+        ))
+        // failed extractors answer with JSON and the trace id
+        .layer(axum::middleware::from_fn(middleware::rejection::layer))
+        // caller's deadline, applied to the DB and downstream calls made by handlers
+        .layer(axum::middleware::from_fn(middleware::deadline::layer))
+        .layer(axum::middleware::from_fn_with_state(
+            std::sync::Arc::new(middleware::timeout::RequestTimeout::new(settings.timeout.clone())),
+            middleware::timeout::layer,
+        ))
+        // the body limit layer replaces axum's built-in 2MB default
+        .layer(axum::extract::DefaultBodyLimit::disable())
+        .layer(axum::middleware::from_fn_with_state(
+            std::sync::Arc::new(middleware::body_limit::BodyLimit::new(settings.body_limit.clone())),
+            middleware::body_limit::layer,
+        ))
+        // runs after auth, so authenticated clients are limited per user
+        .layer(axum::middleware::from_fn_with_state(
+            std::sync::Arc::new(middleware::rate_limit::RateLimiter::new(settings.rate_limit.clone())),
+            middleware::rate_limit::layer,
+        ))
+        // inside auth and the tenant, buckets by the user and adds to the baggage
+        .layer(tower::util::option_layer(
+            middleware::experiment::Experiment::new(&settings.experiment)
+                .map_err(StartupError::config("experiment"))?
+                .map(|experiment| axum::middleware::from_fn_with_state(std::sync::Arc::new(experiment), middleware::experiment::layer)),
+        ))
+        // inside auth, which the tenant claim is read from
+        .layer(axum::middleware::from_fn_with_state(
+            std::sync::Arc::new(settings.tenant.clone()),
+            middleware::tenant::layer,
+        ))
+        .layer(axum::middleware::from_fn_with_state(verifier.clone(), middleware::auth::require_auth))
+        .layer(axum::middleware::from_fn_with_state(
+            std::sync::Arc::new(middleware::concurrency::ConcurrencyLimit::new(settings.concurrency.clone())),
+            middleware::concurrency::layer,
+        ))
+        // errors as a page for browsers, the same trace id and all, whichever layer above
+        // answered with them
+        .layer(axum::middleware::from_fn(error::page_layer))
+        // byte counts on both sides of the compression layer
+        .layer(axum::middleware::map_response(middleware::compression::count_uncompressed))
+        .layer(middleware::compression::layer(&settings.compression))
+        .layer(axum::middleware::map_response_with_state(
+            std::sync::Arc::new(middleware::compression::CompressionMetrics::new()),
+            middleware::compression::count_compressed,
+        ))
+        // body sizes as received and sent, on the request span and per route
+        .layer(axum::middleware::from_fn_with_state(
+            std::sync::Arc::new(middleware::payload_size::PayloadSizeMetrics::new()),
+            middleware::payload_size::layer,
+        ))
+        // answers preflights before auth, which browsers don't send credentials for
+        .layer(tower::util::option_layer(
+            middleware::cors::layer(&settings.cors).map_err(StartupError::config("cors"))?,
+        ))
+        // notices clients leaving mid-request, outside the timeout so the two aren't confused
+        .layer(axum::middleware::from_fn(middleware::disconnect::layer))
+        // counts the tasks handlers spawn, on the request span
+        .layer(axum::middleware::from_fn(middleware::fan_out::layer))
+        // counts the database queries, on the request span and per route
+        .layer(axum::middleware::from_fn_with_state(
+            std::sync::Arc::new(middleware::db_calls::DbCallMetrics::new()),
+            middleware::db_calls::layer,
+        ))
+        // the caller's baggage, current for everything below and passed on to downstream calls
+        .layer(axum::middleware::from_fn(baggage::layer))
+        // the route's own log level, if it has one, for everything above
+        .layer(axum::middleware::from_fn_with_state(
+            std::sync::Arc::new(logging::RouteLevels::new(&settings.routes).map_err(StartupError::config("routes"))?),
+            middleware::log_level::layer,
+        ))
+        // one line per request apart from the application log, with the trace id
+        .layer(tower::util::option_layer(
+            middleware::access_log::AccessLog::new(&settings.access_log)
+                .map_err(StartupError::config("access_log"))?
+                .map(|log| axum::middleware::from_fn_with_state(log, middleware::access_log::layer)),
+        ))
+        // good and bad requests of the routes with an objective, and the burn rates
+        .layer(tower::util::option_layer(
+            slo::Slos::new(&settings.slo, &settings.routes).map_err(StartupError::config("slo"))?.map(|slos| {
+                slos.spawn_evaluation();
+                axum::middleware::from_fn_with_state(slos, slo::layer)
+            }),
+        ))
+        // a share of the requests copied to `[shadow] url`, rejected ones included
+        .layer(tower::util::option_layer(
+            middleware::shadow::Shadow::new(&settings.shadow, &settings.dependencies.http)
+                .map_err(StartupError::config("shadow"))?
+                .map(|shadow| axum::middleware::from_fn_with_state(std::sync::Arc::new(shadow), middleware::shadow::layer)),
+        ));
+    // the request headers of `[header_attributes]` on the request span
+    let app = app.layer(tower::util::option_layer(
+        middleware::header_attributes::HeaderAttributes::new(&settings.header_attributes)
+            .map_err(StartupError::config("header_attributes"))?
+            .map(|headers| axum::middleware::from_fn_with_state(std::sync::Arc::new(headers), middleware::header_attributes::layer)),
+    ));
+    // the client's location and network, inside the layer resolving its address
+    #[cfg(feature = "geoip")]
+    let app = app.layer(tower::util::option_layer(
+        middleware::geoip::GeoIp::new(&settings.geoip)
+            .map_err(StartupError::config("geoip"))?
+            .map(|geoip| axum::middleware::from_fn_with_state(std::sync::Arc::new(geoip), middleware::geoip::layer)),
+    ));
+    // the client behind trusted proxies, on the request span and for the layers above
+    let app = app.layer(axum::middleware::from_fn_with_state(
+        std::sync::Arc::new(proxy::TrustedProxies::new(&settings.server.trusted_proxies).map_err(StartupError::config("server"))?),
+        middleware::client_address::layer,
+    ));
+    // CPU time and allocations of everything above, on the request span
+    #[cfg(feature = "resource-usage")]
+    let app = app.layer(axum::middleware::from_fn(usage::layer));
+    // the time in queries, the blocking pool and downstream calls, and the rest, on the request span
+    let app = app.layer(axum::middleware::from_fn(middleware::time_breakdown::layer));
+    let app = app
+        // request span, wraps everything above
+        .layer(
+            tower_http::trace::TraceLayer::new_for_http()
+                .make_span_with(move |request: &axum::extract::Request| middleware::trace::make_span(request, tenants.resolve(request).as_deref()))
+                .on_response(middleware::trace::on_response)
+        )
+        // the token, checked once for the request span's tenant and for auth
+        .layer(axum::middleware::from_fn_with_state(verifier, middleware::auth::verify_layer))
+        .with_state(state)
+        // probes bypass every layer above, see `health::router`
+        .merge(probes.map(axum::Router::from).unwrap_or_default());
+    Ok(app)
+}
+
+#[cfg(feature = "mysql")]
+#[utoipa::path(get, path = "/", summary = "Reads a row from the database", responses(
+    (status = 200, body = String, description = "ok"),
+    (status = 503, body = String, description = "The database is unavailable"),
+    (status = 504, body = String, description = "The deadline passed"),
+))]
+#[traced_handler::traced_handler]
+async fn root(
+    axum::extract::State(AppState { pool, db_breaker, db_policy, hedger, hasher, .. }): axum::extract::State<AppState>,
+) -> Result<&'static str, axum::http::StatusCode> {
 
     // Emit an info level event
     tracing::info!("Processing request");
 
-    // Synchronous function call can be wrapped with `info_span` macro
-    let _ = tracing::info_span!("some process").in_scope(|| {
-        bcrypt::hash("password", 4)
+    // Synchronous, CPU-bound work goes to the blocking pool, within a span of its own
+    hasher
+        .hash("some process", "password".to_string())
+        .await
+        .trace_err()
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Asynchronous function call can be added with `instrument` method,
+    // the circuit breaker stops calling the database while it is down,
+    // a timed out or refused read is retried, and a slow one may be hedged
+    // with a second attempt
+    let query = sqlcommenter::tag("SELECT COUNT(*) FROM items");
+    let rs = hedger.run(|| {
+        let read = db_policy.run_timed(circuit_breaker::is_db_unavailable, || sqlx::query(&query).fetch_one(&pool).counted());
+        db_breaker.call(circuit_breaker::is_db_unavailable, read)
     });
 
-    // Asynchronous function call can be added with `instrument` method
-    let _ = sqlx::query("SELECT 1 + 1 as result")
-        .fetch_one(&pool)
-        .instrument(tracing::info_span!("fetch row"))
-        .await
-        .expect("Failed to fetch row");
-    
-    "ok"
+    let span = tracing::info_span!("fetch row", db.query.text = tracing::field::Empty, db.response.returned_rows = tracing::field::Empty);
+    attributes::set_all(&span, attributes::db_client("SELECT"));
+    span_fields::record_lazy(&span, "db.query.text", || span_fields::query_text(&query));
+
+    // The caller's deadline bounds the whole lookup
+    let rs = middleware::deadline::bounded("fetch row", rs)
+        .instrument(span.clone())
+        .await;
+
+    match rs {
+        Ok(Ok(_)) => {
+            span.record("db.response.returned_rows", 1i64);
+            Ok("ok")
+        }
+        Ok(Err(e)) => {
+            tracing::error!("Failed to fetch row: {:?}", e);
+            Err(axum::http::StatusCode::SERVICE_UNAVAILABLE)
+        }
+        Err(_) => Err(axum::http::StatusCode::GATEWAY_TIMEOUT),
+    }
 }
 
-#[tracing::instrument]
-async fn cause_error(axum::extract::State(AppState { pool }): axum::extract::State<AppState>) -> &'static str {
+#[cfg(feature = "mysql")]
+#[utoipa::path(get, path = "/cause_error", summary = "Runs a query which fails, for an error in the trace", responses(
+    (status = 200, body = String, description = "ok"),
+))]
+#[traced_handler::traced_handler]
+async fn cause_error(axum::extract::State(AppState { pool, db_breaker, .. }): axum::extract::State<AppState>) -> &'static str {
 
     // This event won't be shown in the stdout log, but will be shown in the opentelemetry log
     tracing::info!("Processing request");
@@ -110,18 +802,450 @@ async fn cause_error(axum::extract::State(AppState { pool }): axum::extract::Sta
     // This event will be shown in the stdout log, and will be shown in the opentelemetry log
     tracing::warn!("possible error");
 
-    let rs = sqlx::query("SQL SYNTAX ERROR")
-        .fetch_one(&pool)
-        .instrument(tracing::info_span!("fetch row"))
-        .await;
+    // A syntax error is not an outage, so it doesn't count towards opening the circuit
+    let span = tracing::info_span!("fetch row");
+    attributes::set_all(&span, attributes::db_client("SELECT"));
+    // The error event will be shown in the stdout log, and will be shown in the opentelemetry log
+    let _ = db_breaker
+        .call(circuit_breaker::is_db_unavailable, sqlx::query(&sqlcommenter::tag("SQL SYNTAX ERROR")).fetch_one(&pool).counted())
+        .instrument(span)
+        .await
+        .trace_err();
 
-    match rs {
-        Ok(_) => (),
-        Err(e) => {
-            // This event will be shown in the stdout log, and will be shown in the opentelemetry log
-            tracing::error!("Error: {:?}", e);
+    "ok"
+}
+
+#[cfg(feature = "mysql")]
+#[utoipa::path(get, path = "/session", summary = "Counts the visits of the session in the cookie", responses(
+    (status = 200, body = String, description = "The visit count"),
+))]
+#[traced_handler::traced_handler]
+async fn visit_counter(session: session::Session) -> String {
+
+    // Session reads and writes happen in memory, the store spans are recorded by the session layer
+    let visits = session.get("visits").and_then(|v| v.as_u64()).unwrap_or(0) + 1;
+    session.insert("visits", visits);
+
+    format!("visits: {visits}")
+}
+
+#[utoipa::path(get, path = "/chain", summary = "Calls the downstream service, continuing the trace", responses(
+    (status = 200, body = String, description = "What the downstream service answered"),
+    (status = 502, body = String, description = "The downstream service is unreachable"),
+))]
+#[traced_handler::traced_handler]
+async fn chain(
+    axum::extract::State(AppState { http, chain_url, .. }): axum::extract::State<AppState>,
+    flags: flags::Flags,
+) -> Result<String, axum::http::StatusCode> {
+    // The downstream call continues this trace, and gets whatever is left of our deadline
+    let response = http
+        .get(&chain_url)
+        .await
+        .map_err(|_| axum::http::StatusCode::BAD_GATEWAY)?;
+    if !response.status().is_success() {
+        return Err(axum::http::StatusCode::BAD_GATEWAY);
+    }
+
+    // What the downstream answered rather than how, behind `chain-body`
+    if flags.is_enabled("chain-body") {
+        return response.text().await.map_err(|_| axum::http::StatusCode::BAD_GATEWAY);
+    }
+    Ok(format!("downstream answered {}", response.status()))
+}
+
+// Route tests: each drives one request through the full middleware stack and compares
+// the span tree it produced with its snapshot in src/snapshots
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, assert_snapshot};
+
+    // The database is a closed port, so queries fail fast instead of needing MySQL
+    #[cfg_attr(not(feature = "mysql"), allow(unused_variables))]
+    fn state(settings: &config::Settings) -> AppState {
+        #[cfg(feature = "mysql")]
+        let pool = sqlx::mysql::MySqlPoolOptions::new()
+            .acquire_timeout(std::time::Duration::from_millis(100))
+            .connect_lazy_with(sqlx::mysql::MySqlConnectOptions::new().host("127.0.0.1").port(1));
+
+        AppState {
+            #[cfg(feature = "mysql")]
+            pool: pool.clone(),
+            #[cfg(feature = "mysql")]
+            db_breaker: std::sync::Arc::new(circuit_breaker::CircuitBreaker::new("mysql", settings.circuit_breaker.clone())),
+            #[cfg(feature = "mysql")]
+            db_policy: std::sync::Arc::new(retry::Policy::new(settings.dependencies.db.clone())),
+            #[cfg(feature = "mysql")]
+            explainer: std::sync::Arc::new(explain::Explainer::new(pool.clone(), &settings.database)),
+            #[cfg(feature = "mysql")]
+            hedger: std::sync::Arc::new(hedging::Hedger::new(settings.hedging.clone())),
+            #[cfg(feature = "mysql")]
+            transactions: std::sync::Arc::new(transaction_retry::TransactionRetry::new(settings.transaction_retry.clone())),
+            #[cfg(feature = "mysql")]
+            hasher: std::sync::Arc::new(hashing::Hasher::new(settings.hashing.clone()).unwrap()),
+            #[cfg(feature = "mysql")]
+            sessions: std::sync::Arc::new(session::SessionStore::new(pool.clone(), settings.session.clone())),
+            #[cfg(feature = "mysql")]
+            idempotency: std::sync::Arc::new(idempotency::IdempotencyStore::new(pool, settings.idempotency.clone())),
+            #[cfg(feature = "mysql")]
+            copies: std::sync::Arc::new(items::Copies::new(&settings.degraded)),
+            http: http_client::HttpClient::new(&settings.dependencies.http).unwrap(),
+            chain_url: "http://127.0.0.1:1/v1/items".to_string(),
+            jobs: std::sync::Arc::new(jobs::Jobs::new(settings.jobs.clone())),
+            connections: settings.connections.clone(),
+            flags: flags::Flags::new(&settings.flags).unwrap(),
+            chaos: chaos::Chaos::new(&settings.chaos).unwrap(),
+            #[cfg(feature = "email")]
+            mailer: std::sync::Arc::new(email::Mailer::new(&settings.email, &settings.dependencies.smtp).unwrap()),
+            #[cfg(feature = "s3")]
+            storage: std::sync::Arc::new(storage::Storage::new(&settings.storage, &settings.dependencies.s3)),
         }
     }
-    
-    "ok"
-}
\ No newline at end of file
+
+    async fn send(uri: &str) -> (axum::http::StatusCode, test_support::Spans) {
+        send_request(axum::http::Request::get(uri).body(axum::body::Body::empty()).unwrap()).await
+    }
+
+    fn admin(settings: &config::Settings) -> Admin {
+        let sampling = sampling::TenantRates::new(&settings.sampling).unwrap();
+        Admin {
+            health: std::sync::Arc::new(health::Health::new(settings.health.clone(), Default::default())),
+            pipeline_stats: std::sync::Arc::new(telemetry::PipelineStats::new()),
+            config: effective_config::EffectiveConfig::new(settings, sampling.clone()),
+            sampling,
+            flags: flags::Flags::new(&settings.flags).unwrap(),
+            chaos: chaos::Chaos::new(&settings.chaos).unwrap(),
+        }
+    }
+
+    async fn send_request(request: axum::http::Request<axum::body::Body>) -> (axum::http::StatusCode, test_support::Spans) {
+        send_to(|settings| router(settings, state(settings), None).unwrap(), request).await
+    }
+
+    // Through the admin listener, where the probes and `/admin` are by default, as an admin
+    async fn send_admin_request(mut request: axum::http::Request<axum::body::Body>) -> (axum::http::StatusCode, test_support::Spans) {
+        let token = bearer(&settings(), &["admin"]);
+        request.headers_mut().insert(axum::http::header::AUTHORIZATION, token.parse().unwrap());
+        send_to(|settings| admin_router(settings, admin(settings)).unwrap(), request).await
+    }
+
+    // The defaults, but with a secret the admin listener takes tokens signed with
+    fn settings() -> config::Settings {
+        let mut settings = config::Settings::default();
+        settings.auth.jwt_secret = "test-secret".to_string();
+        settings
+    }
+
+    // An `Authorization` header for a user with these roles, valid for an hour
+    fn bearer(settings: &config::Settings, roles: &[&str]) -> String {
+        let exp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 3600;
+        let claims = serde_json::json!({ "sub": "user-1", "roles": roles, "exp": exp });
+        let key = jsonwebtoken::EncodingKey::from_secret(settings.auth.jwt_secret.as_bytes());
+        format!("Bearer {}", jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap())
+    }
+
+    // Sends one request and reads the whole response, which ends the request span
+    async fn send_to(
+        app: impl FnOnce(&config::Settings) -> axum::Router,
+        request: axum::http::Request<axum::body::Body>,
+    ) -> (axum::http::StatusCode, test_support::Spans) {
+        use tower::ServiceExt;
+
+        let telemetry = test_support::init();
+        let settings = settings();
+        let response = app(&settings).oneshot(request).await.unwrap();
+        let status = response.status();
+        http_body_util::BodyExt::collect(response.into_body()).await.unwrap();
+
+        (status, telemetry.spans())
+    }
+
+    #[tokio::test]
+    async fn buildinfo() {
+        let (status, spans) = send("/v1/buildinfo").await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        spans
+            .assert_span_exists("GET /v1/buildinfo")
+            .without_parent()
+            .with_attribute("http.route", "/v1/buildinfo")
+            .with_attribute("api.version", "v1")
+            .with_attribute("network.protocol.version", "1.1")
+            .with_attribute_present("http.response.status_code");
+        assert_snapshot("buildinfo", &spans.tree());
+    }
+
+    #[tokio::test]
+    async fn tenant_header() {
+        let request = axum::http::Request::get("/v1/buildinfo").header("x-tenant-id", "acme").body(axum::body::Body::empty());
+        let (status, spans) = send_request(request.unwrap()).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        spans.assert_span_exists("GET /v1/buildinfo").with_attribute_present("tenant.id");
+
+        let request = axum::http::Request::get("/v1/buildinfo").header("x-tenant-id", "*/").body(axum::body::Body::empty());
+        let (status, _) = send_request(request.unwrap()).await;
+        assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn client_behind_a_proxy() {
+        // Without ConnectInfo, as from a proxy on the Unix socket
+        let request = axum::http::Request::get("/v1/buildinfo").header("x-forwarded-for", "203.0.113.7").body(axum::body::Body::empty());
+        let (_, spans) = send_request(request.unwrap()).await;
+        spans.assert_span_exists("GET /v1/buildinfo").with_attribute("client.address", "203.0.113.7");
+    }
+
+    #[tokio::test]
+    async fn change_sampling() {
+        let put = |body: &'static str| {
+            axum::http::Request::put("/admin/sampling")
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(body))
+                .unwrap()
+        };
+        let (status, _) = send_admin_request(put(r#"{"ratio": 0.1, "tenants": {"internal-test": 1.0}}"#)).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let (status, _) = send_admin_request(put("1.5")).await;
+        assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn toggle_flag() {
+        let put = |uri: &str| {
+            axum::http::Request::put(uri)
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from("true"))
+                .unwrap()
+        };
+        let (status, _) = send_admin_request(put("/admin/flags/new-checkout")).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let (status, _) = send_admin_request(put("/admin/flags/New%20Checkout")).await;
+        assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn operational_routes_are_not_public() {
+        let get = |uri| axum::http::Request::get(uri).body(axum::body::Body::empty()).unwrap();
+        for uri in ["/healthz/live", "/admin/sampling", "/admin/config", "/debug/telemetry"] {
+            let (status, _) = send(uri).await;
+            assert_eq!(status, axum::http::StatusCode::NOT_FOUND, "{uri} is on the main listener");
+
+            let (status, spans) = send_admin_request(get(uri)).await;
+            assert_eq!(status, axum::http::StatusCode::OK, "{uri} is not on the admin listener");
+            assert!(spans.all().is_empty(), "{uri} produced spans:\n{}", spans.tree());
+        }
+
+        // Auth is off, but not there
+        for uri in ["/admin/sampling", "/admin/config", "/debug/telemetry"] {
+            let (status, _) = send_to(|settings| admin_router(settings, admin(settings)).unwrap(), get(uri)).await;
+            assert_eq!(status, axum::http::StatusCode::UNAUTHORIZED, "{uri} is open without a token");
+
+            let mut request = get(uri);
+            let token = bearer(&settings(), &["reader"]);
+            request.headers_mut().insert(axum::http::header::AUTHORIZATION, token.parse().unwrap());
+            let (status, _) = send_to(|settings| admin_router(settings, admin(settings)).unwrap(), request).await;
+            assert_eq!(status, axum::http::StatusCode::FORBIDDEN, "{uri} is open to anyone with a token");
+        }
+        let (status, _) = send_to(|settings| admin_router(settings, admin(settings)).unwrap(), get("/healthz/live")).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+    }
+
+    // The spec each listener serves, built from the routes it has
+    async fn spec(app: axum::Router) -> serde_json::Value {
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::get(format!("{}/openapi.json", openapi::PATH)).body(axum::body::Body::empty());
+        let response = app.oneshot(request.unwrap()).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_docs_describe_the_routes() {
+        let settings = settings();
+        let api = spec(router(&settings, state(&settings), Some(admin(&settings))).unwrap()).await;
+        let paths = &api["paths"];
+        for path in ["/v1/chain", "/v1/buildinfo", "/v1/jobs/{id}", "/v1/items/{id}", "/admin/config", "/healthz/ready"] {
+            assert!(paths[path].is_object(), "{path} is not in the spec");
+        }
+        assert!(paths["/admin/sampling"]["put"]["requestBody"].is_object());
+        assert!(paths["/v1/items"]["post"]["responses"]["201"].is_object());
+
+        // Each listener describes what it serves
+        let api = spec(router(&settings, state(&settings), None).unwrap()).await;
+        assert!(api["paths"]["/admin/config"].is_null());
+        let api = spec(admin_router(&settings, admin(&settings)).unwrap()).await;
+        assert!(api["paths"]["/admin/config"]["get"].is_object());
+        assert!(api["paths"]["/healthz/live"].is_object());
+        assert!(api["paths"]["/v1/chain"].is_null());
+    }
+
+    #[tokio::test]
+    async fn unknown_route() {
+        let (status, spans) = send("/missing/42").await;
+        assert_eq!(status, axum::http::StatusCode::NOT_FOUND);
+        spans.assert_span_exists("route not found").with_attribute("url.path_bucket", "/missing/*");
+        assert_snapshot("unknown_route", &spans.tree());
+
+        let request = axum::http::Request::delete("/v1/buildinfo").body(axum::body::Body::empty());
+        let (status, _) = send_request(request.unwrap()).await;
+        assert_eq!(status, axum::http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn probes_are_not_traced() {
+        // With `admin.separate` off, next to the API's middleware stack
+        let request = axum::http::Request::get("/healthz/live").body(axum::body::Body::empty()).unwrap();
+        let (status, spans) = send_to(|settings| router(settings, state(settings), Some(admin(settings))).unwrap(), request).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        assert!(spans.all().is_empty(), "probe produced spans:\n{}", spans.tree());
+    }
+
+    #[tokio::test]
+    async fn chain_with_downstream_down() {
+        let (status, spans) = send("/v1/chain").await;
+        assert_eq!(status, axum::http::StatusCode::BAD_GATEWAY);
+        spans
+            .assert_span_exists("chain")
+            .child_of("GET /v1/chain")
+            .with_attribute_present("http.response.status_code")
+            .with_attribute("code.function", "chain")
+            .with_attribute("retry.count", 2)
+            .has_child("GET");
+        assert_snapshot("chain_with_downstream_down", &spans.tree());
+    }
+
+    #[tokio::test]
+    async fn chain_calls_the_downstream_route() {
+        // The downstream, with its items and nothing at its root
+        let downstream = axum::Router::new().route("/v1/items", axum::routing::get(|| async { "[]" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, downstream).await.unwrap() });
+
+        for (chain_path, answered, expected) in [("/v1/items", "200", axum::http::StatusCode::OK), ("/", "404", axum::http::StatusCode::BAD_GATEWAY)] {
+            let request = axum::http::Request::get("/v1/chain").body(axum::body::Body::empty()).unwrap();
+            let chain_url = format!("{base_url}{chain_path}");
+            let (status, spans) =
+                send_to(|settings| router(settings, AppState { chain_url, ..state(settings) }, None).unwrap(), request).await;
+            assert_eq!(status, expected, "{chain_path}");
+            spans.assert_span_exists("GET").child_of("chain").with_attribute("http.response.status_code", answered);
+        }
+    }
+
+    #[tokio::test]
+    async fn chain_passes_the_body_on_behind_a_flag() {
+        use tower::ServiceExt;
+
+        let downstream = axum::Router::new().route("/v1/items", axum::routing::get(|| async { "[]" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let chain_url = format!("http://{}/v1/items", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, downstream).await.unwrap() });
+
+        let telemetry = test_support::init();
+        let settings = config::Settings::default();
+        let state = AppState { chain_url, ..state(&settings) };
+        let app = router(&settings, state.clone(), None).unwrap();
+        let mut answers = vec![];
+        for enabled in [false, true] {
+            state.flags.set("chain-body", enabled).unwrap();
+            let request = axum::http::Request::get("/v1/chain").body(axum::body::Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            answers.push(http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes());
+        }
+
+        assert_eq!(answers, ["downstream answered 200 OK", "[]"]);
+        let spans = telemetry.spans();
+        let chains: Vec<_> = spans.all().iter().filter(|span| span.name == "chain").collect();
+        let flagged = |span: &&opentelemetry_sdk::export::trace::SpanData| {
+            span.attributes.iter().find(|kv| kv.key.as_str() == "feature_flag.chain-body").map(|kv| kv.value.clone())
+        };
+        assert_eq!(chains.iter().map(flagged).collect::<Vec<_>>(), [Some(false.into()), Some(true.into())]);
+    }
+
+    #[cfg(feature = "mysql")]
+    #[tokio::test]
+    async fn root_with_database_down() {
+        let (status, spans) = send("/v1").await;
+        assert_eq!(status, axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        // the password hash runs on the blocking pool
+        spans.assert_span_exists("GET /v1").with_attribute_present("tasks.spawned");
+        spans
+            .assert_span_exists("root")
+            .child_of("GET /v1")
+            .has_child("fetch row");
+        assert_snapshot("root_with_database_down", &spans.tree());
+    }
+
+    #[cfg(feature = "mysql")]
+    #[tokio::test]
+    async fn items_with_database_down() {
+        let (status, spans) = send("/v1/items?limit=10").await;
+        assert_eq!(status, axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        spans
+            .assert_span_exists("list")
+            .child_of("GET /v1/items")
+            .with_attribute_present("http.response.status_code")
+            .with_error_status()
+            .has_child("SELECT items");
+        assert_snapshot("items_with_database_down", &spans.tree());
+    }
+
+    #[cfg(feature = "mysql")]
+    #[tokio::test]
+    async fn items_from_their_copies_with_database_down() {
+        use tower::ServiceExt;
+
+        let _telemetry = test_support::init();
+        let mut settings = config::Settings::default();
+        settings.degraded.enabled = true;
+        settings.circuit_breaker.failure_threshold = 1;
+        let state = state(&settings);
+        state.copies.keep_read(7);
+
+        // The first read opens the circuit, the others find it open
+        for uri in ["/v1/items", "/v1/items/7", "/v1/pages/items", "/v1/pages/items/7"] {
+            let request = axum::http::Request::get(uri).body(axum::body::Body::empty()).unwrap();
+            let response = router(&settings, state.clone(), None).unwrap().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), axum::http::StatusCode::OK, "{uri}");
+            let headers = response.headers();
+            assert_eq!(headers.get(degraded::HEADER).unwrap(), "true", "{uri}");
+            assert!(headers.contains_key(axum::http::header::AGE), "{uri}");
+            assert_eq!(headers.get(axum::http::header::CACHE_CONTROL).unwrap(), "no-store", "{uri}");
+        }
+        assert_eq!(state.db_breaker.state_name(), "open");
+    }
+
+    #[cfg(feature = "mysql")]
+    #[tokio::test]
+    async fn batch_with_database_down() {
+        let items: Vec<_> = (0..250).map(|n| serde_json::json!({ "name": format!("item {n}") })).collect();
+        let request = axum::http::Request::post("/v1/items:batch")
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(serde_json::to_vec(&items).unwrap()))
+            .unwrap();
+        let (status, spans) = send_request(request).await;
+        assert_eq!(status, axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        spans
+            .assert_span_exists("create_batch")
+            .child_of("POST /v1/items:batch")
+            .with_attribute_present("batch.inserted")
+            .with_error_status();
+        spans
+            .assert_span_exists("chunk 2")
+            .child_of("create_batch")
+            .with_attribute("db.operation.batch.size", 50)
+            .has_child("INSERT items");
+        assert_snapshot("batch_with_database_down", &spans.tree());
+    }
+
+    #[cfg(feature = "mysql")]
+    #[tokio::test]
+    async fn cause_error_with_database_down() {
+        let (status, spans) = send("/v1/cause_error").await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        spans.assert_span_exists("cause_error").has_child("fetch row");
+        assert_snapshot("cause_error_with_database_down", &spans.tree());
+    }
+}
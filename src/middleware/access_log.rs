@@ -0,0 +1,223 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use opentelemetry::trace::TraceContextExt;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::{AccessLogFormat, AccessLogSettings};
+use crate::middleware::body::ObservedBody;
+use crate::middleware::client_address::ClientAddress;
+
+// One line per request, apart from the application log, in Common or Combined Log Format
+// with the latency in milliseconds and the trace id appended
+pub struct AccessLog {
+    format: AccessLogFormat,
+    // Lines are queued for a thread of their own, and dropped when it falls behind rather
+    // than holding up the worker that finished the response
+    out: tracing_appender::non_blocking::NonBlocking,
+    // Writes out what is queued once the router, and so the log, is dropped
+    _flush: tracing_appender::non_blocking::WorkerGuard,
+}
+
+impl AccessLog {
+    // None when it is turned off
+    pub fn new(settings: &AccessLogSettings) -> Result<Option<Arc<Self>>, String> {
+        if !settings.enabled {
+            return Ok(None);
+        }
+
+        let out: Box<dyn Write + Send> = match &settings.path {
+            Some(path) => Box::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("can't open access log {path:?}: {e}"))?,
+            ),
+            None => Box::new(std::io::stdout()),
+        };
+
+        let (out, flush) = tracing_appender::non_blocking::NonBlockingBuilder::default()
+            .lossy(true)
+            .thread_name("access log")
+            .finish(out);
+        Ok(Some(Arc::new(Self { format: settings.format, out, _flush: flush })))
+    }
+
+    fn write(&self, entry: &Entry) {
+        // One write per line, so lines are queued whole
+        let line = entry.line(self.format) + "\n";
+        let _ = self.out.clone().write_all(line.as_bytes());
+    }
+}
+
+struct Entry {
+    peer: Option<std::net::IpAddr>,
+    at: SystemTime,
+    request_line: String,
+    status: u16,
+    bytes: u64,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    latency_ms: u128,
+    trace_id: Option<String>,
+}
+
+impl Entry {
+    fn line(&self, format: AccessLogFormat) -> String {
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        let quoted = |value: &Option<String>| match value {
+            Some(value) => format!("\"{}\"", escape(value)),
+            None => "\"-\"".to_string(),
+        };
+
+        // The user isn't known out here, auth runs further in
+        let mut line = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            or_dash(self.peer.map(|peer| peer.to_string())),
+            clf_time(self.at),
+            escape(&self.request_line),
+            self.status,
+            // CLF writes "-" for an empty body
+            if self.bytes == 0 { "-".to_string() } else { self.bytes.to_string() },
+        );
+        if format == AccessLogFormat::Combined {
+            line += &format!(" {} {}", quoted(&self.referer), quoted(&self.user_agent));
+        }
+        line + &format!(" {} {}", self.latency_ms, or_dash(self.trace_id.clone()))
+    }
+}
+
+// As Apache does, `\"`, `\\` and `\xhh` for control characters, so a crafted path or header
+// can't end its field or the line
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => escaped += &format!("\\x{:02x}", u32::from(c)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// `10/Oct/2000:13:55:36 +0000`, always in UTC
+fn clf_time(at: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, time) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since the epoch, per Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+fn header(request: &axum::extract::Request, name: axum::http::HeaderName) -> Option<String> {
+    request.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string)
+}
+
+// Runs inside the request span for its trace id, and outside compression, so the bytes
+// are those sent. The line is queued once the body is done.
+pub async fn layer(
+    axum::extract::State(log): axum::extract::State<Arc<AccessLog>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let started = Instant::now();
+    let at = SystemTime::now();
+
+    let span_context = tracing::Span::current().context().span().span_context().clone();
+    let mut entry = Entry {
+        peer: request.extensions().get::<ClientAddress>().map(|ClientAddress(ip)| *ip),
+        at,
+        request_line: format!(
+            "{} {} {:?}",
+            request.method(),
+            request.uri().path_and_query().map_or("/", |path| path.as_str()),
+            request.version()
+        ),
+        status: 0,
+        bytes: 0,
+        referer: header(&request, axum::http::header::REFERER),
+        user_agent: header(&request, axum::http::header::USER_AGENT),
+        latency_ms: 0,
+        trace_id: span_context.is_valid().then(|| span_context.trace_id().to_string()),
+    };
+
+    let response = next.run(request).await;
+    entry.status = response.status().as_u16();
+
+    let (parts, body) = response.into_parts();
+    let body = ObservedBody::new(body).on_done(move |total, _| {
+        entry.bytes = total;
+        entry.latency_ms = started.elapsed().as_millis();
+        log.write(&entry);
+    });
+    axum::response::Response::from_parts(parts, axum::body::Body::new(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combined_line() {
+        let entry = Entry {
+            peer: Some("127.0.0.1".parse().unwrap()),
+            at: UNIX_EPOCH + std::time::Duration::from_secs(971_186_136),
+            request_line: "GET /buildinfo HTTP/1.1".to_string(),
+            status: 200,
+            bytes: 2326,
+            referer: None,
+            user_agent: Some("curl/8.5.0".to_string()),
+            latency_ms: 3,
+            trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".to_string()),
+        };
+
+        assert_eq!(
+            entry.line(AccessLogFormat::Combined),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /buildinfo HTTP/1.1\" 200 2326 \"-\" \"curl/8.5.0\" 3 4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+    }
+
+    #[test]
+    fn a_crafted_request_stays_on_its_line() {
+        let entry = Entry {
+            peer: None,
+            at: UNIX_EPOCH,
+            request_line: "GET /a\" 200 1\n127.0.0.1 - - [x] \"GET /\\ HTTP/1.1".to_string(),
+            status: 404,
+            bytes: 0,
+            referer: None,
+            user_agent: Some("a\"\tb".to_string()),
+            latency_ms: 0,
+            trace_id: None,
+        };
+
+        assert_eq!(
+            entry.line(AccessLogFormat::Combined),
+            "- - - [01/Jan/1970:00:00:00 +0000] \"GET /a\\\" 200 1\\x0a127.0.0.1 - - [x] \\\"GET /\\\\ HTTP/1.1\" 404 - \"-\" \"a\\\"\\x09b\" 0 -"
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use std::sync::Arc;
+
+use axum::response::IntoResponse;
+use opentelemetry::baggage::BaggageExt;
+use opentelemetry::trace::FutureExt;
+
+use crate::config::AuthSettings;
+
+#[derive(Debug, serde::Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(flatten)]
+    other: std::collections::HashMap<String, serde_json::Value>,
+}
+
+// Authenticated caller, available to handlers as `Extension<AuthUser>`
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: String,
+    pub roles: Vec<String>,
+    // The token's other claims, for middleware further in (the tenant)
+    pub claims: std::collections::HashMap<String, serde_json::Value>,
+}
+
+impl crate::middleware::extension_fields::SpanFields for AuthUser {
+    fn record(&self, span: &tracing::Span) {
+        span.record("enduser.id", self.id.as_str());
+        span.record("enduser.role", self.roles.join(",").as_str());
+    }
+}
+
+pub struct JwtVerifier {
+    enabled: bool,
+    // None while the secret is empty or the placeholder, every token then refused
+    key: Option<jsonwebtoken::DecodingKey>,
+    validation: jsonwebtoken::Validation,
+}
+
+impl JwtVerifier {
+    pub fn new(settings: &AuthSettings) -> Result<Self, String> {
+        let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+        if let Some(issuer) = &settings.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        let placeholder = settings.jwt_secret.is_empty() || settings.jwt_secret == crate::config::PLACEHOLDER_JWT_SECRET;
+        if settings.enabled && placeholder {
+            return Err("jwt_secret is empty or the placeholder shipped, set a secret of your own".to_string());
+        }
+        Ok(Self {
+            enabled: settings.enabled,
+            key: (!placeholder).then(|| jsonwebtoken::DecodingKey::from_secret(settings.jwt_secret.as_bytes())),
+            validation,
+        })
+    }
+
+    // Tokens checked whatever `auth.enabled` says, for the admin listener
+    pub fn required(mut self) -> Self {
+        self.enabled = true;
+        self
+    }
+
+    fn verify(&self, headers: &axum::http::HeaderMap) -> Result<AuthUser, &'static str> {
+        let token = headers
+            .get(axum::http::header::AUTHORIZATION)
+            .ok_or("missing authorization header")?
+            .to_str()
+            .map_err(|_| "malformed authorization header")?
+            .strip_prefix("Bearer ")
+            .ok_or("not a bearer token")?;
+        let key = self.key.as_ref().ok_or("no jwt_secret set")?;
+
+        let data = jsonwebtoken::decode::<Claims>(token, key, &self.validation).map_err(|e| match e.kind() {
+            jsonwebtoken::errors::ErrorKind::ExpiredSignature => "token expired",
+            jsonwebtoken::errors::ErrorKind::InvalidIssuer => "invalid issuer",
+            _ => "invalid token",
+        })?;
+
+        Ok(AuthUser {
+            id: data.claims.sub,
+            roles: data.claims.roles,
+            claims: data.claims.other,
+        })
+    }
+}
+
+// The token checked once per request, ahead of the request span whose tenant is read from
+// it, and left in the extensions for `require_auth`
+#[derive(Clone)]
+pub struct Verified(Result<AuthUser, &'static str>);
+
+impl Verified {
+    // The caller when the token holds, for reading claims ahead of `require_auth`
+    pub fn user(&self) -> Option<&AuthUser> {
+        self.0.as_ref().ok()
+    }
+}
+
+pub async fn verify_layer(
+    axum::extract::State(verifier): axum::extract::State<Arc<JwtVerifier>>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if verifier.enabled {
+        let verified = Verified(verifier.verify(request.headers()));
+        request.extensions_mut().insert(verified);
+    }
+    next.run(request).await
+}
+
+// The user reaches the request span through `extension_fields`
+pub async fn require_auth(
+    axum::extract::State(verifier): axum::extract::State<Arc<JwtVerifier>>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if !verifier.enabled {
+        return next.run(request).await;
+    }
+
+    // Checked already by `verify_layer`, unless on a router without it
+    let verified = match request.extensions_mut().remove::<Verified>() {
+        Some(Verified(verified)) => verified,
+        None => verifier.verify(request.headers()),
+    };
+    let user = match verified {
+        Ok(user) => user,
+        Err(reason) => {
+            crate::audit::audit!(auth.failure_reason = reason, "Rejected unauthenticated request");
+            return (
+                axum::http::StatusCode::UNAUTHORIZED,
+                [(axum::http::header::WWW_AUTHENTICATE, "Bearer")],
+                "unauthorized",
+            )
+                .into_response();
+        }
+    };
+
+    // Make the user id available to anything propagating the OTel context downstream
+    let cx = opentelemetry::Context::current_with_baggage([opentelemetry::KeyValue::new("enduser.id", user.id.clone())]);
+
+    request.extensions_mut().insert(user);
+    next.run(request).with_context(cx).await
+}
+
+// The caller, for handlers no one but an admin may use: 401 without an authenticated one,
+// auth off included, and 403 for a user without the `admin` role
+pub struct RequireAdmin(pub AuthUser);
+
+#[axum::async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for RequireAdmin {
+    type Rejection = axum::response::Response;
+
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _: &S) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<AuthUser>() {
+            Some(user) if user.roles.iter().any(|role| role == "admin") => Ok(Self(user.clone())),
+            Some(_) => Err((axum::http::StatusCode::FORBIDDEN, "admin role required").into_response()),
+            None => Err((axum::http::StatusCode::UNAUTHORIZED, [(axum::http::header::WWW_AUTHENTICATE, "Bearer")], "unauthorized").into_response()),
+        }
+    }
+}
+
+// Inside `require_auth`, for routers no one but an admin may use
+pub async fn require_admin(_: RequireAdmin, request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_the_placeholder_secret() {
+        for secret in ["", crate::config::PLACEHOLDER_JWT_SECRET] {
+            let settings = AuthSettings { enabled: true, jwt_secret: secret.to_string(), issuer: None };
+            assert!(JwtVerifier::new(&settings).is_err(), "{secret:?} was taken");
+
+            // Off, it starts, but the admin listener's verifier takes no token
+            let verifier = JwtVerifier::new(&AuthSettings { enabled: false, ..settings }).unwrap().required();
+            let token = jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &serde_json::json!({ "sub": "user-1", "roles": ["admin"], "exp": u64::MAX / 2 }),
+                &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+            )
+            .unwrap();
+            let headers = axum::http::HeaderMap::from_iter([(axum::http::header::AUTHORIZATION, format!("Bearer {token}").parse().unwrap())]);
+            assert_eq!(verifier.verify(&headers).unwrap_err(), "no jwt_secret set");
+        }
+    }
+
+    #[tokio::test]
+    async fn only_admins_pass() {
+        let require = |user: Option<AuthUser>| async move {
+            let mut request = axum::http::Request::new(());
+            if let Some(user) = user {
+                request.extensions_mut().insert(user);
+            }
+            let (mut parts, ()) = request.into_parts();
+            <RequireAdmin as axum::extract::FromRequestParts<()>>::from_request_parts(&mut parts, &()).await.map(|admin| admin.0.id).map_err(|response| response.status())
+        };
+        let user = |roles: &[&str]| AuthUser { id: "user-1".to_string(), roles: roles.iter().map(|role| role.to_string()).collect(), claims: Default::default() };
+
+        assert_eq!(require(Some(user(&["reader", "admin"]))).await, Ok("user-1".to_string()));
+        assert_eq!(require(Some(user(&["reader"]))).await, Err(axum::http::StatusCode::FORBIDDEN));
+        assert_eq!(require(None).await, Err(axum::http::StatusCode::UNAUTHORIZED));
+    }
+}
@@ -0,0 +1,76 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+type OnData = Box<dyn FnMut(u64) + Send>;
+type OnDone = Box<dyn FnOnce(u64, bool) + Send>;
+
+// Response body wrapper reporting the data passing through it.
+// `on_done` gets the byte total and whether the body was read to the end; it runs on drop
+// because hyper stops polling as soon as `is_end_stream` is true.
+pub struct ObservedBody {
+    inner: axum::body::Body,
+    total: u64,
+    reached_end: bool,
+    on_data: Option<OnData>,
+    on_done: Option<OnDone>,
+}
+
+impl ObservedBody {
+    pub fn new(inner: axum::body::Body) -> Self {
+        Self {
+            inner,
+            total: 0,
+            reached_end: false,
+            on_data: None,
+            on_done: None,
+        }
+    }
+
+    pub fn on_data(mut self, on_data: impl FnMut(u64) + Send + 'static) -> Self {
+        self.on_data = Some(Box::new(on_data));
+        self
+    }
+
+    pub fn on_done(mut self, on_done: impl FnOnce(u64, bool) + Send + 'static) -> Self {
+        self.on_done = Some(Box::new(on_done));
+        self
+    }
+}
+
+impl http_body::Body for ObservedBody {
+    type Data = bytes::Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(len) = frame.data_ref().map(|data| data.len() as u64) {
+                    self.total += len;
+                    if let Some(on_data) = &mut self.on_data {
+                        on_data(len);
+                    }
+                }
+            }
+            Poll::Ready(None) => self.reached_end = true,
+            _ => (),
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for ObservedBody {
+    fn drop(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done(self.total, self.reached_end || http_body::Body::is_end_stream(&self.inner));
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use axum::response::IntoResponse;
+
+use crate::config::BodyLimitSettings;
+
+pub struct BodyLimit {
+    settings: BodyLimitSettings,
+    rejections: opentelemetry::metrics::Counter<u64>,
+}
+
+impl BodyLimit {
+    pub fn new(settings: BodyLimitSettings) -> Self {
+        let rejections = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .u64_counter("http.server.body_limit_rejections")
+            .with_description("Requests rejected with 413 because the body was too large, by route")
+            .init();
+
+        Self { settings, rejections }
+    }
+
+    fn for_route(&self, route: &str) -> usize {
+        self.settings.routes.get(route).copied().unwrap_or(self.settings.max_bytes)
+    }
+
+    fn reject(&self, route: String) -> axum::response::Response {
+        self.rejections.add(1, &crate::attributes::route_labels(&route));
+        (axum::http::StatusCode::PAYLOAD_TOO_LARGE, "request body too large").into_response()
+    }
+}
+
+// Bodies that declare their length are rejected up front; chunked bodies are cut off
+// once the limit is reached, which makes the body extractor answer 413 itself.
+pub async fn layer(
+    axum::extract::State(limit): axum::extract::State<Arc<BodyLimit>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let route = crate::middleware::matched_route(&request);
+    let max_bytes = limit.for_route(&route);
+
+    let declared = request
+        .headers()
+        .get(axum::http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if let Some(declared) = declared.filter(|declared| *declared > max_bytes as u64) {
+        tracing::info!(http.request.body.size = declared, body_limit.max_bytes = max_bytes, "Rejected oversize request body");
+        return limit.reject(route);
+    }
+
+    let (parts, body) = request.into_parts();
+    let request = axum::extract::Request::from_parts(
+        parts,
+        axum::body::Body::new(http_body_util::Limited::new(body, max_bytes)),
+    );
+
+    let response = next.run(request).await;
+
+    if declared.is_none() && response.status() == axum::http::StatusCode::PAYLOAD_TOO_LARGE {
+        // The real size is unknown, only that it crossed the limit
+        tracing::info!(body_limit.max_bytes = max_bytes, "Rejected oversize streamed request body");
+        limit.rejections.add(1, &crate::attributes::route_labels(&route));
+    }
+
+    response
+}
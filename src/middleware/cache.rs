@@ -0,0 +1,558 @@
+// Responses of the routes with `cache_ttl_secs` kept for that long, in memory or in Redis, so
+// hot reads skip the handler and its queries. Only GETs count, keyed by the path and query,
+// by who asks, the user and the tenant, by the canary variant and by `Accept`; a response is
+// stored when it is a 200 of known length within `max_body_bytes`, sets no cookie, varies by
+// nothing else and allows it. A route's
+// TTL is how stale it may be, unless a handler changing what it answers invalidates its path:
+// the entries of that path go, whatever the query and whoever asked, here and, with
+// `invalidation_channel`, in every instance, told over Redis pub/sub.
+//
+// Each request of such a route has `cache.status` on its span, `hit`, `miss`, or `bypass` when
+// the client asked for a fresh response or the store was unavailable, and is counted in
+// `http.server.cache.requests` by it; `http.server.cache.hit_ratio` is the share of lookups
+// found, per route, since startup.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::response::IntoResponse;
+use opentelemetry::KeyValue;
+use sha2::Digest;
+
+use crate::config::{CacheSettings, CacheStore, RouteSettings};
+
+#[axum::async_trait]
+trait Store: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<bytes::Bytes>, String>;
+    async fn set(&self, key: &str, value: bytes::Bytes, ttl: Duration) -> Result<(), String>;
+    // Every entry whose key starts with `prefix`
+    async fn remove(&self, prefix: &str) -> Result<(), String>;
+}
+
+// Of a request, those the key is by besides who asks; a response varying by any other isn't
+// stored
+const KEYED: [axum::http::HeaderName; 1] = [axum::http::header::ACCEPT];
+
+struct Entry {
+    stored: Instant,
+    expires: Instant,
+    value: bytes::Bytes,
+}
+
+struct Memory {
+    max_entries: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[axum::async_trait]
+impl Store for Memory {
+    async fn get(&self, key: &str) -> Result<Option<bytes::Bytes>, String> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries.get(key).filter(|entry| entry.expires > Instant::now()).map(|entry| entry.value.clone()))
+    }
+
+    async fn set(&self, key: &str, value: bytes::Bytes, ttl: Duration) -> Result<(), String> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        if entries.len() >= self.max_entries && !entries.contains_key(key) {
+            entries.retain(|_, entry| entry.expires > now);
+
+            // Still full, the oldest goes
+            if entries.len() >= self.max_entries {
+                if let Some(oldest) = entries.iter().min_by_key(|(_, entry)| entry.stored).map(|(key, _)| key.clone()) {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key.to_string(), Entry { stored: now, expires: now + ttl, value });
+        Ok(())
+    }
+
+    async fn remove(&self, prefix: &str) -> Result<(), String> {
+        self.entries.lock().unwrap().retain(|key, _| !key.starts_with(prefix));
+        Ok(())
+    }
+}
+
+#[axum::async_trait]
+impl Store for crate::redis::Client {
+    async fn get(&self, key: &str) -> Result<Option<bytes::Bytes>, String> {
+        match self.command(&[b"GET", key.as_bytes()]).await? {
+            crate::redis::Reply::Bulk(value) => Ok(value.map(bytes::Bytes::from)),
+            _ => Err("unexpected reply to GET".to_string()),
+        }
+    }
+
+    async fn set(&self, key: &str, value: bytes::Bytes, ttl: Duration) -> Result<(), String> {
+        let ttl = ttl.as_millis().to_string();
+        self.command(&[b"SET", key.as_bytes(), &value, b"PX", ttl.as_bytes()]).await.map(|_| ())
+    }
+
+    // A scan of the keys, the prefix's own glob characters escaped
+    async fn remove(&self, prefix: &str) -> Result<(), String> {
+        let mut pattern = String::new();
+        for c in prefix.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push('*');
+        let mut cursor = b"0".to_vec();
+        loop {
+            let reply = self.command(&[b"SCAN", &cursor, b"MATCH", pattern.as_bytes(), b"COUNT", b"1000"]).await?;
+            let crate::redis::Reply::Array(mut reply) = reply else {
+                return Err("unexpected reply to SCAN".to_string());
+            };
+            let (Some(crate::redis::Reply::Array(keys)), Some(crate::redis::Reply::Bulk(Some(next)))) = (reply.pop(), reply.pop()) else {
+                return Err("unexpected reply to SCAN".to_string());
+            };
+            let mut del: Vec<&[u8]> = vec![b"DEL"];
+            del.extend(keys.iter().filter_map(|key| match key {
+                crate::redis::Reply::Bulk(Some(key)) => Some(key.as_slice()),
+                _ => None,
+            }));
+            if del.len() > 1 {
+                self.command(&del).await?;
+            }
+            if next == b"0" {
+                return Ok(());
+            }
+            cursor = next;
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Status {
+    Hit,
+    Miss,
+    Bypass,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Miss => "miss",
+            Self::Bypass => "bypass",
+        }
+    }
+}
+
+struct Route {
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+pub struct Cache {
+    store: Box<dyn Store>,
+    key_prefix: String,
+    max_body_bytes: usize,
+    // By route pattern
+    routes: HashMap<String, Route>,
+    requests: opentelemetry::metrics::Counter<u64>,
+    // Where invalidations are published and subscribed to, with `invalidation_channel`
+    invalidations: Option<(Arc<crate::redis::Client>, String)>,
+}
+
+impl Cache {
+    // None when no route is cached
+    pub fn new(settings: &CacheSettings, routes: &HashMap<String, RouteSettings>) -> Result<Option<Arc<Self>>, String> {
+        let mut cached = HashMap::new();
+        for (route, ttl) in routes.iter().filter_map(|(route, settings)| Some((route, settings.cache_ttl_secs?))) {
+            if ttl == 0 {
+                return Err(format!("cache_ttl_secs of route {route:?} is 0"));
+            }
+            cached.insert(route.clone(), Route { ttl: Duration::from_secs(ttl), hits: AtomicU64::new(0), misses: AtomicU64::new(0) });
+        }
+        if cached.is_empty() {
+            return Ok(None);
+        }
+        let store: Box<dyn Store> = match settings.store {
+            CacheStore::Memory => Box::new(Memory { max_entries: settings.max_entries.max(1), entries: Mutex::new(HashMap::new()) }),
+            CacheStore::Redis => Box::new(crate::redis::Client::new(&settings.redis_url)?),
+        };
+        let invalidations = match &settings.invalidation_channel {
+            Some(channel) => Some((Arc::new(crate::redis::Client::new(&settings.redis_url)?), channel.clone())),
+            None => None,
+        };
+
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let cache = Arc::new(Self {
+            store,
+            key_prefix: settings.key_prefix.clone(),
+            max_body_bytes: settings.max_body_bytes,
+            routes: cached,
+            requests: meter
+                .u64_counter("http.server.cache.requests")
+                .with_description("Requests of the cached routes, by route and cache.status")
+                .init(),
+            invalidations,
+        });
+
+        let observed = Arc::downgrade(&cache);
+        meter
+            .f64_observable_gauge("http.server.cache.hit_ratio")
+            .with_description("Of the route's cache lookups since startup, the share found")
+            .with_callback(move |observer| {
+                let Some(cache) = observed.upgrade() else {
+                    return;
+                };
+                for (pattern, route) in &cache.routes {
+                    let (hits, misses) = (route.hits.load(Ordering::Relaxed), route.misses.load(Ordering::Relaxed));
+                    if hits + misses > 0 {
+                        observer.observe(hits as f64 / (hits + misses) as f64, &[crate::attributes::route(pattern)]);
+                    }
+                }
+            })
+            .init();
+        Ok(Some(cache))
+    }
+
+    fn record(&self, pattern: &str, route: &Route, status: Status) {
+        match status {
+            Status::Hit => route.hits.fetch_add(1, Ordering::Relaxed),
+            Status::Miss => route.misses.fetch_add(1, Ordering::Relaxed),
+            Status::Bypass => 0,
+        };
+        tracing::Span::current().record("cache.status", status.as_str());
+        self.requests.add(1, &[crate::attributes::route(pattern), KeyValue::new("cache.status", status.as_str())]);
+    }
+
+    // Hashed, so neither the user nor the tenant end up in the store; the path's hash first,
+    // which the path's entries are invalidated by
+    fn key(&self, request: &axum::extract::Request) -> String {
+        let user = request.extensions().get::<crate::middleware::auth::AuthUser>().map(|user| user.id.as_str());
+        let tenant = request.extensions().get::<crate::middleware::tenant::Tenant>().map(|tenant| tenant.0.as_str());
+        let variant = request.extensions().get::<crate::middleware::canary::Variant>().map(|variant| variant.as_str());
+        let target = request.uri().path_and_query().map_or(request.uri().path(), |target| target.as_str());
+        let mut parts = vec![user.unwrap_or_default(), tenant.unwrap_or_default(), variant.unwrap_or_default(), target];
+        for name in &KEYED {
+            parts.extend(request.headers().get_all(name).iter().map(|value| value.to_str().unwrap_or_default()));
+            parts.push("");
+        }
+        let hash = hex(&parts);
+        format!("{}{hash}", self.path_prefix(request.uri().path()))
+    }
+
+    fn path_prefix(&self, path: &str) -> String {
+        format!("{}{}:", self.key_prefix, &hex(&[path])[..16])
+    }
+
+    // The entries of `path` go here right away, and from every instance subscribed to the
+    // invalidation channel once they're told; the items handlers call it, with the mysql feature
+    #[cfg_attr(not(feature = "mysql"), allow(dead_code))]
+    pub async fn invalidate(&self, path: &str) {
+        self.evict(path).await;
+        if let Some((client, channel)) = &self.invalidations {
+            if let Err(e) = crate::pubsub::publish(client, channel, path).await {
+                tracing::warn!(error = e, "Failed to publish a cache invalidation, other instances keep the entries until they expire");
+            }
+        }
+    }
+
+    async fn evict(&self, path: &str) {
+        if let Err(e) = self.store.remove(&self.path_prefix(path)).await {
+            tracing::warn!(error = e, http.route = path, "Failed to invalidate cached responses");
+        }
+    }
+
+    // Evicts what the instances invalidate, this one included, with `invalidation_channel`;
+    // for the life of the process
+    pub fn subscribe(self: &Arc<Self>) {
+        let Some((client, channel)) = &self.invalidations else {
+            return;
+        };
+        let cache = Arc::downgrade(self);
+        crate::pubsub::subscribe(client.clone(), channel.clone(), move |path| {
+            let cache = cache.upgrade();
+            async move {
+                if let Some(cache) = cache {
+                    cache.evict(&path).await;
+                }
+            }
+        });
+    }
+
+    fn storable(&self, response: &axum::response::Response) -> bool {
+        let headers = response.headers();
+        response.status() == axum::http::StatusCode::OK
+            && !headers.contains_key(axum::http::header::SET_COOKIE)
+            && !cache_control(headers).any(|directive| directive == "no-store" || directive == "private")
+            && headers
+                .get_all(axum::http::header::VARY)
+                .iter()
+                .flat_map(|value| value.to_str().unwrap_or("*").split(','))
+                .all(|name| KEYED.iter().any(|keyed| name.trim().eq_ignore_ascii_case(keyed.as_str())))
+            && http_body::Body::size_hint(response.body()).exact().is_some_and(|len| len <= self.max_body_bytes as u64)
+    }
+}
+
+fn hex(parts: &[&str]) -> String {
+    let mut hash = sha2::Sha256::new();
+    for part in parts {
+        hash.update(part.as_bytes());
+        hash.update([0]);
+    }
+    hash.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn cache_control(headers: &axum::http::HeaderMap) -> impl Iterator<Item = String> + '_ {
+    headers
+        .get_all(axum::http::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+}
+
+// The time stored, then the headers and the body, each length first
+fn encode(headers: &axum::http::HeaderMap, body: &[u8]) -> bytes::Bytes {
+    let stored = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut entry = Vec::with_capacity(body.len() + 256);
+    entry.extend_from_slice(&stored.to_be_bytes());
+    entry.extend_from_slice(&(headers.len() as u32).to_be_bytes());
+    for (name, value) in headers {
+        for part in [name.as_str().as_bytes(), value.as_bytes()] {
+            entry.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            entry.extend_from_slice(part);
+        }
+    }
+    entry.extend_from_slice(body);
+    entry.into()
+}
+
+// None for an entry which doesn't decode, another version's say, which is then replaced
+fn decode(entry: &bytes::Bytes) -> Option<axum::response::Response> {
+    fn take<'a>(entry: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = entry.split_at_checked(len)?;
+        *entry = rest;
+        Some(taken)
+    }
+    fn length(entry: &mut &[u8]) -> Option<usize> {
+        Some(u32::from_be_bytes(take(entry, 4)?.try_into().ok()?) as usize)
+    }
+
+    let mut rest = entry.as_ref();
+    let stored = u64::from_be_bytes(take(&mut rest, 8)?.try_into().ok()?);
+    let mut headers = axum::http::HeaderMap::new();
+    for _ in 0..length(&mut rest)? {
+        let len = length(&mut rest)?;
+        let name = axum::http::HeaderName::from_bytes(take(&mut rest, len)?).ok()?;
+        let len = length(&mut rest)?;
+        let value = axum::http::HeaderValue::from_bytes(take(&mut rest, len)?).ok()?;
+        headers.append(name, value);
+    }
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    headers.insert(axum::http::header::AGE, now.saturating_sub(stored).into());
+
+    let body = entry.slice(entry.len() - rest.len()..);
+    let mut response = axum::response::Response::new(axum::body::Body::from(body));
+    *response.headers_mut() = headers;
+    Some(response)
+}
+
+// Inside auth and the tenant, which the key is by; a hit skips the session and the handler.
+// Handlers find the cache in the request's extensions, to invalidate what they change.
+pub async fn layer(
+    axum::extract::State(cache): axum::extract::State<Arc<Cache>>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    request.extensions_mut().insert(cache.clone());
+    let pattern = crate::middleware::matched_route(&request);
+    let Some(route) = cache.routes.get(&pattern).filter(|_| request.method() == axum::http::Method::GET) else {
+        return next.run(request).await;
+    };
+    if cache_control(request.headers()).any(|directive| directive == "no-cache" || directive == "no-store") {
+        cache.record(&pattern, route, Status::Bypass);
+        return next.run(request).await;
+    }
+
+    let key = cache.key(&request);
+    match cache.store.get(&key).await {
+        Ok(entry) => {
+            if let Some(response) = entry.as_ref().and_then(decode) {
+                cache.record(&pattern, route, Status::Hit);
+                return response;
+            }
+        }
+        Err(e) => {
+            tracing::warn!(error = e, "Response cache unavailable");
+            cache.record(&pattern, route, Status::Bypass);
+            return next.run(request).await;
+        }
+    }
+    cache.record(&pattern, route, Status::Miss);
+
+    let response = next.run(request).await;
+    if !cache.storable(&response) {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match http_body_util::BodyExt::collect(body).await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to read a response to cache");
+            return axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    if let Err(e) = cache.store.set(&key, encode(&parts.headers, &body), route.ttl).await {
+        tracing::warn!(error = e, "Failed to cache a response");
+    }
+    axum::response::Response::from_parts(parts, axum::body::Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tracing::Instrument;
+
+    #[tokio::test]
+    async fn serves_hits_from_the_store() {
+        let telemetry = crate::test_support::init();
+
+        let routes = HashMap::from([("/items/:id".to_string(), RouteSettings { cache_ttl_secs: Some(60), ..Default::default() })]);
+        let cache = Cache::new(&CacheSettings::default(), &routes).unwrap().unwrap();
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counted = handled.clone();
+        let app = axum::Router::new()
+            .route(
+                "/items/:id",
+                axum::routing::get(move || async move {
+                    counted.fetch_add(1, Ordering::Relaxed);
+                    ([(axum::http::header::CONTENT_TYPE, "application/json")], r#"{"id":1}"#)
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(cache, layer));
+
+        let get = |name: &'static str, fresh: bool| {
+            let mut request = axum::http::Request::get("/items/1");
+            if fresh {
+                request = request.header(axum::http::header::CACHE_CONTROL, "no-cache");
+            }
+            let request = request.body(axum::body::Body::empty()).unwrap();
+            tower::ServiceExt::oneshot(app.clone(), request).instrument(tracing::info_span!("request", otel.name = name, cache.status = tracing::field::Empty))
+        };
+        get("first", false).await.unwrap();
+        let hit = get("second", false).await.unwrap();
+        get("third", true).await.unwrap();
+        assert_eq!(handled.load(Ordering::Relaxed), 2);
+
+        assert_eq!(hit.headers()[axum::http::header::CONTENT_TYPE], "application/json");
+        assert!(hit.headers().contains_key(axum::http::header::AGE));
+        let body = http_body_util::BodyExt::collect(hit.into_body()).await.unwrap().to_bytes();
+        assert_eq!(body, r#"{"id":1}"#);
+
+        let spans = telemetry.spans();
+        spans.assert_span_exists("first").with_attribute("cache.status", "miss");
+        spans.assert_span_exists("second").with_attribute("cache.status", "hit");
+        spans.assert_span_exists("third").with_attribute("cache.status", "bypass");
+    }
+
+    #[tokio::test]
+    async fn invalidated_paths_are_evicted_in_every_instance() {
+        let routes = HashMap::from([("/items/:id".to_string(), RouteSettings { cache_ttl_secs: Some(60), ..Default::default() })]);
+        let settings = CacheSettings {
+            redis_url: crate::test_support::fake_redis().await,
+            invalidation_channel: Some("invalidations".to_string()),
+            ..Default::default()
+        };
+        // Two instances, each with a store of its own
+        let (here, there) = (Cache::new(&settings, &routes).unwrap().unwrap(), Cache::new(&settings, &routes).unwrap().unwrap());
+        there.subscribe();
+        let client = crate::redis::Client::new(&settings.redis_url).unwrap();
+        while crate::pubsub::publish(&client, "invalidations", "/").await.unwrap() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let handled = Arc::new(AtomicUsize::new(0));
+        let app = |cache: Arc<Cache>| {
+            let counted = handled.clone();
+            axum::Router::new()
+                .route(
+                    "/items/:id",
+                    axum::routing::get(move || async move {
+                        counted.fetch_add(1, Ordering::Relaxed);
+                        "item"
+                    }),
+                )
+                .layer(axum::middleware::from_fn_with_state(cache, layer))
+        };
+        let get = |app: axum::Router, uri: &'static str| async move {
+            let request = axum::http::Request::get(uri).body(axum::body::Body::empty()).unwrap();
+            tower::ServiceExt::oneshot(app, request).await.unwrap()
+        };
+        for uri in ["/items/1", "/items/1?fields=name", "/items/2"] {
+            get(app(here.clone()), uri).await;
+            get(app(there.clone()), uri).await;
+        }
+        assert_eq!(handled.load(Ordering::Relaxed), 6);
+
+        here.invalidate("/items/1").await;
+        get(app(here.clone()), "/items/1").await;
+        get(app(here.clone()), "/items/2").await;
+        assert_eq!(handled.load(Ordering::Relaxed), 7);
+        // Once the other instance is told, whatever the query
+        while there.store.get(&there.key(&axum::http::Request::get("/items/1").body(axum::body::Body::empty()).unwrap())).await.unwrap().is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        get(app(there.clone()), "/items/1?fields=name").await;
+        get(app(there.clone()), "/items/2").await;
+        assert_eq!(handled.load(Ordering::Relaxed), 8);
+    }
+
+    #[tokio::test]
+    async fn keeps_what_varies_by_accept_apart() {
+        let routes = HashMap::from([("/items/:id".to_string(), RouteSettings { cache_ttl_secs: Some(60), ..Default::default() })]);
+        let cache = Cache::new(&CacheSettings::default(), &routes).unwrap().unwrap();
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counted = handled.clone();
+        let app = axum::Router::new()
+            .route(
+                "/items/:id",
+                axum::routing::get(move |headers: axum::http::HeaderMap| async move {
+                    counted.fetch_add(1, Ordering::Relaxed);
+                    let accept = headers.get(axum::http::header::ACCEPT).cloned();
+                    let vary = if accept.as_ref().is_some_and(|accept| accept == "text/csv") { "Accept, Accept-Language" } else { "Accept" };
+                    ([(axum::http::header::VARY, vary)], format!("{accept:?}"))
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(cache, layer));
+
+        let get = |accept: &'static str| {
+            let request = axum::http::Request::get("/items/1").header(axum::http::header::ACCEPT, accept).body(axum::body::Body::empty()).unwrap();
+            let app = app.clone();
+            async move {
+                let response = tower::ServiceExt::oneshot(app, request).await.unwrap();
+                http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes()
+            }
+        };
+        let json = get("application/json").await;
+        let xml = get("application/xml").await;
+        assert_ne!(json, xml);
+        assert_eq!(get("application/json").await, json);
+        assert_eq!(get("application/xml").await, xml);
+        assert_eq!(handled.load(Ordering::Relaxed), 2);
+
+        // Varying by a header the key isn't by, never stored
+        get("text/csv").await;
+        get("text/csv").await;
+        assert_eq!(handled.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn a_full_memory_store_evicts_the_oldest() {
+        let store = Memory { max_entries: 2, entries: Mutex::new(HashMap::new()) };
+        for key in ["first", "second", "third"] {
+            store.set(key, bytes::Bytes::from(key), Duration::from_secs(60)).await.unwrap();
+        }
+        assert_eq!(store.get("first").await.unwrap(), None);
+        assert_eq!(store.get("second").await.unwrap().as_deref(), Some(&b"second"[..]));
+        assert_eq!(store.get("third").await.unwrap().as_deref(), Some(&b"third"[..]));
+    }
+}
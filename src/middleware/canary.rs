@@ -0,0 +1,211 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::response::IntoResponse;
+use opentelemetry::baggage::BaggageExt;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::{Key, KeyValue};
+use rand::Rng;
+
+use crate::config::{CanarySettings, DependencySettings};
+use crate::http_client::HttpClient;
+
+pub const CANARY_VARIANT: Key = Key::from_static_str("canary.variant");
+
+// Which version answers the request, in the request's extensions for the layers inside
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Stable,
+    Canary,
+}
+
+impl Variant {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Stable => "stable",
+            Self::Canary => "canary",
+        }
+    }
+}
+
+// Says which requests go to the canary, and forwards them to it
+pub struct Canary {
+    header: Option<axum::http::HeaderName>,
+    ratio: f64,
+    url: String,
+    client: HttpClient,
+    routes: Vec<String>,
+    duration: opentelemetry::metrics::Histogram<f64>,
+}
+
+impl Canary {
+    // None without a canary
+    pub fn new(settings: &CanarySettings, client: &DependencySettings) -> Result<Option<Self>, String> {
+        if settings.header.is_empty() && settings.percent == 0.0 {
+            return Ok(None);
+        }
+        let header = match settings.header.as_str() {
+            "" => None,
+            header => Some(axum::http::HeaderName::try_from(header).map_err(|_| format!("{header:?} is not a header name"))?),
+        };
+        if !(0.0..=100.0).contains(&settings.percent) {
+            return Err(format!("percent {} is not between 0 and 100", settings.percent));
+        }
+        // there's no canary implementation in this one, the canary runs elsewhere
+        let Some(url) = &settings.url else {
+            return Err("a canary needs the url to forward its requests to".to_string());
+        };
+        reqwest::Url::parse(url).map_err(|e| format!("invalid url {url:?}: {e}"))?;
+        if let Some(prefix) = settings.routes.iter().find(|prefix| !prefix.starts_with('/')) {
+            return Err(format!("canary path prefix {prefix:?} does not start with /"));
+        }
+
+        let duration = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .f64_histogram("canary.request.duration")
+            .with_unit("s")
+            .with_description("Requests of the routes with a canary by variant and route, the server errors with error.type")
+            .init();
+        Ok(Some(Self {
+            header,
+            ratio: settings.percent / 100.0,
+            url: url.clone(),
+            client: HttpClient::new(client)?,
+            routes: settings.routes.clone(),
+            duration,
+        }))
+    }
+
+    // The header's say, else a draw by `percent`
+    fn pick(&self, headers: &axum::http::HeaderMap) -> Variant {
+        let asked = self.header.as_ref().and_then(|header| headers.get(header)).and_then(|value| value.to_str().ok());
+        match asked.map(|value| value.trim().to_ascii_lowercase()).as_deref() {
+            Some("canary" | "true" | "1") => Variant::Canary,
+            Some("stable" | "false" | "0") => Variant::Stable,
+            _ if rand::thread_rng().gen_bool(self.ratio) => Variant::Canary,
+            _ => Variant::Stable,
+        }
+    }
+
+    // The canary's answer, or a 502 when there is none
+    async fn forward(&self, url: &str, request: axum::extract::Request) -> axum::response::Response {
+        let (parts, body) = request.into_parts();
+        let body = match http_body_util::BodyExt::collect(body).await {
+            Ok(body) => body.to_bytes(),
+            Err(_) => return (axum::http::StatusCode::BAD_REQUEST, "failed to read the request body").into_response(),
+        };
+        let answered = match crate::http_client::forward(url, &parts, body) {
+            Ok(request) => self.client.execute(request).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        let answered = match answered {
+            Ok(answered) => answered,
+            Err(e) => {
+                tracing::warn!(error = e, "Canary didn't answer");
+                return (axum::http::StatusCode::BAD_GATEWAY, "canary unavailable").into_response();
+            }
+        };
+
+        let status = answered.status();
+        let mut headers = answered.headers().clone();
+        headers.remove(axum::http::header::CONNECTION);
+        headers.remove(axum::http::header::TRANSFER_ENCODING);
+        headers.remove(axum::http::header::CONTENT_LENGTH);
+        match answered.bytes().await {
+            Ok(body) => {
+                let mut response = axum::response::Response::new(axum::body::Body::from(body));
+                *response.status_mut() = status;
+                *response.headers_mut() = headers;
+                response
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to read the canary's answer");
+                (axum::http::StatusCode::BAD_GATEWAY, "canary unavailable").into_response()
+            }
+        }
+    }
+}
+
+// The variant goes on the request span and, through the baggage, on the spans below and to
+// downstream calls; canary requests are forwarded to the canary
+pub async fn layer(
+    axum::extract::State(canary): axum::extract::State<Arc<Canary>>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let path = request.uri().path();
+    if !canary.routes.is_empty() && !canary.routes.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+        return next.run(request).await;
+    }
+
+    let variant = canary.pick(request.headers());
+    tracing::Span::current().record("canary.variant", variant.as_str());
+    let cx = opentelemetry::Context::current_with_baggage([KeyValue::new(CANARY_VARIANT, variant.as_str())]);
+    request.extensions_mut().insert(variant);
+
+    let route = crate::middleware::matched_route(&request);
+    let started = Instant::now();
+    let response = match variant {
+        Variant::Canary => canary.forward(&canary.url, request).with_context(cx).await,
+        Variant::Stable => next.run(request).with_context(cx).await,
+    };
+
+    let mut attributes = vec![KeyValue::new(CANARY_VARIANT, variant.as_str()), crate::attributes::route(&route)];
+    if response.status().is_server_error() {
+        attributes.push(KeyValue::new("error.type", response.status().as_u16().to_string()));
+    }
+    canary.duration.record(started.elapsed().as_secs_f64(), &attributes);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+
+    #[tokio::test]
+    async fn forwards_canary_requests() {
+        let telemetry = crate::test_support::init();
+
+        let backend = axum::Router::new().route("/items", axum::routing::get(|| async { "canary" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let settings = CanarySettings {
+            header: "x-canary".to_string(),
+            url: Some(format!("http://{}", listener.local_addr().unwrap())),
+            ..Default::default()
+        };
+        tokio::spawn(async move { axum::serve(listener, backend).await.unwrap() });
+
+        let canary = Canary::new(&settings, &DependencySettings::default()).unwrap().unwrap();
+        let app = axum::Router::new()
+            .route(
+                "/items",
+                axum::routing::get(|axum::Extension(variant): axum::Extension<Variant>| async move { variant.as_str() }),
+            )
+            .layer(axum::middleware::from_fn_with_state(Arc::new(canary), layer));
+        let get = |name: &'static str, header: Option<&'static str>| {
+            let mut request = axum::http::Request::get("/items");
+            if let Some(value) = header {
+                request = request.header("x-canary", value);
+            }
+            let request = request.body(axum::body::Body::empty()).unwrap();
+            tower::ServiceExt::oneshot(app.clone(), request).instrument(tracing::info_span!("request", otel.name = name, canary.variant = tracing::field::Empty))
+        };
+        let body = |response: axum::response::Response| async move { http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes() };
+
+        assert_eq!(body(get("first", Some("canary")).await.unwrap()).await, "canary");
+        assert_eq!(body(get("second", None).await.unwrap()).await, "stable");
+
+        let spans = telemetry.spans();
+        spans.assert_span_exists("first").with_attribute("canary.variant", "canary").has_child("GET");
+        spans.assert_span_exists("second").with_attribute("canary.variant", "stable");
+    }
+
+    #[test]
+    fn a_canary_needs_somewhere_to_forward_to() {
+        let settings = CanarySettings { percent: 5.0, ..Default::default() };
+        assert!(Canary::new(&settings, &DependencySettings::default()).is_err());
+        let settings = CanarySettings { header: "x-canary".to_string(), ..Default::default() };
+        assert!(Canary::new(&settings, &DependencySettings::default()).is_err());
+        assert!(Canary::new(&CanarySettings::default(), &DependencySettings::default()).unwrap().is_none());
+    }
+}
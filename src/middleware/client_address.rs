@@ -0,0 +1,31 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::proxy::TrustedProxies;
+
+// The client as resolved through trusted proxies, for whatever keys on it
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddress(pub IpAddr);
+
+// Runs inside the request span, and before the rate limiter and access log read the
+// address; the connection's peer goes on the span too when it isn't the client
+pub async fn layer(
+    axum::extract::State(proxies): axum::extract::State<Arc<TrustedProxies>>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let peer = request
+        .extensions()
+        .get::<axum::extract::ConnectInfo<SocketAddr>>()
+        .map(|axum::extract::ConnectInfo(addr)| addr.ip());
+
+    if let Some(client) = proxies.client_address(peer, request.headers()) {
+        let span = tracing::Span::current();
+        span.record("client.address", client.to_string());
+        if let Some(peer) = peer.filter(|peer| *peer != client) {
+            span.record("network.peer.address", peer.to_string());
+        }
+        request.extensions_mut().insert(ClientAddress(client));
+    }
+    next.run(request).await
+}
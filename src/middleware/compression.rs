@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::config::CompressionSettings;
+use crate::middleware::body::ObservedBody;
+
+// Builds the compression layer from settings, with every encoding off it passes responses through
+pub fn layer(settings: &CompressionSettings) -> tower_http::compression::CompressionLayer {
+    tower_http::compression::CompressionLayer::new()
+        .gzip(settings.enabled && settings.gzip)
+        .br(settings.enabled && settings.br)
+}
+
+pub struct CompressionMetrics {
+    ratio: opentelemetry::metrics::Histogram<f64>,
+}
+
+impl CompressionMetrics {
+    pub fn new() -> Self {
+        let ratio = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .f64_histogram("http.server.compression_ratio")
+            .with_description("Compressed response size divided by the uncompressed size")
+            .init();
+
+        Self { ratio }
+    }
+}
+
+// Handed from the inner to the outer counter through the response extensions
+#[derive(Clone)]
+struct UncompressedSize(Arc<AtomicU64>);
+
+// Added inside the compression layer, counts the bytes the handler produced
+pub async fn count_uncompressed(response: axum::response::Response) -> axum::response::Response {
+    let size = Arc::new(AtomicU64::new(0));
+    let span = tracing::Span::current();
+
+    let (mut parts, body) = response.into_parts();
+    parts.extensions.insert(UncompressedSize(size.clone()));
+
+    let body = ObservedBody::new(body)
+        .on_data(move |bytes| {
+            size.fetch_add(bytes, Ordering::Relaxed);
+        })
+        .on_done(move |total, _| {
+            span.record("http.response.body.uncompressed_size", total);
+        });
+    axum::response::Response::from_parts(parts, axum::body::Body::new(body))
+}
+
+// Added outside the compression layer, counts the bytes actually sent
+pub async fn count_compressed(
+    axum::extract::State(metrics): axum::extract::State<Arc<CompressionMetrics>>,
+    response: axum::response::Response,
+) -> axum::response::Response {
+    let span = tracing::Span::current();
+    let uncompressed = response.extensions().get::<UncompressedSize>().cloned();
+    let encoding = response
+        .headers()
+        .get(axum::http::header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(crate::attributes::intern);
+
+    if let Some(encoding) = &encoding {
+        span.record("http.response.content_encoding", encoding.as_str());
+    }
+
+    let (parts, body) = response.into_parts();
+    // The size sent goes on the request span from the payload size layer
+    let body = ObservedBody::new(body).on_done(move |total, _| {
+        // The inner body has been fully read by the time the outer one is done with it
+        if let (Some(encoding), Some(UncompressedSize(uncompressed))) = (encoding, uncompressed) {
+            let uncompressed = uncompressed.load(Ordering::Relaxed);
+            if uncompressed > 0 {
+                let ratio = total as f64 / uncompressed as f64;
+                span.record("compression.ratio", ratio);
+                metrics.ratio.record(ratio, &[opentelemetry::KeyValue::new("encoding", encoding)]);
+            }
+        }
+    });
+    axum::response::Response::from_parts(parts, axum::body::Body::new(body))
+}
@@ -0,0 +1,117 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::response::IntoResponse;
+use opentelemetry::KeyValue;
+
+use crate::config::ConcurrencySettings;
+use crate::contention::TracedSemaphore;
+
+// Bounds how many requests run handlers at once; the rest wait in a bounded queue or are shed
+pub struct ConcurrencyLimit {
+    settings: ConcurrencySettings,
+    permits: TracedSemaphore,
+    queued: AtomicUsize,
+    in_flight_gauge: opentelemetry::metrics::UpDownCounter<i64>,
+    queued_gauge: opentelemetry::metrics::UpDownCounter<i64>,
+    wait_time: opentelemetry::metrics::Histogram<f64>,
+    shed: opentelemetry::metrics::Counter<u64>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(settings: ConcurrencySettings) -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+
+        Self {
+            // Waits past a millisecond are worth an event on the request span
+            permits: TracedSemaphore::new("concurrency", Duration::from_millis(1), settings.max_in_flight),
+            queued: AtomicUsize::new(0),
+            in_flight_gauge: meter
+                .i64_up_down_counter("http.server.active_requests")
+                .with_description("Requests currently running past the concurrency limit")
+                .init(),
+            queued_gauge: meter
+                .i64_up_down_counter("http.server.queued_requests")
+                .with_description("Requests waiting for a concurrency permit")
+                .init(),
+            wait_time: meter
+                .f64_histogram("http.server.queue_wait")
+                .with_unit("ms")
+                .with_description("Time spent waiting for a concurrency permit")
+                .init(),
+            shed: meter
+                .u64_counter("http.server.shed_requests")
+                .with_description("Requests rejected because the queue was full or the wait timed out")
+                .init(),
+            settings,
+        }
+    }
+}
+
+// Decrements the queue depth however the wait ends, including cancellation
+struct QueueSlot<'a>(&'a ConcurrencyLimit);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+        self.0.queued_gauge.add(-1, &[]);
+    }
+}
+
+// Holds the permit for the lifetime of the handler, including cancellation
+struct InFlight<'a> {
+    limit: &'a ConcurrencyLimit,
+    _permit: tokio::sync::SemaphorePermit<'a>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.limit.in_flight_gauge.add(-1, &[]);
+    }
+}
+
+fn shed(limit: &ConcurrencyLimit, reason: &'static str) -> axum::response::Response {
+    let span = tracing::Span::current();
+    span.record("concurrency.shed", reason);
+    tracing::warn!(concurrency.shed = reason, "Shedding request over the concurrency limit");
+    limit.shed.add(1, &[KeyValue::new("reason", reason)]);
+
+    (axum::http::StatusCode::SERVICE_UNAVAILABLE, "server busy").into_response()
+}
+
+// Runs inside the request span, the wait is recorded there separately from handler time
+pub async fn layer(
+    axum::extract::State(limit): axum::extract::State<Arc<ConcurrencyLimit>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let started = Instant::now();
+
+    let permit = match limit.permits.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            if limit.queued.fetch_add(1, Ordering::Relaxed) >= limit.settings.max_queued {
+                limit.queued.fetch_sub(1, Ordering::Relaxed);
+                return shed(&limit, "queue_full");
+            }
+            limit.queued_gauge.add(1, &[]);
+            let _slot = QueueSlot(&limit);
+
+            let timeout = Duration::from_millis(limit.settings.queue_timeout_ms);
+            match tokio::time::timeout(timeout, limit.permits.acquire()).await {
+                Ok(permit) => permit.expect("the semaphore is never closed"),
+                Err(_) => return shed(&limit, "queue_timeout"),
+            }
+        }
+    };
+
+    let waited_ms = started.elapsed().as_secs_f64() * 1000.0;
+    tracing::Span::current().record("concurrency.wait_ms", waited_ms);
+    limit.wait_time.record(waited_ms, &[]);
+
+    limit.in_flight_gauge.add(1, &[]);
+    let _in_flight = InFlight { limit: &limit, _permit: permit };
+
+    next.run(request).await
+}
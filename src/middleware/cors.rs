@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use crate::config::CorsSettings;
+
+// Builds the CORS layer from settings, `None` when CORS is disabled
+pub fn layer(settings: &CorsSettings) -> Result<Option<tower_http::cors::CorsLayer>, String> {
+    if !settings.enabled {
+        return Ok(None);
+    }
+
+    // Any origin with credentials would let every site make requests as the signed-in user
+    let origins = if settings.allowed_origins.iter().any(|origin| origin == "*") {
+        if settings.allow_credentials {
+            return Err("allowed_origins = [\"*\"] can't go with allow_credentials = true, list the origins".to_string());
+        }
+        tower_http::cors::AllowOrigin::any()
+    } else {
+        let origins = settings
+            .allowed_origins
+            .iter()
+            .map(|origin| origin.parse().map_err(|_| format!("invalid CORS origin: {origin}")))
+            .collect::<Result<Vec<axum::http::HeaderValue>, String>>()?;
+        tower_http::cors::AllowOrigin::list(origins)
+    };
+
+    let methods = settings
+        .allowed_methods
+        .iter()
+        .map(|method| method.parse().map_err(|_| format!("invalid CORS method: {method}")))
+        .collect::<Result<Vec<axum::http::Method>, String>>()?;
+
+    let headers = settings
+        .allowed_headers
+        .iter()
+        .map(|header| header.parse().map_err(|_| format!("invalid CORS header: {header}")))
+        .collect::<Result<Vec<axum::http::HeaderName>, String>>()?;
+
+    Ok(Some(
+        tower_http::cors::CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(settings.allow_credentials)
+            .max_age(Duration::from_secs(settings.max_age_secs)),
+    ))
+}
+
+pub fn is_preflight<B>(request: &axum::http::Request<B>) -> bool {
+    request.method() == axum::http::Method::OPTIONS
+        && request.headers().contains_key(axum::http::header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+// Separate span for preflights so they don't blend in with the real requests they precede
+pub fn preflight_span<B>(request: &axum::http::Request<B>) -> tracing::Span {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value: &axum::http::HeaderValue| value.to_str().ok())
+            .unwrap_or("")
+    };
+
+    tracing::info_span!(
+        "cors preflight",
+        otel.kind = "server",
+        url.path = request.uri().path(),
+        cors.origin = header(axum::http::header::ORIGIN),
+        cors.request_method = header(axum::http::header::ACCESS_CONTROL_REQUEST_METHOD),
+        cors.request_headers = header(axum::http::header::ACCESS_CONTROL_REQUEST_HEADERS),
+        cors.allowed = tracing::field::Empty,
+        http.response.status_code = tracing::field::Empty,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_any_origin_with_credentials() {
+        let settings = CorsSettings { enabled: true, allowed_origins: vec!["*".to_string()], allow_credentials: true, ..Default::default() };
+        assert!(layer(&settings).unwrap_err().contains("allow_credentials"));
+
+        let settings = CorsSettings { allow_credentials: false, ..settings };
+        assert!(layer(&settings).unwrap().is_some());
+    }
+}
@@ -0,0 +1,127 @@
+// Queries made while handling a request and the time spent in them, so "is it the database
+// or the app" shows at a glance: `db.calls` and `db.total_time_ms` on the request span, and
+// per route in the `http.server.db.calls` and `http.server.db.duration` histograms. Each
+// attempt counts, retries and a hedge's second one included; what runs after the handler
+// returns, a streamed body, is past the request's count.
+
+// Only the database handlers so far
+#![cfg_attr(not(feature = "mysql"), allow(dead_code))]
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    static CALLS: Arc<Calls>;
+}
+
+#[derive(Default)]
+struct Calls {
+    count: AtomicU64,
+    time_us: AtomicU64,
+}
+
+impl Calls {
+    fn time(&self) -> Duration {
+        Duration::from_micros(self.time_us.load(Ordering::Relaxed))
+    }
+}
+
+// A query, counted against the request making it when there is one
+pub trait Counted: Future + Sized {
+    fn counted(self) -> impl Future<Output = Self::Output> {
+        async move {
+            let started = Instant::now();
+            let output = self.await;
+            let elapsed = started.elapsed();
+            let _ = CALLS.try_with(|calls| {
+                calls.count.fetch_add(1, Ordering::Relaxed);
+                calls.time_us.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+            });
+            crate::middleware::time_breakdown::add(crate::middleware::time_breakdown::Part::Db, elapsed);
+            output
+        }
+    }
+}
+
+impl<F: Future> Counted for F {}
+
+pub struct DbCallMetrics {
+    calls: opentelemetry::metrics::Histogram<u64>,
+    duration: opentelemetry::metrics::Histogram<f64>,
+}
+
+impl DbCallMetrics {
+    pub fn new() -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        Self {
+            calls: meter
+                .u64_histogram("http.server.db.calls")
+                .with_unit("{call}")
+                .with_description("Database queries made per request, by route")
+                .init(),
+            duration: meter
+                .f64_histogram("http.server.db.duration")
+                .with_unit("ms")
+                .with_description("Time per request spent in database queries, by route")
+                .init(),
+        }
+    }
+}
+
+// Runs inside the request span; requests which matched no route aren't in the histograms
+pub async fn layer(
+    axum::extract::State(metrics): axum::extract::State<Arc<DbCallMetrics>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let route = crate::middleware::matched_route(&request);
+    let calls = Arc::new(Calls::default());
+    let response = CALLS.scope(calls.clone(), next.run(request)).await;
+
+    let (count, time_ms) = (calls.count.load(Ordering::Relaxed), calls.time().as_secs_f64() * 1000.0);
+    let span = tracing::Span::current();
+    span.record("db.calls", count as i64);
+    span.record("db.total_time_ms", time_ms);
+    if !route.is_empty() {
+        let attributes = [crate::attributes::route(&route)];
+        metrics.calls.record(count, &attributes);
+        metrics.duration.record(time_ms, &attributes);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn queries_are_counted_on_the_request_span() {
+        let telemetry = crate::test_support::init();
+
+        let app = axum::Router::new()
+            .route(
+                "/",
+                axum::routing::get(|| async {
+                    for _ in 0..3 {
+                        tokio::time::sleep(Duration::from_millis(5)).counted().await;
+                    }
+                    // Not a query
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(Arc::new(DbCallMetrics::new()), layer));
+        let request = axum::http::Request::get("/").body(axum::body::Body::empty()).unwrap();
+        let span = tracing::info_span!("request", db.calls = tracing::field::Empty, db.total_time_ms = tracing::field::Empty);
+        tracing::Instrument::instrument(tower::ServiceExt::oneshot(app, request), span).await.unwrap();
+
+        let spans = telemetry.spans();
+        let request = spans.assert_span_exists("request").with_attribute("db.calls", 3i64);
+        let Some(opentelemetry::Value::F64(time_ms)) = request.attribute("db.total_time_ms") else {
+            panic!("no db.total_time_ms");
+        };
+        assert!((15.0..35.0).contains(time_ms), "{time_ms}");
+    }
+}
@@ -0,0 +1,155 @@
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::response::IntoResponse;
+
+// Absolute deadline as milliseconds since the Unix epoch, also sent on outbound calls
+pub const DEADLINE_HEADER: &str = "x-request-deadline";
+// Relative budget in the gRPC format, e.g. `250m` or `2S`
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+tokio::task_local! {
+    static DEADLINE: Deadline;
+}
+
+// Point in time by which the caller wants an answer.
+// Available to handlers as `Extension<Deadline>`, and to clients through `Deadline::current`.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+
+    // Deadline of the request being handled by this task, if the caller sent one
+    pub fn current() -> Option<Deadline> {
+        DEADLINE.try_with(|deadline| *deadline).ok()
+    }
+
+    // Header value handing the same deadline on to a downstream service
+    pub fn to_header_value(self) -> String {
+        let at = SystemTime::now() + self.remaining();
+        at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis().to_string()
+    }
+}
+
+// Milliseconds MySQL may run a statement of the current request for, its remaining budget
+// rounded up to a power of two, so a statement comes in a few texts rather than one a request
+#[cfg_attr(not(feature = "mysql"), allow(dead_code))]
+pub fn statement_limit_ms() -> Option<u64> {
+    let remaining = u64::try_from(Deadline::current()?.remaining().as_millis()).unwrap_or(u64::MAX);
+    Some(remaining.max(1).checked_next_power_of_two().unwrap_or(u64::MAX))
+}
+
+// Only the database routes bound their calls so far
+#[cfg_attr(not(feature = "mysql"), allow(dead_code))]
+#[derive(Debug)]
+pub struct DeadlineExceeded;
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request deadline exceeded")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+// Runs `fut` within the remaining budget of the current request, unbounded without a deadline
+#[cfg_attr(not(feature = "mysql"), allow(dead_code))]
+pub async fn bounded<F: Future>(what: &'static str, fut: F) -> Result<F::Output, DeadlineExceeded> {
+    let Some(deadline) = Deadline::current() else {
+        return Ok(fut.await);
+    };
+
+    let budget = deadline.remaining();
+    match tokio::time::timeout(budget, fut).await {
+        Ok(output) => Ok(output),
+        Err(_) => {
+            tracing::warn!(deadline.operation = what, deadline.budget_ms = budget.as_millis() as u64, "Request deadline exhausted");
+            Err(DeadlineExceeded)
+        }
+    }
+}
+
+// Hours or minutes past what a Duration holds come out as Duration::MAX, which `layer` rejects
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    let amount: u64 = amount.parse().ok()?;
+    let secs = |per: u64| amount.checked_mul(per).map_or(Duration::MAX, Duration::from_secs);
+    match unit {
+        "H" => Some(secs(3600)),
+        "M" => Some(secs(60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+fn parse_deadline(value: &str) -> Option<Duration> {
+    let at = UNIX_EPOCH.checked_add(Duration::from_millis(value.parse().ok()?))?;
+    // A deadline in the past leaves no budget at all
+    Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+fn budget(headers: &axum::http::HeaderMap) -> Option<Duration> {
+    let header = |name| headers.get(name).and_then(|value: &axum::http::HeaderValue| value.to_str().ok());
+
+    header(DEADLINE_HEADER)
+        .and_then(parse_deadline)
+        .or_else(|| header(GRPC_TIMEOUT_HEADER).and_then(parse_grpc_timeout))
+}
+
+pub async fn layer(mut request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let Some(budget) = budget(request.headers()) else {
+        return next.run(request).await;
+    };
+
+    tracing::Span::current().record("deadline.budget_ms", budget.as_millis() as u64);
+    if budget.is_zero() {
+        tracing::warn!("Request deadline exhausted before processing started");
+        return (axum::http::StatusCode::GATEWAY_TIMEOUT, "deadline exceeded").into_response();
+    }
+
+    // A budget no clock reaches is the caller's mistake, not a deadline to keep
+    let Some(at) = Instant::now().checked_add(budget) else {
+        tracing::info!("Rejected request with a deadline out of range");
+        return (axum::http::StatusCode::BAD_REQUEST, "deadline out of range").into_response();
+    };
+    let deadline = Deadline(at);
+    request.extensions_mut().insert(deadline);
+    DEADLINE.scope(deadline, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_budget_out_of_range_is_rejected() {
+        assert_eq!(parse_grpc_timeout("18446744073709551615H"), Some(Duration::MAX));
+        assert_eq!(parse_grpc_timeout("18446744073709551615M"), Some(Duration::MAX));
+        assert_eq!(parse_grpc_timeout("2M"), Some(Duration::from_secs(120)));
+
+        let app = axum::Router::new().route("/", axum::routing::get(|| async { "ok" })).layer(axum::middleware::from_fn(layer));
+        for timeout in ["18446744073709551615H", "18446744073709551615M", "18446744073709551615S", "250m"] {
+            let request = axum::http::Request::get("/").header(GRPC_TIMEOUT_HEADER, timeout).body(axum::body::Body::empty()).unwrap();
+            let status = tower::ServiceExt::oneshot(app.clone(), request).await.unwrap().status();
+            let expected = if timeout == "250m" { axum::http::StatusCode::OK } else { axum::http::StatusCode::BAD_REQUEST };
+            assert_eq!(status, expected, "grpc-timeout: {timeout}");
+        }
+    }
+
+    #[cfg(feature = "mysql")]
+    #[tokio::test]
+    async fn selects_are_limited_to_the_budget() {
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async { [crate::sqlcommenter::tag("SELECT 1"), crate::sqlcommenter::tag("DELETE FROM items")].join(";") }))
+            .layer(axum::middleware::from_fn(layer));
+        let request = axum::http::Request::get("/").header(GRPC_TIMEOUT_HEADER, "250m").body(axum::body::Body::empty()).unwrap();
+        let body = axum::body::to_bytes(tower::ServiceExt::oneshot(app, request).await.unwrap().into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "SELECT /*+ MAX_EXECUTION_TIME(256) */ 1;DELETE FROM items");
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::middleware::body::ObservedBody;
+
+// How far a request got, handlers move it forward with `mark`, the item writes say while
+// the database has the statement. Available to handlers as `Extension<Progress>`.
+#[derive(Debug, Clone)]
+pub struct Progress(Arc<Mutex<&'static str>>);
+
+impl Progress {
+    fn new(stage: &'static str) -> Self {
+        Self(Arc::new(Mutex::new(stage)))
+    }
+
+    pub fn mark(&self, stage: &'static str) {
+        *self.0.lock().unwrap() = stage;
+    }
+
+    fn stage(&self) -> &'static str {
+        *self.0.lock().unwrap()
+    }
+}
+
+fn record_abort(span: &tracing::Span, progress: &Progress, started: Instant) {
+    let stage = progress.stage();
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+
+    span.record("http.request.aborted", true);
+    span.record("http.request.aborted_stage", stage);
+    span.record("http.request.aborted_after_ms", elapsed_ms);
+    span.in_scope(|| {
+        tracing::warn!(http.request.aborted_stage = stage, http.request.aborted_after_ms = elapsed_ms, "Client disconnected before the response completed");
+    });
+}
+
+// hyper drops the service future when the client goes away, so a guard that is dropped
+// while still armed means the handler never got to return
+struct AbortGuard {
+    span: tracing::Span,
+    progress: Progress,
+    started: Instant,
+    armed: bool,
+}
+
+impl Drop for AbortGuard {
+    fn drop(&mut self) {
+        if self.armed {
+            record_abort(&self.span, &self.progress, self.started);
+        }
+    }
+}
+
+// Must sit outside the timeout layer, which drops the handler future itself
+pub async fn layer(mut request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let progress = Progress::new("handler");
+    request.extensions_mut().insert(progress.clone());
+
+    let mut guard = AbortGuard {
+        span: tracing::Span::current(),
+        progress,
+        started: Instant::now(),
+        armed: true,
+    };
+
+    let response = next.run(request).await;
+    guard.armed = false;
+    guard.progress.mark("streaming response");
+
+    // The client can also leave while the body is being written
+    let (span, progress, started) = (guard.span.clone(), guard.progress.clone(), guard.started);
+    let (parts, body) = response.into_parts();
+    let body = ObservedBody::new(body).on_done(move |_, completed| {
+        if !completed {
+            record_abort(&span, &progress, started);
+        }
+    });
+
+    axum::response::Response::from_parts(parts, axum::body::Body::new(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_client_leaving_is_told_where_the_handler_was() {
+        use tower::ServiceExt;
+        use tracing::Instrument;
+
+        let telemetry = crate::test_support::init();
+        let app = axum::Router::new()
+            .route(
+                "/",
+                axum::routing::get(|axum::Extension(progress): axum::Extension<Progress>| async move {
+                    progress.mark("database write");
+                    std::future::pending::<()>().await
+                }),
+            )
+            .layer(axum::middleware::from_fn(layer));
+
+        let span = tracing::info_span!(
+            "request",
+            http.request.aborted = tracing::field::Empty,
+            http.request.aborted_stage = tracing::field::Empty,
+            http.request.aborted_after_ms = tracing::field::Empty,
+        );
+        let request = axum::http::Request::get("/").body(axum::body::Body::empty()).unwrap();
+        // The client going away drops the request's future
+        let response = tokio::time::timeout(std::time::Duration::from_millis(50), app.oneshot(request).instrument(span)).await;
+        assert!(response.is_err());
+
+        telemetry
+            .spans()
+            .assert_span_exists("request")
+            .with_attribute("http.request.aborted", true)
+            .with_attribute("http.request.aborted_stage", "database write");
+    }
+}
@@ -0,0 +1,160 @@
+// ETags on the 200s of GETs, hashed from the body unless the handler set one, and a bodiless
+// 304 for a client whose `If-None-Match` still matches. The tags are weak, the compression
+// layer outside changing the bytes sent with the encoding. A conditional request has
+// `conditional.outcome` on its span, `not_modified` or `modified`, and every response
+// with a tag is counted in `http.server.conditional.requests` by it, as `unconditional`
+// when the client sent none, so the 304 rate of a route is there to read.
+
+use std::sync::Arc;
+
+use axum::response::IntoResponse;
+use opentelemetry::KeyValue;
+use sha2::Digest;
+
+use crate::config::EtagSettings;
+
+// Of a 200, those a 304 keeps
+const KEPT: [axum::http::HeaderName; 5] = [
+    axum::http::header::ETAG,
+    axum::http::header::CACHE_CONTROL,
+    axum::http::header::CONTENT_LOCATION,
+    axum::http::header::EXPIRES,
+    axum::http::header::VARY,
+];
+
+pub struct Etags {
+    max_body_bytes: usize,
+    requests: opentelemetry::metrics::Counter<u64>,
+}
+
+impl Etags {
+    // None when disabled
+    pub fn new(settings: &EtagSettings) -> Option<Self> {
+        settings.enabled.then(|| Self {
+            max_body_bytes: settings.max_body_bytes,
+            requests: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("http.server.conditional.requests")
+                .with_description("GET responses with an ETag, by route and conditional.outcome")
+                .init(),
+        })
+    }
+
+    fn hashable(&self, response: &axum::response::Response) -> bool {
+        http_body::Body::size_hint(response.body()).exact().is_some_and(|len| len <= self.max_body_bytes as u64)
+            && !response
+                .headers()
+                .get_all(axum::http::header::CACHE_CONTROL)
+                .iter()
+                .any(|value| value.to_str().is_ok_and(|value| value.to_ascii_lowercase().contains("no-store")))
+    }
+}
+
+// Weak comparison, as If-None-Match calls for
+fn matches(if_none_match: &axum::http::HeaderValue, etag: &axum::http::HeaderValue) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let (Ok(candidates), Ok(etag)) = (if_none_match.to_str(), etag.to_str()) else {
+        return false;
+    };
+    candidates.trim() == "*" || candidates.split(',').any(|candidate| opaque(candidate) == opaque(etag))
+}
+
+pub async fn layer(
+    axum::extract::State(etags): axum::extract::State<Arc<Etags>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if request.method() != axum::http::Method::GET {
+        return next.run(request).await;
+    }
+    let route = crate::middleware::matched_route(&request);
+    let if_none_match = request.headers().get(axum::http::header::IF_NONE_MATCH).cloned();
+    let response = next.run(request).await;
+    if response.status() != axum::http::StatusCode::OK {
+        return response;
+    }
+
+    let response = match response.headers().get(axum::http::header::ETAG) {
+        Some(_) => response,
+        None if etags.hashable(&response) => {
+            let (mut parts, body) = response.into_parts();
+            let body = match http_body_util::BodyExt::collect(body).await {
+                Ok(body) => body.to_bytes(),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to read a response for its ETag");
+                    return axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            };
+            let hash: String = sha2::Sha256::digest(&body)[..16].iter().map(|b| format!("{b:02x}")).collect();
+            let etag = axum::http::HeaderValue::try_from(format!("W/\"{hash}\"")).expect("hex is a valid header value");
+            parts.headers.insert(axum::http::header::ETAG, etag);
+            axum::response::Response::from_parts(parts, axum::body::Body::from(body))
+        }
+        None => return response,
+    };
+
+    let etag = &response.headers()[axum::http::header::ETAG];
+    let outcome = match &if_none_match {
+        None => "unconditional",
+        Some(if_none_match) if matches(if_none_match, etag) => "not_modified",
+        Some(_) => "modified",
+    };
+    if if_none_match.is_some() {
+        tracing::Span::current().record("conditional.outcome", outcome);
+    }
+    etags.requests.add(1, &[crate::attributes::route(&route), KeyValue::new("conditional.outcome", outcome)]);
+    if outcome != "not_modified" {
+        return response;
+    }
+
+    let mut not_modified = axum::http::StatusCode::NOT_MODIFIED.into_response();
+    for name in KEPT {
+        for value in response.headers().get_all(&name) {
+            not_modified.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    not_modified
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+
+    #[tokio::test]
+    async fn answers_a_matching_tag_with_304() {
+        let telemetry = crate::test_support::init();
+
+        let etags = Etags::new(&EtagSettings::default()).unwrap();
+        let app = axum::Router::new()
+            .route("/items/:id", axum::routing::get(|| async { r#"{"id":1}"# }))
+            .layer(axum::middleware::from_fn_with_state(Arc::new(etags), layer));
+        let get = |name: &'static str, if_none_match: Option<axum::http::HeaderValue>| {
+            let mut request = axum::http::Request::get("/items/1");
+            if let Some(tag) = if_none_match {
+                request = request.header(axum::http::header::IF_NONE_MATCH, tag);
+            }
+            let request = request.body(axum::body::Body::empty()).unwrap();
+            tower::ServiceExt::oneshot(app.clone(), request).instrument(tracing::info_span!("request", otel.name = name, conditional.outcome = tracing::field::Empty))
+        };
+
+        let first = get("first", None).await.unwrap();
+        assert_eq!(first.status(), axum::http::StatusCode::OK);
+        let etag = first.headers()[axum::http::header::ETAG].clone();
+        assert!(etag.to_str().unwrap().starts_with("W/\""), "{etag:?}");
+
+        let revalidated = get("second", Some(etag.clone())).await.unwrap();
+        assert_eq!(revalidated.status(), axum::http::StatusCode::NOT_MODIFIED);
+        assert_eq!(revalidated.headers()[axum::http::header::ETAG], etag);
+        assert!(!revalidated.headers().contains_key(axum::http::header::CONTENT_TYPE));
+        let body = http_body_util::BodyExt::collect(revalidated.into_body()).await.unwrap().to_bytes();
+        assert!(body.is_empty());
+
+        let stale = get("third", Some(axum::http::HeaderValue::from_static("\"other\""))).await.unwrap();
+        assert_eq!(stale.status(), axum::http::StatusCode::OK);
+
+        let spans = telemetry.spans();
+        assert_eq!(spans.assert_span_exists("first").attribute("conditional.outcome"), None);
+        spans.assert_span_exists("second").with_attribute("conditional.outcome", "not_modified");
+        spans.assert_span_exists("third").with_attribute("conditional.outcome", "modified");
+    }
+}
@@ -0,0 +1,176 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use opentelemetry::baggage::BaggageExt;
+use opentelemetry::trace::FutureExt;
+use opentelemetry::{Key, KeyValue};
+
+use crate::config::ExperimentSettings;
+use crate::middleware::auth::AuthUser;
+use crate::middleware::client_address::ClientAddress;
+
+pub const EXPERIMENT_NAME: Key = Key::from_static_str("experiment.name");
+pub const EXPERIMENT_VARIANT: Key = Key::from_static_str("experiment.variant");
+
+// The running A/B experiment: which variant a caller is in, and how each variant does
+pub struct Experiment {
+    name: String,
+    // With the upper end of each one's share of the buckets
+    variants: Vec<(String, u64)>,
+    buckets: u64,
+    routes: Vec<String>,
+    // Callers whose assignment is kept
+    trusted_peers: crate::proxy::TrustedProxies,
+    duration: opentelemetry::metrics::Histogram<f64>,
+}
+
+// FNV-1a, stable across builds and hosts unlike the std hasher, so a caller stays in its
+// variant through restarts and on every replica
+fn fnv1a(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for byte in part.bytes().chain([0]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+impl Experiment {
+    // None while no experiment is running
+    pub fn new(settings: &ExperimentSettings) -> Result<Option<Self>, String> {
+        if settings.name.is_empty() {
+            return Ok(None);
+        }
+
+        let mut buckets = 0;
+        let variants: Vec<_> = settings
+            .variants
+            .iter()
+            .map(|(variant, weight)| {
+                buckets += u64::from(*weight);
+                (variant.clone(), buckets)
+            })
+            .collect();
+        if buckets == 0 {
+            return Err(format!("experiment {:?} has no variant with a weight", settings.name));
+        }
+        if let Some(prefix) = settings.routes.iter().find(|prefix| !prefix.starts_with('/')) {
+            return Err(format!("experiment path prefix {prefix:?} does not start with /"));
+        }
+        let trusted_peers = crate::proxy::TrustedProxies::new(&settings.trusted_peers)?;
+
+        let duration = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .f64_histogram("experiment.request.duration")
+            .with_unit("s")
+            .with_description("Requests in the experiment by variant and route, the server errors with error.type")
+            .init();
+        Ok(Some(Self {
+            name: settings.name.clone(),
+            variants,
+            buckets,
+            routes: settings.routes.clone(),
+            trusted_peers,
+            duration,
+        }))
+    }
+
+    // The same for the same caller, each variant getting its weight's share of callers
+    fn assign(&self, unit: &str) -> &str {
+        let bucket = fnv1a(&[&self.name, unit]) % self.buckets;
+        let (variant, _) = self.variants.iter().find(|(_, upper)| bucket < *upper).expect("buckets are below the last upper end");
+        variant
+    }
+
+    // The caller's own assignment, when it's in this experiment and the caller a service
+    // further up which assigned it and passed it on; a client's would pick its own variant
+    fn inherited(&self, client: Option<std::net::IpAddr>, baggage: &opentelemetry::baggage::Baggage) -> Option<String> {
+        if !client.is_some_and(|ip| self.trusted_peers.contains(ip)) {
+            return None;
+        }
+        let name = baggage.get(EXPERIMENT_NAME)?;
+        let variant = baggage.get(EXPERIMENT_VARIANT)?.as_str();
+        (name.as_str() == self.name && self.variants.iter().any(|(known, _)| *known == variant)).then(|| variant.to_string())
+    }
+}
+
+// Inside auth, to bucket by the user; else by the client's address. The variant goes on
+// the request span and, through the baggage, on the spans below and to downstream calls.
+pub async fn layer(
+    axum::extract::State(experiment): axum::extract::State<Arc<Experiment>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let path = request.uri().path();
+    if !experiment.routes.is_empty() && !experiment.routes.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+        return next.run(request).await;
+    }
+
+    let client = request.extensions().get::<ClientAddress>().map(|ClientAddress(ip)| *ip);
+    let unit = match (request.extensions().get::<AuthUser>(), client) {
+        (Some(user), _) => user.id.clone(),
+        (None, Some(ip)) => ip.to_string(),
+        (None, None) => return next.run(request).await,
+    };
+    let variant = match experiment.inherited(client, crate::propagation::extract(request.headers()).baggage()) {
+        Some(variant) => variant,
+        None => experiment.assign(&unit).to_string(),
+    };
+
+    let span = tracing::Span::current();
+    span.record("experiment.name", experiment.name.as_str());
+    span.record("experiment.variant", variant.as_str());
+    let cx = opentelemetry::Context::current().with_baggage([
+        KeyValue::new(EXPERIMENT_NAME, experiment.name.clone()),
+        KeyValue::new(EXPERIMENT_VARIANT, variant.clone()),
+    ]);
+
+    let route = crate::middleware::matched_route(&request);
+    let started = Instant::now();
+    let response = next.run(request).with_context(cx).await;
+
+    let mut attributes = vec![
+        KeyValue::new(EXPERIMENT_NAME, experiment.name.clone()),
+        KeyValue::new(EXPERIMENT_VARIANT, variant),
+        crate::attributes::route(&route),
+    ];
+    if response.status().is_server_error() {
+        attributes.push(KeyValue::new("error.type", response.status().as_u16().to_string()));
+    }
+    experiment.duration.record(started.elapsed().as_secs_f64(), &attributes);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assignment_is_deterministic_and_weighted() {
+        let settings = ExperimentSettings {
+            name: "checkout".to_string(),
+            variants: [("control".to_string(), 3), ("new".to_string(), 1)].into(),
+            routes: Vec::new(),
+            trusted_peers: vec!["10.0.0.0/8".to_string()],
+        };
+        let experiment = Experiment::new(&settings).unwrap().unwrap();
+
+        assert_eq!(experiment.assign("user-1"), Experiment::new(&settings).unwrap().unwrap().assign("user-1"));
+        let new = (0..4000).filter(|n| experiment.assign(&format!("user-{n}")) == "new").count();
+        assert!((800..1200).contains(&new), "{new} of 4000 in the new variant");
+
+        let baggage = opentelemetry::Context::new()
+            .with_baggage([KeyValue::new(EXPERIMENT_NAME, "checkout"), KeyValue::new(EXPERIMENT_VARIANT, "new")]);
+        let (peer, client) = (Some("10.1.2.3".parse().unwrap()), Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(experiment.inherited(peer, baggage.baggage()).as_deref(), Some("new"));
+        assert_eq!(experiment.inherited(client, baggage.baggage()), None);
+        assert_eq!(experiment.inherited(None, baggage.baggage()), None);
+        let other = opentelemetry::Context::new()
+            .with_baggage([KeyValue::new(EXPERIMENT_NAME, "search"), KeyValue::new(EXPERIMENT_VARIANT, "new")]);
+        assert_eq!(experiment.inherited(peer, other.baggage()), None);
+
+        assert!(Experiment::new(&ExperimentSettings { name: "off".to_string(), ..Default::default() }).is_err());
+        assert!(Experiment::new(&ExperimentSettings::default()).unwrap().is_none());
+    }
+}
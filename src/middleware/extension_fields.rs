@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+// A request extension describing the request, which `layer` records on the request span
+// so whatever inserts it needn't
+pub trait SpanFields: Send + Sync + 'static {
+    fn record(&self, span: &tracing::Span);
+}
+
+// The extension types `layer` records, each a field of the request span in trace.rs
+#[derive(Default)]
+pub struct ExtensionFields(Vec<fn(&axum::http::Extensions, &tracing::Span)>);
+
+impl ExtensionFields {
+    pub fn with<T: SpanFields>(mut self) -> Self {
+        self.0.push(|extensions, span| {
+            if let Some(value) = extensions.get::<T>() {
+                value.record(span);
+            }
+        });
+        self
+    }
+}
+
+// Runs after the other middleware, right before the handler, so it sees what they inserted
+pub async fn layer(
+    axum::extract::State(fields): axum::extract::State<Arc<ExtensionFields>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let span = tracing::Span::current();
+    for record in &fields.0 {
+        record(request.extensions(), &span);
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+
+    #[tokio::test]
+    async fn records_extensions_inserted_further_out() {
+        let telemetry = crate::test_support::init();
+
+        let fields = ExtensionFields::default().with::<crate::middleware::auth::AuthUser>();
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async {}))
+            .layer(axum::middleware::from_fn_with_state(Arc::new(fields), layer))
+            .layer(axum::middleware::map_request(|mut request: axum::extract::Request| async {
+                request.extensions_mut().insert(crate::middleware::auth::AuthUser {
+                    id: "alice".to_string(),
+                    roles: vec!["admin".to_string()],
+                    claims: Default::default(),
+                });
+                request
+            }));
+
+        let span = tracing::info_span!("request", enduser.id = tracing::field::Empty, enduser.role = tracing::field::Empty);
+        let request = axum::http::Request::get("/").body(axum::body::Body::empty()).unwrap();
+        tower::ServiceExt::oneshot(app, request).instrument(span).await.unwrap();
+
+        telemetry
+            .spans()
+            .assert_span_exists("request")
+            .with_attribute("enduser.id", "alice")
+            .with_attribute("enduser.role", "admin");
+    }
+}
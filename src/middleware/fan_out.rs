@@ -0,0 +1,19 @@
+use std::sync::Arc;
+
+use crate::tasks::FanOut;
+
+// Runs inside the request span, which gets the number of tasks spawned while handling
+// the request and the time spent awaiting them, when there were any
+pub async fn layer(request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let fan_out = Arc::new(FanOut::default());
+    let response = crate::tasks::scope(fan_out.clone(), next.run(request)).await;
+
+    let spawned = fan_out.spawned();
+    if spawned > 0 {
+        let span = tracing::Span::current();
+        span.record("tasks.spawned", spawned);
+        span.record("tasks.awaited_ms", fan_out.awaited().as_secs_f64() * 1000.0);
+    }
+
+    response
+}
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use maxminddb::geoip2;
+use opentelemetry::KeyValue;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::{GeoGranularity, GeoIpSettings};
+use crate::middleware::client_address::ClientAddress;
+
+// The MaxMind databases client addresses are looked up in, read into memory at startup
+pub struct GeoIp {
+    location: Option<maxminddb::Reader<Vec<u8>>>,
+    asn: Option<maxminddb::Reader<Vec<u8>>>,
+    granularity: GeoGranularity,
+}
+
+fn open(path: &str) -> Result<maxminddb::Reader<Vec<u8>>, String> {
+    maxminddb::Reader::open_readfile(path).map_err(|e| format!("failed to open GeoIP database {path}: {e}"))
+}
+
+impl GeoIp {
+    // None while neither database is set
+    pub fn new(settings: &GeoIpSettings) -> Result<Option<Self>, String> {
+        if settings.database.is_none() && settings.asn_database.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            location: settings.database.as_deref().map(open).transpose()?,
+            asn: settings.asn_database.as_deref().map(open).transpose()?,
+            granularity: settings.granularity,
+        }))
+    }
+
+    // Nothing for addresses the databases don't know, private ones say
+    fn lookup(&self, ip: std::net::IpAddr) -> Vec<KeyValue> {
+        let mut attributes = Vec::new();
+        if let Some(reader) = &self.location {
+            match reader.lookup::<geoip2::City>(ip) {
+                Ok(city) => attributes.extend(location(&city, self.granularity)),
+                Err(maxminddb::MaxMindDBError::AddressNotFoundError(_)) => {}
+                Err(e) => tracing::debug!("GeoIP lookup failed: {}", e),
+            }
+        }
+        if let Some(reader) = &self.asn {
+            match reader.lookup::<geoip2::Asn>(ip) {
+                Ok(asn) => attributes.extend(network(&asn)),
+                Err(maxminddb::MaxMindDBError::AddressNotFoundError(_)) => {}
+                Err(e) => tracing::debug!("ASN lookup failed: {}", e),
+            }
+        }
+        attributes
+    }
+}
+
+// The record down to `granularity` and no further; a Country database has no region or city
+fn location(city: &geoip2::City, granularity: GeoGranularity) -> Vec<KeyValue> {
+    let mut attributes = Vec::new();
+    if let Some(code) = city.continent.as_ref().and_then(|continent| continent.code) {
+        attributes.push(KeyValue::new("geo.continent.code", code.to_string()));
+    }
+    if granularity == GeoGranularity::Continent {
+        return attributes;
+    }
+
+    let country = city.country.as_ref().and_then(|country| country.iso_code);
+    if let Some(country) = country {
+        attributes.push(KeyValue::new("geo.country.iso_code", country.to_string()));
+    }
+    if granularity == GeoGranularity::Country {
+        return attributes;
+    }
+
+    // ISO 3166-2, the country's code and the subdivision's
+    let subdivision = city.subdivisions.as_ref().and_then(|subdivisions| subdivisions.first()).and_then(|subdivision| subdivision.iso_code);
+    if let (Some(country), Some(subdivision)) = (country, subdivision) {
+        attributes.push(KeyValue::new("geo.region.iso_code", format!("{country}-{subdivision}")));
+    }
+    if granularity == GeoGranularity::Region {
+        return attributes;
+    }
+
+    if let Some(name) = city.city.as_ref().and_then(|city| city.names.as_ref()).and_then(|names| names.get("en")) {
+        attributes.push(KeyValue::new("geo.locality.name", name.to_string()));
+    }
+    attributes
+}
+
+fn network(asn: &geoip2::Asn) -> Vec<KeyValue> {
+    let mut attributes = Vec::new();
+    if let Some(number) = asn.autonomous_system_number {
+        attributes.push(KeyValue::new("client.as.number", i64::from(number)));
+    }
+    if let Some(organization) = asn.autonomous_system_organization {
+        attributes.push(KeyValue::new("client.as.organization.name", organization.to_string()));
+    }
+    attributes
+}
+
+// Inside the client address layer, whose address is looked up; the location and network go
+// on the request span
+pub async fn layer(
+    axum::extract::State(geoip): axum::extract::State<Arc<GeoIp>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if let Some(ClientAddress(ip)) = request.extensions().get::<ClientAddress>() {
+        let span = tracing::Span::current();
+        for KeyValue { key, value } in geoip.lookup(*ip) {
+            span.set_attribute(key, value);
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn location_stops_at_the_granularity() {
+        let city = geoip2::City {
+            city: Some(geoip2::city::City { geoname_id: None, names: Some(BTreeMap::from([("en", "Montreal")])) }),
+            continent: Some(geoip2::city::Continent { code: Some("NA"), geoname_id: None, names: None }),
+            country: Some(geoip2::city::Country { geoname_id: None, is_in_european_union: None, iso_code: Some("CA"), names: None }),
+            location: None,
+            postal: None,
+            registered_country: None,
+            represented_country: None,
+            subdivisions: Some(vec![geoip2::city::Subdivision { geoname_id: None, iso_code: Some("QC"), names: None }]),
+            traits: None,
+        };
+        let keys = |granularity| location(&city, granularity).into_iter().map(|kv| format!("{}={}", kv.key, kv.value)).collect::<Vec<_>>();
+
+        assert_eq!(keys(GeoGranularity::Continent), ["geo.continent.code=NA"]);
+        assert_eq!(keys(GeoGranularity::Country), ["geo.continent.code=NA", "geo.country.iso_code=CA"]);
+        assert_eq!(keys(GeoGranularity::Region), ["geo.continent.code=NA", "geo.country.iso_code=CA", "geo.region.iso_code=CA-QC"]);
+        assert_eq!(keys(GeoGranularity::City).last().map(String::as_str), Some("geo.locality.name=Montreal"));
+
+        let asn = geoip2::Asn { autonomous_system_number: Some(64496), autonomous_system_organization: Some("Example Networks") };
+        let network: Vec<_> = network(&asn).into_iter().map(|kv| kv.value.to_string()).collect();
+        assert_eq!(network, ["64496", "Example Networks"]);
+    }
+}
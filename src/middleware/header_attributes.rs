@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use sha2::Digest;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::HeaderAttributeSettings;
+
+// Credentials, which may only be recorded hashed
+const SENSITIVE: &[&str] = &["authorization", "proxy-authorization", "cookie", "set-cookie"];
+
+struct Mapping {
+    header: axum::http::HeaderName,
+    attribute: opentelemetry::Key,
+    hash: bool,
+}
+
+// The request headers of `[header_attributes]` as attributes of the request span, so a new
+// trace dimension (the client's version, its platform) is a config change. Several values of
+// a header are joined with ", ", as HTTP would; a header the request lacks records nothing.
+pub struct HeaderAttributes(Vec<Mapping>);
+
+impl HeaderAttributes {
+    // None without headers to record
+    pub fn new(settings: &HashMap<String, HeaderAttributeSettings>) -> Result<Option<Self>, String> {
+        let mut mappings = settings
+            .iter()
+            .map(|(name, mapping)| {
+                let header = axum::http::HeaderName::try_from(name.as_str()).map_err(|_| format!("{name:?} is not a header name"))?;
+                if SENSITIVE.contains(&header.as_str()) && !mapping.hash {
+                    return Err(format!("header {name:?} holds credentials, it can only be recorded with hash = true"));
+                }
+                let attribute = match &mapping.attribute {
+                    Some(attribute) if attribute.is_empty() => return Err(format!("attribute of header {name:?} is empty")),
+                    Some(attribute) => attribute.clone(),
+                    None => format!("http.request.header.{header}"),
+                };
+                Ok(Mapping { header, attribute: opentelemetry::Key::from(attribute), hash: mapping.hash })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        mappings.sort_by(|a, b| a.header.as_str().cmp(b.header.as_str()));
+        Ok((!mappings.is_empty()).then_some(Self(mappings)))
+    }
+
+    fn attributes(&self, headers: &axum::http::HeaderMap) -> Vec<opentelemetry::KeyValue> {
+        self.0
+            .iter()
+            .filter_map(|mapping| {
+                let values: Vec<_> = headers.get_all(&mapping.header).iter().map(|value| String::from_utf8_lossy(value.as_bytes())).collect();
+                if values.is_empty() {
+                    return None;
+                }
+                let value = values.join(", ");
+                let value = match mapping.hash {
+                    true => sha2::Sha256::digest(value.as_bytes()).iter().map(|b| format!("{b:02x}")).collect(),
+                    false => value,
+                };
+                Some(opentelemetry::KeyValue::new(mapping.attribute.clone(), value))
+            })
+            .collect()
+    }
+}
+
+pub async fn layer(
+    axum::extract::State(headers): axum::extract::State<Arc<HeaderAttributes>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let span = tracing::Span::current();
+    for attribute in headers.attributes(request.headers()) {
+        span.set_attribute(attribute.key, attribute.value);
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+
+    #[tokio::test]
+    async fn records_the_configured_headers() {
+        let telemetry = crate::test_support::init();
+
+        let settings = HashMap::from([
+            ("x-client-version".to_string(), HeaderAttributeSettings::default()),
+            ("x-platform".to_string(), HeaderAttributeSettings { attribute: Some("client.platform".to_string()), hash: false }),
+            ("x-device-id".to_string(), HeaderAttributeSettings { attribute: Some("client.device".to_string()), hash: true }),
+        ]);
+        let headers = HeaderAttributes::new(&settings).unwrap().unwrap();
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async {}))
+            .layer(axum::middleware::from_fn_with_state(Arc::new(headers), layer));
+        let request = axum::http::Request::get("/")
+            .header("x-client-version", "4.2.0")
+            .header("x-platform", "ios")
+            .header("x-device-id", "abc")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        tower::ServiceExt::oneshot(app, request).instrument(tracing::info_span!("request")).await.unwrap();
+
+        telemetry
+            .spans()
+            .assert_span_exists("request")
+            .with_attribute("http.request.header.x-client-version", "4.2.0")
+            .with_attribute("client.platform", "ios")
+            .with_attribute("client.device", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let cookie = HashMap::from([("cookie".to_string(), HeaderAttributeSettings::default())]);
+        assert!(HeaderAttributes::new(&cookie).is_err());
+    }
+}
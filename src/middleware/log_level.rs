@@ -0,0 +1,14 @@
+use std::sync::Arc;
+
+use crate::logging::RouteLevels;
+
+// Handles the request at its route's `log_level`, while the response body streams the
+// static levels apply again
+pub async fn layer(
+    axum::extract::State(levels): axum::extract::State<Arc<RouteLevels>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let route = crate::middleware::matched_route(&request);
+    levels.scope(&route, next.run(request)).await
+}
@@ -0,0 +1,39 @@
+pub mod access_log;
+pub mod auth;
+pub mod body;
+pub mod body_limit;
+pub mod cache;
+pub mod canary;
+pub mod client_address;
+pub mod compression;
+pub mod concurrency;
+pub mod cors;
+pub mod db_calls;
+pub mod deadline;
+pub mod disconnect;
+pub mod etag;
+pub mod experiment;
+pub mod extension_fields;
+pub mod fan_out;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+pub mod header_attributes;
+pub mod log_level;
+pub mod payload_size;
+pub mod queue_time;
+pub mod rate_limit;
+pub mod rejection;
+pub mod shadow;
+pub mod tenant;
+pub mod time_breakdown;
+pub mod timeout;
+pub mod trace;
+
+// Route pattern the request matched, empty when no route matched
+pub fn matched_route<B>(request: &axum::http::Request<B>) -> String {
+    request
+        .extensions()
+        .get::<axum::extract::MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default()
+}
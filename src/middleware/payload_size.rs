@@ -0,0 +1,108 @@
+// Request and response body sizes, per route in the `http.server.request.body.size` and
+// `http.server.response.body.size` histograms and on the request span, next to its
+// duration, so a payload growing shows before the latency it brings does. The response is
+// counted as sent, after compression, and recorded once its body is done; a request body the
+// handler didn't read to the end, turned away with a 413 say, counts as the bytes received.
+
+use std::sync::Arc;
+
+use crate::middleware::body::ObservedBody;
+
+pub struct PayloadSizeMetrics {
+    request: opentelemetry::metrics::Histogram<u64>,
+    response: opentelemetry::metrics::Histogram<u64>,
+}
+
+impl PayloadSizeMetrics {
+    pub fn new() -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let request = meter
+            .u64_histogram("http.server.request.body.size")
+            .with_unit("By")
+            .with_description("Size of the request bodies, by route")
+            .init();
+        let response = meter
+            .u64_histogram("http.server.response.body.size")
+            .with_unit("By")
+            .with_description("Size of the response bodies as sent, by route")
+            .init();
+
+        Self { request, response }
+    }
+}
+
+// Outside the compression layer
+pub async fn layer(
+    axum::extract::State(metrics): axum::extract::State<Arc<PayloadSizeMetrics>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let span = tracing::Span::current();
+    let labels = crate::attributes::http_server(request.method(), &crate::middleware::matched_route(&request));
+
+    let (parts, body) = request.into_parts();
+    let body = ObservedBody::new(body).on_done({
+        let (metrics, span, labels) = (metrics.clone(), span.clone(), labels.clone());
+        move |total, _| {
+            span.record("http.request.body.size", total as i64);
+            metrics.request.record(total, &labels);
+        }
+    });
+    let request = axum::extract::Request::from_parts(parts, axum::body::Body::new(body));
+
+    let response = next.run(request).await;
+
+    let (parts, body) = response.into_parts();
+    let body = ObservedBody::new(body).on_done(move |total, _| {
+        span.record("http.response.body.size", total as i64);
+        metrics.response.record(total, &labels);
+    });
+    axum::response::Response::from_parts(parts, axum::body::Body::new(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn both_sizes_go_on_the_request_span() {
+        let telemetry = crate::test_support::init();
+
+        let app = axum::Router::new()
+            .route("/echo", axum::routing::post(|body: String| async move { body.repeat(2) }))
+            .layer(axum::middleware::from_fn_with_state(Arc::new(PayloadSizeMetrics::new()), layer));
+        let request = axum::http::Request::post("/echo").body(axum::body::Body::from("hello")).unwrap();
+        let span = tracing::info_span!(
+            "request",
+            http.request.body.size = tracing::field::Empty,
+            http.response.body.size = tracing::field::Empty,
+        );
+        let response = tracing::Instrument::instrument(tower::ServiceExt::oneshot(app, request), span).await.unwrap();
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        let spans = telemetry.spans();
+        spans
+            .assert_span_exists("request")
+            .with_attribute("http.request.body.size", 5_i64)
+            .with_attribute("http.response.body.size", 10_i64);
+    }
+
+    #[tokio::test]
+    async fn a_body_turned_away_counts_as_received() {
+        let telemetry = crate::test_support::init();
+
+        let app = axum::Router::new()
+            .route("/upload", axum::routing::post(|| async { axum::http::StatusCode::PAYLOAD_TOO_LARGE }))
+            .layer(axum::middleware::from_fn_with_state(Arc::new(PayloadSizeMetrics::new()), layer));
+        let request = axum::http::Request::post("/upload")
+            .header(axum::http::header::CONTENT_LENGTH, "1073741824")
+            .body(axum::body::Body::from("hello"))
+            .unwrap();
+        let span = tracing::info_span!("request", http.request.body.size = tracing::field::Empty);
+        let response = tracing::Instrument::instrument(tower::ServiceExt::oneshot(app, request), span).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::PAYLOAD_TOO_LARGE);
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        telemetry.spans().assert_span_exists("request").with_attribute("http.request.body.size", 0_i64);
+    }
+}
@@ -0,0 +1,65 @@
+// How long a request waited between hyper handing it over and its route's handler starting:
+// the middleware stack, the concurrency limit's queue, the rate limiter and whatever else
+// held it up on the way. On the request span as `server.queue_time_ms` and per route in the
+// `http.server.queue_time` histogram, so a saturated service shows apart from a slow handler.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+// When the connection's service got the request, put in its extensions by the server
+#[derive(Debug, Clone, Copy)]
+pub struct Received(pub Instant);
+
+pub struct QueueTimeMetrics {
+    queue_time: opentelemetry::metrics::Histogram<f64>,
+}
+
+impl QueueTimeMetrics {
+    pub fn new() -> Self {
+        let queue_time = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .f64_histogram("http.server.queue_time")
+            .with_unit("ms")
+            .with_description("Time from receiving a request to its handler starting, by route")
+            .init();
+        Self { queue_time }
+    }
+}
+
+// A route layer, the last thing before the handler; requests the server didn't receive, in
+// the route tests say, have nothing to measure
+pub async fn layer(
+    axum::extract::State(metrics): axum::extract::State<Arc<QueueTimeMetrics>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if let Some(Received(received)) = request.extensions().get::<Received>().copied() {
+        let queue_time_ms = received.elapsed().as_secs_f64() * 1000.0;
+        tracing::Span::current().record("server.queue_time_ms", queue_time_ms);
+        metrics.queue_time.record(queue_time_ms, &[crate::attributes::route(&crate::middleware::matched_route(&request))]);
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn the_wait_goes_on_the_request_span() {
+        let telemetry = crate::test_support::init();
+
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async {}))
+            .route_layer(axum::middleware::from_fn_with_state(Arc::new(QueueTimeMetrics::new()), layer));
+        let mut request = axum::http::Request::get("/").body(axum::body::Body::empty()).unwrap();
+        request.extensions_mut().insert(Received(Instant::now() - Duration::from_millis(50)));
+        let span = tracing::info_span!("request", server.queue_time_ms = tracing::field::Empty);
+        tracing::Instrument::instrument(tower::ServiceExt::oneshot(app, request), span).await.unwrap();
+
+        let spans = telemetry.spans();
+        let request = spans.assert_span_exists("request").span();
+        let queue_time = request.attributes.iter().find(|kv| kv.key.as_str() == "server.queue_time_ms").map(|kv| kv.value.clone());
+        assert!(matches!(queue_time, Some(opentelemetry::Value::F64(ms)) if ms >= 50.0), "{queue_time:?}");
+    }
+}
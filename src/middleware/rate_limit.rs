@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use axum::response::IntoResponse;
+
+use crate::config::RateLimitSettings;
+use crate::middleware::auth::AuthUser;
+use crate::middleware::client_address::ClientAddress;
+
+// Buckets kept at most; reaching it, idle buckets and then the least recently used go, down to
+// KEPT_BUCKETS, so the next sweep is a quarter of the map's new clients away
+const MAX_BUCKETS: usize = 100_000;
+const KEPT_BUCKETS: usize = MAX_BUCKETS / 4 * 3;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+// Token bucket per client, keyed by the authenticated user or the client's address; a request
+// with neither, over a Unix socket, isn't limited rather than sharing one bucket with all the
+// others
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    rejections: opentelemetry::metrics::Counter<u64>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        let rejections = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .u64_counter("http.server.rate_limited")
+            .with_description("Requests rejected by the rate limiter, by route")
+            .init();
+
+        Self {
+            settings,
+            buckets: Mutex::new(HashMap::new()),
+            rejections,
+        }
+    }
+
+    // Takes a token, or returns how many seconds until one is available
+    fn acquire(&self, key: &str) -> Result<(), f64> {
+        let now = Instant::now();
+        let rate = self.settings.requests_per_second;
+        let burst = self.settings.burst as f64;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            evict(&mut buckets, now, rate, burst);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * rate).min(burst);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err((1.0 - bucket.tokens) / rate)
+        }
+    }
+}
+
+fn evict(buckets: &mut HashMap<String, Bucket>, now: Instant, rate: f64, burst: f64) {
+    // A bucket that would be full again carries no state worth keeping
+    buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * rate < burst);
+    if buckets.len() <= KEPT_BUCKETS {
+        return;
+    }
+    let mut used: Vec<Instant> = buckets.values().map(|bucket| bucket.updated_at).collect();
+    let (_, oldest_kept, _) = used.select_nth_unstable_by(KEPT_BUCKETS - 1, |a, b| b.cmp(a));
+    let oldest_kept = *oldest_kept;
+    buckets.retain(|_, bucket| bucket.updated_at > oldest_kept);
+}
+
+pub async fn layer(
+    axum::extract::State(limiter): axum::extract::State<Arc<RateLimiter>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if !limiter.settings.enabled {
+        return next.run(request).await;
+    }
+
+    let key = match (request.extensions().get::<AuthUser>(), request.extensions().get::<ClientAddress>()) {
+        (Some(user), _) => format!("user:{}", user.id),
+        (None, Some(ClientAddress(ip))) => format!("ip:{ip}"),
+        (None, None) => return next.run(request).await,
+    };
+
+    let retry_after = match limiter.acquire(&key) {
+        Ok(()) => return next.run(request).await,
+        Err(retry_after) => retry_after,
+    };
+
+    let route = crate::middleware::matched_route(&request);
+
+    // Short span of its own so rejections are easy to find without scanning request spans
+    tracing::info_span!("rate limited", rate_limited = true, rate_limit.key = key, http.route = route).in_scope(|| {
+        tracing::info!(retry_after_secs = retry_after, "Rejected request over the rate limit");
+    });
+    limiter.rejections.add(1, &crate::attributes::route_labels(&route));
+
+    (
+        axum::http::StatusCode::TOO_MANY_REQUESTS,
+        [(axum::http::header::RETRY_AFTER, (retry_after.ceil() as u64).max(1).to_string())],
+        "too many requests",
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_least_recently_used_at_the_cap() {
+        let limiter = RateLimiter::new(RateLimitSettings { enabled: true, requests_per_second: 0.001, burst: 1 });
+        for client in 0..MAX_BUCKETS {
+            limiter.acquire(&client.to_string()).unwrap();
+        }
+        assert!(limiter.acquire("0").is_err(), "known clients are kept");
+
+        limiter.acquire("new").unwrap();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.len() <= KEPT_BUCKETS + 1);
+        assert!(buckets.contains_key("0") && buckets.contains_key("new"));
+        assert!(!buckets.contains_key("1"), "the least recently used goes first");
+        assert!(buckets.contains_key(&(MAX_BUCKETS - 1).to_string()));
+    }
+
+    #[tokio::test]
+    async fn clients_without_an_address_share_no_bucket() {
+        let limiter = Arc::new(RateLimiter::new(RateLimitSettings { enabled: true, requests_per_second: 0.001, burst: 1 }));
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(limiter.clone(), layer));
+        let get = |client: Option<[u8; 4]>| {
+            let mut request = axum::http::Request::get("/").body(axum::body::Body::empty()).unwrap();
+            if let Some(client) = client {
+                request.extensions_mut().insert(ClientAddress(client.into()));
+            }
+            let app = app.clone();
+            async move { tower::ServiceExt::oneshot(app, request).await.unwrap().status() }
+        };
+
+        assert_eq!(get(Some([10, 0, 0, 1])).await, axum::http::StatusCode::OK);
+        assert_eq!(get(Some([10, 0, 0, 1])).await, axum::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(get(Some([10, 0, 0, 2])).await, axum::http::StatusCode::OK);
+        for _ in 0..3 {
+            assert_eq!(get(None).await, axum::http::StatusCode::OK);
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,99 @@
+use axum::response::IntoResponse;
+use tracing_subscriber::registry::LookupSpan;
+
+// axum logs every extractor rejection to this target at TRACE, with the body it answers
+// with; the global filter lets these through, so the rejection is a span event of the
+// request, then `RejectionLayer` and `layer` turn its response into a JSON error
+pub const TARGET: &str = "axum::rejection";
+
+// What the extractor rejected the request with, kept on the request span for `layer`
+#[derive(Default)]
+struct Rejection {
+    kind: String,
+    reason: String,
+}
+
+impl tracing::field::Visit for Rejection {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        match field.name() {
+            // `axum::extract::rejection::JsonSyntaxError`, say
+            "rejection_type" => self.kind = value.rsplit("::").next().unwrap_or(value).to_string(),
+            "body" => self.reason = value.to_string(),
+            _ => (),
+        }
+    }
+
+    fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn std::fmt::Debug) {}
+}
+
+pub struct RejectionLayer;
+
+impl<S> tracing_subscriber::Layer<S> for RejectionLayer
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if event.metadata().target() != TARGET {
+            return;
+        }
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+
+        let mut rejection = Rejection::default();
+        event.record(&mut rejection);
+        span.extensions_mut().insert(rejection);
+    }
+}
+
+fn take_rejection(span: &tracing::Span) -> Option<Rejection> {
+    span.with_subscriber(|(id, dispatch)| {
+        let span = dispatch.downcast_ref::<tracing_subscriber::Registry>()?.span(id)?;
+        let mut extensions = span.extensions_mut();
+        extensions.remove::<Rejection>()
+    })
+    .flatten()
+}
+
+// Inside the request span, where extraction happens. A rejected request gets the reason
+// as an `AppError`, with the trace id to look it up by, instead of axum's plain text.
+pub async fn layer(request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let response = next.run(request).await;
+
+    let span = tracing::Span::current();
+    let Some(rejection) = take_rejection(&span) else {
+        return response;
+    };
+
+    crate::error::AppError::new(response.status(), rejection.reason).with("rejection", rejection.kind).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+
+    #[tokio::test]
+    async fn rejections_are_json_with_the_trace_id() {
+        let telemetry = crate::test_support::init();
+
+        let app = axum::Router::new()
+            .route("/", axum::routing::post(|_: axum::Json<serde_json::Value>| async {}))
+            .layer(axum::middleware::from_fn(layer));
+        let request = axum::http::Request::post("/")
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from("{"))
+            .unwrap();
+        let response = tower::ServiceExt::oneshot(app, request).instrument(tracing::info_span!("request")).await.unwrap();
+
+        assert_eq!(response.status(), axum::http::StatusCode::BAD_REQUEST);
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["rejection"], "JsonSyntaxError");
+
+        let spans = telemetry.spans();
+        let span = spans.assert_span_exists("request").span();
+        assert_eq!(body["trace_id"], span.span_context.trace_id().to_string());
+        assert!(span.events.iter().any(|event| event.name == "rejecting request"));
+    }
+}
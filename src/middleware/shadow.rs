@@ -0,0 +1,245 @@
+// Shadow traffic: `percent` of the requests are also sent to `[shadow] url`, a new version
+// of the service say, from a task of their own, so its answer and its failures never reach
+// the client. Each copy is a "shadow request" span, `shadow = true`, linked to the request span,
+// with the status codes of both answers, and is counted in `http.server.shadow.requests` by
+// `shadow.outcome`: `match` or `mismatch` of the two codes, `error` when the shadow didn't
+// answer, `skipped` when `max_in_flight` copies were already under way. Only `methods` are
+// mirrored, and the copies lose the clients' credentials unless `forward_credentials` keeps them.
+
+use std::sync::Arc;
+
+use axum::response::IntoResponse;
+use opentelemetry::KeyValue;
+use rand::Rng;
+use tracing::Instrument;
+
+use crate::config::{DependencySettings, ShadowSettings};
+use crate::http_client::HttpClient;
+
+// On the copies, so the shadow's request spans are marked too
+pub const HEADER: &str = "x-shadow-request";
+
+// Removed from the copies unless `forward_credentials` names them
+const CREDENTIALS: [axum::http::HeaderName; 3] =
+    [axum::http::header::AUTHORIZATION, axum::http::header::COOKIE, axum::http::header::PROXY_AUTHORIZATION];
+
+pub struct Shadow {
+    url: String,
+    ratio: f64,
+    max_body_bytes: usize,
+    methods: Vec<axum::http::Method>,
+    // Of `CREDENTIALS`, those not kept
+    stripped: Vec<axum::http::HeaderName>,
+    in_flight: Arc<tokio::sync::Semaphore>,
+    client: HttpClient,
+    requests: opentelemetry::metrics::Counter<u64>,
+}
+
+impl Shadow {
+    // None without a backend to mirror to
+    pub fn new(settings: &ShadowSettings, client: &DependencySettings) -> Result<Option<Self>, String> {
+        let Some(url) = &settings.url else {
+            return Ok(None);
+        };
+        reqwest::Url::parse(url).map_err(|e| format!("invalid url {url:?}: {e}"))?;
+        if !(0.0..=100.0).contains(&settings.percent) {
+            return Err(format!("percent {} is not between 0 and 100", settings.percent));
+        }
+        let methods = settings
+            .methods
+            .iter()
+            .map(|method| method.to_ascii_uppercase().parse().map_err(|_| format!("invalid method {method:?}")))
+            .collect::<Result<_, String>>()?;
+        let mut stripped = CREDENTIALS.to_vec();
+        for name in &settings.forward_credentials {
+            let name = axum::http::HeaderName::try_from(name.as_str()).map_err(|_| format!("invalid header name {name:?}"))?;
+            if !CREDENTIALS.contains(&name) {
+                return Err(format!("{name} in forward_credentials is not a credential header, all others are forwarded anyway"));
+            }
+            stripped.retain(|stripped| *stripped != name);
+        }
+        Ok(Some(Self {
+            url: url.clone(),
+            ratio: settings.percent / 100.0,
+            max_body_bytes: settings.max_body_bytes,
+            methods,
+            stripped,
+            in_flight: Arc::new(tokio::sync::Semaphore::new(settings.max_in_flight)),
+            client: HttpClient::new(client)?,
+            requests: opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .u64_counter("http.server.shadow.requests")
+                .with_description("Requests mirrored to the shadow backend, by route and shadow.outcome")
+                .init(),
+        }))
+    }
+
+    fn copy(&self, parts: &axum::http::request::Parts, body: bytes::Bytes) -> Result<reqwest::Request, String> {
+        let mut request = crate::http_client::forward(&self.url, parts, body)?;
+        for name in &self.stripped {
+            request.headers_mut().remove(name);
+        }
+        request.headers_mut().insert(HEADER, axum::http::HeaderValue::from_static("1"));
+        Ok(request)
+    }
+}
+
+pub async fn layer(
+    axum::extract::State(shadow): axum::extract::State<Arc<Shadow>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let mirrored = shadow.methods.contains(request.method())
+        && rand::thread_rng().gen_bool(shadow.ratio)
+        && http_body::Body::size_hint(request.body()).exact().is_some_and(|len| len <= shadow.max_body_bytes as u64);
+    if !mirrored {
+        return next.run(request).await;
+    }
+    let route = crate::middleware::matched_route(&request);
+    let Ok(permit) = shadow.in_flight.clone().try_acquire_owned() else {
+        shadow.requests.add(1, &[crate::attributes::route(&route), KeyValue::new("shadow.outcome", "skipped")]);
+        return next.run(request).await;
+    };
+
+    // Read whole, for both; its length is known and within bounds
+    let (parts, body) = request.into_parts();
+    let body = match http_body_util::BodyExt::collect(body).await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return (axum::http::StatusCode::BAD_REQUEST, "failed to read the request body").into_response(),
+    };
+    let copy = shadow.copy(&parts, body.clone());
+    let request = axum::extract::Request::from_parts(parts, axum::body::Body::from(body));
+
+    // A trace of its own, linked to the request's, which ends without waiting for it
+    let span = crate::tasks::link_to_current!(tracing::info_span!(
+        parent: None,
+        "shadow request",
+        otel.name = format!("shadow {}", request.method()),
+        shadow = true,
+        shadow.status_code = tracing::field::Empty,
+        shadow.primary_status_code = tracing::field::Empty,
+        shadow.outcome = tracing::field::Empty,
+    ));
+    let (primary, primary_status) = tokio::sync::oneshot::channel::<axum::http::StatusCode>();
+    let mirror = shadow.clone();
+    tokio::spawn(
+        async move {
+            let _permit = permit;
+            let answered = match copy {
+                Ok(copy) => mirror.client.execute(copy).await.map(|response| response.status()).ok(),
+                Err(e) => {
+                    tracing::warn!(error = e, "Failed to copy a request for the shadow");
+                    None
+                }
+            };
+            let span = tracing::Span::current();
+            // None when the client left before the primary answered
+            let primary = primary_status.await.ok();
+            if let Some(primary) = primary {
+                span.record("shadow.primary_status_code", primary.as_u16() as i64);
+            }
+            let outcome = match answered {
+                Some(status) => {
+                    span.record("shadow.status_code", status.as_u16() as i64);
+                    if Some(status) == primary { "match" } else { "mismatch" }
+                }
+                None => "error",
+            };
+            span.record("shadow.outcome", outcome);
+            mirror.requests.add(1, &[crate::attributes::route(&route), KeyValue::new("shadow.outcome", outcome)]);
+        }
+        .instrument(span),
+    );
+
+    let response = next.run(request).await;
+    let _ = primary.send(response.status());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The shadow, answering differently and handing over the method, whether the copy was
+    // marked and carried credentials, and the body
+    async fn backend() -> (String, tokio::sync::mpsc::UnboundedReceiver<(axum::http::Method, bool, bool, String)>) {
+        let (received, receiving) = tokio::sync::mpsc::unbounded_channel();
+        let backend = axum::Router::new().route(
+            "/items",
+            axum::routing::any(move |method: axum::http::Method, headers: axum::http::HeaderMap, body: String| async move {
+                let credentials = headers.contains_key("authorization") || headers.contains_key("cookie");
+                let _ = received.send((method, headers.contains_key(HEADER), credentials, body));
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, backend).await.unwrap() });
+        (url, receiving)
+    }
+
+    #[tokio::test]
+    async fn mirrors_without_touching_the_response() {
+        let telemetry = crate::test_support::init();
+
+        let (url, mut receiving) = backend().await;
+        let settings = ShadowSettings { url: Some(url), percent: 100.0, methods: vec!["post".to_string()], ..Default::default() };
+        let shadow = Shadow::new(&settings, &DependencySettings::default()).unwrap().unwrap();
+        let app = axum::Router::new()
+            .route("/items", axum::routing::post(|body: String| async move { (axum::http::StatusCode::CREATED, body) }))
+            .layer(axum::middleware::from_fn_with_state(Arc::new(shadow), layer));
+        let request = axum::http::Request::post("/items")
+            .header("content-length", "7")
+            .header("authorization", "Bearer secret")
+            .header("cookie", "session=secret")
+            .body(axum::body::Body::from("{\"a\":1}"))
+            .unwrap();
+        let response = tower::ServiceExt::oneshot(app, request).instrument(tracing::info_span!("request")).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::CREATED);
+        let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+        assert_eq!(body, "{\"a\":1}");
+
+        assert_eq!(receiving.recv().await, Some((axum::http::Method::POST, true, false, "{\"a\":1}".to_string())));
+        let shadowed = loop {
+            let spans = telemetry.spans();
+            if spans.find("shadow POST").is_some() {
+                break spans;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        let request = shadowed.find("request").unwrap().span_context.clone();
+        let mirrored = shadowed.assert_span_exists("shadow POST").without_parent();
+        assert_eq!(mirrored.span().links.links[0].span_context, request);
+        mirrored
+            .with_attribute("shadow", true)
+            .with_attribute("shadow.status_code", 500i64)
+            .with_attribute("shadow.primary_status_code", 201i64)
+            .with_attribute("shadow.outcome", "mismatch");
+    }
+
+    #[tokio::test]
+    async fn mirrors_safe_methods_only_unless_told_otherwise() {
+        let (url, mut receiving) = backend().await;
+        let settings = ShadowSettings { url: Some(url), percent: 100.0, forward_credentials: vec!["Authorization".to_string()], ..Default::default() };
+        let shadow = Arc::new(Shadow::new(&settings, &DependencySettings::default()).unwrap().unwrap());
+        let app = axum::Router::new()
+            .route("/items", axum::routing::any(|| async { axum::http::StatusCode::OK }))
+            .layer(axum::middleware::from_fn_with_state(shadow, layer));
+
+        for method in [axum::http::Method::DELETE, axum::http::Method::GET] {
+            let request = axum::http::Request::builder()
+                .method(method)
+                .uri("/items")
+                .header("content-length", "0")
+                .header("authorization", "Bearer secret")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            tower::ServiceExt::oneshot(app.clone(), request).await.unwrap();
+        }
+        // The GET, with the credentials it was allowed to keep, and not the DELETE
+        assert_eq!(receiving.recv().await, Some((axum::http::Method::GET, true, true, String::new())));
+        assert!(receiving.try_recv().is_err());
+
+        let settings = ShadowSettings { url: Some("http://127.0.0.1:1".to_string()), forward_credentials: vec!["x-api-key".to_string()], ..Default::default() };
+        assert!(Shadow::new(&settings, &DependencySettings::default()).is_err());
+    }
+}
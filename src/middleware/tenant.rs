@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use axum::response::IntoResponse;
+use opentelemetry::baggage::BaggageExt;
+use opentelemetry::trace::FutureExt;
+
+use crate::config::TenantSettings;
+use crate::middleware::auth::{AuthUser, Verified};
+
+// Tenant ids end up in span attributes and SQL comments, so they are kept short and plain
+const MAX_LEN: usize = 64;
+
+// Tenant the request is for, available to handlers as `Extension<Tenant>`
+#[derive(Debug, Clone)]
+pub struct Tenant(pub String);
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+}
+
+// The token's claim wins over the header, which any caller can set
+fn resolve(settings: &TenantSettings, user: Option<&AuthUser>, headers: &axum::http::HeaderMap) -> Option<String> {
+    let claim = user.and_then(|user| user.claims.get(&settings.claim)).and_then(|claim| claim.as_str());
+    if let Some(claim) = claim {
+        return Some(claim.to_string());
+    }
+
+    if settings.header.is_empty() {
+        return None;
+    }
+    headers.get(settings.header.as_str()).map(|value| value.to_str().unwrap_or_default().to_string())
+}
+
+// The tenant for the request span as it's built, from the token `auth::verify_layer` checked
+// ahead of auth, so that the sampler's first decision for the trace, made before `layer`
+// runs, is the tenant's
+pub struct TenantResolver {
+    settings: TenantSettings,
+}
+
+impl TenantResolver {
+    pub fn new(settings: &TenantSettings) -> Self {
+        Self { settings: settings.clone() }
+    }
+
+    // None for an invalid id too, which `layer` rejects
+    pub fn resolve<B>(&self, request: &axum::http::Request<B>) -> Option<String> {
+        let user = request.extensions().get::<Verified>().and_then(Verified::user);
+        resolve(&self.settings, user, request.headers()).filter(|id| is_valid(id))
+    }
+}
+
+// Runs inside auth, so the claim of an authenticated caller is there to read. The tenant
+// goes on the request span and into the baggage, from where it reaches the spans below,
+// the log lines, the SQL comments and downstream calls.
+pub async fn layer(
+    axum::extract::State(settings): axum::extract::State<Arc<TenantSettings>>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let Some(id) = resolve(&settings, request.extensions().get::<AuthUser>(), request.headers()) else {
+        return next.run(request).await;
+    };
+
+    if !is_valid(&id) {
+        tracing::info!(tenant.id_len = id.len(), "Rejected request with an invalid tenant id");
+        return (axum::http::StatusCode::BAD_REQUEST, "invalid tenant id").into_response();
+    }
+
+    // Already on the span from `TenantResolver`, which read the same token and header
+    tracing::Span::current().record("tenant.id", id.as_str());
+    let cx = opentelemetry::Context::current_with_baggage([opentelemetry::KeyValue::new(crate::attributes::TENANT_ID, id.clone())]);
+
+    request.extensions_mut().insert(Tenant(id));
+    next.run(request).with_context(cx).await
+}
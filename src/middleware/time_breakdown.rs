@@ -0,0 +1,112 @@
+// Where a request's time went, on the request span: `time.db_ms` in queries, `time.blocking_ms`
+// waiting for the blocking pool, `time.downstream_ms` waiting for other services, and
+// `time.other_ms` the rest, the handler's own work mostly. The spans of those calls may be
+// sampled out or dropped, these stay. Each part is a sum, so calls made concurrently can
+// add up to more than the request took, with `time.other_ms` 0; as for `db_calls`, what
+// runs after the response head is sent isn't counted.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    static BREAKDOWN: Arc<Breakdown>;
+}
+
+#[derive(Clone, Copy)]
+pub enum Part {
+    Db,
+    Blocking,
+    Downstream,
+}
+
+#[derive(Default)]
+struct Breakdown {
+    db_us: AtomicU64,
+    blocking_us: AtomicU64,
+    downstream_us: AtomicU64,
+}
+
+impl Breakdown {
+    fn part(&self, part: Part) -> &AtomicU64 {
+        match part {
+            Part::Db => &self.db_us,
+            Part::Blocking => &self.blocking_us,
+            Part::Downstream => &self.downstream_us,
+        }
+    }
+
+    fn ms(&self, part: Part) -> f64 {
+        self.part(part).load(Ordering::Relaxed) as f64 / 1000.0
+    }
+}
+
+// Time spent in `part`, counted against the request being handled when there is one
+pub fn add(part: Part, elapsed: Duration) {
+    let _ = BREAKDOWN.try_with(|breakdown| breakdown.part(part).fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed));
+}
+
+// What `future` takes, as time spent in `part`
+pub async fn timed<F: Future>(part: Part, future: F) -> F::Output {
+    let started = Instant::now();
+    let output = future.await;
+    add(part, started.elapsed());
+    output
+}
+
+// Right inside the request span, so the rest is everything in between
+pub async fn layer(request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let breakdown = Arc::new(Breakdown::default());
+    let started = Instant::now();
+    let response = BREAKDOWN.scope(breakdown.clone(), next.run(request)).await;
+
+    let total_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let (db, blocking, downstream) = (breakdown.ms(Part::Db), breakdown.ms(Part::Blocking), breakdown.ms(Part::Downstream));
+    let span = tracing::Span::current();
+    span.record("time.db_ms", db);
+    span.record("time.blocking_ms", blocking);
+    span.record("time.downstream_ms", downstream);
+    span.record("time.other_ms", (total_ms - db - blocking - downstream).max(0.0));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn splits_the_request_time() {
+        let telemetry = crate::test_support::init();
+
+        let app = axum::Router::new()
+            .route(
+                "/",
+                axum::routing::get(|| async {
+                    timed(Part::Db, tokio::time::sleep(Duration::from_millis(20))).await;
+                    timed(Part::Downstream, tokio::time::sleep(Duration::from_millis(30))).await;
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }),
+            )
+            .layer(axum::middleware::from_fn(layer));
+        let span = tracing::info_span!(
+            "request",
+            time.db_ms = tracing::field::Empty,
+            time.blocking_ms = tracing::field::Empty,
+            time.downstream_ms = tracing::field::Empty,
+            time.other_ms = tracing::field::Empty,
+        );
+        let request = axum::http::Request::get("/").body(axum::body::Body::empty()).unwrap();
+        tracing::Instrument::instrument(tower::ServiceExt::oneshot(app, request), span).await.unwrap();
+
+        let spans = telemetry.spans();
+        let request = spans.assert_span_exists("request").with_attribute("time.blocking_ms", 0.0);
+        let ms = |key: &str| match request.attribute(key) {
+            Some(opentelemetry::Value::F64(ms)) => *ms,
+            other => panic!("{key} is {other:?}"),
+        };
+        assert!((20.0..35.0).contains(&ms("time.db_ms")), "{}", ms("time.db_ms"));
+        assert!((30.0..45.0).contains(&ms("time.downstream_ms")), "{}", ms("time.downstream_ms"));
+        assert!((5.0..30.0).contains(&ms("time.other_ms")), "{}", ms("time.other_ms"));
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::response::IntoResponse;
+
+use crate::config::TimeoutSettings;
+
+pub struct RequestTimeout {
+    settings: TimeoutSettings,
+}
+
+impl RequestTimeout {
+    pub fn new(settings: TimeoutSettings) -> Self {
+        Self { settings }
+    }
+
+    fn for_route(&self, route: &str) -> Duration {
+        let ms = self.settings.routes.get(route).copied().unwrap_or(self.settings.default_ms);
+        let limit = Duration::from_millis(ms);
+        // A CPU profile takes as long as it was asked to, which the admin listener doesn't
+        // bound but the API's may have to
+        #[cfg(feature = "pprof")]
+        if route == crate::profiling::PATH {
+            return limit.max(Duration::from_secs(crate::profiling::MAX_SECONDS) + PROFILE_MARGIN);
+        }
+        limit
+    }
+}
+
+// For encoding the longest profile, once sampled
+#[cfg(feature = "pprof")]
+const PROFILE_MARGIN: Duration = Duration::from_secs(30);
+
+// Runs inside the request span. When the deadline passes the handler future is dropped,
+// which closes its spans; the request span is then closed explicitly as a failed request
+// instead of being left without a status.
+pub async fn layer(
+    axum::extract::State(timeout): axum::extract::State<Arc<RequestTimeout>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let route = crate::middleware::matched_route(&request);
+    let limit = timeout.for_route(&route);
+    let started = Instant::now();
+
+    match tokio::time::timeout(limit, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            let elapsed_ms = started.elapsed().as_millis() as u64;
+
+            let span = tracing::Span::current();
+            span.record("timeout", true);
+            span.record("timeout.elapsed_ms", elapsed_ms);
+            span.record("otel.status_code", "error");
+            span.record("otel.status_message", "request timed out");
+            tracing::error!(timeout.limit_ms = limit.as_millis() as u64, timeout.elapsed_ms = elapsed_ms, "Request timed out, handler cancelled");
+
+            (axum::http::StatusCode::GATEWAY_TIMEOUT, "request timed out").into_response()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "pprof"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_longest_profile_fits_in_the_limit() {
+        let timeout = RequestTimeout::new(TimeoutSettings::default());
+        assert!(timeout.for_route(crate::profiling::PATH) > Duration::from_secs(crate::profiling::MAX_SECONDS));
+        assert_eq!(timeout.for_route("/v1/items"), Duration::from_millis(TimeoutSettings::default().default_ms));
+    }
+}
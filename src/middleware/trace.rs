@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use axum::extract::MatchedPath;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+// `network.protocol.version` as the semantic conventions spell it
+fn protocol_version(version: axum::http::Version) -> &'static str {
+    match version {
+        axum::http::Version::HTTP_09 => "0.9",
+        axum::http::Version::HTTP_10 => "1.0",
+        axum::http::Version::HTTP_11 => "1.1",
+        axum::http::Version::HTTP_2 => "2",
+        axum::http::Version::HTTP_3 => "3",
+        _ => "unknown",
+    }
+}
+
+// Root span for every request, the handler spans are nested below it.
+// Fields filled in later by other middleware must be declared here as `Empty`.
+pub fn make_span<B>(request: &axum::http::Request<B>, tenant: Option<&str>) -> tracing::Span {
+    if crate::middleware::cors::is_preflight(request) {
+        return crate::middleware::cors::preflight_span(request);
+    }
+
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or("");
+
+    let span = tracing::info_span!(
+        "request",
+        // the method alone when no route matched
+        otel.name = if route.is_empty() { request.method().to_string() } else { format!("{} {}", request.method(), route) },
+        otel.kind = "server",
+        otel.status_code = tracing::field::Empty,
+        otel.status_message = tracing::field::Empty,
+        http.request.method = crate::attributes::method(request.method()).as_str(),
+        http.route = route,
+        url.path = request.uri().path(),
+        network.protocol.version = protocol_version(request.version()),
+        client.address = tracing::field::Empty,
+        network.peer.address = tracing::field::Empty,
+        http.response.status_code = tracing::field::Empty,
+        http.request.aborted = tracing::field::Empty,
+        http.request.aborted_stage = tracing::field::Empty,
+        http.request.aborted_after_ms = tracing::field::Empty,
+        http.request.body.size = tracing::field::Empty,
+        http.response.body.size = tracing::field::Empty,
+        http.response.body.uncompressed_size = tracing::field::Empty,
+        http.response.content_encoding = tracing::field::Empty,
+        compression.ratio = tracing::field::Empty,
+        enduser.id = tracing::field::Empty,
+        enduser.role = tracing::field::Empty,
+        // for the sampler, which decides on the first look at the span's context
+        tenant.id = tenant,
+        experiment.name = tracing::field::Empty,
+        experiment.variant = tracing::field::Empty,
+        canary.variant = tracing::field::Empty,
+        server.queue_time_ms = tracing::field::Empty,
+        concurrency.wait_ms = tracing::field::Empty,
+        concurrency.shed = tracing::field::Empty,
+        timeout = tracing::field::Empty,
+        timeout.elapsed_ms = tracing::field::Empty,
+        deadline.budget_ms = tracing::field::Empty,
+        tasks.spawned = tracing::field::Empty,
+        tasks.awaited_ms = tracing::field::Empty,
+        cache.status = tracing::field::Empty,
+        conditional.outcome = tracing::field::Empty,
+        db.calls = tracing::field::Empty,
+        db.total_time_ms = tracing::field::Empty,
+        time.db_ms = tracing::field::Empty,
+        time.blocking_ms = tracing::field::Empty,
+        time.downstream_ms = tracing::field::Empty,
+        time.other_ms = tracing::field::Empty,
+        shadow = tracing::field::Empty,
+        degraded = tracing::field::Empty,
+    );
+    // a copy another instance's `shadow` layer sent
+    if request.headers().contains_key(crate::middleware::shadow::HEADER) {
+        span.record("shadow", true);
+    }
+
+    if let Some(version) = crate::attributes::api_version(route) {
+        span.set_attribute(crate::attributes::API_VERSION, crate::attributes::intern(version));
+    }
+
+    // Continue the caller's trace when it sent one
+    span.set_parent(crate::propagation::extract(request.headers()));
+    span
+}
+
+pub fn on_response<B>(response: &axum::http::Response<B>, _latency: Duration, span: &tracing::Span) {
+    span.record("http.response.status_code", response.status().as_u16());
+
+    // Only present on preflight spans
+    span.record("cors.allowed", response.headers().contains_key(axum::http::header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+    // Answered from a copy while the database is down
+    #[cfg(feature = "mysql")]
+    if response.headers().contains_key(crate::degraded::HEADER) {
+        span.record("degraded", true);
+    }
+
+    // Only 5xx are errors from the server's point of view
+    if response.status().is_server_error() {
+        span.record("otel.status_code", "error");
+    }
+}
+
@@ -0,0 +1,37 @@
+// OpenAPI description of the routes at `/docs/openapi.json`, and Swagger UI for it at
+// `/docs/`, its assets built into the binary. The routers add their routes with
+// utoipa-axum's `routes!`, which takes each path, its parameters and responses from the
+// handler's `#[utoipa::path]`, so a route is described wherever it is added; each listener
+// describes the routes it serves. Doc requests aren't sampled, see `sampling::TenantSampler`.
+
+use utoipa::OpenApi;
+
+pub const PATH: &str = "/docs";
+
+// The title and version, from Cargo
+#[derive(utoipa::OpenApi)]
+#[openapi()]
+struct Api;
+
+// What a router adds its routes to
+pub fn router<S: Clone + Send + Sync + 'static>() -> utoipa_axum::router::OpenApiRouter<S> {
+    utoipa_axum::router::OpenApiRouter::with_openapi(Api::openapi())
+}
+
+// A route ending in a wildcard, `{key}` in the spec but `*key` to axum, which `routes!`
+// would make `:key`
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+pub fn wildcard<S: Clone + Send + Sync + 'static>(
+    path: &str,
+    (schemas, paths, method_router): utoipa_axum::router::UtoipaMethodRouter<S>,
+) -> utoipa_axum::router::OpenApiRouter<S> {
+    let mut api = Api::openapi();
+    api.paths = paths;
+    api.components.get_or_insert_with(Default::default).schemas.extend(schemas);
+    utoipa_axum::router::OpenApiRouter::with_openapi(api).route(path, method_router)
+}
+
+// The spec as JSON and Swagger UI
+pub fn routes<S: Clone + Send + Sync + 'static>(api: utoipa::openapi::OpenApi) -> axum::Router<S> {
+    utoipa_swagger_ui::SwaggerUi::new(PATH).url(format!("{PATH}/openapi.json"), api).into()
+}
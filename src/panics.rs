@@ -0,0 +1,79 @@
+// A panic ties back to what it broke: before it unwinds, an ERROR event on the span it
+// happened in, with `exception.message`, where and on which thread, and that span's status
+// set to error, so it shows in the request's trace and log lines like any other error. On
+// stderr the default hook's message follows a line naming the trace and span.
+
+use std::cell::Cell;
+
+use opentelemetry::trace::TraceContextExt;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+thread_local! {
+    // A panic within the hook, in the subscriber say, goes straight to the default hook
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+fn message(info: &std::panic::PanicHookInfo<'_>) -> String {
+    let payload = info.payload();
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "Box<dyn Any>".to_string(),
+    }
+}
+
+// Call once the subscriber is installed
+pub fn install_hook() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if IN_HOOK.replace(true) {
+            return default(info);
+        }
+
+        let message = message(info);
+        let location = info.location().map(ToString::to_string).unwrap_or_default();
+        let thread = std::thread::current();
+        let thread = thread.name().unwrap_or("unnamed");
+        let span = tracing::Span::current();
+        let span_name = span.metadata().map(|metadata| metadata.name()).unwrap_or("none");
+        let span_context = span.context().span().span_context().clone();
+        let trace_id = span_context.is_valid().then(|| span_context.trace_id().to_string());
+
+        tracing::error!(
+            exception.message = message,
+            "exception.type" = "panic",
+            code.location = location,
+            thread.name = thread,
+            "Panicked: {}",
+            message
+        );
+        crate::result_ext::set_error_status(&span, format!("panicked: {message}"));
+
+        eprintln!("Panic in span {span_name:?} of trace {}, thread {thread}:", trace_id.as_deref().unwrap_or("none"));
+        default(info);
+        IN_HOOK.set(false);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::Status;
+
+    #[test]
+    fn a_panic_is_an_error_on_its_span() {
+        let telemetry = crate::test_support::init();
+        let previous = std::panic::take_hook();
+        install_hook();
+
+        let result = std::panic::catch_unwind(|| tracing::info_span!("request").in_scope(|| panic!("index out of bounds")));
+        std::panic::set_hook(previous);
+        assert!(result.is_err());
+
+        let spans = telemetry.spans();
+        let request = spans.assert_span_exists("request").span();
+        assert!(matches!(&request.status, Status::Error { description } if description == "panicked: index out of bounds"));
+        let event = request.events.iter().find(|event| event.name == "Panicked: index out of bounds").unwrap();
+        assert!(event.attributes.iter().any(|kv| kv.key.as_str() == "exception.type" && kv.value.as_str() == "panic"));
+    }
+}
@@ -0,0 +1,168 @@
+// `preflight`: what `serve` needs, checked without serving, for an init container which
+// holds the pod back until the service would start. The config is valid, the OTLP endpoint
+// resolves and takes a connection, MySQL answers and its migrations are this build's; a
+// line per check, and the first failure's exit code if there was one. Pending migrations
+// pass, `serve` applies them.
+
+use std::time::{Duration, Instant};
+
+use crate::config::Settings;
+use crate::startup::StartupError;
+
+// For resolving and connecting to the OTLP endpoint
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn run(settings: &Settings) -> Result<(), StartupError> {
+    println!("Preflight with the settings from {}", crate::startup::config_source());
+
+    let checks = vec![("config", config(settings)), ("otlp endpoint", otlp_endpoint(settings).await)];
+    #[cfg(feature = "mysql")]
+    let checks: Vec<_> = checks.into_iter().chain(database(settings).await).collect();
+
+    let mut failure = None;
+    for (name, result) in checks {
+        match result {
+            Ok(detail) => println!("  pass  {name:<14} {detail}"),
+            Err(e) => {
+                println!("  FAIL  {name:<14} {e}");
+                failure = failure.or(Some(e));
+            }
+        }
+    }
+    failure.map_or(Ok(()), Err)
+}
+
+// The sections which only `serve` reads; the rest are read before any command runs
+fn config(settings: &Settings) -> Result<String, StartupError> {
+    crate::flags::Flags::new(&settings.flags).map_err(StartupError::config("flags"))?;
+    crate::chaos::Chaos::new(&settings.chaos).map_err(StartupError::config("chaos"))?;
+    crate::middleware::experiment::Experiment::new(&settings.experiment).map_err(StartupError::config("experiment"))?;
+    crate::middleware::canary::Canary::new(&settings.canary, &settings.dependencies.http).map_err(StartupError::config("canary"))?;
+    crate::middleware::cors::layer(&settings.cors).map_err(StartupError::config("cors"))?;
+    crate::middleware::access_log::AccessLog::new(&settings.access_log).map_err(StartupError::config("access_log"))?;
+    crate::slo::Slos::new(&settings.slo, &settings.routes).map_err(StartupError::config("slo"))?;
+    crate::middleware::shadow::Shadow::new(&settings.shadow, &settings.dependencies.http).map_err(StartupError::config("shadow"))?;
+    crate::middleware::cache::Cache::new(&settings.cache, &settings.routes).map_err(StartupError::config("cache"))?;
+    crate::middleware::header_attributes::HeaderAttributes::new(&settings.header_attributes).map_err(StartupError::config("header_attributes"))?;
+    crate::proxy::TrustedProxies::new(&settings.server.trusted_proxies).map_err(StartupError::config("server"))?;
+    #[cfg(feature = "geoip")]
+    crate::middleware::geoip::GeoIp::new(&settings.geoip).map_err(StartupError::config("geoip"))?;
+    #[cfg(feature = "email")]
+    crate::email::Mailer::new(&settings.email, &settings.dependencies.smtp).map_err(StartupError::config("email"))?;
+    Ok("valid".to_string())
+}
+
+async fn otlp_endpoint(settings: &Settings) -> Result<String, StartupError> {
+    if !settings.telemetry.enabled {
+        return Ok("telemetry is off".to_string());
+    }
+    let endpoint = crate::telemetry::endpoint(&settings.telemetry).map_err(StartupError::config("telemetry"))?;
+    let outage = StartupError::dependency("the OTLP endpoint", endpoint.clone());
+
+    // A collector sidecar's socket
+    if let Some(socket) = endpoint.strip_prefix("unix:") {
+        tokio::time::timeout(TIMEOUT, tokio::net::UnixStream::connect(socket))
+            .await
+            .map_err(|_| "timed out connecting".to_string())
+            .and_then(|connected| connected.map_err(|e| e.to_string()))
+            .map_err(outage)?;
+        return Ok(format!("{endpoint} takes connections"));
+    }
+
+    let url = reqwest::Url::parse(&endpoint)
+        .map_err(|e| format!("invalid endpoint {endpoint:?}: {e}, is telemetry.protocol built in?"))
+        .map_err(StartupError::config("telemetry"))?;
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return Err(StartupError::config("telemetry")(format!("endpoint {endpoint:?} has no host")));
+    };
+
+    let started = Instant::now();
+    let ping = async {
+        let addresses: Vec<_> = tokio::net::lookup_host((host, port)).await.map_err(|e| format!("can't resolve {host}: {e}"))?.collect();
+        let connected = tokio::net::TcpStream::connect(addresses.as_slice()).await.map_err(|e| e.to_string())?;
+        let peer = connected.peer_addr().map_err(|e| e.to_string())?;
+        Ok::<_, String>((addresses.len(), peer))
+    };
+    let (resolved, peer) = tokio::time::timeout(TIMEOUT, ping)
+        .await
+        .map_err(|_| format!("timed out after {}s", TIMEOUT.as_secs()))
+        .and_then(|pinged| pinged)
+        .map_err(outage)?;
+    Ok(format!("{host} resolves to {resolved} address(es), connected to {peer} in {}ms", started.elapsed().as_millis()))
+}
+
+// The connection and the migrations, the latter only once connected
+#[cfg(feature = "mysql")]
+async fn database(settings: &Settings) -> Vec<(&'static str, Result<String, StartupError>)> {
+    let target = crate::db::target(&settings.database);
+    let pool = match crate::connect_database(settings).await {
+        Ok(pool) => pool,
+        Err(e) => return vec![("mysql", Err(e))],
+    };
+    let ping = sqlx::query("SELECT 1")
+        .execute(&pool)
+        .await
+        .map(|_| format!("{target} answers"))
+        .map_err(StartupError::dependency("MySQL", target.clone()));
+    let migrations = migrations(&pool).await.map_err(StartupError::dependency("MySQL", target));
+    pool.close().await;
+    vec![("mysql", ping), ("migrations", migrations)]
+}
+
+// Read only, nothing is created when the database has never been migrated
+#[cfg(feature = "mysql")]
+async fn migrations(pool: &sqlx::MySqlPool) -> Result<String, String> {
+    use sqlx::migrate::Migrate;
+
+    let mut connection = pool.acquire().await.map_err(|e| e.to_string())?;
+    let (tables,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = '_sqlx_migrations'",
+    )
+    .fetch_one(&mut *connection)
+    .await
+    .map_err(|e| e.to_string())?;
+    let applied = match tables {
+        0 => Vec::new(),
+        _ => {
+            if let Some(version) = connection.dirty_version().await.map_err(|e| e.to_string())? {
+                return Err(format!("migration {version} failed partway, fix it by hand"));
+            }
+            connection.list_applied_migrations().await.map_err(|e| e.to_string())?
+        }
+    };
+
+    let migrator = sqlx::migrate!();
+    let known: Vec<_> = migrator.iter().filter(|migration| !migration.migration_type.is_down_migration()).collect();
+    for migration in &applied {
+        match known.iter().find(|known| known.version == migration.version) {
+            Some(known) if known.checksum != migration.checksum => {
+                return Err(format!("migration {} was applied from a different file than this build's", migration.version));
+            }
+            Some(_) => {}
+            None => return Err(format!("migration {} is applied but not in this build, a newer one's", migration.version)),
+        }
+    }
+    Ok(format!("{} applied, {} pending", applied.len(), known.len() - applied.len()))
+}
+
+// The socket being the one endpoint a test can choose
+#[cfg(all(test, feature = "otlp-grpc"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_collector_socket_takes_connections() {
+        let path = std::env::temp_dir().join(format!("preflight-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut settings = Settings::default();
+        settings.telemetry.protocol = "grpc".to_string();
+        settings.telemetry.collector_socket = Some(path.to_string_lossy().into_owned());
+
+        let down = otlp_endpoint(&settings).await.unwrap_err();
+        assert!(matches!(down, StartupError::Dependency { .. }), "{down}");
+
+        let _listener = tokio::net::UnixListener::bind(&path).unwrap();
+        assert!(otlp_endpoint(&settings).await.is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
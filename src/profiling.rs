@@ -0,0 +1,424 @@
+// CPU profiles on demand, with the `pprof` feature. `GET /debug/pprof/profile?seconds=30`
+// samples every thread for that long and answers with the profile, protobuf for `go tool
+// pprof` or `format=flamegraph` for an SVG; admins only, and one profile at
+// a time. The traces which overlapped it are tagged both ways: each span started meanwhile
+// gets `pprof.profile_id`, and the profile lists the trace ids in its comments. Or
+// continuously, with `profiling.pyroscope_url`: one profile after another pushed to
+// Pyroscope, tagged alike; one asked for meanwhile cuts the one in flight short and takes
+// its turn, the profiler being process-wide.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use opentelemetry::trace::{Span as _, TraceId};
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::trace::Span;
+
+use crate::config::ProfilingSettings;
+
+pub const PATH: &str = "/debug/pprof/profile";
+
+pub const DEFAULT_SECONDS: u64 = 30;
+pub const MAX_SECONDS: u64 = 120;
+// Samples per second, off the round numbers so it doesn't beat with periodic work
+const FREQUENCY: i32 = 99;
+// Trace ids kept for the comments, the busiest windows having many more
+const MAX_TRACES: usize = 1000;
+
+// The profile being taken, process-wide like the profiler's own signal handler
+struct Window {
+    id: String,
+    traces: HashSet<TraceId>,
+    // Beyond `MAX_TRACES`
+    more: usize,
+}
+
+static WINDOW: Mutex<Option<Window>> = Mutex::new(None);
+// Whether `WINDOW` holds one, so spans don't contend for the lock outside the windows
+static OPEN: AtomicBool = AtomicBool::new(false);
+
+// Taken by each profile for its sampling, continuous or on demand, in the order asked
+static PROFILER: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+// A profile on demand waiting for the profiler or being taken, which the continuous one in
+// flight ends early for
+static ON_DEMAND: AtomicBool = AtomicBool::new(false);
+
+// The on-demand profile's turn, given up when dropped
+struct OnDemand;
+
+impl OnDemand {
+    // None while another is waiting or being taken
+    fn take() -> Option<Self> {
+        ON_DEMAND.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).ok().map(|_| Self)
+    }
+}
+
+impl Drop for OnDemand {
+    fn drop(&mut self) {
+        ON_DEMAND.store(false, Ordering::Release);
+    }
+}
+
+// The window taken by one profile, closed when dropped, by a panic while sampling too, so
+// the next profile isn't turned away for good
+struct OpenWindow(String);
+
+impl OpenWindow {
+    fn id(&self) -> &str {
+        &self.0
+    }
+
+    // What was recorded in it, None when it had been closed already
+    fn close(self) -> Option<Window> {
+        take_window(&self.0)
+    }
+}
+
+impl Drop for OpenWindow {
+    fn drop(&mut self) {
+        take_window(&self.0);
+    }
+}
+
+fn take_window(id: &str) -> Option<Window> {
+    let mut window = WINDOW.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    let taken = window.take_if(|open| open.id == id);
+    OPEN.store(window.is_some(), Ordering::Release);
+    taken
+}
+
+// None while another profile is being taken
+fn open_window() -> Option<OpenWindow> {
+    let mut window = WINDOW.lock().unwrap();
+    if window.is_some() {
+        return None;
+    }
+    let id = uuid::Uuid::new_v4().to_string();
+    *window = Some(Window { id: id.clone(), traces: HashSet::new(), more: 0 });
+    OPEN.store(true, Ordering::Release);
+    Some(OpenWindow(id))
+}
+
+// Registered with the tracer provider, tags the spans started while a profile is taken
+#[derive(Debug)]
+pub struct WindowProcessor;
+
+impl crate::telemetry::ChainedProcessor for WindowProcessor {
+    fn on_start(&self, span: &mut Span, _cx: &opentelemetry::Context) {
+        if !OPEN.load(Ordering::Acquire) {
+            return;
+        }
+        let mut window = WINDOW.lock().unwrap();
+        let Some(window) = window.as_mut() else { return };
+
+        span.set_attribute(opentelemetry::KeyValue::new("pprof.profile_id", window.id.clone()));
+        let trace_id = span.span_context().trace_id();
+        if window.traces.len() < MAX_TRACES || window.traces.contains(&trace_id) {
+            window.traces.insert(trace_id);
+        } else {
+            window.more += 1;
+        }
+    }
+
+    fn on_end(&self, span: SpanData) -> Option<SpanData> {
+        Some(span)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Pprof,
+    Flamegraph,
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+pub struct Params {
+    #[param(minimum = 1, maximum = 120, default = 30)]
+    seconds: Option<u64>,
+    #[serde(default)]
+    format: Format,
+}
+
+// For `seconds`, or, when `yields`, until a profile is asked for on demand
+fn wait(seconds: u64, yields: bool) {
+    let until = std::time::Instant::now() + std::time::Duration::from_secs(seconds);
+    while let Some(left) = until.checked_duration_since(std::time::Instant::now()).filter(|left| !left.is_zero()) {
+        if yields && ON_DEMAND.load(Ordering::Acquire) {
+            return;
+        }
+        std::thread::sleep(left.min(std::time::Duration::from_millis(100)));
+    }
+}
+
+// Samples for `seconds` on the calling thread, a blocking one, then closes `window`
+fn sample(seconds: u64, yields: bool, window: OpenWindow) -> Result<(pprof::Report, Window), String> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| e.to_string());
+    let report = guard.and_then(|guard| {
+        wait(seconds, yields);
+        guard.report().build().map_err(|e| e.to_string())
+    });
+    let traces = window.close().ok_or("the profiling window was closed early")?;
+    Ok((report?, traces))
+}
+
+fn encode(report: &pprof::Report, format: Format, window: &Window) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+    match format {
+        // An idle process has no samples, and inferno no graph to draw
+        Format::Flamegraph if report.data.is_empty() => return Err("no samples, the process was idle".to_string()),
+        Format::Flamegraph => report.flamegraph(&mut body).map_err(|e| e.to_string())?,
+        Format::Pprof => {
+            let mut profile = report.pprof().map_err(|e| e.to_string())?;
+            let mut comment = |text: String| {
+                profile.comment.push(profile.string_table.len() as i64);
+                profile.string_table.push(text);
+            };
+            comment(format!("pprof.profile_id={}", window.id));
+            for trace_id in &window.traces {
+                comment(format!("trace_id={trace_id}"));
+            }
+            if window.more > 0 {
+                comment(format!("{} more spans in traces not listed", window.more));
+            }
+            pprof::protos::Message::encode(&profile, &mut body).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(body)
+}
+
+fn profile(seconds: u64, yields: bool, format: Format, window: OpenWindow) -> Result<(Vec<u8>, Window), String> {
+    let (report, traces) = sample(seconds, yields, window)?;
+    Ok((encode(&report, format, &traces)?, traces))
+}
+
+#[utoipa::path(get, path = "/debug/pprof/profile", summary = "Samples the CPU for a while, with the trace ids of the busiest spans as comments", params(Params), responses(
+    (status = 200, description = "The profile, as an attachment", content(([u8] = "application/octet-stream"), (String = "image/svg+xml"))),
+    (status = 400, body = String, description = "The seconds are out of range"),
+    (status = 401, body = String, description = "No token"),
+    (status = 403, body = String, description = "The caller is not an admin"),
+    (status = 409, body = String, description = "A profile is already being taken"),
+))]
+pub async fn profile_handler(
+    axum::extract::Query(params): axum::extract::Query<Params>,
+    crate::middleware::auth::RequireAdmin(admin): crate::middleware::auth::RequireAdmin,
+) -> axum::response::Response {
+    let seconds = params.seconds.unwrap_or(DEFAULT_SECONDS);
+    if !(1..=MAX_SECONDS).contains(&seconds) {
+        return (StatusCode::BAD_REQUEST, format!("seconds must be from 1 to {MAX_SECONDS}")).into_response();
+    }
+    let Some(on_demand) = OnDemand::take() else {
+        return (StatusCode::CONFLICT, "a profile is already being taken").into_response();
+    };
+    // Once the continuous profile in flight, if any, has ended
+    let profiler = PROFILER.lock().await;
+    let Some(open) = open_window() else {
+        return (StatusCode::CONFLICT, "a profile is already being taken").into_response();
+    };
+    let window = open.id().to_string();
+
+    // The request's own span started before the window
+    tracing_opentelemetry::OpenTelemetrySpanExt::set_attribute(&tracing::Span::current(), "pprof.profile_id", window.clone());
+    crate::audit::audit!(pprof.profile_id = window, pprof.seconds = seconds, enduser.id = admin.id, "CPU profile started");
+
+    // Held until sampled, should the request go first
+    let profiled = move || {
+        let _turn = (on_demand, profiler);
+        profile(seconds, false, params.format, open)
+    };
+    match crate::blocking::run_blocking("pprof profile", profiled).await {
+        Ok((body, traces)) => {
+            tracing::info!(pprof.profile_id = window, pprof.traces = traces.traces.len() as i64, "CPU profile taken");
+            let (content_type, file) = match params.format {
+                Format::Pprof => ("application/octet-stream", "profile.pb"),
+                Format::Flamegraph => ("image/svg+xml", "profile.svg"),
+            };
+            let headers = [
+                (axum::http::header::CONTENT_TYPE, content_type.to_string()),
+                (axum::http::header::CONTENT_DISPOSITION, format!("attachment; filename=\"{file}\"")),
+                (axum::http::HeaderName::from_static("x-profile-id"), window),
+            ];
+            (headers, body).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to take a CPU profile: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to take a profile").into_response()
+        }
+    }
+}
+
+fn unix_secs() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// Pushes a profile to Pyroscope, labelled with `labels`; `from` and `until` in seconds
+// since the epoch
+async fn push(client: &reqwest::Client, settings: &ProfilingSettings, labels: &str, from: u64, until: u64, body: Vec<u8>) -> Result<(), String> {
+    let Some(url) = &settings.pyroscope_url else { return Ok(()) };
+    let mut request = client.post(format!("{}/ingest", url.trim_end_matches('/'))).query(&[
+        ("name", format!("{}.cpu{{{labels}}}", env!("CARGO_PKG_NAME"))),
+        ("from", from.to_string()),
+        ("until", until.to_string()),
+        ("format", "pprof".to_string()),
+        ("sampleRate", FREQUENCY.to_string()),
+    ]);
+    if let Some(username) = &settings.username {
+        request = request.basic_auth(username, settings.password.as_ref());
+    }
+    let response = request.body(body).send().await.map_err(|e| e.to_string())?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(format!("Pyroscope answered {status}")),
+    }
+}
+
+// The labels of every profile pushed, Pyroscope's `{key=value,...}` without the braces
+fn labels(settings: &ProfilingSettings, environment: &str) -> String {
+    [
+        ("service_name".to_string(), env!("CARGO_PKG_NAME").to_string()),
+        ("service_version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ("environment".to_string(), environment.to_string()),
+    ]
+    .into_iter()
+    .chain(settings.tags.clone())
+    .map(|(key, value)| format!("{key}={value}"))
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+// Profiles until the process exits, when `pyroscope_url` is set; untraced, failures are a
+// warning and the next profile is taken all the same
+pub fn spawn_continuous(settings: &ProfilingSettings, environment: &str) -> Result<(), String> {
+    if settings.pyroscope_url.is_none() {
+        return Ok(());
+    }
+    let settings = settings.clone();
+    let labels = labels(&settings, environment);
+    let seconds = settings.interval_secs.max(1);
+    let client = reqwest::Client::builder().timeout(std::time::Duration::from_secs(seconds)).build().map_err(|e| format!("can't build the Pyroscope client: {e}"))?;
+
+    tokio::spawn(async move {
+        loop {
+            // Its turn to the one on demand, which may not be waiting for the profiler yet
+            if ON_DEMAND.load(Ordering::Acquire) {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+            let profiler = PROFILER.lock().await;
+            let Some(window) = open_window() else {
+                tokio::time::sleep(std::time::Duration::from_secs(seconds)).await;
+                continue;
+            };
+            let from = unix_secs();
+            let profiled = move || {
+                let _profiler = profiler;
+                profile(seconds, true, Format::Pprof, window)
+            };
+            let encoded = tokio::task::spawn_blocking(profiled).await.map_err(|e| e.to_string());
+            let pushed = match encoded.and_then(|profiled| profiled) {
+                Ok((body, _)) => push(&client, &settings, &labels, from, unix_secs(), body).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = pushed {
+                tracing::warn!("Failed to push a CPU profile: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{Tracer as _, TracerProvider as _};
+
+    #[test]
+    fn spans_in_the_window_are_tagged() {
+        let exporter = opentelemetry_sdk::testing::trace::InMemorySpanExporter::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_span_processor(crate::telemetry::chain(vec![Box::new(WindowProcessor)], exporter.clone()))
+            .build();
+        let tracer = provider.tracer("test");
+
+        tracer.in_span("before", |_| ());
+        let open = open_window().unwrap();
+        let id = open.id().to_string();
+        assert!(open_window().is_none());
+        let trace_id = tracer.in_span("during", |cx| opentelemetry::trace::TraceContextExt::span(&cx).span_context().trace_id());
+        let window = open.close().unwrap();
+        tracer.in_span("after", |_| ());
+
+        // A profile which panicked leaves the window to the next one
+        let open = open_window().unwrap();
+        let sampling = std::thread::spawn(move || {
+            let _open = open;
+            panic!("sampling failed");
+        });
+        assert!(sampling.join().is_err());
+        drop(open_window().expect("the window was left open"));
+
+        assert_eq!(window.id, id);
+        assert_eq!(window.traces, HashSet::from([trace_id]));
+        let tagged: Vec<_> = exporter
+            .get_finished_spans()
+            .unwrap()
+            .into_iter()
+            .filter(|span| span.attributes.iter().any(|kv| kv.key.as_str() == "pprof.profile_id"))
+            .map(|span| span.name)
+            .collect();
+        assert_eq!(tagged, ["during"]);
+    }
+
+    #[test]
+    fn a_profile_on_demand_ends_the_continuous_one_early() {
+        let continuous = std::thread::spawn(|| {
+            let started = std::time::Instant::now();
+            wait(MAX_SECONDS, true);
+            started.elapsed()
+        });
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let on_demand = OnDemand::take().unwrap();
+        assert!(OnDemand::take().is_none());
+        assert!(continuous.join().unwrap() < std::time::Duration::from_secs(5));
+
+        drop(on_demand);
+        drop(OnDemand::take().expect("the turn was given up"));
+    }
+
+    #[tokio::test]
+    async fn profiles_are_pushed_to_pyroscope_with_the_labels() {
+        let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/ingest",
+            axum::routing::post(move |axum::extract::Query(query): axum::extract::Query<std::collections::HashMap<String, String>>, body: bytes::Bytes| async move {
+                sender.send((query, body)).unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let settings = ProfilingSettings {
+            pyroscope_url: Some(format!("http://{}/", listener.local_addr().unwrap())),
+            tags: [("region".to_string(), "eu-west-1".to_string())].into(),
+            ..Default::default()
+        };
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let labels = labels(&settings, "staging");
+        push(&reqwest::Client::new(), &settings, &labels, 100, 115, b"profile".to_vec()).await.unwrap();
+
+        let (query, body) = received.recv().await.unwrap();
+        assert_eq!(
+            query["name"],
+            format!("rust-trace-minimum.cpu{{service_name=rust-trace-minimum,service_version={},environment=staging,region=eu-west-1}}", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!((query["from"].as_str(), query["until"].as_str(), query["format"].as_str()), ("100", "115", "pprof"));
+        assert_eq!(body.as_ref(), b"profile");
+    }
+}
@@ -0,0 +1,109 @@
+// Carriers for the global text map propagator over `http` header maps
+
+pub struct HeaderExtractor<'a>(pub &'a axum::http::HeaderMap);
+
+impl opentelemetry::propagation::Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+pub struct HeaderInjector<'a>(pub &'a mut axum::http::HeaderMap);
+
+impl opentelemetry::propagation::Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            axum::http::HeaderName::from_bytes(key.as_bytes()),
+            axum::http::HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+// Remote parent of an incoming request, without the entries only this service may set
+pub fn extract(headers: &axum::http::HeaderMap) -> opentelemetry::Context {
+    untrusted(opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers))))
+}
+
+// The tenant comes from the token or the tenant header, through the tenant layer; one in the
+// caller's baggage would reach the SQL comments, the sampler and the metrics unchecked
+fn untrusted(cx: opentelemetry::Context) -> opentelemetry::Context {
+    use opentelemetry::baggage::{BaggageExt, KeyValueMetadata};
+
+    if cx.baggage().get(crate::attributes::TENANT_ID).is_none() {
+        return cx;
+    }
+    let kept: Vec<_> = cx
+        .baggage()
+        .iter()
+        .filter(|(key, _)| **key != crate::attributes::TENANT_ID)
+        .map(|(key, (value, metadata))| KeyValueMetadata::new(key.clone(), value.clone(), metadata.clone()))
+        .collect();
+    cx.with_cleared_baggage().with_baggage(kept)
+}
+
+// The span's context has the caller's baggage; what middleware added to it (the tenant,
+// the experiment) is in the current context, which wins
+fn outgoing_context(span: &tracing::Span) -> opentelemetry::Context {
+    use opentelemetry::baggage::{BaggageExt, KeyValueMetadata};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let current = opentelemetry::Context::current();
+    let added = current
+        .baggage()
+        .iter()
+        .map(|(key, (value, metadata))| KeyValueMetadata::new(key.clone(), value.clone(), metadata.clone()));
+    span.context().with_baggage(added)
+}
+
+// Context of `span` into outgoing request headers
+pub fn inject(span: &tracing::Span, headers: &mut axum::http::HeaderMap) {
+    let cx = outgoing_context(span);
+    opentelemetry::global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut HeaderInjector(headers)));
+}
+
+// `inject` for clients with header maps of their own, the AWS SDK's say
+#[cfg_attr(not(feature = "aws"), allow(dead_code))]
+pub fn inject_with(span: &tracing::Span, mut set: impl FnMut(&str, String)) {
+    struct Setter<'a, F>(&'a mut F);
+
+    impl<F: FnMut(&str, String)> opentelemetry::propagation::Injector for Setter<'_, F> {
+        fn set(&mut self, key: &str, value: String) {
+            (self.0)(key, value)
+        }
+    }
+
+    let cx = outgoing_context(span);
+    opentelemetry::global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut Setter(&mut set)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::baggage::BaggageExt;
+    use opentelemetry::trace::FutureExt;
+
+    #[tokio::test]
+    async fn hands_on_baggage_added_by_middleware() {
+        let _telemetry = crate::test_support::init();
+        let cx = opentelemetry::Context::current().with_baggage([opentelemetry::KeyValue::new("experiment.variant", "new")]);
+
+        let outgoing = async { outgoing_context(&tracing::info_span!("GET")) }.with_context(cx).await;
+        assert_eq!(outgoing.baggage().get("experiment.variant").map(|value| value.to_string()).as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn drops_the_callers_tenant() {
+        let caller = opentelemetry::Context::new()
+            .with_baggage([opentelemetry::KeyValue::new("tenant.id", "acme"), opentelemetry::KeyValue::new("session.id", "s-1")]);
+
+        let cx = untrusted(caller);
+        assert!(cx.baggage().get(crate::attributes::TENANT_ID).is_none());
+        assert_eq!(cx.baggage().get("session.id").map(|value| value.to_string()).as_deref(), Some("s-1"));
+    }
+}
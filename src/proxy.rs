@@ -0,0 +1,238 @@
+// The client behind the load balancer. Forwarded and X-Forwarded-For are believed only from
+// `server.trusted_proxies`, walked from the nearest hop back to the first address which
+// isn't a trusted proxy; anyone could send them otherwise. With `server.proxy_protocol` every
+// connection starts with a PROXY protocol header (v1 or v2) naming the client instead.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+
+// A proxy which doesn't send its header by then is dropped
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// v1 lines are at most 107 bytes, CRLF included
+const V1_MAX: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(cidr: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid trusted proxy {cidr:?}, expected an address or CIDR block");
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (cidr, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= bits).ok_or_else(invalid)?,
+            None => bits,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        fn masked(bits: u128, prefix: u32, width: u32) -> u128 {
+            match prefix {
+                0 => 0,
+                prefix => bits >> (width - prefix),
+            }
+        }
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => masked(u32::from(net).into(), self.prefix, 32) == masked(u32::from(ip).into(), self.prefix, 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => masked(net.into(), self.prefix, 128) == masked(ip.into(), self.prefix, 128),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    cidrs: Vec<Cidr>,
+}
+
+// `for=` of each Forwarded element, nearest hop last; quoted, bracketed IPv6 and ports
+// allowed, obfuscated and "unknown" identifiers kept as unparseable
+fn forwarded_for(value: &str) -> impl Iterator<Item = Option<IpAddr>> + '_ {
+    value.split(',').filter_map(|element| {
+        let node = element.split(';').find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            key.eq_ignore_ascii_case("for").then(|| value.trim().trim_matches('"'))
+        })?;
+        Some(parse_node(node))
+    })
+}
+
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse().ok().or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+impl TrustedProxies {
+    pub fn new(cidrs: &[String]) -> Result<Self, String> {
+        let cidrs = cidrs.iter().map(|cidr| Cidr::parse(cidr)).collect::<Result<_, _>>()?;
+        Ok(Self { cidrs })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // The peer unless it's a trusted proxy, then the nearest forwarded address which isn't;
+    // `None` for a peer on the Unix socket, the proxy in front being local. Forwarded wins
+    // over X-Forwarded-For when both are sent
+    pub fn client_address(&self, peer: Option<IpAddr>, headers: &axum::http::HeaderMap) -> Option<IpAddr> {
+        if peer.is_some_and(|peer| !self.contains(peer)) {
+            return peer;
+        }
+
+        let values = |name| headers.get_all(name).iter().filter_map(|value| value.to_str().ok()).collect::<Vec<_>>();
+        let forwarded = values(axum::http::header::FORWARDED);
+        let hops: Vec<Option<IpAddr>> = if !forwarded.is_empty() {
+            forwarded.iter().flat_map(|value| forwarded_for(value)).collect()
+        } else {
+            values(axum::http::HeaderName::from_static("x-forwarded-for"))
+                .iter()
+                .flat_map(|value| value.split(','))
+                .map(|hop| parse_node(hop.trim()))
+                .collect()
+        };
+
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            // Whatever came before an unparseable hop can't be told apart from a forgery
+            let Some(hop) = hop else { break };
+            client = Some(hop);
+            if !self.contains(hop) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+async fn read_v1<R: tokio::io::AsyncRead + Unpin>(stream: &mut R, mut line: Vec<u8>, peer: SocketAddr) -> std::io::Result<SocketAddr> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("PROXY header: {message}"));
+
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX {
+            return Err(invalid("line too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(peer),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("bad source address"))?;
+            let port: u16 = source_port.parse().map_err(|_| invalid("bad source port"))?;
+            Ok(SocketAddr::new(ip, port))
+        }
+        _ => Err(invalid("malformed v1 line")),
+    }
+}
+
+async fn read_v2<R: tokio::io::AsyncRead + Unpin>(stream: &mut R, peer: SocketAddr) -> std::io::Result<SocketAddr> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("PROXY header: {message}"));
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, length @ ..] = header;
+    let mut addresses = vec![0u8; usize::from(u16::from_be_bytes(length))];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unknown v2 version"));
+    }
+    // LOCAL: the proxy's own connection, health checks say
+    if version_command & 0x0f == 0 {
+        return Ok(peer);
+    }
+
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family >> 4 {
+        1 if addresses.len() >= 12 => Ok(SocketAddr::new(<[u8; 4]>::try_from(&addresses[..4]).unwrap().into(), port(8))),
+        2 if addresses.len() >= 36 => Ok(SocketAddr::new(<[u8; 16]>::try_from(&addresses[..16]).unwrap().into(), port(32))),
+        // Unix sockets and unspecified families carry no client address
+        _ => Ok(peer),
+    }
+}
+
+// Reads the PROXY header a connection starts with, and the client it names; the proxy's
+// own address when the header has none
+pub async fn read_header<R: tokio::io::AsyncRead + Unpin>(stream: &mut R, peer: SocketAddr) -> std::io::Result<SocketAddr> {
+    let read = async {
+        // Shorter than either version's header can be
+        let mut start = vec![0u8; V2_SIGNATURE.len()];
+        stream.read_exact(&mut start).await?;
+        if start.as_slice() == V2_SIGNATURE {
+            read_v2(stream, peer).await
+        } else if start.starts_with(b"PROXY ") {
+            read_v1(stream, start, peer).await
+        } else {
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "no PROXY header"))
+        }
+    };
+    tokio::time::timeout(HEADER_TIMEOUT, read)
+        .await
+        .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "no PROXY header in time")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_addresses_from_trusted_proxies_only() {
+        let proxies = TrustedProxies::new(&["10.0.0.0/8".to_string(), "::1".to_string()]).unwrap();
+        let headers = |name: &'static str, value: &'static str| {
+            let mut headers = axum::http::HeaderMap::new();
+            headers.insert(name, axum::http::HeaderValue::from_static(value));
+            headers
+        };
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+
+        // The client forged the first hop, the load balancer appended the real one
+        let xff = headers("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.1.2.3");
+        assert_eq!(proxies.client_address(ip("10.0.0.1"), &xff), ip("203.0.113.7"));
+        assert_eq!(proxies.client_address(ip("198.51.100.1"), &xff), ip("198.51.100.1"));
+
+        let forwarded = headers("forwarded", r#"for=192.0.2.60;proto=http, for="[2001:db8::1]:4711""#);
+        assert_eq!(proxies.client_address(ip("::ffff:10.0.0.1"), &forwarded), ip("2001:db8::1"));
+        assert_eq!(proxies.client_address(None, &headers("forwarded", "for=unknown")), None);
+
+        assert!(TrustedProxies::new(&["10.0.0.0/33".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_headers() {
+        let peer: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let client: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+
+        let mut v1: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.2 51234 443\r\nGET /";
+        assert_eq!(read_header(&mut v1, peer).await.unwrap(), client);
+        assert_eq!(v1, b"GET /");
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x11, 0, 12, 203, 0, 113, 7, 10, 0, 0, 2]);
+        v2.extend(51234u16.to_be_bytes());
+        v2.extend(443u16.to_be_bytes());
+        assert_eq!(read_header(&mut v2.as_slice(), peer).await.unwrap(), client);
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut local.as_slice(), peer).await.unwrap(), peer);
+
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\n\r\n"[..], peer).await.is_err());
+    }
+}
@@ -0,0 +1,158 @@
+// Redis pub/sub messages carrying the publisher's trace context. `publish` wraps the payload
+// in a JSON envelope with the propagator's fields, `traceparent` and the like, under a
+// "publish {channel}" producer span; `subscribe` unwraps each message into a
+// "process {channel}" consumer span, a child of the publisher's, its handler running inside.
+// Both have the `messaging.*` attributes, so a cache invalidation fanned out to every
+// instance is one trace. A message without the envelope is handled as it is, in a trace of
+// its own.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::redis::{Client, Reply};
+
+// Before subscribing again after losing the connection
+const RESUBSCRIBE: Duration = Duration::from_secs(1);
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Envelope {
+    context: HashMap<String, String>,
+    payload: String,
+}
+
+// The subscribers which got it
+#[cfg_attr(not(feature = "mysql"), allow(dead_code))]
+pub async fn publish(client: &Client, channel: &str, payload: &str) -> Result<i64, String> {
+    let span = tracing::info_span!(
+        "messaging publish",
+        otel.name = format!("publish {channel}"),
+        otel.kind = "producer",
+        messaging.system = "redis",
+        "messaging.operation.type" = "send",
+        messaging.operation.name = "publish",
+        messaging.destination.name = channel,
+        messaging.message.body.size = payload.len() as i64,
+        messaging.redis.receivers = tracing::field::Empty,
+    );
+    let mut context = HashMap::new();
+    crate::propagation::inject_with(&span, |key, value| {
+        context.insert(key.to_string(), value);
+    });
+    let envelope = serde_json::to_vec(&Envelope { context, payload: payload.to_string() }).map_err(|e| e.to_string())?;
+
+    match client.command(&[b"PUBLISH", channel.as_bytes(), &envelope]).instrument(span.clone()).await? {
+        Reply::Integer(receivers) => {
+            span.record("messaging.redis.receivers", receivers);
+            Ok(receivers)
+        }
+        _ => Err("unexpected reply to PUBLISH".to_string()),
+    }
+}
+
+// Hands each message of `channel` to `handler`, on a connection of its own, subscribing
+// again whenever it's lost; until the task is aborted
+pub fn subscribe<F, Fut>(client: Arc<Client>, channel: String, handler: F) -> tokio::task::JoinHandle<()>
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = listen(&client, &channel, &handler).await {
+                tracing::warn!(error = e, messaging.destination.name = channel, "Redis subscription lost, subscribing again");
+            }
+            tokio::time::sleep(RESUBSCRIBE).await;
+        }
+    })
+}
+
+async fn listen<F, Fut>(client: &Client, channel: &str, handler: &F) -> Result<(), String>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut connection = tokio::time::timeout(crate::redis::TIMEOUT, client.connect()).await.map_err(|_| "timed out connecting".to_string())??;
+    crate::redis::send(&mut connection, &[b"SUBSCRIBE", channel.as_bytes()]).await?;
+    loop {
+        // `message`, the channel and the payload; the subscription's confirmation aside
+        if let Reply::Array(parts) = crate::redis::read(&mut connection).await? {
+            if let [Reply::Bulk(Some(kind)), _, Reply::Bulk(Some(payload))] = parts.as_slice() {
+                if kind == b"message" {
+                    process(channel, payload, handler).await;
+                }
+            }
+        }
+    }
+}
+
+async fn process<F, Fut>(channel: &str, message: &[u8], handler: &F)
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let span = tracing::info_span!(
+        "messaging process",
+        otel.name = format!("process {channel}"),
+        otel.kind = "consumer",
+        messaging.system = "redis",
+        "messaging.operation.type" = "process",
+        messaging.operation.name = "process",
+        messaging.destination.name = channel,
+        messaging.message.body.size = tracing::field::Empty,
+    );
+    let payload = match serde_json::from_slice::<Envelope>(message) {
+        Ok(envelope) => {
+            span.set_parent(opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(&envelope.context)));
+            envelope.payload
+        }
+        Err(_) => String::from_utf8_lossy(message).into_owned(),
+    };
+    span.record("messaging.message.body.size", payload.len() as i64);
+    handler(payload).instrument(span).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fake_redis;
+
+    #[tokio::test]
+    async fn the_subscriber_continues_the_publishers_trace() {
+        let telemetry = crate::test_support::init();
+        opentelemetry::global::set_text_map_propagator(opentelemetry_sdk::propagation::TraceContextPropagator::new());
+
+        let client = Arc::new(Client::new(&fake_redis().await).unwrap());
+        let (handled, mut handling) = tokio::sync::mpsc::unbounded_channel();
+        let subscription = subscribe(client.clone(), "invalidations".to_string(), move |payload| {
+            let handled = handled.clone();
+            async move {
+                let _ = handled.send(payload);
+            }
+        });
+
+        // Until the subscriber is there to get it
+        while publish(&client, "invalidations", "/items/1").await.unwrap() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(handling.recv().await.as_deref(), Some("/items/1"));
+        subscription.abort();
+
+        let spans = loop {
+            let spans = telemetry.spans();
+            if spans.find("process invalidations").is_some() {
+                break spans;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        spans
+            .assert_span_exists("process invalidations")
+            .with_attribute("messaging.operation.type", "process")
+            .with_attribute("messaging.destination.name", "invalidations")
+            .child_of("publish invalidations");
+    }
+}
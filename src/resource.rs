@@ -0,0 +1,321 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::resource::{EnvResourceDetector, ResourceDetector};
+use opentelemetry_sdk::Resource;
+
+// Runs the detectors named in the settings and merges what they found; an unknown
+// name is a configuration error. Detectors may block, call this off the runtime.
+pub fn detect(names: &[String], timeout: Duration, cloud_cache: Option<&str>) -> Result<Resource, String> {
+    let detectors = names
+        .iter()
+        .map(|name| -> Result<Box<dyn ResourceDetector>, String> {
+            Ok(match name.as_str() {
+                "env" => Box::new(EnvResourceDetector::new()),
+                #[cfg(feature = "resource-detectors")]
+                "host" => Box::new(opentelemetry_resource_detectors::HostResourceDetector::default()),
+                #[cfg(feature = "resource-detectors")]
+                "os" => Box::new(opentelemetry_resource_detectors::OsResourceDetector),
+                #[cfg(feature = "resource-detectors")]
+                "process" => Box::new(opentelemetry_resource_detectors::ProcessResourceDetector),
+                #[cfg(not(feature = "resource-detectors"))]
+                "host" | "os" | "process" => {
+                    return Err(format!("resource detector {name:?} needs the resource-detectors feature"))
+                }
+                "container" => Box::new(ContainerResourceDetector),
+                "kubernetes" => Box::new(KubernetesResourceDetector),
+                "ec2" => Box::new(Ec2ResourceDetector),
+                "gce" => Box::new(GceResourceDetector),
+                "azure" => Box::new(AzureResourceDetector),
+                "cloud" => Box::new(CloudResourceDetector { cache: cloud_cache.map(PathBuf::from) }),
+                other => return Err(format!("unknown resource detector {other:?}")),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Resource::from_detectors(timeout, detectors))
+}
+
+// Container ID from the cgroup paths of this process, which end in the 64 hex digit ID
+// under Docker, containerd and CRI-O
+struct ContainerResourceDetector;
+
+impl ResourceDetector for ContainerResourceDetector {
+    fn detect(&self, _timeout: Duration) -> Resource {
+        let id = ["/proc/self/cgroup", "/proc/self/mountinfo"]
+            .iter()
+            .filter_map(|path| std::fs::read_to_string(path).ok())
+            .find_map(|contents| contents.lines().find_map(container_id));
+
+        Resource::new(id.map(|id| KeyValue::new("container.id", id)))
+    }
+}
+
+fn container_id(line: &str) -> Option<String> {
+    line.split(['/', ' ', '-', ':', '.'])
+        .find(|part| part.len() == 64 && part.bytes().all(|b| b.is_ascii_hexdigit()))
+        .map(str::to_string)
+}
+
+// Pod metadata from the downward API variables set in the deployment manifest
+// (POD_NAME, POD_NAMESPACE, POD_UID, NODE_NAME), falling back to what every pod has
+struct KubernetesResourceDetector;
+
+const SERVICE_ACCOUNT_NAMESPACE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+impl ResourceDetector for KubernetesResourceDetector {
+    fn detect(&self, _timeout: Duration) -> Resource {
+        if std::env::var_os("KUBERNETES_SERVICE_HOST").is_none() {
+            return Resource::empty();
+        }
+
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let pod_name = env("POD_NAME").or_else(|| env("HOSTNAME"));
+        let namespace = env("POD_NAMESPACE").or_else(|| {
+            std::fs::read_to_string(SERVICE_ACCOUNT_NAMESPACE).ok().map(|namespace| namespace.trim().to_string())
+        });
+
+        Resource::new(
+            [
+                pod_name.map(|value| KeyValue::new("k8s.pod.name", value)),
+                env("POD_UID").map(|value| KeyValue::new("k8s.pod.uid", value)),
+                namespace.map(|value| KeyValue::new("k8s.namespace.name", value)),
+                env("NODE_NAME").map(|value| KeyValue::new("k8s.node.name", value)),
+            ]
+            .into_iter()
+            .flatten(),
+        )
+    }
+}
+
+// Instance identity from IMDSv2. Off by default, outside EC2 it waits for the timeout.
+// Plain HTTP/1.0 on a std socket, since detectors are synchronous; GCE's and Azure's
+// metadata servers are at the same address.
+struct Ec2ResourceDetector;
+
+const IMDS_ADDR: ([u8; 4], u16) = ([169, 254, 169, 254], 80);
+
+impl ResourceDetector for Ec2ResourceDetector {
+    fn detect(&self, timeout: Duration) -> Resource {
+        let deadline = Instant::now() + timeout;
+        let document = imds_request(deadline, "PUT", "/latest/api/token", "X-aws-ec2-metadata-token-ttl-seconds: 60")
+            .and_then(|token| {
+                imds_request(
+                    deadline,
+                    "GET",
+                    "/latest/dynamic/instance-identity/document",
+                    &format!("X-aws-ec2-metadata-token: {token}"),
+                )
+            })
+            .and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok());
+
+        let Some(document) = document else {
+            return Resource::empty();
+        };
+        let field = |name: &str| document.get(name).and_then(|value| value.as_str()).map(str::to_string);
+
+        Resource::new(
+            [
+                Some(KeyValue::new("cloud.provider", "aws")),
+                Some(KeyValue::new("cloud.platform", "aws_ec2")),
+                field("region").map(|value| KeyValue::new("cloud.region", value)),
+                field("availabilityZone").map(|value| KeyValue::new("cloud.availability_zone", value)),
+                field("accountId").map(|value| KeyValue::new("cloud.account.id", value)),
+                field("instanceId").map(|value| KeyValue::new("host.id", value)),
+                field("instanceType").map(|value| KeyValue::new("host.type", value)),
+                field("imageId").map(|value| KeyValue::new("host.image.id", value)),
+            ]
+            .into_iter()
+            .flatten(),
+        )
+    }
+}
+
+// Body of a successful response, None on any failure or once the deadline passes
+fn imds_request(deadline: Instant, method: &str, path: &str, header: &str) -> Option<String> {
+    let remaining = || deadline.checked_duration_since(Instant::now()).filter(|d| !d.is_zero());
+
+    let mut stream = TcpStream::connect_timeout(&SocketAddr::from(IMDS_ADDR), remaining()?).ok()?;
+    // None would be no timeout at all, past the deadline it's given up on instead
+    stream.set_read_timeout(Some(remaining()?)).ok()?;
+    stream.set_write_timeout(Some(remaining()?)).ok()?;
+    write!(stream, "{method} {path} HTTP/1.0\r\nHost: 169.254.169.254\r\n{header}\r\nContent-Length: 0\r\n\r\n").ok()?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    (head.split_whitespace().nth(1) == Some("200")).then(|| body.trim().to_string())
+}
+
+// The instance from GCE's metadata server
+struct GceResourceDetector;
+
+impl ResourceDetector for GceResourceDetector {
+    fn detect(&self, timeout: Duration) -> Resource {
+        let deadline = Instant::now() + timeout;
+        let get = |path: &str| imds_request(deadline, "GET", &format!("/computeMetadata/v1/{path}"), "Metadata-Flavor: Google");
+        let Some(instance) = get("instance/?recursive=true").and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok()) else {
+            return Resource::empty();
+        };
+        gce_resource(&instance, get("project/project-id"))
+    }
+}
+
+// `zone` and `machineType` come as paths, projects/123/zones/europe-west1-b say
+fn gce_resource(instance: &serde_json::Value, project: Option<String>) -> Resource {
+    let last = |name: &str| instance.get(name).and_then(|value| value.as_str()).and_then(|path| path.rsplit('/').next()).map(str::to_string);
+    let zone = last("zone");
+    let id = instance.get("id").and_then(|id| id.as_u64().map(|id| id.to_string()).or_else(|| id.as_str().map(str::to_string)));
+
+    Resource::new(
+        [
+            Some(KeyValue::new("cloud.provider", "gcp")),
+            Some(KeyValue::new("cloud.platform", "gcp_compute_engine")),
+            zone.as_deref().and_then(|zone| zone.rsplit_once('-')).map(|(region, _)| KeyValue::new("cloud.region", region.to_string())),
+            zone.map(|value| KeyValue::new("cloud.availability_zone", value)),
+            project.map(|value| KeyValue::new("cloud.account.id", value)),
+            id.map(|value| KeyValue::new("host.id", value)),
+            last("machineType").map(|value| KeyValue::new("host.type", value)),
+            last("name").map(|value| KeyValue::new("host.name", value)),
+        ]
+        .into_iter()
+        .flatten(),
+    )
+}
+
+// The VM from Azure's Instance Metadata Service
+struct AzureResourceDetector;
+
+impl ResourceDetector for AzureResourceDetector {
+    fn detect(&self, timeout: Duration) -> Resource {
+        let deadline = Instant::now() + timeout;
+        let document = imds_request(deadline, "GET", "/metadata/instance/compute?api-version=2021-02-01", "Metadata: true")
+            .and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok());
+        document.map(|compute| azure_resource(&compute)).unwrap_or_else(Resource::empty)
+    }
+}
+
+fn azure_resource(compute: &serde_json::Value) -> Resource {
+    let field = |name: &str| compute.get(name).and_then(|value| value.as_str()).filter(|value| !value.is_empty()).map(str::to_string);
+    let region = field("location");
+
+    Resource::new(
+        [
+            Some(KeyValue::new("cloud.provider", "azure")),
+            Some(KeyValue::new("cloud.platform", "azure_vm")),
+            // Zones are numbered within the region, "1" to "3"
+            field("zone").zip(region.clone()).map(|(zone, region)| KeyValue::new("cloud.availability_zone", format!("{region}-{zone}"))),
+            region.map(|value| KeyValue::new("cloud.region", value)),
+            field("subscriptionId").map(|value| KeyValue::new("cloud.account.id", value)),
+            field("vmId").map(|value| KeyValue::new("host.id", value)),
+            field("vmSize").map(|value| KeyValue::new("host.type", value)),
+            field("name").map(|value| KeyValue::new("host.name", value)),
+        ]
+        .into_iter()
+        .flatten(),
+    )
+}
+
+// Whichever of EC2, GCE and Azure answers, asked at once so off the cloud it's one timeout
+// rather than three. With `cache`, what was found is kept there for the rest of the boot: a
+// restart doesn't ask again, and a new instance or a reboot does. Nothing found isn't kept,
+// a metadata service slow to answer once would otherwise go unasked until the next boot.
+struct CloudResourceDetector {
+    cache: Option<PathBuf>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedResource {
+    boot_id: String,
+    attributes: std::collections::BTreeMap<String, String>,
+}
+
+fn boot_id() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/random/boot_id").ok().map(|id| id.trim().to_string())
+}
+
+fn read_cache(path: &std::path::Path, boot_id: &str) -> Option<Resource> {
+    let cached: CachedResource = serde_json::from_slice(&std::fs::read(path).ok()?).ok()?;
+    (cached.boot_id == boot_id && !cached.attributes.is_empty()).then(|| Resource::new(cached.attributes.into_iter().map(|(key, value)| KeyValue::new(key, value))))
+}
+
+fn write_cache(path: &std::path::Path, boot_id: &str, resource: &Resource) {
+    if resource.is_empty() {
+        let _ = std::fs::remove_file(path);
+        return;
+    }
+    let cached = CachedResource {
+        boot_id: boot_id.to_string(),
+        attributes: resource.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+    };
+    // Detected again next time if it can't be written
+    let _ = serde_json::to_vec(&cached).map(|json| std::fs::write(path, json));
+}
+
+impl ResourceDetector for CloudResourceDetector {
+    fn detect(&self, timeout: Duration) -> Resource {
+        let cache = self.cache.as_deref().zip(boot_id());
+        if let Some(resource) = cache.as_ref().and_then(|(path, boot_id)| read_cache(path, boot_id)) {
+            return resource;
+        }
+
+        let detectors: [&(dyn ResourceDetector + Sync); 3] = [&Ec2ResourceDetector, &GceResourceDetector, &AzureResourceDetector];
+        let resource = std::thread::scope(|scope| {
+            let detecting: Vec<_> = detectors.iter().map(|detector| scope.spawn(move || detector.detect(timeout))).collect();
+            detecting.into_iter().filter_map(|detecting| detecting.join().ok()).find(|resource| !resource.is_empty())
+        })
+        .unwrap_or_else(Resource::empty);
+
+        if let Some((path, boot_id)) = &cache {
+            write_cache(path, boot_id, &resource);
+        }
+        resource
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(resource: &Resource, key: &'static str) -> Option<String> {
+        resource.get(opentelemetry::Key::from_static_str(key)).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn regions_and_zones_from_the_metadata() {
+        let gce = gce_resource(
+            &serde_json::json!({ "id": 4520031799277581759u64, "zone": "projects/123/zones/europe-west1-b", "machineType": "projects/123/machineTypes/e2-medium" }),
+            Some("shop-prod".to_string()),
+        );
+        assert_eq!(attribute(&gce, "cloud.region").as_deref(), Some("europe-west1"));
+        assert_eq!(attribute(&gce, "cloud.availability_zone").as_deref(), Some("europe-west1-b"));
+        assert_eq!(attribute(&gce, "host.type").as_deref(), Some("e2-medium"));
+        assert_eq!(attribute(&gce, "host.id").as_deref(), Some("4520031799277581759"));
+
+        let azure = azure_resource(&serde_json::json!({ "location": "westeurope", "zone": "2", "vmSize": "Standard_D2s_v3" }));
+        assert_eq!(attribute(&azure, "cloud.region").as_deref(), Some("westeurope"));
+        assert_eq!(attribute(&azure, "cloud.availability_zone").as_deref(), Some("westeurope-2"));
+        assert_eq!(attribute(&azure, "host.type").as_deref(), Some("Standard_D2s_v3"));
+    }
+
+    #[test]
+    fn the_cache_lasts_the_boot() {
+        let path = std::env::temp_dir().join(format!("cloud-resource-{}.json", std::process::id()));
+        let resource = Resource::new([KeyValue::new("cloud.region", "eu-west-1")]);
+        write_cache(&path, "boot-1", &resource);
+
+        let cached = read_cache(&path, "boot-1").unwrap();
+        let next_boot = read_cache(&path, "boot-2");
+        // Finding nothing, it's asked again next time
+        write_cache(&path, "boot-1", &Resource::empty());
+        let failed = read_cache(&path, "boot-1");
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(attribute(&cached, "cloud.region").as_deref(), Some("eu-west-1"));
+        assert!(next_boot.is_none());
+        assert!(failed.is_none());
+    }
+}
@@ -0,0 +1,60 @@
+// Responses which record how they turned out on the span they were made in: the status,
+// the body size, and how long making the response (serializing, mostly) took
+
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub struct Traced<T> {
+    inner: T,
+    // Current when the handler made it; axum converts it after the handler's span is left
+    span: tracing::Span,
+}
+
+impl<T> Traced<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner, span: tracing::Span::current() }
+    }
+}
+
+impl<T: axum::response::IntoResponse> axum::response::IntoResponse for Traced<T> {
+    fn into_response(self) -> axum::response::Response {
+        let started = std::time::Instant::now();
+        let response = self.inner.into_response();
+        let elapsed = started.elapsed();
+
+        // Attributes rather than fields, which the span would have to declare; but not twice
+        // on the request span, or a `#[traced_handler]` one
+        let status = response.status().as_u16();
+        if self.span.has_field("http.response.status_code") {
+            self.span.record("http.response.status_code", status);
+        } else {
+            self.span.set_attribute("http.response.status_code", i64::from(status));
+        }
+        self.span.set_attribute("http.response.serialize_us", elapsed.as_micros() as i64);
+        // Unknown for streamed bodies
+        if let Some(size) = http_body::Body::size_hint(response.body()).exact() {
+            self.span.set_attribute("http.response.body.size", size as i64);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    #[test]
+    fn records_the_outcome_on_the_span_it_was_made_in() {
+        let telemetry = crate::test_support::init();
+
+        let response = tracing::info_span!("handler").in_scope(|| Traced::new(axum::Json(serde_json::json!({"ok": true}))));
+        assert_eq!(response.into_response().status(), axum::http::StatusCode::OK);
+
+        telemetry
+            .spans()
+            .assert_span_exists("handler")
+            .with_attribute("http.response.status_code", 200)
+            .with_attribute("http.response.body.size", 11)
+            .with_attribute_present("http.response.serialize_us");
+    }
+}
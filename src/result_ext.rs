@@ -0,0 +1,92 @@
+// Errors recorded where they are handled, in one call: an event on the current span with
+// the error, its type, its sources and, when RUST_BACKTRACE asks for one, a backtrace; and
+// the span's status set to the error
+
+// Only the database handlers and the mailer use it so far
+#![cfg_attr(not(any(feature = "mysql", feature = "email")), allow(dead_code))]
+
+use std::backtrace::{Backtrace, BacktraceStatus};
+
+use tracing::Level;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::registry::LookupSpan;
+
+pub trait ResultExt {
+    // Records an `Err` at ERROR level and passes the result on
+    fn trace_err(self) -> Self;
+
+    // The same at `level`, for errors which are expected now and then; the span's status
+    // is an error regardless
+    fn trace_err_with(self, level: Level) -> Self;
+}
+
+impl<T, E: std::error::Error + 'static> ResultExt for Result<T, E> {
+    fn trace_err(self) -> Self {
+        self.trace_err_with(Level::ERROR)
+    }
+
+    fn trace_err_with(self, level: Level) -> Self {
+        if let Err(e) = &self {
+            record(level, e);
+        }
+        self
+    }
+}
+
+fn record<E: std::error::Error + 'static>(level: Level, error: &E) {
+    let backtrace = Backtrace::capture();
+    let backtrace = (backtrace.status() == BacktraceStatus::Captured).then(|| tracing::field::display(&backtrace));
+    let error_type = std::any::type_name::<E>();
+    let message = error.to_string();
+    // The OpenTelemetry layer turns an error field into `exception.message` and
+    // `exception.stacktrace`, the messages of its sources
+    let error = error as &(dyn std::error::Error + 'static);
+
+    macro_rules! event {
+        ($level:expr) => {
+            tracing::event!($level, error, "error.type" = error_type, error.backtrace = backtrace, "{}", message)
+        };
+    }
+    match level {
+        Level::ERROR => event!(Level::ERROR),
+        Level::WARN => event!(Level::WARN),
+        Level::INFO => event!(Level::INFO),
+        Level::DEBUG => event!(Level::DEBUG),
+        Level::TRACE => event!(Level::TRACE),
+    }
+
+    set_error_status(&tracing::Span::current(), message);
+}
+
+// `otel.status_code` only works for spans declaring it, this goes to the span data instead
+pub fn set_error_status(span: &tracing::Span, message: String) {
+    span.with_subscriber(|(id, dispatch)| {
+        let Some(span) = dispatch.downcast_ref::<tracing_subscriber::Registry>().and_then(|registry| registry.span(id)) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<OtelData>() {
+            data.builder.status = opentelemetry::trace::Status::error(message);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_the_error_on_the_current_span() {
+        let telemetry = crate::test_support::init();
+
+        tracing::info_span!("parse").in_scope(|| {
+            let _ = "twelve".parse::<u32>().trace_err_with(Level::WARN);
+        });
+
+        let spans = telemetry.spans();
+        let span = spans.assert_span_exists("parse").with_error_status().span();
+        let event = &span.events.events[0];
+        assert!(event.attributes.iter().any(|kv| kv.key.as_str() == "error.type" && kv.value.as_str() == "core::num::error::ParseIntError"));
+        assert!(event.attributes.iter().any(|kv| kv.key.as_str() == "exception.message" && kv.value.as_str() == "invalid digit found in string"));
+    }
+}
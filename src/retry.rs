@@ -0,0 +1,136 @@
+// Timeouts and retries of the calls to one dependency, from its `[dependencies.*]` section.
+// Connect timeouts are applied by the dependency's client; `run` retries failures worth
+// retrying with a doubling backoff, and records `retry.count` and `timeout.configured_ms`
+// on the current span; an attempt finds how many retries came before it with `retries`, for
+// the spans of its own.
+
+use std::future::Future;
+use std::time::Duration;
+
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::DependencySettings;
+
+tokio::task_local! {
+    // Of the `run` the attempt being polled belongs to
+    static RETRIES: u32;
+}
+
+// The retries before the attempt of `run` this is called in, 0 outside one
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+pub fn retries() -> u32 {
+    RETRIES.try_with(|retries| *retries).unwrap_or(0)
+}
+
+// The error of an attempt which ran out of time in `run_timed`
+pub trait TimedOut {
+    fn timed_out(timeout: Duration) -> Self;
+}
+
+#[cfg(feature = "mysql")]
+impl TimedOut for sqlx::Error {
+    // An I/O error, so the circuit breaker counts it against the database
+    fn timed_out(timeout: Duration) -> Self {
+        let message = format!("no reply within {}ms", timeout.as_millis());
+        sqlx::Error::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, message))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    settings: DependencySettings,
+}
+
+impl Policy {
+    pub fn new(settings: DependencySettings) -> Self {
+        Self { settings }
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.settings.connect_timeout_ms)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_millis(self.settings.read_timeout_ms)
+    }
+
+    // For clients which time out reads themselves
+    pub async fn run<T, E, F, Fut>(&self, is_retryable: impl Fn(&E) -> bool, mut call: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let span = tracing::Span::current();
+        span.set_attribute("timeout.configured_ms", self.settings.read_timeout_ms as i64);
+
+        let mut retries = 0;
+        loop {
+            match RETRIES.scope(retries, call()).await {
+                Err(e) if retries < self.settings.retries && is_retryable(&e) => {
+                    retries += 1;
+                    let backoff = Duration::from_millis(self.settings.retry_backoff_ms) * 2u32.saturating_pow(retries - 1);
+                    tracing::info!(retry.attempt = retries, retry.backoff_ms = backoff.as_millis() as u64, "Retrying failed call");
+                    tokio::time::sleep(backoff).await;
+                }
+                result => {
+                    span.set_attribute("retry.count", i64::from(retries));
+                    return result;
+                }
+            }
+        }
+    }
+
+    // `run` with each attempt cut off after the read timeout
+    #[cfg_attr(not(any(feature = "mysql", feature = "email")), allow(dead_code))]
+    pub async fn run_timed<T, E, F, Fut>(&self, is_retryable: impl Fn(&E) -> bool, mut call: F) -> Result<T, E>
+    where
+        E: TimedOut,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        self.run(is_retryable, || self.timed(call())).await
+    }
+
+    // One attempt, cut off after the read timeout
+    #[cfg_attr(not(any(feature = "mysql", feature = "email")), allow(dead_code))]
+    pub async fn timed<T, E: TimedOut>(&self, call: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+        let timeout = self.read_timeout();
+        tokio::time::timeout(timeout, call).await.unwrap_or_else(|_| Err(E::timed_out(timeout)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn retries_and_records_how_often() {
+        let telemetry = crate::test_support::init();
+        let policy = Policy::new(DependencySettings {
+            connect_timeout_ms: 100,
+            read_timeout_ms: 250,
+            retries: 2,
+            retry_backoff_ms: 1,
+        });
+
+        let mut attempts = 0;
+        let result: Result<(), &str> = tracing::Instrument::instrument(
+            policy.run(
+                |_| true,
+                || {
+                    attempts += 1;
+                    std::future::ready(if attempts < 2 { Err("refused") } else { Ok(()) })
+                },
+            ),
+            tracing::info_span!("call"),
+        )
+        .await;
+        assert!(result.is_ok());
+
+        telemetry
+            .spans()
+            .assert_span_exists("call")
+            .with_attribute("retry.count", 1)
+            .with_attribute("timeout.configured_ms", 250);
+    }
+}
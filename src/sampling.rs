@@ -0,0 +1,408 @@
+// Head sampling of new traces by tenant and route: a ratio per configured tenant, else per
+// path prefix, the default ratio for the rest. Spans with a parent, local or the caller's,
+// follow its decision, so a trace is kept or dropped whole. In debug mode the spans kept
+// say why, as `sampling.rule` and `sampling.ratio`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use opentelemetry::trace::{Link, SamplingDecision, SamplingResult, SpanKind, TraceContextExt, TraceId, TraceState};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::trace::{Sampler, ShouldSample};
+
+use crate::config::SamplingSettings;
+
+struct Rates {
+    default: f64,
+    tenants: HashMap<String, f64>,
+    // Longest prefix first
+    routes: Vec<(String, f64)>,
+    debug: bool,
+}
+
+// The ratios in use, shared with whatever replaces them at runtime
+#[derive(Clone)]
+pub struct TenantRates(Arc<RwLock<Rates>>);
+
+fn validate(settings: &SamplingSettings) -> Result<Rates, String> {
+    let invalid = |ratio: f64| !(0.0..=1.0).contains(&ratio);
+    if invalid(settings.ratio) {
+        return Err(format!("sampling ratio {} is not between 0 and 1", settings.ratio));
+    }
+    if let Some((tenant, ratio)) = settings.tenants.iter().find(|(_, ratio)| invalid(**ratio)) {
+        return Err(format!("sampling ratio {ratio} of tenant {tenant:?} is not between 0 and 1"));
+    }
+    if let Some((prefix, ratio)) = settings.routes.iter().find(|(_, ratio)| invalid(**ratio)) {
+        return Err(format!("sampling ratio {ratio} of paths {prefix:?} is not between 0 and 1"));
+    }
+    if let Some(prefix) = settings.routes.keys().find(|prefix| !prefix.starts_with('/')) {
+        return Err(format!("sampling path prefix {prefix:?} does not start with /"));
+    }
+
+    let mut routes: Vec<_> = settings.routes.iter().map(|(prefix, ratio)| (prefix.clone(), *ratio)).collect();
+    routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    Ok(Rates {
+        default: settings.ratio,
+        tenants: settings.tenants.clone(),
+        routes,
+        debug: settings.debug,
+    })
+}
+
+pub const RATIO_ENV: &str = "TRACE_SAMPLE_RATE";
+
+fn parse_ratio(value: &str) -> Result<f64, String> {
+    let ratio: f64 = value.trim().parse().map_err(|e| format!("{value:?} is not a number: {e}"))?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(format!("{ratio} is not between 0 and 1"));
+    }
+    Ok(ratio)
+}
+
+// TRACE_SAMPLE_RATE in place of the configured default ratio, which stays when the
+// variable is invalid; the error is for the caller to log
+pub fn ratio_from_env(settings: &mut SamplingSettings) -> Result<(), String> {
+    let Ok(value) = std::env::var(RATIO_ENV) else {
+        return Ok(());
+    };
+    settings.ratio = parse_ratio(&value)?;
+    Ok(())
+}
+
+impl TenantRates {
+    pub fn new(settings: &SamplingSettings) -> Result<Self, String> {
+        Ok(Self(Arc::new(RwLock::new(validate(settings)?))))
+    }
+
+    // Applies to traces started from now on; invalid settings leave the current ones
+    pub fn set(&self, settings: &SamplingSettings) -> Result<(), String> {
+        *self.0.write().unwrap() = validate(settings)?;
+        Ok(())
+    }
+
+    pub fn get(&self) -> SamplingSettings {
+        let rates = self.0.read().unwrap();
+        SamplingSettings {
+            ratio: rates.default,
+            tenants: rates.tenants.clone(),
+            routes: rates.routes.iter().cloned().collect(),
+            debug: rates.debug,
+        }
+    }
+
+    // The rule deciding for a new trace of `tenant` at `path`, and its ratio
+    fn rule(&self, tenant: Option<&str>, path: Option<&str>) -> (&'static str, f64) {
+        let rates = self.0.read().unwrap();
+        if let Some(ratio) = tenant.and_then(|tenant| rates.tenants.get(tenant)) {
+            return ("tenant", *ratio);
+        }
+        let route = path.and_then(|path| rates.routes.iter().find(|(prefix, _)| path.starts_with(prefix.as_str())));
+        match route {
+            Some((_, ratio)) => ("route", *ratio),
+            None => ("default", rates.default),
+        }
+    }
+
+    fn debug(&self) -> bool {
+        self.0.read().unwrap().debug
+    }
+}
+
+impl std::fmt::Debug for TenantRates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rates = self.0.read().unwrap();
+        f.debug_struct("TenantRates")
+            .field("default", &rates.default)
+            .field("tenants", &rates.tenants)
+            .field("routes", &rates.routes)
+            .field("debug", &rates.debug)
+            .finish()
+    }
+}
+
+// Sampler of new traces reading the tenant and path from the root span's `tenant.id` and
+// `url.path` attributes;
+// spans with a parent follow its decision, as with `Sampler::ParentBased`, which can't
+// say so on the span. Requests for the API docs are never sampled.
+#[derive(Clone, Debug)]
+pub struct TenantSampler(TenantRates);
+
+impl ShouldSample for TenantSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&opentelemetry::Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let parent = parent_context.filter(|cx| cx.has_active_span()).map(|cx| cx.span().span_context().clone());
+        let (mut result, rule, ratio) = match parent {
+            Some(parent) => {
+                let follow = if parent.is_sampled() { Sampler::AlwaysOn } else { Sampler::AlwaysOff };
+                let rule = if parent.is_remote() { "remote parent" } else { "parent" };
+                let result = follow.should_sample(parent_context, trace_id, name, span_kind, attributes, links);
+                (result, rule, None)
+            }
+            None => {
+                // It checks the exports, so always goes out
+                if attributes.iter().any(|kv| kv.key.as_str() == crate::self_test::ATTRIBUTE) {
+                    return Sampler::AlwaysOn.should_sample(parent_context, trace_id, name, span_kind, attributes, links);
+                }
+
+                let path = attributes.iter().find(|kv| kv.key.as_str() == "url.path").map(|kv| kv.value.as_str());
+                if path.as_ref().is_some_and(|path| path.starts_with(crate::openapi::PATH)) {
+                    return Sampler::AlwaysOff.should_sample(parent_context, trace_id, name, span_kind, attributes, links);
+                }
+
+                let tenant = attributes.iter().find(|kv| kv.key == crate::attributes::TENANT_ID).map(|kv| kv.value.as_str());
+                let (rule, ratio) = self.0.rule(tenant.as_deref(), path.as_deref());
+                let result = Sampler::TraceIdRatioBased(ratio).should_sample(parent_context, trace_id, name, span_kind, attributes, links);
+                (result, rule, Some(ratio))
+            }
+        };
+
+        // Said in the tracestate too, for what counts the spans kept to scale up by
+        if let Some(ratio) = ratio.filter(|_| result.decision == SamplingDecision::RecordAndSample) {
+            result.trace_state = with_threshold(&result.trace_state, ratio);
+        }
+        if result.decision == SamplingDecision::RecordAndSample && self.0.debug() {
+            result.attributes.push(KeyValue::new("sampling.rule", rule));
+            result.attributes.extend(ratio.map(|ratio| KeyValue::new("sampling.ratio", ratio)));
+        }
+        result
+    }
+}
+
+// A trace's sampling ratio as OTel has it, `th` in the tracestate's `ot` entry: the
+// rejection threshold out of 2^56, in up to 14 hex digits less the trailing zeros. It goes
+// with the trace, to the spans under it and the services called.
+const THRESHOLD_SCALE: f64 = (1u64 << 56) as f64;
+
+fn threshold(ratio: f64) -> String {
+    let threshold = (((1.0 - ratio) * THRESHOLD_SCALE).round() as u64).min((1 << 56) - 1);
+    match format!("{threshold:014x}").trim_end_matches('0') {
+        "" => "0".to_string(),
+        th => th.to_string(),
+    }
+}
+
+fn with_threshold(trace_state: &TraceState, ratio: f64) -> TraceState {
+    let others = trace_state.get("ot").into_iter().flat_map(|ot| ot.split(';')).filter(|entry| !entry.starts_with("th:"));
+    let ot: Vec<String> = others.map(str::to_string).chain([format!("th:{}", threshold(ratio))]).collect();
+    trace_state.insert("ot", ot.join(";")).unwrap_or_else(|_| trace_state.clone())
+}
+
+// How many spans one sampled by its trace's threshold stands for, 1 / ratio; none for a
+// trace without one
+pub fn adjusted_count(trace_state: &TraceState) -> Option<f64> {
+    let th = trace_state.get("ot")?.split(';').find_map(|entry| entry.strip_prefix("th:"))?;
+    if th.is_empty() || th.len() > 14 {
+        return None;
+    }
+    let threshold = u64::from_str_radix(&format!("{th:0<14}"), 16).ok()?;
+    Some(THRESHOLD_SCALE / (THRESHOLD_SCALE - threshold as f64))
+}
+
+pub fn sampler(rates: TenantRates) -> TenantSampler {
+    TenantSampler(rates)
+}
+
+// Body of `PUT /admin/sampling`: a bare ratio applies to every tenant
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+#[serde(untagged)]
+pub enum Update {
+    Ratio(f64),
+    Rules(SamplingSettings),
+}
+
+#[utoipa::path(get, path = "/admin/sampling", summary = "Sampling ratios in use", responses(
+    (status = 200, body = SamplingSettings, description = "Default and per-tenant ratios"),
+    (status = 401, body = String, description = "No token"),
+    (status = 403, body = String, description = "The caller is not an admin"),
+))]
+pub async fn get_handler(
+    axum::extract::State(rates): axum::extract::State<TenantRates>,
+) -> crate::response::Traced<axum::Json<SamplingSettings>> {
+    crate::response::Traced::new(axum::Json(rates.get()))
+}
+
+// Swaps the ratios for new traces, say to keep everything during an incident; they last
+// until the next change or SIGHUP, which goes back to the config file. Admins only.
+#[utoipa::path(put, path = "/admin/sampling", summary = "Replaces the sampling ratios until the next change or SIGHUP", request_body = Update, responses(
+    (status = 200, body = SamplingSettings, description = "The new ratios"),
+    (status = 400, body = String, description = "A ratio is not between 0 and 1"),
+    (status = 401, body = String, description = "No token"),
+    (status = 403, body = String, description = "The caller is not an admin"),
+))]
+pub async fn put_handler(
+    axum::extract::State(rates): axum::extract::State<TenantRates>,
+    crate::middleware::auth::RequireAdmin(admin): crate::middleware::auth::RequireAdmin,
+    axum::Json(update): axum::Json<Update>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let settings = match update {
+        Update::Ratio(ratio) => SamplingSettings { ratio, debug: rates.get().debug, ..Default::default() },
+        Update::Rules(settings) => settings,
+    };
+    if let Err(e) = rates.set(&settings) {
+        return (axum::http::StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    crate::audit::audit!(sampling = ?rates, enduser.id = admin.id, "Sampling changed at runtime");
+    axum::Json(rates.get()).into_response()
+}
+
+// Re-reads the sampling settings from the config file on SIGHUP
+pub fn spawn_reload_on_sighup(rates: TenantRates) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("Failed to install the SIGHUP handler, the sampling settings won't be reloaded: {}", e);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            let settings = crate::config::Settings::load().map(|mut settings| {
+                if let Err(e) = ratio_from_env(&mut settings.sampling) {
+                    tracing::warn!("Ignoring {}: {}, keeping the configured ratio {}", RATIO_ENV, e, settings.sampling.ratio);
+                }
+                settings
+            });
+            match settings.and_then(|settings| rates.set(&settings.sampling)) {
+                Ok(()) => crate::audit::audit!(sampling = ?rates, "Reloaded sampling settings"),
+                Err(e) => tracing::error!("Failed to reload sampling settings, keeping the current ones: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ratio_by_tenant() {
+        let settings = SamplingSettings {
+            ratio: 0.5,
+            tenants: HashMap::from([("internal-test".to_string(), 1.0)]),
+            ..Default::default()
+        };
+        let rates = TenantRates::new(&settings).unwrap();
+        assert_eq!(rates.rule(Some("internal-test"), None), ("tenant", 1.0));
+        assert_eq!(rates.rule(Some("acme"), None), ("default", 0.5));
+        assert_eq!(rates.rule(None, None), ("default", 0.5));
+
+        let invalid = SamplingSettings { ratio: 1.5, ..Default::default() };
+        assert!(rates.set(&invalid).is_err());
+        assert_eq!(rates.rule(None, None).1, 0.5);
+    }
+
+    #[test]
+    fn ratio_by_longest_path_prefix() {
+        let settings = SamplingSettings {
+            tenants: HashMap::from([("internal-test".to_string(), 1.0)]),
+            routes: HashMap::from([("/v1/items".to_string(), 0.01), ("/v1/items:batch".to_string(), 1.0)]),
+            ..Default::default()
+        };
+        let rates = TenantRates::new(&settings).unwrap();
+        assert_eq!(rates.rule(None, Some("/v1/items/42")), ("route", 0.01));
+        assert_eq!(rates.rule(None, Some("/v1/items:batch")), ("route", 1.0));
+        assert_eq!(rates.rule(None, Some("/v1/chain")), ("default", 1.0));
+        assert_eq!(rates.rule(Some("internal-test"), Some("/v1/items/42")), ("tenant", 1.0));
+
+        let relative = SamplingSettings { routes: HashMap::from([("v1".to_string(), 0.5)]), ..Default::default() };
+        assert!(TenantRates::new(&relative).is_err());
+    }
+
+    #[test]
+    fn ratio_from_the_environment_is_validated() {
+        assert_eq!(parse_ratio(" 0.25\n"), Ok(0.25));
+        assert!(parse_ratio("1.5").is_err());
+        assert!(parse_ratio("NaN").is_err());
+        assert!(parse_ratio("ten percent").is_err());
+    }
+
+    #[test]
+    fn the_ratio_goes_in_the_tracestate() {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let settings = SamplingSettings { routes: HashMap::from([("/v1/items".to_string(), 0.25)]), ..Default::default() };
+        let _telemetry = crate::test_support::init_with_sampler(sampler(TenantRates::new(&settings).unwrap()));
+
+        let (request, query) = (0..64)
+            .map(|_| tracing::info_span!("request", url.path = "/v1/items"))
+            .find(|span| span.context().span().span_context().is_sampled())
+            .map(|span| (span.context().span().span_context().trace_state().clone(), span.in_scope(|| tracing::info_span!("query").context())))
+            .unwrap();
+        assert_eq!(request.get("ot"), Some("th:c"));
+        assert_eq!(adjusted_count(&request), Some(4.0));
+        assert_eq!(adjusted_count(query.span().span_context().trace_state()), Some(4.0));
+
+        let everything = tracing::info_span!("request", url.path = "/v1/chain").context().span().span_context().trace_state().clone();
+        assert_eq!(everything.get("ot"), Some("th:0"));
+        assert_eq!(adjusted_count(&everything), Some(1.0));
+
+        let caller = TraceState::from_key_value([("ot", "rv:abcdef01234567;th:fd70a4")]).unwrap();
+        assert_eq!(with_threshold(&caller, 0.01).get("ot"), Some("rv:abcdef01234567;th:fd70a3d70a3d7"));
+        assert!((adjusted_count(&caller).unwrap() - 100.0).abs() < 0.01);
+        assert_eq!(adjusted_count(&TraceState::default()), None);
+    }
+
+    #[test]
+    fn the_request_span_is_sampled_by_its_tenant() {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let settings = SamplingSettings {
+            ratio: 0.0,
+            tenants: HashMap::from([("internal-test".to_string(), 1.0)]),
+            ..Default::default()
+        };
+        let telemetry = crate::test_support::init_with_sampler(sampler(TenantRates::new(&settings).unwrap()));
+
+        let request = axum::http::Request::get("/v1/items").body(()).unwrap();
+        let span = crate::middleware::trace::make_span(&request, Some("internal-test"));
+        assert!(span.context().span().span_context().is_sampled());
+        drop(span);
+        let span = crate::middleware::trace::make_span(&request, None);
+        assert!(!span.context().span().span_context().is_sampled());
+        drop(span);
+
+        telemetry.spans().assert_span_exists("GET").with_attribute("tenant.id", "internal-test");
+    }
+
+    #[test]
+    fn docs_are_not_sampled() {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let settings = SamplingSettings { ratio: 1.0, ..Default::default() };
+        let _telemetry = crate::test_support::init_with_sampler(sampler(TenantRates::new(&settings).unwrap()));
+
+        let span = tracing::info_span!("request", url.path = "/docs/openapi.json");
+        assert!(!span.context().span().span_context().is_sampled());
+    }
+
+    #[test]
+    fn debug_mode_says_why_spans_were_kept() {
+        let settings = SamplingSettings {
+            tenants: HashMap::from([("internal-test".to_string(), 1.0)]),
+            debug: true,
+            ..Default::default()
+        };
+        let telemetry = crate::test_support::init_with_sampler(sampler(TenantRates::new(&settings).unwrap()));
+
+        tracing::info_span!("request", tenant.id = "internal-test").in_scope(|| drop(tracing::info_span!("query")));
+
+        let spans = telemetry.spans();
+        spans
+            .assert_span_exists("request")
+            .with_attribute("sampling.rule", "tenant")
+            .with_attribute("sampling.ratio", 1.0);
+        spans.assert_span_exists("query").with_attribute("sampling.rule", "parent");
+        assert!(spans.assert_span_exists("query").attribute("sampling.ratio").is_none());
+    }
+}
@@ -0,0 +1,115 @@
+// Telemetry self-test: every `telemetry.self_test_interval_secs` a trace of one "telemetry
+// self-test" span is started, sampled whatever the ratio, and the span exports are watched for
+// it. When the collector accepts it within `self_test_timeout_secs` that's all; when its export
+// failed, or it never went out at all, dropped from a full queue say, it's an ERROR in the
+// log, which goes out whether or not the exporter works. Each run is counted in
+// `telemetry.self_test.runs` by its outcome. A collector which accepts spans and then loses
+// them passes; from here an accepted export is all there is to see.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use opentelemetry::trace::{TraceContextExt, TraceId};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::TelemetrySettings;
+use crate::telemetry::ExporterHealth;
+
+// On the span, for the sampler to let it through
+pub const ATTRIBUTE: &str = "telemetry.self_test";
+
+// How often the outcome is looked at while waiting for it
+const POLL: Duration = Duration::from_millis(250);
+
+// The span, ended so it's on its way; None when nothing would export it
+fn start(health: &ExporterHealth) -> Option<TraceId> {
+    let span = tracing::info_span!(parent: None, "telemetry self-test", telemetry.self_test = true);
+    let trace_id = span.context().span().span_context().trace_id();
+    if trace_id == TraceId::INVALID {
+        return None;
+    }
+    health.await_canary(trace_id);
+    drop(span);
+    Some(trace_id)
+}
+
+async fn run(health: &ExporterHealth, timeout: Duration, runs: &opentelemetry::metrics::Counter<u64>) {
+    let Some(trace_id) = start(health) else {
+        return;
+    };
+    let started = Instant::now();
+    let outcome = loop {
+        match health.canary_outcome() {
+            Some(outcome) => break Some(outcome),
+            None if started.elapsed() >= timeout => break None,
+            None => tokio::time::sleep(POLL).await,
+        }
+    };
+
+    let trace_id = trace_id.to_string();
+    let waited_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let outcome = match outcome {
+        Some(Ok(())) => {
+            tracing::debug!(trace_id, self_test.waited_ms = waited_ms, "Telemetry self-test trace exported");
+            "exported"
+        }
+        Some(Err(e)) => {
+            tracing::error!(trace_id, self_test.waited_ms = waited_ms, error = e, "Telemetry self-test failed, the export of its trace failed");
+            "failed"
+        }
+        None => {
+            tracing::error!(
+                trace_id,
+                self_test.timeout_secs = timeout.as_secs(),
+                "Telemetry self-test failed, its trace wasn't exported in time"
+            );
+            "missing"
+        }
+    };
+    runs.add(1, &[opentelemetry::KeyValue::new("outcome", outcome)]);
+}
+
+// Until the process exits; not at all with `self_test_interval_secs = 0`
+pub fn spawn(settings: &TelemetrySettings, health: Arc<ExporterHealth>) {
+    let interval = Duration::from_secs(settings.self_test_interval_secs);
+    if interval.is_zero() {
+        return;
+    }
+    let timeout = Duration::from_secs(settings.self_test_timeout_secs);
+    let runs = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+        .u64_counter("telemetry.self_test.runs")
+        .with_description("Telemetry self-tests, by outcome: exported, failed or missing")
+        .init();
+
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            run(&health, timeout, &runs).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TracerProvider as _;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn the_trace_is_watched_through_the_exporter() {
+        let exporter = opentelemetry_sdk::testing::trace::InMemorySpanExporter::default();
+        let health = Arc::new(ExporterHealth::default());
+        let tracked = crate::telemetry::TrackedExporter::new(exporter.clone(), health.clone(), Arc::new(crate::telemetry::PipelineStats::new()));
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().with_simple_exporter(tracked).build();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::OpenTelemetryLayer::new(provider.tracer("test")));
+
+        let trace_id = tracing::subscriber::with_default(subscriber, || start(&health)).unwrap();
+
+        assert_eq!(health.canary_outcome(), Some(Ok(())));
+        let spans = exporter.get_finished_spans().unwrap();
+        assert!(spans.iter().any(|span| span.name == "telemetry self-test" && span.span_context.trace_id() == trace_id));
+        assert_eq!(start(&ExporterHealth::default()), None, "no tracer, nothing to watch");
+    }
+}
@@ -0,0 +1,551 @@
+use std::future::Future;
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::{AdminSettings, ServerSettings};
+use crate::proxy::TrustedProxies;
+use crate::tls::Certificates;
+
+// First descriptor passed by systemd socket activation (sd_listen_fds)
+const SD_LISTEN_FDS_START: i32 = 3;
+
+// Listener inherited through systemd socket activation, if this process was given one
+fn inherited_listener() -> Option<std::net::TcpListener> {
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: i32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if pid != std::process::id() || fds < 1 {
+        return None;
+    }
+
+    // Not meant for child processes
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    // SAFETY: systemd hands over ownership of the descriptors starting at SD_LISTEN_FDS_START
+    Some(unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) })
+}
+
+// With SO_REUSEPORT a new version can bind the same port while the old one drains
+fn bind_reuse_port(addr: std::net::SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+// A bound Unix socket, its file removed again once it is dropped
+pub struct UnixListener {
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn bind_unix(path: &Path, mode: u32) -> std::io::Result<UnixListener> {
+    // Left behind by a process which didn't get to remove it, unless one still listens
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, format!("{} is in use", path.display())));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    let listener = UnixListener { listener, path: path.to_owned() };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    tracing::info!(server.unix_socket = %path.display(), "Bound Unix socket");
+    Ok(listener)
+}
+
+// The TCP listener unless `tcp = false`, and the Unix socket if there is one; with
+// certificates the TCP listener serves HTTPS
+pub struct Listeners {
+    tcp: Option<tokio::net::TcpListener>,
+    unix: Option<UnixListener>,
+    tls: Option<Arc<Certificates>>,
+}
+
+pub async fn bind(settings: &ServerSettings) -> std::io::Result<Listeners> {
+    let tcp = match settings.tcp {
+        true => Some(bind_tcp(settings).await?),
+        false => None,
+    };
+    let unix = match &settings.unix_socket {
+        Some(path) => Some(bind_unix(path.as_ref(), settings.unix_socket_mode)?),
+        None => None,
+    };
+
+    if tcp.is_none() && unix.is_none() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "server.tcp is off and there is no server.unix_socket"));
+    }
+
+    let tls = match &settings.tls {
+        Some(tls) => {
+            let certificates = Certificates::load(tls, settings.http2).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            let certificates = Arc::new(certificates);
+            certificates.spawn_reload();
+            Some(certificates)
+        }
+        None => None,
+    };
+    Ok(Listeners { tcp, unix, tls })
+}
+
+// Where the listener binds, for diagnostics
+pub fn address(settings: &ServerSettings) -> String {
+    let tcp = settings.tcp.then(|| settings.bind.clone());
+    tcp.into_iter().chain(settings.unix_socket.clone()).collect::<Vec<_>>().join(" and ")
+}
+
+// Where the service listens, for the resource; the configured address rather than one
+// passed in by systemd
+pub fn resource_attributes(settings: &ServerSettings) -> Vec<opentelemetry::KeyValue> {
+    let tcp = settings.tcp.then(|| opentelemetry::KeyValue::new("server.address", settings.bind.clone()));
+    let unix = settings.unix_socket.clone().map(|path| opentelemetry::KeyValue::new("server.unix_socket", path));
+    tcp.into_iter().chain(unix).collect()
+}
+
+async fn bind_tcp(settings: &ServerSettings) -> std::io::Result<tokio::net::TcpListener> {
+    if let Some(listener) = inherited_listener() {
+        tracing::info!(listener.source = "systemd", "Using inherited listener");
+        listener.set_nonblocking(true)?;
+        return tokio::net::TcpListener::from_std(listener);
+    }
+
+    if settings.reuse_port {
+        let addr = settings
+            .bind
+            .parse()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let listener = bind_reuse_port(addr)?;
+        listener.set_nonblocking(true)?;
+        tracing::info!(listener.source = "reuse_port", server.address = settings.bind, "Bound listener");
+        return tokio::net::TcpListener::from_std(listener);
+    }
+
+    tokio::net::TcpListener::bind(&settings.bind).await
+}
+
+pub async fn bind_admin(settings: &AdminSettings) -> std::io::Result<tokio::net::TcpListener> {
+    let listener = tokio::net::TcpListener::bind(&settings.bind).await?;
+    tracing::info!(server.address = settings.bind, "Bound admin listener");
+    Ok(listener)
+}
+
+// Serves the admin routes until aborted, with no drain of its own
+pub fn spawn_admin(listener: tokio::net::TcpListener, app: axum::Router) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::error!(error = %e, "Admin listener failed");
+        }
+    })
+}
+
+async fn shutdown_signal() {
+    // A handler that can't be installed is logged, and the other signal is waited for alone
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %e, "Failed to install SIGINT handler");
+            std::future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to install SIGTERM handler");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    let signal = tokio::select! {
+        _ = ctrl_c => "SIGINT",
+        _ = terminate => "SIGTERM",
+    };
+    tracing::warn!(signal, "Shutdown requested, draining connections");
+}
+
+// Every listener is served here, on hyper-util, as axum::serve can't be told which HTTP
+// versions to speak nor takes Unix sockets
+enum Incoming {
+    Unix(UnixListener),
+    Tcp(tokio::net::TcpListener, Option<Arc<Certificates>>),
+}
+
+enum Accepted {
+    Unix(tokio::net::UnixStream),
+    Tcp(tokio::net::TcpStream, std::net::SocketAddr, Option<Arc<Certificates>>),
+}
+
+impl Incoming {
+    async fn accept(&self) -> std::io::Result<Accepted> {
+        match self {
+            Incoming::Unix(listener) => Ok(Accepted::Unix(listener.listener.accept().await?.0)),
+            Incoming::Tcp(listener, certificates) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok(Accepted::Tcp(stream, peer, certificates.clone()))
+            }
+        }
+    }
+}
+
+// Requests per connection, one for HTTP/1.1 without keep-alive and many where HTTP/2
+// multiplexes them
+struct ConnectionMetrics {
+    streams: opentelemetry::metrics::Histogram<u64>,
+}
+
+impl ConnectionMetrics {
+    fn new() -> Self {
+        let streams = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .u64_histogram("http.server.connection.streams")
+            .with_description("Requests served on a connection by the time it closed")
+            .with_unit("{stream}")
+            .init();
+        Self { streams }
+    }
+}
+
+// The root span of an accepted connection, for as long as it is open; none without
+// `connection_spans`
+fn connection_span(settings: &ServerSettings, transport: &'static str, peer: Option<std::net::SocketAddr>) -> tracing::Span {
+    if !settings.connection_spans {
+        return tracing::Span::none();
+    }
+    tracing::info_span!(
+        parent: None,
+        "connection",
+        otel.name = format!("{transport} connection"),
+        network.transport = if transport == "unix" { "unix" } else { "tcp" },
+        network.peer.address = peer.map(|peer| peer.ip().to_string()),
+        network.peer.port = peer.map(|peer| peer.port()),
+        client.address = tracing::field::Empty,
+        network.protocol.version = tracing::field::Empty,
+        tls.protocol.version = tracing::field::Empty,
+        tls.cipher = tracing::field::Empty,
+        tls.next_protocol = tracing::field::Empty,
+        tls.handshake.duration_ms = tracing::field::Empty,
+        connection.requests = tracing::field::Empty,
+        otel.status_code = tracing::field::Empty,
+    )
+}
+
+// Counted as the connection's requests come in
+#[derive(Default)]
+struct Streams {
+    count: std::sync::atomic::AtomicU64,
+    http2: std::sync::atomic::AtomicBool,
+}
+
+// Serves one connection until it closes, or until its open requests are done once `stop`
+// says so; HTTP/1.1 only unless `http2`. `peer` is the requests' ConnectInfo, as with
+// axum::serve; a Unix socket's peer has no address. The requests it served go on `span`,
+// which ends with it
+async fn serve_connection<I>(
+    io: I,
+    app: axum::Router,
+    (peer, http2): (Option<std::net::SocketAddr>, bool),
+    (metrics, span): (Arc<ConnectionMetrics>, tracing::Span),
+    mut stop: tokio::sync::watch::Receiver<bool>,
+) where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use std::sync::atomic::Ordering;
+
+    let streams = Arc::new(Streams::default());
+    let app = {
+        let streams = streams.clone();
+        tower::ServiceExt::map_request(app, move |mut request: axum::http::Request<hyper::body::Incoming>| {
+            request.extensions_mut().insert(crate::middleware::queue_time::Received(Instant::now()));
+            streams.count.fetch_add(1, Ordering::Relaxed);
+            streams.http2.store(request.version() == axum::http::Version::HTTP_2, Ordering::Relaxed);
+            if let Some(peer) = peer {
+                request.extensions_mut().insert(axum::extract::ConnectInfo(peer));
+            }
+            request
+        })
+    };
+
+    // Without upgrades, which no route uses and which would ignore `http1_only`
+    let builder = hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+    let builder = if http2 { builder } else { builder.http1_only() };
+    let connection = builder.serve_connection(hyper_util::rt::TokioIo::new(io), hyper_util::service::TowerToHyperService::new(app));
+    tokio::pin!(connection);
+    let finished = tokio::select! {
+        _ = connection.as_mut() => true,
+        _ = stop.wait_for(|stop| *stop) => false,
+    };
+    if !finished {
+        connection.as_mut().graceful_shutdown();
+        let _ = connection.await;
+    }
+
+    let (version, requests) = (if streams.http2.load(Ordering::Relaxed) { "2" } else { "1.1" }, streams.count.load(Ordering::Relaxed));
+    metrics.streams.record(requests, &[opentelemetry::KeyValue::new("network.protocol.version", version)]);
+    span.record("connection.requests", requests as i64);
+    if requests > 0 {
+        span.record("network.protocol.version", version);
+    }
+}
+
+// The client named by the PROXY header of a connection from a trusted proxy, which has to
+// send one; any other connection is dropped
+async fn proxied_peer(proxies: &TrustedProxies, stream: &mut tokio::net::TcpStream, peer: std::net::SocketAddr) -> Option<std::net::SocketAddr> {
+    if !proxies.contains(peer.ip()) {
+        tracing::warn!(network.peer.address = %peer.ip(), "Dropped connection from an untrusted proxy");
+        return None;
+    }
+
+    match crate::proxy::read_header(stream, peer).await {
+        Ok(client) => Some(client),
+        Err(e) => {
+            tracing::warn!(network.peer.address = %peer.ip(), error = %e, "Dropped connection without a valid PROXY header");
+            None
+        }
+    }
+}
+
+// Accepts until `stopped`, each connection in a task of its own, then waits for the open
+// ones to finish their requests. HTTP/2 is offered over TLS with `http2`, and otherwise
+// taken with prior knowledge when `h2c` is on as well
+async fn serve_incoming(incoming: Incoming, app: axum::Router, settings: &ServerSettings, proxies: Arc<TrustedProxies>, stopped: impl Future<Output = ()>) {
+    let (stop_tx, _) = tokio::sync::watch::channel(false);
+    let metrics = Arc::new(ConnectionMetrics::new());
+    let h2c = settings.http2 && settings.h2c;
+    tokio::pin!(stopped);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = incoming.accept() => accepted,
+            () = &mut stopped => break,
+        };
+
+        let (app, metrics, stop) = (app.clone(), metrics.clone(), stop_tx.subscribe());
+        match accepted {
+            Ok(Accepted::Unix(stream)) => {
+                let span = connection_span(settings, "unix", None);
+                tokio::spawn(serve_connection(stream, app, (None, h2c), (metrics, span), stop));
+            }
+            Ok(Accepted::Tcp(mut stream, peer, certificates)) => {
+                let (proxies, proxy_protocol, http2) = (proxies.clone(), settings.proxy_protocol, settings.http2);
+                let span = connection_span(settings, if certificates.is_some() { "tls" } else { "tcp" }, Some(peer));
+                tokio::spawn(async move {
+                    let peer = match proxy_protocol {
+                        true => match tracing::Instrument::instrument(proxied_peer(&proxies, &mut stream, peer), span.clone()).await {
+                            Some(client) => client,
+                            None => {
+                                span.record("otel.status_code", "error");
+                                return;
+                            }
+                        },
+                        false => peer,
+                    };
+                    if proxy_protocol {
+                        span.record("client.address", peer.ip().to_string());
+                    }
+                    match certificates {
+                        None => serve_connection(stream, app, (Some(peer), h2c), (metrics, span), stop).await,
+                        Some(certificates) => {
+                            if let Some(stream) = certificates.handshake(stream, &span).await {
+                                serve_connection(stream, app, (Some(peer), http2), (metrics, span), stop).await;
+                            }
+                        }
+                    }
+                });
+            }
+            // Most likely out of file descriptors, as axum::serve does
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to accept a connection");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+
+    // No more connections, a Unix socket's file goes with its listener
+    drop(incoming);
+    let _ = stop_tx.send(true);
+    stop_tx.closed().await;
+}
+
+// Serves until a shutdown signal arrives, then stops accepting and lets in-flight
+// requests finish for up to `shutdown_drain_secs` within a `service.drain` span
+pub async fn serve(listeners: Listeners, app: axum::Router, settings: &ServerSettings) -> std::io::Result<()> {
+    let (stop_tx, stop_rx) = tokio::sync::watch::channel(false);
+    let stopped = move || {
+        let mut stop_rx = stop_rx.clone();
+        async move {
+            let _ = stop_rx.wait_for(|stop| *stop).await;
+        }
+    };
+
+    // Checked at startup already, with the client address layer
+    let proxies = TrustedProxies::new(&settings.trusted_proxies).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let proxies = Arc::new(proxies);
+
+    let Listeners { tcp, unix, tls } = listeners;
+    let tcp = {
+        let (app, proxies, stopped) = (app.clone(), proxies.clone(), stopped());
+        async move {
+            if let Some(listener) = tcp {
+                serve_incoming(Incoming::Tcp(listener, tls), app, settings, proxies, stopped).await;
+            }
+            Ok::<_, std::io::Error>(())
+        }
+    };
+    let unix = {
+        let stopped = stopped();
+        async move {
+            if let Some(listener) = unix {
+                serve_incoming(Incoming::Unix(listener), app, settings, proxies, stopped).await;
+            }
+            Ok(())
+        }
+    };
+    let server = async { tokio::try_join!(tcp, unix).map(|_| ()) };
+    tokio::pin!(server);
+
+    let started = tokio::select! {
+        result = &mut server => return result,
+        () = shutdown_signal() => Instant::now(),
+    };
+    // Before the drain, which systemd then shows as the service stopping rather than running
+    #[cfg(feature = "systemd")]
+    crate::systemd::notify_stopping();
+    let _ = stop_tx.send(true);
+
+    let span = tracing::info_span!("service.drain", drain.timed_out = tracing::field::Empty, drain.duration_ms = tracing::field::Empty);
+    let limit = Duration::from_secs(settings.shutdown_drain_secs);
+    let result = tracing::Instrument::instrument(tokio::time::timeout(limit, &mut server), span.clone()).await;
+
+    span.record("drain.duration_ms", started.elapsed().as_millis() as u64);
+    match result {
+        Ok(result) => {
+            span.record("drain.timed_out", false);
+            span.in_scope(|| tracing::info!("Drained all connections"));
+            result
+        }
+        Err(_) => {
+            span.record("drain.timed_out", true);
+            span.in_scope(|| tracing::warn!(drain.limit_secs = settings.shutdown_drain_secs, "Drain timed out, dropping remaining connections"));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn serves_on_a_unix_socket_and_cleans_up() {
+        let path = std::env::temp_dir().join(format!("rust-trace-minimum-{}.sock", std::process::id()));
+        // A stale socket, nothing listening on it
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let listener = bind_unix(&path, 0o600).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(bind_unix(&path, 0o600).is_err());
+
+        let app = axum::Router::new().route("/", axum::routing::get(|| async { "hello" }));
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            serve_incoming(Incoming::Unix(listener), app, &ServerSettings::default(), Arc::new(TrustedProxies::new(&[]).unwrap()), async move {
+                let _ = stop_rx.await;
+            })
+            .await
+        });
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("hello"));
+
+        stop_tx.send(()).unwrap();
+        server.await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn serves_https_with_a_span_per_connection() {
+        let telemetry = crate::test_support::init();
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+        let tls = crate::config::TlsSettings {
+            cert_path: format!("{fixtures}/server.pem"),
+            key_path: format!("{fixtures}/server.key"),
+            reload_secs: 0,
+        };
+        let certificates = Arc::new(Certificates::load(&tls, true).unwrap());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route("/", axum::routing::get(|| async { "hello" }));
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            serve_incoming(Incoming::Tcp(listener, Some(certificates)), app, &ServerSettings::default(), Arc::new(TrustedProxies::new(&[]).unwrap()), async move {
+                let _ = stop_rx.await;
+            })
+            .await
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        let ca = std::fs::read(format!("{fixtures}/ca.pem")).unwrap();
+        for certificate in rustls_pemfile::certs(&mut ca.as_slice()) {
+            roots.add(certificate.unwrap()).unwrap();
+        }
+        let mut config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector.connect("localhost".try_into().unwrap(), stream).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        // The server closes without close_notify once the response is sent
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 200"));
+
+        // Plain HTTP on the HTTPS port
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n").await.unwrap();
+        let _ = stream.read_to_end(&mut Vec::new()).await;
+
+        stop_tx.send(()).unwrap();
+        server.await.unwrap();
+
+        let spans = telemetry.spans();
+        let connections: Vec<_> = spans.all().iter().filter(|span| span.name == "tls connection").collect();
+        assert_eq!(connections.len(), 2);
+        let attribute = |span: &opentelemetry_sdk::export::trace::SpanData, key: &str| {
+            span.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| kv.value.to_string())
+        };
+        let served = connections.iter().find(|span| span.events.is_empty()).unwrap();
+        assert_eq!(attribute(served, "tls.protocol.version").as_deref(), Some("1.3"));
+        assert_eq!(attribute(served, "tls.next_protocol").as_deref(), Some("http/1.1"));
+        assert!(attribute(served, "tls.cipher").is_some());
+        assert!(attribute(served, "tls.handshake.duration_ms").is_some());
+        assert_eq!(attribute(served, "connection.requests").as_deref(), Some("1"));
+        assert_eq!(attribute(served, "network.peer.address").as_deref(), Some("127.0.0.1"));
+        let failed = connections.iter().find(|span| !span.events.is_empty()).unwrap();
+        assert_eq!(failed.events.iter().next().unwrap().name, "TLS handshake failed");
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use opentelemetry::KeyValue;
+use rand::Rng;
+
+use crate::config::SessionSettings;
+use crate::middleware::db_calls::Counted;
+
+pub type SessionData = HashMap<String, serde_json::Value>;
+
+// Request-scoped handle inserted into the request extensions by `layer`
+#[derive(Debug, Clone)]
+pub struct Session {
+    inner: Arc<Mutex<SessionState>>,
+}
+
+#[derive(Debug)]
+struct SessionState {
+    id: String,
+    data: SessionData,
+    is_new: bool,
+    modified: bool,
+}
+
+impl Session {
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        self.inner.lock().unwrap().data.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: impl Into<String>, value: impl Into<serde_json::Value>) {
+        let mut state = self.inner.lock().unwrap();
+        state.data.insert(key.into(), value.into());
+        state.modified = true;
+    }
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for Session {
+    type Rejection = (axum::http::StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Session>()
+            .cloned()
+            .ok_or((axum::http::StatusCode::INTERNAL_SERVER_ERROR, "session layer is not installed"))
+    }
+}
+
+struct CachedSession {
+    data: SessionData,
+    cached_at: Instant,
+}
+
+// MySQL backed store with a small in-process read cache in front of it
+pub struct SessionStore {
+    pool: sqlx::MySqlPool,
+    settings: SessionSettings,
+    cache: Mutex<HashMap<String, CachedSession>>,
+    cache_lookups: opentelemetry::metrics::Counter<u64>,
+    // Kept alongside the counter for the health details endpoint
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl SessionStore {
+    pub fn new(pool: sqlx::MySqlPool, settings: SessionSettings) -> Self {
+        let cache_lookups = opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+            .u64_counter("session.cache.lookups")
+            .with_description("Session cache lookups, by result (hit or miss)")
+            .init();
+
+        Self {
+            pool,
+            settings,
+            cache: Mutex::new(HashMap::new()),
+            cache_lookups,
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.cache.lock().unwrap().len(),
+            capacity: self.settings.cache_capacity,
+            hits: self.cache_hits.load(Ordering::Relaxed),
+            misses: self.cache_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn cached(&self, id: &str) -> Option<SessionData> {
+        let ttl = Duration::from_secs(self.settings.cache_ttl_secs);
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(id)
+            .filter(|entry| entry.cached_at.elapsed() < ttl)
+            .map(|entry| entry.data.clone())
+    }
+
+    fn cache(&self, id: &str, data: &SessionData) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.settings.cache_capacity {
+            let ttl = Duration::from_secs(self.settings.cache_ttl_secs);
+            cache.retain(|_, entry| entry.cached_at.elapsed() < ttl);
+
+            // Still full, start over rather than tracking recency
+            if cache.len() >= self.settings.cache_capacity {
+                cache.clear();
+            }
+        }
+        cache.insert(
+            id.to_string(),
+            CachedSession {
+                data: data.clone(),
+                cached_at: Instant::now(),
+            },
+        );
+    }
+
+    #[tracing::instrument(name = "session load", skip(self, id), fields(session.cache_hit = tracing::field::Empty))]
+    pub async fn load(&self, id: &str) -> Result<Option<SessionData>, sqlx::Error> {
+        let span = tracing::Span::current();
+
+        if let Some(data) = self.cached(id) {
+            span.record("session.cache_hit", true);
+            self.cache_lookups.add(1, &[KeyValue::new("result", "hit")]);
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(data));
+        }
+        span.record("session.cache_hit", false);
+        self.cache_lookups.add(1, &[KeyValue::new("result", "miss")]);
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+
+        let row: Option<(String,)> = sqlx::query_as(&crate::sqlcommenter::tag("SELECT data FROM sessions WHERE id = ? AND expires_at > ?"))
+            .bind(id)
+            .bind(unix_now())
+            .fetch_optional(&self.pool)
+            .counted()
+            .await?;
+
+        let Some((raw,)) = row else {
+            return Ok(None);
+        };
+
+        // A row we can't decode is treated like an expired session
+        let data: SessionData = match serde_json::from_str(&raw) {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::warn!("Discarding undecodable session: {:?}", e);
+                return Ok(None);
+            }
+        };
+
+        self.cache(id, &data);
+        Ok(Some(data))
+    }
+
+    #[tracing::instrument(name = "session save", skip_all, fields(session.keys = data.len()))]
+    pub async fn save(&self, id: &str, data: &SessionData) -> Result<(), sqlx::Error> {
+        let raw = serde_json::to_string(data).expect("session data is always serializable");
+
+        sqlx::query(&crate::sqlcommenter::tag(
+            "INSERT INTO sessions (id, data, expires_at) VALUES (?, ?, ?)
+             ON DUPLICATE KEY UPDATE data = VALUES(data), expires_at = VALUES(expires_at)",
+        ))
+        .bind(id)
+        .bind(raw)
+        .bind(unix_now() + self.settings.ttl_secs as i64)
+        .execute(&self.pool)
+        .counted()
+        .await?;
+
+        self.cache(id, data);
+        Ok(())
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+fn new_session_id() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn session_cookie<'a>(headers: &'a axum::http::HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(axum::http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Loads the session before the handler runs and saves it afterwards if it was modified
+pub async fn layer(
+    axum::extract::State(store): axum::extract::State<Arc<SessionStore>>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let cookie_name = store.settings.cookie_name.as_str();
+
+    let existing = match session_cookie(request.headers(), cookie_name) {
+        Some(id) => match store.load(id).await {
+            Ok(data) => data.map(|data| (id.to_string(), data)),
+            Err(e) => {
+                // Serve the request with a fresh session rather than failing it
+                tracing::error!("Failed to load session: {:?}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let (id, data, is_new) = match existing {
+        Some((id, data)) => (id, data, false),
+        None => (new_session_id(), SessionData::new(), true),
+    };
+
+    let session = Session {
+        inner: Arc::new(Mutex::new(SessionState {
+            id,
+            data,
+            is_new,
+            modified: false,
+        })),
+    };
+    request.extensions_mut().insert(session.clone());
+
+    let mut response = next.run(request).await;
+
+    let (id, data, is_new) = {
+        let state = session.inner.lock().unwrap();
+        if !state.modified {
+            return response;
+        }
+        (state.id.clone(), state.data.clone(), state.is_new)
+    };
+
+    if let Err(e) = store.save(&id, &data).await {
+        tracing::error!("Failed to save session: {:?}", e);
+        return response;
+    }
+
+    if is_new {
+        let mut cookie = format!("{cookie_name}={id}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}", store.settings.ttl_secs);
+        if store.settings.secure_cookie {
+            cookie.push_str("; Secure");
+        }
+        response
+            .headers_mut()
+            .append(axum::http::header::SET_COOKIE, cookie.parse().expect("cookie is a valid header value"));
+    }
+
+    response
+}
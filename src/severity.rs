@@ -0,0 +1,75 @@
+// OpenTelemetry severity on exported events. tracing-opentelemetry marks the span events
+// it makes from tracing events with a `level` string only, which backends don't filter
+// on; this replaces it with `severity_number` and `severity_text` as the log data model
+// has them (https://opentelemetry.io/docs/specs/otel/logs/data-model/#field-severitynumber).
+
+use opentelemetry::{Key, KeyValue};
+use tracing_core::Level;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::registry::LookupSpan;
+
+const LEVEL: Key = Key::from_static_str("level");
+const SEVERITY_NUMBER: Key = Key::from_static_str("severity_number");
+const SEVERITY_TEXT: Key = Key::from_static_str("severity_text");
+
+// The first number of each range; tracing has no FATAL (21)
+fn severity_number(level: Level) -> i64 {
+    match level {
+        Level::TRACE => 1,
+        Level::DEBUG => 5,
+        Level::INFO => 9,
+        Level::WARN => 13,
+        Level::ERROR => 17,
+    }
+}
+
+pub struct SeverityLayer;
+
+impl<S> tracing_subscriber::Layer<S> for SeverityLayer
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+
+        // The OpenTelemetry layer, which sits below this one, has just added the event
+        let Some(otel_event) = extensions
+            .get_mut::<OtelData>()
+            .and_then(|data| data.builder.events.as_mut()?.last_mut())
+        else {
+            return;
+        };
+        let level = *event.metadata().level();
+        let Some(position) = otel_event
+            .attributes
+            .iter()
+            .position(|kv| kv.key == LEVEL && kv.value.as_str() == level.as_str())
+        else {
+            return;
+        };
+
+        otel_event.attributes[position] = KeyValue::new(SEVERITY_TEXT, level.as_str());
+        otel_event.attributes.push(KeyValue::new(SEVERITY_NUMBER, severity_number(level)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support;
+
+    #[test]
+    fn replaces_the_level_of_span_events() {
+        let telemetry = test_support::init();
+        tracing::info_span!("request").in_scope(|| tracing::warn!("possible error"));
+
+        let spans = telemetry.spans();
+        let event = &spans.assert_span_exists("request").span().events[0];
+        let attribute = |key: &str| event.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| kv.value.clone());
+        assert_eq!(attribute("severity_number"), Some(13.into()));
+        assert_eq!(attribute("severity_text"), Some("WARN".into()));
+        assert_eq!(attribute("level"), None);
+    }
+}
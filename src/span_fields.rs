@@ -0,0 +1,51 @@
+// Span fields which are costly to compute, and only worth it for spans that get exported
+
+// Only the database spans use them so far
+#![cfg_attr(not(feature = "mysql"), allow(dead_code))]
+
+use opentelemetry::trace::TraceContextExt;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+// Whether anything will see the span's fields: some subscriber enabled it, and the
+// OpenTelemetry layer sampled it. Without that layer the answer is always no.
+pub fn is_recorded(span: &tracing::Span) -> bool {
+    !span.is_disabled() && span.context().span().span_context().is_sampled()
+}
+
+// Records `field` with what `value` returns, running it only when the span declares the
+// field and will be exported
+pub fn record_lazy<V: tracing::Value>(span: &tracing::Span, field: &str, value: impl FnOnce() -> V) {
+    if span.has_field(field) && is_recorded(span) {
+        span.record(field, value());
+    }
+}
+
+// A statement on one line, as `db.query.text` is shown by most trace viewers
+pub fn query_text(sql: &str) -> String {
+    sql.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn computes_fields_of_sampled_spans() {
+        let telemetry = test_support::init();
+
+        let span = tracing::info_span!("fetch row", db.query.text = tracing::field::Empty);
+        record_lazy(&span, "db.query.text", || query_text("SELECT 1\n    + 1"));
+        drop(span);
+
+        telemetry.spans().assert_span_exists("fetch row").with_attribute("db.query.text", "SELECT 1 + 1");
+    }
+
+    #[tokio::test]
+    async fn skips_fields_of_unsampled_spans() {
+        let _telemetry = test_support::init_with_sampler(opentelemetry_sdk::trace::Sampler::AlwaysOff);
+
+        let span = tracing::info_span!("fetch row", db.query.text = tracing::field::Empty);
+        record_lazy(&span, "db.query.text", || -> String { panic!("computed for an unsampled span") });
+    }
+}
@@ -0,0 +1,72 @@
+// Request rates and errors counted from the spans themselves, for dashboards built on span
+// metrics: `span.calls` by `span.name`, `span.kind` and `status.code`. Under head sampling
+// a span kept stands for the ones dropped with it, so each adds its trace's adjusted count,
+// 1 / ratio by the threshold in the tracestate, tagged `sampling.estimated`; a trace
+// without one, started by a caller which doesn't say, counts 1 a span.
+
+use std::sync::OnceLock;
+
+use opentelemetry::trace::{SpanKind, Status};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::export::trace::SpanData;
+
+#[derive(Default)]
+pub struct SpanMetricsProcessor {
+    // Made at the first span ended, the meter provider being installed after the tracer's
+    calls: OnceLock<opentelemetry::metrics::Counter<f64>>,
+}
+
+impl std::fmt::Debug for SpanMetricsProcessor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpanMetricsProcessor").finish_non_exhaustive()
+    }
+}
+
+fn kind(kind: &SpanKind) -> &'static str {
+    match kind {
+        SpanKind::Client => "client",
+        SpanKind::Server => "server",
+        SpanKind::Producer => "producer",
+        SpanKind::Consumer => "consumer",
+        SpanKind::Internal => "internal",
+    }
+}
+
+fn status(status: &Status) -> &'static str {
+    match status {
+        Status::Unset => "unset",
+        Status::Error { .. } => "error",
+        Status::Ok => "ok",
+    }
+}
+
+// What a span adds to the count, and whether that's an estimate
+fn weight(span: &SpanData) -> (f64, bool) {
+    match crate::sampling::adjusted_count(span.span_context.trace_state()) {
+        Some(count) if count > 1.0 => (count, true),
+        _ => (1.0, false),
+    }
+}
+
+impl crate::telemetry::ChainedProcessor for SpanMetricsProcessor {
+    fn on_end(&self, span: SpanData) -> Option<SpanData> {
+        let calls = self.calls.get_or_init(|| {
+            opentelemetry::global::meter(env!("CARGO_PKG_NAME"))
+                .f64_counter("span.calls")
+                .with_description("Spans ended, scaled up by the sampling ratio where it's known")
+                .init()
+        });
+
+        let (count, estimated) = weight(&span);
+        calls.add(
+            count,
+            &[
+                KeyValue::new("span.name", span.name.clone()),
+                KeyValue::new("span.kind", kind(&span.span_kind)),
+                KeyValue::new("status.code", status(&span.status)),
+                KeyValue::new("sampling.estimated", estimated),
+            ],
+        );
+        Some(span)
+    }
+}
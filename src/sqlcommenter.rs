@@ -0,0 +1,89 @@
+// sqlcommenter (https://google.github.io/sqlcommenter/spec/) comments on queries, so the
+// database's own process list and slow query log tell which tenant a query ran for.
+//
+// Only low-cardinality keys go in: every distinct text is a prepared statement of its own
+// in each connection's cache, which rules out the per-request traceparent. For the same
+// reason the `MAX_EXECUTION_TIME` hint a SELECT gets from the request's deadline is rounded.
+
+use opentelemetry::baggage::BaggageExt;
+
+// Keys the spec allows are sorted, values percent-encoded then quoted
+fn comment(entries: &[(&str, &str)]) -> String {
+    let mut encoded: Vec<String> = entries
+        .iter()
+        .map(|(key, value)| format!("{}='{}'", encode(key), encode(value)))
+        .collect();
+    encoded.sort();
+    format!("/*{}*/", encoded.join(","))
+}
+
+// Everything but unreserved characters, so a value can't close the comment or the quotes
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+// `sql` with the current context's tenant appended, as is when there is none, and limited to
+// the request's deadline; call it where the request's context is current, not in a response
+// body stream
+pub fn tag(sql: &str) -> String {
+    let sql = limit_time(sql);
+    let cx = opentelemetry::Context::current();
+    match cx.baggage().get(crate::attributes::TENANT_ID) {
+        Some(tenant) => format!("{sql} {}", comment(&[("tenant_id", tenant.as_str().as_ref())])),
+        None => sql.into_owned(),
+    }
+}
+
+// A SELECT of a request with a deadline gets a `MAX_EXECUTION_TIME` hint, so MySQL stops
+// once the caller has stopped waiting; it bounds no other statement that way
+fn limit_time(sql: &str) -> std::borrow::Cow<'_, str> {
+    let Some(ms) = crate::middleware::deadline::statement_limit_ms() else {
+        return sql.into();
+    };
+    match sql.get(..6) {
+        Some(select) if select.eq_ignore_ascii_case("select") => format!("{select} /*+ MAX_EXECUTION_TIME({ms}) */{}", &sql[6..]).into(),
+        _ => sql.into(),
+    }
+}
+
+// `sql` as it was before `tag`, which is the same statement whatever the tenant and deadline
+pub fn untag(sql: &str) -> String {
+    let sql = match sql.rfind(" /*") {
+        Some(comment) if !sql[comment..].starts_with(" /*+") && sql.ends_with("*/") => &sql[..comment],
+        _ => sql,
+    };
+    match (sql.get(6..), sql.find(" */")) {
+        (Some(hinted), Some(end)) if hinted.starts_with(" /*+ MAX_EXECUTION_TIME(") => format!("{}{}", &sql[..6], &sql[end + 3..]),
+        _ => sql.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_the_tenant() {
+        let _cx = opentelemetry::Context::current_with_baggage([opentelemetry::KeyValue::new(
+            crate::attributes::TENANT_ID,
+            "acme*/ DROP",
+        )])
+        .attach();
+
+        assert_eq!(tag("SELECT 1"), "SELECT 1 /*tenant_id='acme%2A%2F%20DROP'*/");
+    }
+
+    #[test]
+    fn untagged_is_the_statement_as_written() {
+        let sql = "SELECT id FROM items WHERE id = ?";
+        assert_eq!(untag(&tag(sql)), sql);
+        assert_eq!(untag("SELECT /*+ MAX_EXECUTION_TIME(256) */ id FROM items WHERE id = ? /*tenant_id='acme'*/"), sql);
+        assert_eq!(untag("DELETE FROM items WHERE id = ? /*tenant_id='acme'*/"), "DELETE FROM items WHERE id = ?");
+    }
+}
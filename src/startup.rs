@@ -0,0 +1,271 @@
+// The `service.startup` trace: one root span from process start until the server is
+// ready, with a child span per phase. Config load and telemetry init happen before there
+// is a tracer, so they are timed and their spans created afterwards with those times.
+
+use std::time::SystemTime;
+
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::registry::LookupSpan;
+
+pub struct Startup {
+    started: SystemTime,
+    // Phases timed before the tracer was installed
+    phases: Vec<(&'static str, SystemTime, SystemTime)>,
+}
+
+impl Startup {
+    pub fn begin() -> Self {
+        Self { started: SystemTime::now(), phases: Vec::new() }
+    }
+
+    pub fn phase<T>(&mut self, name: &'static str, work: impl FnOnce() -> T) -> T {
+        let started = SystemTime::now();
+        let result = work();
+        self.phases.push((name, started, SystemTime::now()));
+        result
+    }
+
+    pub async fn phase_async<T>(&mut self, name: &'static str, work: impl std::future::Future<Output = T>) -> T {
+        let started = SystemTime::now();
+        let result = work.await;
+        self.phases.push((name, started, SystemTime::now()));
+        result
+    }
+
+    // Once the subscriber is installed: the root span, with the phases so far below it.
+    // Later phases are spans of their own within it; it ends when dropped.
+    pub fn into_span(self) -> tracing::Span {
+        let root = tracing::info_span!(parent: None, "service.startup", otel.kind = "internal");
+        set_times(&root, self.started, None);
+
+        for (name, started, ended) in self.phases {
+            let phase = tracing::info_span!(parent: &root, "startup phase", otel.name = name);
+            set_times(&phase, started, Some(ended));
+        }
+        root
+    }
+}
+
+// What is running and how it's wired, the first event of every log once the subscriber is
+// up and the attributes of the `service.startup` span: the build, its features, where
+// telemetry goes and how it's sampled, and the database it uses
+pub struct Fingerprint {
+    exporter: &'static str,
+    protocol: String,
+    endpoint: String,
+    sampling_ratio: f64,
+    sampling_rules: i64,
+    #[cfg(feature = "mysql")]
+    db_target: String,
+}
+
+impl Fingerprint {
+    pub fn new(settings: &crate::config::Settings) -> Self {
+        let telemetry = &settings.telemetry;
+        let exporter = match telemetry.traces_exporter {
+            _ if !telemetry.enabled => "none",
+            crate::config::TracesExporter::Jaeger if cfg!(feature = "jaeger") => "jaeger",
+            _ => "otlp",
+        };
+        Self {
+            exporter,
+            protocol: crate::telemetry::protocol(telemetry),
+            endpoint: crate::telemetry::endpoint(telemetry).map_or_else(|e| e, |endpoint| crate::redact::scrub_urls(&endpoint).into_owned()),
+            sampling_ratio: settings.sampling.ratio,
+            sampling_rules: (settings.sampling.tenants.len() + settings.sampling.routes.len()) as i64,
+            #[cfg(feature = "mysql")]
+            db_target: crate::db::target(&settings.database),
+        }
+    }
+
+    pub fn log(&self) {
+        tracing::info!(
+            service.version = env!("CARGO_PKG_VERSION"),
+            vcs.revision = crate::build_info::VCS_REVISION,
+            vcs.dirty = crate::build_info::VCS_DIRTY == "true",
+            build.features = crate::build_info::FEATURES,
+            telemetry.exporter = self.exporter,
+            telemetry.protocol = self.protocol,
+            telemetry.endpoint = self.endpoint,
+            sampling.sampler = SAMPLER,
+            sampling.ratio = self.sampling_ratio,
+            sampling.rules = self.sampling_rules,
+            db.target = self.db_target(),
+            "Starting {} {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+        );
+    }
+
+    // The same on `span`, less what the resource says already
+    pub fn record(&self, span: &tracing::Span) {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        span.set_attribute("build.features", crate::build_info::FEATURES);
+        span.set_attribute("telemetry.exporter", self.exporter);
+        span.set_attribute("telemetry.protocol", self.protocol.clone());
+        span.set_attribute("telemetry.endpoint", self.endpoint.clone());
+        span.set_attribute("sampling.sampler", SAMPLER);
+        span.set_attribute("sampling.ratio", self.sampling_ratio);
+        span.set_attribute("sampling.rules", self.sampling_rules);
+        if let Some(target) = self.db_target() {
+            span.set_attribute("db.target", target.to_string());
+        }
+    }
+
+    fn db_target(&self) -> Option<&str> {
+        #[cfg(feature = "mysql")]
+        return Some(&self.db_target);
+        #[cfg(not(feature = "mysql"))]
+        None
+    }
+}
+
+// Of the banner (its module's, the default), which the stdout and file logs let through
+// below their usual WARN
+pub const TARGET: &str = module_path!();
+
+// See `sampling::TenantSampler`
+const SAMPLER: &str = "parent_based(tenant_ratio)";
+
+// Why the service didn't start, told apart by the exit code: a mistake in the config won't
+// go away by restarting, a dependency which is down may. The codes are sysexits.h's.
+#[derive(Debug)]
+pub enum StartupError {
+    // The config file couldn't be read or parsed
+    ConfigFile(String),
+    // `section` of the config is invalid
+    Config { section: &'static str, reason: String },
+    // `dependency` at `target` couldn't be reached
+    Dependency { dependency: &'static str, target: String, reason: String },
+    // A listener couldn't be bound to `address`
+    Bind { listener: &'static str, address: String, reason: String },
+    // The server failed once running
+    Serve(std::io::Error),
+}
+
+impl StartupError {
+    pub fn config(section: &'static str) -> impl FnOnce(String) -> Self {
+        move |reason| Self::Config { section, reason }
+    }
+
+    pub fn dependency<E: std::fmt::Display>(dependency: &'static str, target: String) -> impl FnOnce(E) -> Self {
+        move |e| Self::Dependency { dependency, target, reason: e.to_string() }
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            // EX_CONFIG
+            Self::ConfigFile(_) | Self::Config { .. } => 78,
+            // EX_UNAVAILABLE
+            Self::Dependency { .. } => 69,
+            // EX_OSERR
+            Self::Bind { .. } => 71,
+            // EX_SOFTWARE
+            Self::Serve(_) => 70,
+        }
+    }
+}
+
+// Which config was used: a missing file means the defaults
+pub fn config_source() -> String {
+    let path = crate::config::path();
+    match std::path::Path::new(&path).exists() {
+        true => path,
+        false => format!("the defaults, {path} not found"),
+    }
+}
+
+impl std::fmt::Display for StartupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let config = config_source();
+        match self {
+            // Naming the file already
+            Self::ConfigFile(reason) => write!(f, "{reason}"),
+            Self::Config { section, reason } => write!(f, "invalid [{section}] settings in {config}: {reason}"),
+            Self::Dependency { dependency, target, reason } => {
+                write!(f, "{dependency} at {target} is unavailable: {reason} (settings from {config})")
+            }
+            Self::Bind { listener, address, reason } => {
+                write!(f, "failed to bind the {listener} listener to {address}: {reason} (settings from {config})")
+            }
+            Self::Serve(e) => write!(f, "the server failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StartupError {}
+
+// Start (and end) times of a span which has not been exported yet
+fn set_times(span: &tracing::Span, started: SystemTime, ended: Option<SystemTime>) {
+    span.with_subscriber(|(id, dispatch)| {
+        let Some(span) = dispatch.downcast_ref::<tracing_subscriber::Registry>().and_then(|registry| registry.span(id)) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<OtelData>() {
+            data.builder.start_time = Some(started);
+            data.builder.end_time = ended;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases_before_the_tracer_keep_their_times() {
+        let mut startup = Startup::begin();
+        startup.phase("config load", || std::thread::sleep(std::time::Duration::from_millis(5)));
+
+        let telemetry = crate::test_support::init();
+        drop(startup.into_span());
+
+        let spans = telemetry.spans();
+        let phase = spans.assert_span_exists("config load").child_of("service.startup").span();
+        assert!(phase.end_time.duration_since(phase.start_time).unwrap() >= std::time::Duration::from_millis(5));
+        assert!(phase.end_time < spans.find("service.startup").unwrap().end_time);
+    }
+
+    #[test]
+    fn config_errors_and_outages_exit_differently() {
+        let config = StartupError::config("cors")("origin \"*\" with credentials".to_string());
+        let outage = StartupError::dependency("MySQL", "app@db:3306/app".to_string())("connection refused");
+        assert_ne!(config.exit_code(), outage.exit_code());
+        assert!(config.to_string().starts_with("invalid [cors] settings in "), "{config}");
+        assert!(outage.to_string().starts_with("MySQL at app@db:3306/app is unavailable: connection refused"), "{outage}");
+    }
+
+    #[test]
+    fn the_banner_reaches_stdout() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::logging::fmt_layer(move || writer.clone(), &Default::default(), &Default::default(), &Default::default()));
+        tracing::subscriber::with_default(subscriber, || {
+            Fingerprint::new(&Default::default()).log();
+            tracing::info!(target: "rust_trace_minimum::items", "Not at WARN");
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains(concat!("Starting ", env!("CARGO_PKG_NAME"))), "{output}");
+        assert_eq!(output.lines().count(), 1, "{output}");
+    }
+
+    // Collects what the fmt layer writes
+    #[derive(Clone, Default)]
+    struct Output(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,290 @@
+// Uploaded files, kept in S3 or an S3-compatible store with the AWS SDK. Each operation is
+// a span, "storage put" or "storage get", with the bucket, the key and the bytes sent or
+// received; the client spans of the SDK calls are under it, see `aws`. The SDK's own
+// retries are off: a 5xx, throttling or a failure to connect is retried by
+// `retry::Policy`, so `retry.count` and a "Retrying failed call" event per retry are on
+// the operation's span, with a call span per attempt.
+
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use tracing::Instrument;
+
+use crate::config::{DependencySettings, StorageSettings};
+use crate::retry::Policy;
+use crate::AppState;
+
+// S3's own limit on a key
+const MAX_KEY_BYTES: usize = 1024;
+
+pub struct Storage {
+    settings: StorageSettings,
+    policy: Policy,
+    // Made on first use, the credential chain being async
+    client: tokio::sync::OnceCell<aws_sdk_s3::Client>,
+}
+
+// Worth another attempt: the store was unreachable, slow, overloaded or throttling us
+fn is_retryable<E: ProvideErrorMetadata>(e: &SdkError<E, aws_sdk_s3::config::http::HttpResponse>) -> bool {
+    match e {
+        SdkError::TimeoutError(_) | SdkError::ResponseError(_) => true,
+        SdkError::DispatchFailure(failure) => failure.is_io() || failure.is_timeout(),
+        SdkError::ServiceError(e) => {
+            let status = e.raw().status();
+            status.is_server_error()
+                || status.as_u16() == 429
+                || matches!(e.err().code(), Some("SlowDown" | "Throttling" | "ThrottlingException" | "RequestTimeout"))
+        }
+        _ => false,
+    }
+}
+
+// The span of one S3 operation, its outcome recorded by `finish`
+fn operation_span(operation: &'static str, bucket: &str, key: &str) -> tracing::Span {
+    tracing::info_span!(
+        "storage",
+        otel.name = operation,
+        otel.status_code = tracing::field::Empty,
+        aws.s3.bucket = bucket,
+        aws.s3.key = key,
+        http.request.body.size = tracing::field::Empty,
+        http.response.body.size = tracing::field::Empty,
+    )
+}
+
+fn finish<T, E: std::fmt::Debug>(span: &tracing::Span, result: &Result<T, SdkError<E, aws_sdk_s3::config::http::HttpResponse>>) {
+    if let Err(e) = result {
+        span.record("otel.status_code", "error");
+        span.in_scope(|| tracing::error!("S3 call failed: {:?}", e));
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct Stored {
+    pub key: String,
+    pub size: usize,
+    pub etag: Option<String>,
+}
+
+impl Storage {
+    pub fn new(settings: &StorageSettings, dependency: &DependencySettings) -> Self {
+        Self { settings: settings.clone(), policy: Policy::new(dependency.clone()), client: tokio::sync::OnceCell::new() }
+    }
+
+    async fn client(&self) -> &aws_sdk_s3::Client {
+        self.client
+            .get_or_init(|| async {
+                let credentials = match (&self.settings.access_key_id, &self.settings.secret_access_key) {
+                    (Some(id), Some(secret)) => Some(aws_credential_types::Credentials::new(id, secret, None, None, "settings")),
+                    _ => None,
+                };
+                let sdk_config = crate::aws::load_config(&self.settings.region, &self.policy, credentials).await;
+
+                let mut config = aws_sdk_s3::config::Builder::from(&sdk_config)
+                    .force_path_style(self.settings.force_path_style)
+                    .interceptor(crate::aws::Tracing);
+                if let Some(endpoint) = &self.settings.endpoint {
+                    config = config.endpoint_url(endpoint);
+                }
+                aws_sdk_s3::Client::from_conf(config.build())
+            })
+            .await
+    }
+
+    fn object_key(&self, key: &str) -> String {
+        format!("{}{key}", self.settings.prefix)
+    }
+
+    pub async fn put(&self, key: &str, body: bytes::Bytes, content_type: Option<&str>) -> Result<Stored, SdkError<aws_sdk_s3::operation::put_object::PutObjectError, aws_sdk_s3::config::http::HttpResponse>> {
+        let object_key = self.object_key(key);
+        let span = operation_span("storage put", &self.settings.bucket, &object_key);
+        span.record("http.request.body.size", body.len() as i64);
+
+        let client = self.client().await;
+        let result = self
+            .policy
+            .run(is_retryable, || {
+                client
+                    .put_object()
+                    .bucket(&self.settings.bucket)
+                    .key(&object_key)
+                    .set_content_type(content_type.map(str::to_string))
+                    .content_length(body.len() as i64)
+                    .body(aws_sdk_s3::primitives::ByteStream::from(body.clone()))
+                    .send()
+            })
+            .instrument(span.clone())
+            .await;
+        finish(&span, &result);
+
+        let output = result?;
+        Ok(Stored { key: key.to_string(), size: body.len(), etag: output.e_tag().map(str::to_string) })
+    }
+
+    // The object and its content type, `None` when there's no such key
+    pub async fn get(&self, key: &str) -> Result<Option<(bytes::Bytes, Option<String>)>, String> {
+        let object_key = self.object_key(key);
+        let span = operation_span("storage get", &self.settings.bucket, &object_key);
+
+        let client = self.client().await;
+        let read = async {
+            let result = self.policy.run(is_retryable, || client.get_object().bucket(&self.settings.bucket).key(&object_key).send()).await;
+            if result.as_ref().err().and_then(SdkError::as_service_error).is_some_and(|e| e.is_no_such_key()) {
+                return Ok(None);
+            }
+            finish(&tracing::Span::current(), &result);
+
+            let output = result.map_err(|e| e.to_string())?;
+            let content_type = output.content_type().map(str::to_string);
+            let body = output.body.collect().await.map_err(|e| e.to_string())?.into_bytes();
+            tracing::Span::current().record("http.response.body.size", body.len() as i64);
+            Ok(Some((body, content_type)))
+        };
+        read.instrument(span).await
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_BYTES && !key.split('/').any(|segment| segment.is_empty() || segment == "." || segment == "..")
+}
+
+// Stores the body under `key`, with its Content-Type. It's in memory meanwhile, so
+// `[body_limit]` bounds how big an upload can be.
+#[utoipa::path(put, path = "/files/{key}", summary = "Stores the body in the bucket, with its Content-Type",
+    params(("key" = String, Path, description = "May have slashes, up to 1024 bytes")),
+    request_body(content = [u8], content_type = "*/*"),
+    responses(
+        (status = 201, body = Stored, description = "Where it was stored, its size and ETag"),
+        (status = 400, body = String, description = "The key is empty, too long or has an empty, `.` or `..` segment"),
+        (status = 502, body = String, description = "The store is unavailable"),
+        (status = 504, body = String, description = "The store timed out"),
+    ),
+)]
+#[traced_handler::traced_handler]
+pub async fn put(
+    axum::extract::State(AppState { storage, .. }): axum::extract::State<AppState>,
+    axum::extract::Path(key): axum::extract::Path<String>,
+    headers: axum::http::HeaderMap,
+    body: bytes::Bytes,
+) -> axum::response::Response {
+    if !valid_key(&key) {
+        return (StatusCode::BAD_REQUEST, "invalid key").into_response();
+    }
+
+    let content_type = headers.get(axum::http::header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    match storage.put(&key, body, content_type).await {
+        Ok(stored) => (StatusCode::CREATED, axum::Json(stored)).into_response(),
+        Err(SdkError::TimeoutError(_)) => (StatusCode::GATEWAY_TIMEOUT, "storage timed out").into_response(),
+        Err(_) => (StatusCode::BAD_GATEWAY, "storage is unavailable").into_response(),
+    }
+}
+
+// A stored file, as an attachment so a browser doesn't render what someone uploaded
+#[utoipa::path(get, path = "/files/{key}", summary = "A stored file, as an attachment",
+    params(("key" = String, Path, description = "May have slashes, up to 1024 bytes")),
+    responses(
+        (status = 200, body = [u8], content_type = "*/*", description = "The file"),
+        (status = 404, body = String, description = "No such file"),
+        (status = 502, body = String, description = "The store is unavailable"),
+    ),
+)]
+#[traced_handler::traced_handler]
+pub async fn get(
+    axum::extract::State(AppState { storage, .. }): axum::extract::State<AppState>,
+    axum::extract::Path(key): axum::extract::Path<String>,
+) -> axum::response::Response {
+    if !valid_key(&key) {
+        return (StatusCode::BAD_REQUEST, "invalid key").into_response();
+    }
+
+    match storage.get(&key).await {
+        Ok(Some((body, content_type))) => {
+            let content_type = content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+            let headers = [
+                (axum::http::header::CONTENT_TYPE, content_type),
+                (axum::http::header::CONTENT_DISPOSITION, "attachment".to_string()),
+                (axum::http::header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            ];
+            (headers, body).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "no such file").into_response(),
+        Err(_) => (StatusCode::BAD_GATEWAY, "storage is unavailable").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    // A store answering the first PUT with a 503 SlowDown, the next ones with an ETag
+    async fn store() -> (String, Arc<AtomicU32>) {
+        let puts = Arc::new(AtomicU32::new(0));
+        let app = axum::Router::new().route(
+            "/*path",
+            axum::routing::put({
+                let puts = puts.clone();
+                move |axum::extract::Path(path): axum::extract::Path<String>| async move {
+                    assert_eq!(path, "uploads/files/report.txt");
+                    match puts.fetch_add(1, Ordering::Relaxed) {
+                        0 => (StatusCode::SERVICE_UNAVAILABLE, [("content-type", "application/xml")], "<Error><Code>SlowDown</Code></Error>"),
+                        _ => (StatusCode::OK, [("etag", "\"abc\"")], ""),
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (endpoint, puts)
+    }
+
+    #[tokio::test]
+    async fn uploads_are_retried_and_traced() {
+        let telemetry = crate::test_support::init();
+        let (endpoint, puts) = store().await;
+        let settings = StorageSettings {
+            prefix: "files/".to_string(),
+            endpoint: Some(endpoint),
+            force_path_style: true,
+            access_key_id: Some("test".to_string()),
+            secret_access_key: Some("test".to_string()),
+            ..Default::default()
+        };
+        let dependency = DependencySettings { connect_timeout_ms: 1_000, read_timeout_ms: 5_000, retries: 2, retry_backoff_ms: 1 };
+        let storage = Storage::new(&settings, &dependency);
+
+        let stored = storage.put("report.txt", bytes::Bytes::from_static(b"hello"), Some("text/plain")).await.unwrap();
+        assert_eq!(stored.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(puts.load(Ordering::Relaxed), 2);
+
+        telemetry
+            .spans()
+            .assert_span_exists("storage put")
+            .with_attribute("aws.s3.bucket", "uploads")
+            .with_attribute("aws.s3.key", "files/report.txt")
+            .with_attribute("http.request.body.size", 5)
+            .with_attribute("retry.count", 1)
+            .without_error_status();
+        // A span per attempt, each with the retries before it
+        let spans = telemetry.spans();
+        let mut attempts: Vec<_> = spans
+            .all()
+            .iter()
+            .filter(|span| span.name == "S3.PutObject")
+            .flat_map(|span| span.attributes.iter().find(|kv| kv.key.as_str() == "retry.count").map(|kv| kv.value.to_string()))
+            .collect();
+        attempts.sort();
+        assert_eq!(attempts, ["0", "1"]);
+    }
+
+    #[test]
+    fn keys() {
+        assert!(valid_key("reports/2026/10.csv"));
+        assert!(!valid_key(""));
+        assert!(!valid_key("reports/../secrets"));
+        assert!(!valid_key("reports//10.csv"));
+        assert!(!valid_key(&"k".repeat(MAX_KEY_BYTES + 1)));
+    }
+}
@@ -0,0 +1,43 @@
+// Tells systemd the service is up, for `Type=notify` units
+pub fn notify_ready() {
+    match sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        Ok(()) => tracing::info!("Notified systemd of readiness"),
+        Err(e) => tracing::warn!("Failed to notify systemd of readiness: {:?}", e),
+    }
+}
+
+// That the service is stopping, as its drain begins
+pub fn notify_stopping() {
+    let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]);
+}
+
+// Pets the watchdog at half the configured interval, but only while the pool answers,
+// so systemd restarts a service that is running but can't reach its database
+#[cfg(feature = "mysql")]
+pub fn spawn_watchdog(pool: sqlx::MySqlPool) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    let interval = std::time::Duration::from_micros(usec) / 2;
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            let alive = tracing::Instrument::instrument(
+                sqlx::query("SELECT 1").execute(&pool),
+                tracing::debug_span!("watchdog ping"),
+            )
+            .await;
+
+            match alive {
+                Ok(_) => {
+                    let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]);
+                }
+                Err(e) => tracing::warn!("Skipping watchdog notification, database ping failed: {:?}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,127 @@
+// Traced task spawning, counted against the request that spawned the tasks. Work the
+// request doesn't wait for gets a trace of its own, linked back to the request.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tracing::instrument::WithSubscriber;
+use tracing::Instrument;
+
+tokio::task_local! {
+    static FAN_OUT: Arc<FanOut>;
+}
+
+// Tasks spawned while handling one request, and how long the request spent awaiting them
+#[derive(Default)]
+pub struct FanOut {
+    spawned: AtomicU64,
+    awaited_us: AtomicU64,
+}
+
+impl FanOut {
+    pub fn spawned(&self) -> u64 {
+        self.spawned.load(Ordering::Relaxed)
+    }
+
+    pub fn awaited(&self) -> Duration {
+        Duration::from_micros(self.awaited_us.load(Ordering::Relaxed))
+    }
+}
+
+// Counts what `future` spawns through this module into `fan_out`
+pub async fn scope<F: Future>(fan_out: Arc<FanOut>, future: F) -> F::Output {
+    FAN_OUT.scope(fan_out, future).await
+}
+
+fn count_spawn() -> Option<Arc<FanOut>> {
+    let fan_out = FAN_OUT.try_with(Arc::clone).ok()?;
+    fan_out.spawned.fetch_add(1, Ordering::Relaxed);
+    Some(fan_out)
+}
+
+// Evaluates to `span` with a link to the current span, when that is being traced. For the
+// root span of work the current one starts but doesn't wait for, which would otherwise
+// have nothing leading back to it.
+macro_rules! link_to_current {
+    ($span:expr) => {{
+        use opentelemetry::trace::TraceContextExt as _;
+        use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+        let span: tracing::Span = $span;
+        let current = tracing::Span::current().context().span().span_context().clone();
+        if current.is_valid() {
+            span.add_link(current);
+        }
+        span
+    }};
+}
+pub(crate) use link_to_current;
+
+// Spawns `future` detached from the current request: its span `name` is a root span,
+// linked to the current one, and it isn't counted into the fan-out as nothing awaits it
+pub fn spawn_detached<F>(name: &'static str, future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let span = link_to_current!(tracing::info_span!(parent: None, "task", otel.name = name));
+    tokio::spawn(future.instrument(span).with_current_subscriber())
+}
+
+// Runs `work` on the blocking pool, counted into the request's fan-out; no span, see
+// `blocking::run_blocking`
+pub fn spawn_blocking<T, F>(work: F) -> Task<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    Task {
+        fan_out: count_spawn(),
+        handle: tokio::task::spawn_blocking(work),
+        awaited_since: None,
+    }
+}
+
+// A spawned task's handle, adds the time from the first poll to completion to the fan-out
+pub struct Task<T> {
+    fan_out: Option<Arc<FanOut>>,
+    handle: tokio::task::JoinHandle<T>,
+    awaited_since: Option<Instant>,
+}
+
+impl<T> Future for Task<T> {
+    type Output = Result<T, tokio::task::JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let since = *this.awaited_since.get_or_insert_with(Instant::now);
+
+        let poll = Pin::new(&mut this.handle).poll(cx);
+        if let (Poll::Ready(_), Some(fan_out)) = (&poll, &this.fan_out) {
+            fan_out.awaited_us.fetch_add(since.elapsed().as_micros() as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn detached_work_links_back_to_the_request() {
+        use tracing::Instrument;
+
+        let telemetry = crate::test_support::init();
+        async { super::spawn_detached("send receipt", async {}).await.unwrap() }
+            .instrument(tracing::info_span!("request"))
+            .await;
+
+        let spans = telemetry.spans();
+        let request = spans.find("request").unwrap();
+        let task = spans.assert_span_exists("send receipt").without_parent().span();
+        assert_eq!(task.links.links[0].span_context.span_id(), request.span_context.span_id());
+    }
+}
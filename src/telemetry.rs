@@ -0,0 +1,1118 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::future::BoxFuture;
+use opentelemetry::trace::TracerProvider as _;
+#[cfg(any(feature = "otlp-grpc", feature = "otlp-http"))]
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::trace::{RandomIdGenerator, ShouldSample, Span, SpanProcessor};
+use opentelemetry_sdk::Resource;
+use opentelemetry_semantic_conventions::{
+    attribute::{SERVICE_NAME, SERVICE_VERSION},
+    SCHEMA_URL,
+};
+
+#[cfg(feature = "metrics")]
+use crate::config::{MetricViewSettings, MetricsTemporality};
+use crate::config::{SpanProcessor as SpanProcessorKind, TelemetrySettings, TracesExporter};
+use crate::vendor::ExportTarget;
+
+#[cfg(feature = "otlp-grpc")]
+const GRPC_ENDPOINT: &str = "http://localhost:4317";
+#[cfg(feature = "otlp-http")]
+const HTTP_ENDPOINT: &str = "http://localhost:4318";
+
+// Prefixes `telemetry.collector_socket` in an endpoint
+#[cfg(feature = "otlp-grpc")]
+const UNIX_SCHEME: &str = "unix:";
+
+// `telemetry.protocol`, or any for a vendor, is built in; a config error at startup rather than
+// exporters which never come up
+fn check_protocol(settings: &TelemetrySettings) -> Result<(), String> {
+    if settings.vendor.is_some() {
+        return match cfg!(any(feature = "otlp-grpc", feature = "otlp-http")) {
+            true => Ok(()),
+            false => Err("exporting to a vendor needs the otlp-grpc or otlp-http feature".to_string()),
+        };
+    }
+    match settings.protocol.as_str() {
+        "grpc" if cfg!(feature = "otlp-grpc") => Ok(()),
+        "http/protobuf" if cfg!(feature = "otlp-http") => Ok(()),
+        "grpc" => Err("telemetry.protocol \"grpc\" needs the otlp-grpc feature".to_string()),
+        "http/protobuf" => Err("telemetry.protocol \"http/protobuf\" needs the otlp-http feature".to_string()),
+        other => Err(format!("telemetry.protocol {other:?} is unknown, it's \"grpc\" or \"http/protobuf\"")),
+    }
+}
+
+// The local collector for `telemetry.protocol`, or the vendor's endpoint
+fn export_target(settings: &TelemetrySettings) -> Result<ExportTarget, String> {
+    check_protocol(settings)?;
+    if let Some(socket) = &settings.collector_socket {
+        #[cfg(feature = "otlp-grpc")]
+        if settings.vendor.is_none() && settings.protocol == "grpc" {
+            return Ok(ExportTarget {
+                protocol: settings.protocol.clone(),
+                endpoint: format!("{UNIX_SCHEME}{socket}"),
+                headers: Vec::new(),
+                gzip: false,
+            });
+        }
+        return Err(format!("telemetry.collector_socket {socket:?} is for a collector speaking OTLP/gRPC, with the otlp-grpc feature"));
+    }
+
+    let Some(vendor) = settings.vendor else {
+        let endpoint = match settings.protocol.as_str() {
+            #[cfg(feature = "otlp-grpc")]
+            "grpc" => GRPC_ENDPOINT,
+            #[cfg(feature = "otlp-http")]
+            "http/protobuf" => HTTP_ENDPOINT,
+            _ => "",
+        };
+        return Ok(ExportTarget {
+            protocol: settings.protocol.clone(),
+            endpoint: endpoint.to_string(),
+            headers: Vec::new(),
+            gzip: false,
+        });
+    };
+
+    let api_key = std::env::var(crate::vendor::API_KEY_ENV)
+        .ok()
+        .or_else(|| settings.api_key.clone())
+        .ok_or_else(|| format!("exporting to {vendor:?} needs an API key, in telemetry.api_key or {}", crate::vendor::API_KEY_ENV))?;
+    crate::vendor::preset(vendor, settings.vendor_region.as_deref(), &api_key)
+}
+
+// The target for each of `telemetry.endpoints`, in order, or the one target without them
+fn export_targets(settings: &TelemetrySettings) -> Result<Vec<ExportTarget>, String> {
+    let target = export_target(settings)?;
+    if settings.endpoints.is_empty() {
+        return Ok(vec![target]);
+    }
+    Ok(settings.endpoints.iter().map(|endpoint| ExportTarget { endpoint: endpoint.clone(), ..target.clone() }).collect())
+}
+
+// Where spans and metrics are exported to, the first of the endpoints, for `preflight`
+pub fn endpoint(settings: &TelemetrySettings) -> Result<String, String> {
+    export_targets(settings).map(|targets| targets[0].endpoint.clone())
+}
+
+// The protocol spans and metrics are exported with, a vendor's say, for the startup banner
+pub fn protocol(settings: &TelemetrySettings) -> String {
+    export_target(settings).map_or_else(|_| settings.protocol.clone(), |target| target.protocol)
+}
+
+// Those of the targets, for the failover's logs and `/admin/config`
+pub fn endpoints(settings: &TelemetrySettings) -> Vec<String> {
+    match export_targets(settings) {
+        Ok(targets) => targets.into_iter().map(|target| target.endpoint).collect(),
+        Err(_) => vec![String::new(); settings.endpoints.len().max(1)],
+    }
+}
+
+// What the SDK reads from the environment per signal; the exporters here have channels and
+// clients of their own, which it doesn't configure, so they honour the same variables
+#[cfg(any(feature = "otlp-grpc", feature = "otlp-http"))]
+#[derive(Debug, Clone, Copy)]
+enum Signal {
+    Traces,
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    Metrics,
+}
+
+#[cfg(any(feature = "otlp-grpc", feature = "otlp-http"))]
+impl Signal {
+    // OTEL_EXPORTER_OTLP_TRACES_<name> say, or OTEL_EXPORTER_OTLP_<name>
+    fn env(self, name: &str) -> Option<String> {
+        let signal = match self {
+            Signal::Traces => "TRACES",
+            Signal::Metrics => "METRICS",
+        };
+        std::env::var(format!("OTEL_EXPORTER_OTLP_{signal}_{name}"))
+            .or_else(|_| std::env::var(format!("OTEL_EXPORTER_OTLP_{name}")))
+            .ok()
+            .filter(|value| !value.is_empty())
+    }
+
+    // In milliseconds, as the specification has the variables, over `telemetry.timeout_ms`
+    fn timeout(self, settings: &TelemetrySettings) -> std::time::Duration {
+        let ms = self.env("TIMEOUT").and_then(|ms| ms.parse().ok()).unwrap_or(settings.timeout_ms);
+        std::time::Duration::from_millis(ms)
+    }
+}
+
+#[cfg(feature = "otlp-grpc")]
+fn tonic_exporter(target: &ExportTarget, signal: Signal, settings: &TelemetrySettings) -> Result<opentelemetry_otlp::TonicExporterBuilder, String> {
+    let mut metadata = tonic::metadata::MetadataMap::new();
+    for (name, value) in &target.headers {
+        let key = tonic::metadata::MetadataKey::from_bytes(name.as_bytes()).map_err(|e| format!("invalid header name {name:?}: {e}"))?;
+        let value = value.parse().map_err(|_| format!("invalid value of header {name:?}"))?;
+        metadata.insert(key, value);
+    }
+
+    let timeout = signal.timeout(settings);
+    let mut exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_timeout(timeout)
+        .with_metadata(metadata);
+    exporter = match target.endpoint.strip_prefix(UNIX_SCHEME) {
+        Some(socket) => exporter.with_channel(unix_channel(socket.into(), timeout)),
+        // the variables win over the endpoint, as with the SDK's own channel
+        None => exporter.with_channel(tcp_channel(&signal.env("ENDPOINT").unwrap_or_else(|| target.endpoint.clone()), timeout)?),
+    };
+    if target.gzip {
+        exporter = exporter.with_compression(opentelemetry_otlp::Compression::Gzip);
+    }
+    Ok(exporter)
+}
+
+// Connected on first export, and again whenever the collector went away, the host looked
+// up through `dns` each time; over TLS for https. An export taking longer than `timeout`,
+// connecting included, fails
+#[cfg(feature = "otlp-grpc")]
+fn tcp_channel(endpoint: &str, timeout: std::time::Duration) -> Result<tonic::transport::Channel, String> {
+    let mut channel = tonic::transport::Endpoint::from_shared(endpoint.to_string())
+        .map_err(|e| format!("invalid endpoint {endpoint:?}: {e}"))?
+        .connect_timeout(timeout)
+        .timeout(timeout);
+    if endpoint.starts_with("https://") {
+        channel = channel.tls_config(tonic::transport::ClientTlsConfig::new().with_native_roots()).map_err(|e| e.to_string())?;
+    }
+    Ok(channel.connect_with_connector_lazy(tower::service_fn(|uri: axum::http::Uri| async move {
+        crate::dns::connect(&uri, crate::dns::Origin::Exporter).await.map(hyper_util::rt::TokioIo::new)
+    })))
+}
+
+// Connected on first export, and again whenever the collector went away; the URI is only
+// used for the requests' :authority
+#[cfg(feature = "otlp-grpc")]
+fn unix_channel(socket: std::path::PathBuf, timeout: std::time::Duration) -> tonic::transport::Channel {
+    tonic::transport::Endpoint::from_static("http://localhost")
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .connect_with_connector_lazy(tower::service_fn(move |_: axum::http::Uri| {
+            let socket = socket.clone();
+            async move { tokio::net::UnixStream::connect(socket).await.map(hyper_util::rt::TokioIo::new) }
+        }))
+}
+
+// A client of our own, so the collector's host is looked up through `dns`
+#[cfg(feature = "otlp-http")]
+fn http_client(timeout: std::time::Duration) -> Result<reqwest::Client, String> {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .dns_resolver(Arc::new(crate::dns::Resolver(crate::dns::Origin::Exporter)))
+        .build()
+        .map_err(|e| format!("failed to build the OTLP/HTTP client: {e}"))
+}
+
+// The SDK reads the endpoint variables itself, appending the signal's path
+#[cfg(feature = "otlp-http")]
+fn http_exporter(target: &ExportTarget, signal: Signal, settings: &TelemetrySettings) -> Result<opentelemetry_otlp::HttpExporterBuilder, String> {
+    let path = match signal {
+        Signal::Traces => "/v1/traces",
+        Signal::Metrics => "/v1/metrics",
+    };
+    Ok(opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(format!("{}{path}", target.endpoint))
+        .with_headers(target.headers.iter().cloned().collect())
+        .with_http_client(http_client(signal.timeout(settings))?))
+}
+
+// What can't be put right by retrying, a config error at startup: the protocol, the
+// targets and an exporter for each, which the metrics exporters are built from as well.
+// With these settings building them again can't fail, nothing is connected to yet.
+pub fn check(settings: &TelemetrySettings) -> Result<(), String> {
+    check_protocol(settings)?;
+    #[cfg(any(feature = "otlp-grpc", feature = "otlp-http"))]
+    for target in export_targets(settings)? {
+        span_exporter(&target, settings)?.build_span_exporter().map_err(|e| format!("{}: {e}", target.endpoint))?;
+    }
+    Ok(())
+}
+
+// The exporter builders for the target's protocol, each protocol needs its cargo feature
+#[cfg_attr(not(any(feature = "otlp-grpc", feature = "otlp-http")), allow(unused_variables))]
+fn span_exporter(target: &ExportTarget, settings: &TelemetrySettings) -> Result<opentelemetry_otlp::SpanExporterBuilder, String> {
+    match target.protocol.as_str() {
+        #[cfg(feature = "otlp-grpc")]
+        "grpc" => Ok(tonic_exporter(target, Signal::Traces, settings)?.into()),
+        #[cfg(feature = "otlp-http")]
+        "http/protobuf" => Ok(http_exporter(target, Signal::Traces, settings)?.into()),
+        other => Err(format!("OTLP protocol {other:?} is unknown or its feature isn't enabled")),
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[cfg_attr(not(any(feature = "otlp-grpc", feature = "otlp-http")), allow(unused_variables))]
+fn metrics_exporter(target: &ExportTarget, settings: &TelemetrySettings) -> Result<opentelemetry_otlp::MetricsExporterBuilder, String> {
+    match target.protocol.as_str() {
+        #[cfg(feature = "otlp-grpc")]
+        "grpc" => Ok(tonic_exporter(target, Signal::Metrics, settings)?.into()),
+        #[cfg(feature = "otlp-http")]
+        "http/protobuf" => Ok(http_exporter(target, Signal::Metrics, settings)?.into()),
+        other => Err(format!("OTLP protocol {other:?} is unknown or its feature isn't enabled")),
+    }
+}
+
+// Temporality by instrument kind for `metrics_temporality = "delta"`
+#[cfg(feature = "metrics")]
+struct DeltaTemporality;
+
+#[cfg(feature = "metrics")]
+impl opentelemetry_sdk::metrics::reader::TemporalitySelector for DeltaTemporality {
+    fn temporality(&self, kind: opentelemetry_sdk::metrics::InstrumentKind) -> opentelemetry_sdk::metrics::data::Temporality {
+        use opentelemetry_sdk::metrics::{data::Temporality, InstrumentKind};
+
+        match kind {
+            // A delta of a value that goes both ways can't be summed back up reliably
+            InstrumentKind::UpDownCounter | InstrumentKind::ObservableUpDownCounter => Temporality::Cumulative,
+            _ => Temporality::Delta,
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn temporality(temporality: MetricsTemporality) -> Box<dyn opentelemetry_sdk::metrics::reader::TemporalitySelector> {
+    match temporality {
+        MetricsTemporality::Cumulative => Box::new(opentelemetry_sdk::metrics::reader::DefaultTemporalitySelector::new()),
+        MetricsTemporality::Delta => Box::new(DeltaTemporality),
+    }
+}
+
+// The metrics exporters the same way, one per endpoint, with the temporality known before
+// they're built
+#[cfg(feature = "metrics")]
+struct DeferredMetrics {
+    exporters: Vec<Deferred<opentelemetry_otlp::MetricsExporter>>,
+    failover: crate::failover::Failover,
+    temporality: MetricsTemporality,
+}
+
+#[cfg(feature = "metrics")]
+impl opentelemetry_sdk::metrics::reader::TemporalitySelector for DeferredMetrics {
+    fn temporality(&self, kind: opentelemetry_sdk::metrics::InstrumentKind) -> opentelemetry_sdk::metrics::data::Temporality {
+        temporality(self.temporality).temporality(kind)
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[axum::async_trait]
+impl opentelemetry_sdk::metrics::exporter::PushMetricsExporter for DeferredMetrics {
+    async fn export(&self, metrics: &mut opentelemetry_sdk::metrics::data::ResourceMetrics) -> opentelemetry::metrics::Result<()> {
+        let mut result = Ok(());
+        for index in self.failover.order() {
+            result = match self.exporters[index].get() {
+                Ok(exporter) => exporter.export(metrics).await,
+                Err(e) => Err(opentelemetry::metrics::MetricsError::Other(e)),
+            };
+            self.failover.record(index, result.is_ok());
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    async fn force_flush(&self) -> opentelemetry::metrics::Result<()> {
+        for exporter in self.exporters.iter().filter_map(|exporter| exporter.inner.get()) {
+            exporter.force_flush().await?;
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) -> opentelemetry::metrics::Result<()> {
+        self.exporters.iter().filter_map(|exporter| exporter.inner.get()).try_for_each(|exporter| exporter.shutdown())
+    }
+}
+
+// The SDK view for one `[[telemetry.metric_views]]` entry
+#[cfg(feature = "metrics")]
+fn metric_view(settings: &MetricViewSettings) -> Result<Box<dyn opentelemetry_sdk::metrics::View>, String> {
+    use opentelemetry_sdk::metrics::{Aggregation, Instrument, Stream};
+
+    // The SDK drops views it considers invalid with only a logged error
+    if settings.rename.is_some() && settings.instrument.contains(['*', '?']) {
+        return Err(format!("metric view for {:?} renames more than one instrument", settings.instrument));
+    }
+    if settings.buckets.as_ref().is_some_and(|buckets| buckets.windows(2).any(|pair| pair[0] >= pair[1])) {
+        return Err(format!("metric view for {:?} has buckets which aren't increasing", settings.instrument));
+    }
+
+    let mut stream = Stream::new();
+    if settings.drop {
+        stream = stream.aggregation(Aggregation::Drop);
+    } else {
+        if let Some(name) = &settings.rename {
+            stream = stream.name(name.clone());
+        }
+        if let Some(boundaries) = &settings.buckets {
+            stream = stream.aggregation(Aggregation::ExplicitBucketHistogram {
+                boundaries: boundaries.clone(),
+                record_min_max: true,
+            });
+        }
+        if let Some(keys) = &settings.attributes {
+            stream = stream.allowed_attribute_keys(keys.iter().cloned().map(opentelemetry::Key::new));
+        }
+    }
+
+    opentelemetry_sdk::metrics::new_view(Instrument::new().name(settings.instrument.clone()), stream)
+        .map_err(|e| format!("invalid metric view for {:?}: {e}", settings.instrument))
+}
+
+// The span processor of `telemetry.span_processor` handing spans to `exporter`
+fn exporting_processor(kind: SpanProcessorKind, exporter: impl SpanExporter + 'static) -> Box<dyn SpanProcessor> {
+    match kind {
+        SpanProcessorKind::Batch => Box::new(opentelemetry_sdk::trace::BatchSpanProcessor::builder(exporter, opentelemetry_sdk::runtime::Tokio).build()),
+        SpanProcessorKind::Simple => Box::new(opentelemetry_sdk::trace::SimpleSpanProcessor::new(Box::new(exporter))),
+    }
+}
+
+// Tracer and meter providers exporting to the collector, installed globally
+pub struct Pipeline {
+    provider: opentelemetry_sdk::trace::TracerProvider,
+    #[cfg(feature = "metrics")]
+    meter_provider: opentelemetry_sdk::metrics::SdkMeterProvider,
+    // What went wrong installing it, for the caller to log once there is a log
+    warnings: Vec<String>,
+}
+
+// A processor of the caller's own, see `PipelineBuilder::with_span_processor`. Unlike the
+// SDK's, which all get the same span, it hands the span on to the next once it ends,
+// changed or not, or drops it.
+pub trait ChainedProcessor: std::fmt::Debug + Send + Sync {
+    fn on_start(&self, _span: &mut Span, _cx: &opentelemetry::Context) {}
+
+    // None drops the span, the processors after this one and the exporter never see it
+    fn on_end(&self, span: SpanData) -> Option<SpanData>;
+}
+
+// The caller's processors in order, then the one exporting; spans are counted going in and,
+// those the processors kept, going on to the exporting one. The batch processor doesn't
+// report drops, they're counted when a span is handed to it while the queue is already full.
+#[derive(Debug)]
+struct Chain {
+    processors: Vec<Box<dyn ChainedProcessor>>,
+    stats: Arc<PipelineStats>,
+    exporting: Box<dyn SpanProcessor>,
+}
+
+impl SpanProcessor for Chain {
+    fn on_start(&self, span: &mut Span, cx: &opentelemetry::Context) {
+        self.stats.started.fetch_add(1, Ordering::Relaxed);
+        for processor in &self.processors {
+            processor.on_start(span, cx);
+        }
+        self.exporting.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        let Some(span) = self.processors.iter().try_fold(span, |span, processor| processor.on_end(span)) else {
+            return;
+        };
+        if self.stats.queued() >= self.stats.queue_capacity {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.stats.ended.fetch_add(1, Ordering::Relaxed);
+        self.exporting.on_end(span);
+    }
+
+    fn force_flush(&self) -> opentelemetry::trace::TraceResult<()> {
+        self.exporting.force_flush()
+    }
+
+    fn shutdown(&self) -> opentelemetry::trace::TraceResult<()> {
+        self.exporting.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.exporting.set_resource(resource);
+    }
+}
+
+// The same exporting as each span ends, for tests of processors elsewhere
+#[cfg(all(test, feature = "pprof"))]
+pub fn chain(processors: Vec<Box<dyn ChainedProcessor>>, exporter: impl SpanExporter + 'static) -> impl SpanProcessor {
+    Chain { processors, stats: Arc::new(PipelineStats::new()), exporting: exporting_processor(SpanProcessorKind::Simple, exporter) }
+}
+
+// The pipeline before it's installed, for processors of the caller's own: enrichment,
+// redaction, filtering. They run in the order they're registered, in front of the exporter.
+// What one sets in `on_start` is exported with the span, and the processors after it see it;
+// in `on_end` each gets the span as the one before handed it on, and what it hands on is
+// what's exported, unless it drops the span.
+pub struct PipelineBuilder<'a, S> {
+    settings: &'a TelemetrySettings,
+    resource_attributes: Vec<opentelemetry::KeyValue>,
+    sampler: S,
+    exporter_health: Arc<ExporterHealth>,
+    pipeline_stats: Arc<PipelineStats>,
+    processors: Vec<Box<dyn ChainedProcessor>>,
+}
+
+impl<S: ShouldSample + 'static> PipelineBuilder<'_, S> {
+    // Added to the resource, such as where the server listens
+    pub fn with_resource_attributes(mut self, attributes: Vec<opentelemetry::KeyValue>) -> Self {
+        self.resource_attributes.extend(attributes);
+        self
+    }
+
+    pub fn with_span_processor(mut self, processor: impl ChainedProcessor + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    pub async fn install(self) -> Pipeline {
+        let Self { settings, resource_attributes, sampler, exporter_health, pipeline_stats, processors } = self;
+
+        // Resource setup, detected attributes first so the ones set here take precedence
+        let detectors = settings.resource_detectors.clone();
+        let timeout = std::time::Duration::from_millis(settings.resource_detection_timeout_ms);
+        let cloud_cache = settings.cloud_metadata_cache.clone();
+        let mut warnings = Vec::new();
+        let detected = tokio::task::spawn_blocking(move || crate::resource::detect(&detectors, timeout, cloud_cache.as_deref()))
+            .await
+            .unwrap()
+            .unwrap_or_else(|e| {
+                warnings.push(format!("Resource detection failed, exporting without the detected attributes: {e}"));
+                Resource::empty()
+            });
+
+        let resource = detected.merge(&Resource::from_schema_url(
+            [
+                opentelemetry::KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")),
+                opentelemetry::KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")),
+                opentelemetry::KeyValue::new("service.instance.id", instance_id()),
+                opentelemetry::KeyValue::new("deployment.environment", settings.environment()),
+            ]
+            .into_iter()
+            .chain(crate::build_info::resource_attributes())
+            .chain(resource_attributes),
+            SCHEMA_URL,
+        ));
+
+        // Tracer setup; the exporters may only come up later, the collectors' settings fixed,
+        // and the result of every export is kept for the health endpoints
+        #[cfg(not(feature = "jaeger"))]
+        if settings.traces_exporter == TracesExporter::Jaeger {
+            warnings.push("traces_exporter = \"jaeger\" needs the jaeger feature, exporting spans over OTLP".to_string());
+        }
+        let exporting = match settings.traces_exporter {
+            #[cfg(feature = "jaeger")]
+            TracesExporter::Jaeger => {
+                let agent = settings.jaeger_agent.clone();
+                let (exporter, error) = Deferred::new("span", move || crate::jaeger::JaegerExporter::new(&agent));
+                warnings.extend(error);
+                exporting_processor(settings.span_processor, TrackedExporter::new(exporter, exporter_health, pipeline_stats.clone()))
+            }
+            // one per endpoint to fail over between
+            _ => {
+                let exporters = (0..settings.endpoints.len().max(1))
+                    .map(|index| {
+                        let exporter_settings = settings.clone();
+                        let (exporter, error) = Deferred::new("span", move || {
+                            span_exporter(&export_targets(&exporter_settings)?[index], &exporter_settings)?.build_span_exporter().map_err(|e| e.to_string())
+                        });
+                        warnings.extend(error);
+                        exporter
+                    })
+                    .collect();
+                let failover = crate::failover::Failover::new("span", endpoints(settings), settings);
+                let exporter = crate::failover::FailoverExporter::new(exporters, failover);
+                exporting_processor(settings.span_processor, TrackedExporter::new(exporter, exporter_health, pipeline_stats.clone()))
+            }
+        };
+        // the caller's processors in front of it, the spans counted for `/debug/telemetry`
+        let builder = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_span_processor(Chain { processors, stats: pipeline_stats.clone(), exporting });
+
+        let config = opentelemetry_sdk::trace::Config::default()
+            // sampling rate
+            .with_sampler(sampler)
+
+            // resource
+            .with_resource(resource.clone());
+
+        // id generator, seeded for ids which are the same every run
+        let config = match settings.id_seed {
+            Some(seed) => config.with_id_generator(crate::ids::SeededIdGenerator::new(seed)),
+            None => config.with_id_generator(RandomIdGenerator::default()),
+        };
+        let provider = builder.with_config(config).build();
+        opentelemetry::global::set_tracer_provider(provider.clone());
+
+        // Meter setup, instruments are created from the global meter provider
+        #[cfg(feature = "metrics")]
+        let meter_provider = {
+            let exporters = (0..settings.endpoints.len().max(1))
+                .map(|index| {
+                    let exporter_settings = settings.clone();
+                    let (exporter, error) = Deferred::new("metrics", move || {
+                        metrics_exporter(&export_targets(&exporter_settings)?[index], &exporter_settings)?
+                            .build_metrics_exporter(temporality(exporter_settings.metrics_temporality))
+                            .map_err(|e| e.to_string())
+                    });
+                    warnings.extend(error);
+                    exporter
+                })
+                .collect();
+            let failover = crate::failover::Failover::new("metrics", endpoints(settings), settings);
+            let exporter = DeferredMetrics { exporters, failover, temporality: settings.metrics_temporality };
+            let reader = opentelemetry_sdk::metrics::PeriodicReader::builder(exporter, opentelemetry_sdk::runtime::Tokio)
+                .with_interval(std::time::Duration::from_millis(settings.metrics_export_interval_ms))
+                .build();
+
+            let builder = opentelemetry_sdk::metrics::SdkMeterProvider::builder()
+                .with_reader(reader)
+                .with_resource(resource);
+            let views = settings.metric_views.iter().map(metric_view).filter_map(|view| view.map_err(|e| warnings.push(format!("Ignoring a metric view: {e}"))).ok());
+            views.fold(builder, |builder, view| builder.with_view(view)).build()
+        };
+        #[cfg(feature = "metrics")]
+        opentelemetry::global::set_meter_provider(meter_provider.clone());
+        pipeline_stats.register_metrics();
+
+        Pipeline {
+            provider,
+            #[cfg(feature = "metrics")]
+            meter_provider,
+            warnings,
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn builder<S>(settings: &TelemetrySettings, sampler: S, exporter_health: Arc<ExporterHealth>, pipeline_stats: Arc<PipelineStats>) -> PipelineBuilder<'_, S> {
+        PipelineBuilder { settings, resource_attributes: Vec::new(), sampler, exporter_health, pipeline_stats, processors: Vec::new() }
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    pub fn tracer(&self) -> opentelemetry_sdk::trace::Tracer {
+        self.provider.tracer(env!("CARGO_PKG_NAME"))
+    }
+
+    // Flushes what is still queued
+    pub fn shutdown(self) {
+        let _ = self.provider.shutdown();
+        #[cfg(feature = "metrics")]
+        let _ = self.meter_provider.shutdown();
+    }
+}
+
+// The batch processor's queue size, it reads the same variable
+fn batch_queue_capacity() -> u64 {
+    std::env::var("OTEL_BSP_MAX_QUEUE_SIZE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(2048)
+}
+
+// Generated once per process, tells replicas of the same version apart
+pub fn instance_id() -> &'static str {
+    static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+    INSTANCE_ID.get_or_init(|| uuid::Uuid::new_v4().to_string())
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+// Outcome of the most recent span exports, 0 meaning "never"
+#[derive(Debug, Default)]
+pub struct ExporterHealth {
+    last_success_ms: AtomicU64,
+    last_failure_ms: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    // The self-test's trace, and how its export went once there was one
+    canary: Mutex<Option<(opentelemetry::trace::TraceId, Option<CanaryOutcome>)>>,
+}
+
+type CanaryOutcome = Result<(), String>;
+
+impl ExporterHealth {
+    pub fn last_success_ms(&self) -> Option<u64> {
+        Some(self.last_success_ms.load(Ordering::Relaxed)).filter(|ms| *ms > 0)
+    }
+
+    pub fn last_failure_ms(&self) -> Option<u64> {
+        Some(self.last_failure_ms.load(Ordering::Relaxed)).filter(|ms| *ms > 0)
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    // Healthy until an export fails, and again after the next one succeeds
+    pub fn is_healthy(&self) -> bool {
+        self.last_failure_ms() <= self.last_success_ms()
+    }
+
+    // Looks out for this trace in the exports, in place of the one before
+    pub fn await_canary(&self, trace_id: opentelemetry::trace::TraceId) {
+        *self.canary.lock().unwrap() = Some((trace_id, None));
+    }
+
+    // How the export of the awaited trace went, None until one carried it
+    pub fn canary_outcome(&self) -> Option<CanaryOutcome> {
+        self.canary.lock().unwrap().as_ref().and_then(|(_, outcome)| outcome.clone())
+    }
+
+    // The awaited trace, when the batch carries it
+    fn carries_canary(&self, batch: &[SpanData]) -> Option<opentelemetry::trace::TraceId> {
+        let canary = self.canary.lock().unwrap();
+        let (trace_id, _) = canary.as_ref()?;
+        batch.iter().any(|span| span.span_context.trace_id() == *trace_id).then_some(*trace_id)
+    }
+
+    // Only while that trace is still the one awaited, a later self-test's isn't decided by it
+    fn canary_exported(&self, trace_id: opentelemetry::trace::TraceId, result: &ExportResult) {
+        if let Some((awaited, outcome)) = self.canary.lock().unwrap().as_mut() {
+            if *awaited == trace_id {
+                *outcome = Some(result.as_ref().map(|_| ()).map_err(ToString::to_string));
+            }
+        }
+    }
+}
+
+type StatsCounter = fn(&PipelineStats) -> &AtomicU64;
+
+// Span counts through the pipeline, from the tracer to the collector
+#[derive(Debug)]
+pub struct PipelineStats {
+    started: AtomicU64,
+    ended: AtomicU64,
+    exported: AtomicU64,
+    export_errors: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+    queue_capacity: u64,
+}
+
+impl PipelineStats {
+    pub fn new() -> Self {
+        Self {
+            started: AtomicU64::new(0),
+            ended: AtomicU64::new(0),
+            exported: AtomicU64::new(0),
+            export_errors: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            queue_capacity: batch_queue_capacity(),
+        }
+    }
+
+    // Ended spans not yet exported, failed or dropped, i.e. waiting in the batch queue
+    fn queued(&self) -> u64 {
+        let ended = self.ended.load(Ordering::Relaxed);
+        let done = self.exported.load(Ordering::Relaxed) + self.failed.load(Ordering::Relaxed) + self.dropped.load(Ordering::Relaxed);
+        ended.saturating_sub(done)
+    }
+
+    fn queue_fill_ratio(&self) -> f64 {
+        self.queued() as f64 / self.queue_capacity as f64
+    }
+
+    // Observable instruments reading the counters; call after the meter provider is installed
+    pub fn register_metrics(self: &Arc<Self>) {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+
+        let counters: [(&str, &str, StatsCounter); 6] = [
+            ("telemetry.spans.started", "Sampled spans started", |stats| &stats.started),
+            ("telemetry.spans.ended", "Sampled spans ended and handed to the batch processor", |stats| &stats.ended),
+            ("telemetry.spans.exported", "Spans accepted by the collector", |stats| &stats.exported),
+            ("telemetry.spans.failed", "Spans in batches the collector did not accept", |stats| &stats.failed),
+            ("telemetry.spans.dropped", "Spans dropped because the batch queue was full (estimated)", |stats| &stats.dropped),
+            ("telemetry.export.errors", "Failed span export calls", |stats| &stats.export_errors),
+        ];
+        for (name, description, counter) in counters {
+            let stats = self.clone();
+            meter
+                .u64_observable_counter(name)
+                .with_description(description)
+                .with_callback(move |observer| observer.observe(counter(&stats).load(Ordering::Relaxed), &[]))
+                .init();
+        }
+
+        let stats = self.clone();
+        meter
+            .f64_observable_gauge("telemetry.queue.fill_ratio")
+            .with_description("Share of the batch span queue in use")
+            .with_callback(move |observer| observer.observe(stats.queue_fill_ratio(), &[]))
+            .init();
+    }
+
+    pub fn snapshot(&self) -> serde_json::Value {
+        serde_json::json!({
+            "spans": {
+                "started": self.started.load(Ordering::Relaxed),
+                "ended": self.ended.load(Ordering::Relaxed),
+                "exported": self.exported.load(Ordering::Relaxed),
+                "failed": self.failed.load(Ordering::Relaxed),
+                "dropped": self.dropped.load(Ordering::Relaxed),
+            },
+            "export_errors": self.export_errors.load(Ordering::Relaxed),
+            "queue": {
+                "queued": self.queued(),
+                "capacity": self.queue_capacity,
+                "fill_ratio": self.queue_fill_ratio(),
+            },
+        })
+    }
+}
+
+// An exporter built when first needed, and at every export until it can be: the pipeline
+// comes up, and the service serves, however the network is at startup, the Jaeger agent's
+// name not resolving say; the settings were checked already, see `check`. What's exported
+// meanwhile is dropped, and counted as failed.
+struct Deferred<E> {
+    signal: &'static str,
+    build: Box<dyn Fn() -> Result<E, String> + Send + Sync>,
+    inner: OnceLock<E>,
+    failed: std::sync::atomic::AtomicBool,
+    // Given before the exporter was built, for when it is
+    resource: Option<Resource>,
+}
+
+impl<E> Deferred<E> {
+    // Tries right away, the error being for the caller to warn about
+    fn new(signal: &'static str, build: impl Fn() -> Result<E, String> + Send + Sync + 'static) -> (Self, Option<String>) {
+        let deferred = Self { signal, build: Box::new(build), inner: OnceLock::new(), failed: Default::default(), resource: None };
+        let error = deferred.get().err().map(|e| format!("{e}; serving without it, retrying at every export"));
+        (deferred, error)
+    }
+
+    fn get(&self) -> Result<&E, String> {
+        if let Some(inner) = self.inner.get() {
+            return Ok(inner);
+        }
+        match (self.build)() {
+            Ok(inner) => {
+                if self.failed.load(Ordering::Relaxed) {
+                    tracing::info!("The {} exporter is up after failing to build", self.signal);
+                }
+                Ok(self.inner.get_or_init(|| inner))
+            }
+            Err(e) => {
+                self.failed.store(true, Ordering::Relaxed);
+                Err(format!("{} exporter unavailable: {e}", self.signal))
+            }
+        }
+    }
+}
+
+impl<E> std::fmt::Debug for Deferred<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Deferred").field("signal", &self.signal).field("built", &self.inner.get().is_some()).finish()
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for Deferred<E> {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        if let Err(e) = self.get() {
+            return Box::pin(std::future::ready(Err(e.into())));
+        }
+        let inner = self.inner.get_mut().expect("built above");
+        if let Some(resource) = self.resource.take() {
+            inner.set_resource(&resource);
+        }
+        inner.export(batch)
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(inner) = self.inner.get_mut() {
+            inner.shutdown();
+        }
+    }
+
+    fn force_flush(&mut self) -> BoxFuture<'static, ExportResult> {
+        match self.inner.get_mut() {
+            Some(inner) => inner.force_flush(),
+            None => Box::pin(std::future::ready(Ok(()))),
+        }
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        match self.inner.get_mut() {
+            Some(inner) => inner.set_resource(resource),
+            None => self.resource = Some(resource.clone()),
+        }
+    }
+}
+
+// Span exporter wrapper recording the result of every export into `ExporterHealth`
+// and `PipelineStats`
+#[derive(Debug)]
+pub struct TrackedExporter<E> {
+    inner: E,
+    health: Arc<ExporterHealth>,
+    stats: Arc<PipelineStats>,
+}
+
+impl<E> TrackedExporter<E> {
+    pub fn new(inner: E, health: Arc<ExporterHealth>, stats: Arc<PipelineStats>) -> Self {
+        Self { inner, health, stats }
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for TrackedExporter<E> {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let spans = batch.len() as u64;
+        let canary = self.health.carries_canary(&batch);
+        let export = self.inner.export(batch);
+        let health = self.health.clone();
+        let stats = self.stats.clone();
+
+        Box::pin(async move {
+            let result = export.await;
+            if let Some(trace_id) = canary {
+                health.canary_exported(trace_id, &result);
+            }
+            match &result {
+                Ok(()) => {
+                    health.last_success_ms.store(unix_millis(), Ordering::Relaxed);
+                    stats.exported.fetch_add(spans, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    health.last_failure_ms.store(unix_millis(), Ordering::Relaxed);
+                    stats.export_errors.fetch_add(1, Ordering::Relaxed);
+                    stats.failed.fetch_add(spans, Ordering::Relaxed);
+                    *health.last_error.lock().unwrap() = Some(e.to_string());
+                }
+            }
+            result
+        })
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn force_flush(&mut self) -> BoxFuture<'static, ExportResult> {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &opentelemetry_sdk::Resource) {
+        self.inner.set_resource(resource)
+    }
+}
+
+#[utoipa::path(get, path = "/debug/telemetry", summary = "Span export pipeline counters", responses(
+    (status = 200, body = Object, description = "Pipeline counters"),
+    (status = 401, body = String, description = "No token"),
+    (status = 403, body = String, description = "The caller is not an admin"),
+))]
+pub async fn debug_handler(
+    axum::extract::State(stats): axum::extract::State<Arc<PipelineStats>>,
+) -> crate::response::Traced<axum::Json<serde_json::Value>> {
+    crate::response::Traced::new(axum::Json(stats.snapshot()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::Tracer as _;
+
+    #[test]
+    fn the_exporter_comes_up_once_it_can() {
+        let exporter = opentelemetry_sdk::testing::trace::InMemorySpanExporter::default();
+        let attempts = Arc::new(AtomicU64::new(0));
+        let (deferred, error) = Deferred::new("span", {
+            let (exporter, attempts) = (exporter.clone(), attempts.clone());
+            move || match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 | 1 => Err("no such collector".to_string()),
+                _ => Ok(exporter.clone()),
+            }
+        });
+        assert!(error.unwrap().contains("no such collector"));
+
+        let health = Arc::new(ExporterHealth::default());
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(TrackedExporter::new(deferred, health.clone(), Arc::new(PipelineStats::new())))
+            .build();
+        let tracer = provider.tracer("test");
+        tracer.in_span("dropped", |_| ());
+        assert!(!health.is_healthy());
+        tracer.in_span("exported", |_| ());
+
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.iter().map(|span| span.name.as_ref()).collect::<Vec<_>>(), ["exported"]);
+        assert!(health.is_healthy());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    }
+
+    // Drops the spans named "noise", and the other's "card.number"
+    #[derive(Debug)]
+    struct Redacting;
+
+    impl ChainedProcessor for Redacting {
+        fn on_end(&self, mut span: SpanData) -> Option<SpanData> {
+            span.attributes.retain(|kv| kv.key.as_str() != "card.number");
+            (span.name != "noise").then_some(span)
+        }
+    }
+
+    #[test]
+    fn processors_redact_and_drop_before_the_export() {
+        let exporter = opentelemetry_sdk::testing::trace::InMemorySpanExporter::default();
+        let stats = Arc::new(PipelineStats::new());
+        let chain = Chain {
+            processors: vec![Box::new(Redacting), Box::new(crate::span_metrics::SpanMetricsProcessor::default())],
+            stats: stats.clone(),
+            exporting: exporting_processor(SpanProcessorKind::Simple, exporter.clone()),
+        };
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(chain).build();
+        let tracer = provider.tracer("test");
+        tracer.in_span("noise", |_| ());
+        tracer.in_span("payment", |cx| {
+            opentelemetry::trace::TraceContextExt::span(&cx).set_attribute(opentelemetry::KeyValue::new("card.number", "4111"));
+        });
+
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.iter().map(|span| span.name.as_ref()).collect::<Vec<_>>(), ["payment"]);
+        assert!(spans[0].attributes.is_empty());
+        // the dropped span isn't waiting for the exporter
+        assert_eq!(stats.snapshot()["spans"]["ended"], 1);
+    }
+
+    #[test]
+    fn a_superseded_canary_decides_nothing() {
+        let (first, second) = (opentelemetry::trace::TraceId::from_u128(1), opentelemetry::trace::TraceId::from_u128(2));
+        let health = ExporterHealth::default();
+        health.await_canary(first);
+        health.await_canary(second);
+        health.canary_exported(first, &Ok(()));
+        assert_eq!(health.canary_outcome(), None);
+        health.canary_exported(second, &Ok(()));
+        assert_eq!(health.canary_outcome(), Some(Ok(())));
+    }
+
+    // Accepts connections and never answers on them, counting them
+    #[cfg(feature = "otlp-grpc")]
+    async fn blackhole() -> (String, Arc<AtomicU64>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let accepted = Arc::new(AtomicU64::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                held.push(stream);
+            }
+        });
+        (endpoint, accepted)
+    }
+
+    #[cfg(feature = "otlp-grpc")]
+    #[tokio::test]
+    async fn a_hanging_collector_times_out_and_is_failed_over() {
+        let (primary, _) = blackhole().await;
+        let (secondary, reached) = blackhole().await;
+        let settings = TelemetrySettings {
+            endpoints: vec![primary, secondary],
+            timeout_ms: 200,
+            failover_after: 1,
+            failback_probe_secs: 3600,
+            ..TelemetrySettings::default()
+        };
+        let exporters = export_targets(&settings)
+            .unwrap()
+            .iter()
+            .map(|target| span_exporter(target, &settings).unwrap().build_span_exporter().unwrap())
+            .collect();
+        let failover = crate::failover::Failover::new("span", endpoints(&settings), &settings);
+        let mut exporter = crate::failover::FailoverExporter::new(exporters, failover);
+
+        let recorded = opentelemetry_sdk::testing::trace::InMemorySpanExporter::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().with_simple_exporter(recorded.clone()).build();
+        provider.tracer("test").in_span("exported", |_| ());
+        let batch = recorded.get_finished_spans().unwrap();
+
+        let export = tokio::time::timeout(std::time::Duration::from_secs(5), exporter.export(batch.clone()));
+        assert!(export.await.expect("the export to the blackholed collector never ended").is_err());
+        let export = tokio::time::timeout(std::time::Duration::from_secs(5), exporter.export(batch));
+        assert!(export.await.expect("the export to the secondary never ended").is_err());
+        assert_eq!(reached.load(Ordering::Relaxed), 1);
+    }
+
+    #[cfg(feature = "otlp-grpc")]
+    #[tokio::test]
+    async fn exports_to_a_collector_on_a_unix_socket() {
+        let socket = std::env::temp_dir().join(format!("otlp-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let (exported, mut received) = tokio::sync::mpsc::unbounded_channel();
+        // Answers each export with an empty response and an OK status, as a collector does
+        let collector = axum::Router::new().route(
+            "/opentelemetry.proto.collector.trace.v1.TraceService/Export",
+            axum::routing::post(move |body: bytes::Bytes| {
+                exported.send(body.len()).unwrap();
+                async {
+                    let mut trailers = axum::http::HeaderMap::new();
+                    trailers.insert("grpc-status", axum::http::HeaderValue::from_static("0"));
+                    let frames = futures_util::stream::iter([
+                        Ok::<_, std::convert::Infallible>(http_body::Frame::data(bytes::Bytes::from_static(&[0, 0, 0, 0, 0]))),
+                        Ok(http_body::Frame::trailers(trailers)),
+                    ]);
+                    ([(axum::http::header::CONTENT_TYPE, "application/grpc")], axum::body::Body::new(http_body_util::StreamBody::new(frames)))
+                }
+            }),
+        );
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = hyper_util::service::TowerToHyperService::new(collector.clone());
+                tokio::spawn(
+                    hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new())
+                        .http2_only()
+                        .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                        .into_owned(),
+                );
+            }
+        });
+
+        let settings = TelemetrySettings { collector_socket: Some(socket.to_string_lossy().into_owned()), ..TelemetrySettings::default() };
+        let targets = export_targets(&settings).unwrap();
+        let mut exporter = span_exporter(&targets[0], &settings).unwrap().build_span_exporter().unwrap();
+        let recorded = opentelemetry_sdk::testing::trace::InMemorySpanExporter::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().with_simple_exporter(recorded.clone()).build();
+        provider.tracer("test").in_span("exported", |_| ());
+
+        let export = tokio::time::timeout(std::time::Duration::from_secs(5), exporter.export(recorded.get_finished_spans().unwrap()));
+        export.await.expect("the export never ended").unwrap();
+        assert!(received.recv().await.unwrap() > 0);
+        std::fs::remove_file(&socket).unwrap();
+
+        // Not in place of a vendor
+        let vendor = TelemetrySettings { vendor: Some(crate::config::Vendor::Honeycomb), ..settings };
+        assert!(export_target(&vendor).unwrap_err().contains("collector_socket"));
+    }
+
+    #[cfg(feature = "otlp-grpc")]
+    #[tokio::test]
+    async fn invalid_collector_settings_are_a_config_error() {
+        let settings = |endpoint: &str| TelemetrySettings { endpoints: vec![GRPC_ENDPOINT.to_string(), endpoint.to_string()], ..Default::default() };
+        assert!(check(&settings("http://collector-b:4317")).is_ok());
+        assert!(check(&settings("http://collector b:4317")).unwrap_err().contains("invalid endpoint"));
+
+        // A vendor without its key is no better next time round
+        let vendor = TelemetrySettings { vendor: Some(crate::config::Vendor::Honeycomb), api_key: None, ..Default::default() };
+        if std::env::var(crate::vendor::API_KEY_ENV).is_err() {
+            assert!(check(&vendor).unwrap_err().contains("API key"));
+        }
+    }
+
+    #[test]
+    fn a_protocol_not_built_in_is_a_config_error() {
+        let settings = |protocol: &str| TelemetrySettings { protocol: protocol.to_string(), ..TelemetrySettings::default() };
+        assert_eq!(check_protocol(&settings("grpc")).is_ok(), cfg!(feature = "otlp-grpc"));
+        assert_eq!(check_protocol(&settings("http/protobuf")).is_ok(), cfg!(feature = "otlp-http"));
+        assert!(check_protocol(&settings("thrift")).unwrap_err().contains("unknown"));
+    }
+}
@@ -0,0 +1,63 @@
+// HTML from the askama templates in templates/, each render a "template render" span with
+// `template.name`, so what a page costs to render shows apart from the handler's queries.
+// askama resolves and compiles the templates into the binary at build time, there is no
+// lookup at request time to trace; the span is the render itself, with how long it took
+// and how big the page came out. A failed render is an error on it.
+
+use std::time::Instant;
+
+use crate::result_ext::ResultExt;
+
+// `name` being the template's path under templates/
+pub fn render<T: askama::Template>(name: &'static str, template: &T) -> Result<String, askama::Error> {
+    let span = tracing::info_span!(
+        "template render",
+        otel.name = format!("render {name}"),
+        template.name = name,
+        template.engine = "askama",
+        template.render_duration_ms = tracing::field::Empty,
+        template.output.size = tracing::field::Empty,
+    );
+    let _entered = span.enter();
+    let started = Instant::now();
+    let page = template.render().trace_err();
+    span.record("template.render_duration_ms", started.elapsed().as_secs_f64() * 1000.0);
+    if let Ok(page) = &page {
+        span.record("template.output.size", page.len() as i64);
+    }
+    page
+}
+
+// A page as the response, a server error when it doesn't render; only the database's
+// routes have pages
+#[cfg(feature = "mysql")]
+pub fn page<T: askama::Template>(name: &'static str, template: &T) -> Result<axum::response::Html<String>, axum::http::StatusCode> {
+    render(name, template).map(axum::response::Html).map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(askama::Template)]
+    #[template(source = "<p>{{ name }}</p>", ext = "html")]
+    struct Greeting<'a> {
+        name: &'a str,
+    }
+
+    #[test]
+    fn renders_in_a_span_of_its_own() {
+        let telemetry = crate::test_support::init();
+        let page = tracing::info_span!("handler").in_scope(|| render("greeting.html", &Greeting { name: "<b>you</b>" })).unwrap();
+        assert_eq!(page, "<p>&lt;b&gt;you&lt;/b&gt;</p>");
+
+        let spans = telemetry.spans();
+        let span = spans
+            .assert_span_exists("render greeting.html")
+            .with_attribute("template.name", "greeting.html")
+            .with_attribute("template.output.size", page.len() as i64)
+            .child_of("handler")
+            .span();
+        assert!(span.attributes.iter().any(|kv| kv.key.as_str() == "template.render_duration_ms"));
+    }
+}
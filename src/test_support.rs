@@ -0,0 +1,286 @@
+// Spans recorded in memory for tests, with assertions on names, attributes and nesting
+
+// Not every test uses every helper
+#![allow(dead_code)]
+
+use opentelemetry::trace::{SpanId, Status, TracerProvider as _};
+use opentelemetry::Value;
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+use tokio::io::AsyncWriteExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::redis::Reply;
+
+// Records the spans of the current thread while alive; use with `#[tokio::test]`, whose
+// runtime runs spawned tasks on the test thread too
+pub struct TestTelemetry {
+    exporter: InMemorySpanExporter,
+    provider: opentelemetry_sdk::trace::TracerProvider,
+    _guard: tracing::subscriber::DefaultGuard,
+}
+
+pub fn init() -> TestTelemetry {
+    init_with_sampler(opentelemetry_sdk::trace::Sampler::AlwaysOn)
+}
+
+pub fn init_with_sampler(sampler: impl opentelemetry_sdk::trace::ShouldSample + 'static) -> TestTelemetry {
+    let exporter = InMemorySpanExporter::default();
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_config(opentelemetry_sdk::trace::Config::default().with_sampler(sampler))
+        .with_simple_exporter(exporter.clone())
+        .build();
+
+    // The span layers `main` installs
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::OpenTelemetryLayer::new(provider.tracer("test")))
+        .with(crate::severity::SeverityLayer)
+        .with(crate::baggage::SpanLayer::new(&Default::default()))
+        .with(crate::middleware::rejection::RejectionLayer);
+
+    TestTelemetry {
+        exporter,
+        provider,
+        _guard: tracing::subscriber::set_default(subscriber),
+    }
+}
+
+impl TestTelemetry {
+    // Spans that have ended so far, in the order they ended
+    pub fn spans(&self) -> Spans {
+        for result in self.provider.force_flush() {
+            result.expect("failed to flush spans");
+        }
+        Spans(self.exporter.get_finished_spans().expect("failed to read spans"))
+    }
+}
+
+pub struct Spans(Vec<SpanData>);
+
+impl Spans {
+    pub fn all(&self) -> &[SpanData] {
+        &self.0
+    }
+
+    pub fn find(&self, name: &str) -> Option<&SpanData> {
+        self.0.iter().find(|span| span.name == name)
+    }
+
+    fn parent_of(&self, span: &SpanData) -> Option<&SpanData> {
+        (span.parent_span_id != SpanId::INVALID)
+            .then(|| self.0.iter().find(|parent| parent.span_context.span_id() == span.parent_span_id))
+            .flatten()
+    }
+
+    pub fn children_of<'a>(&'a self, span: &'a SpanData) -> impl Iterator<Item = &'a SpanData> {
+        self.0
+            .iter()
+            .filter(move |child| child.parent_span_id == span.span_context.span_id())
+    }
+
+    #[track_caller]
+    pub fn assert_span_exists(&self, name: &str) -> SpanAssert<'_> {
+        match self.find(name) {
+            Some(span) => SpanAssert { spans: self, span },
+            None => panic!("no span named {name:?}, recorded: {:?}", self.names()),
+        }
+    }
+
+    #[track_caller]
+    pub fn assert_no_span(&self, name: &str) {
+        assert!(self.find(name).is_none(), "unexpected span named {name:?}");
+    }
+
+    // Indented span tree with error statuses marked, children in start order; the shape
+    // of a trace without its timings, ids or attributes
+    pub fn tree(&self) -> String {
+        let mut out = String::new();
+        let mut roots: Vec<&SpanData> = self.0.iter().filter(|span| self.parent_of(span).is_none()).collect();
+        roots.sort_by_key(|span| span.start_time);
+        for root in roots {
+            self.render(root, 0, &mut out);
+        }
+        out
+    }
+
+    fn render(&self, span: &SpanData, depth: usize, out: &mut String) {
+        let status = if matches!(span.status, Status::Error { .. }) { " [error]" } else { "" };
+        out.push_str(&format!("{}{}{status}\n", "  ".repeat(depth), span.name));
+
+        let mut children: Vec<&SpanData> = self.children_of(span).collect();
+        children.sort_by_key(|child| child.start_time);
+        for child in children {
+            self.render(child, depth + 1, out);
+        }
+    }
+
+    fn names(&self) -> Vec<&str> {
+        self.0.iter().map(|span| span.name.as_ref()).collect()
+    }
+}
+
+pub struct SpanAssert<'a> {
+    spans: &'a Spans,
+    span: &'a SpanData,
+}
+
+impl<'a> SpanAssert<'a> {
+    pub fn span(&self) -> &'a SpanData {
+        self.span
+    }
+
+    pub fn attribute(&self, key: &str) -> Option<&'a Value> {
+        self.span
+            .attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| &kv.value)
+    }
+
+    #[track_caller]
+    pub fn with_attribute(self, key: &str, value: impl Into<Value>) -> Self {
+        let expected = value.into();
+        match self.attribute(key) {
+            Some(actual) => assert_eq!(actual, &expected, "attribute {key:?} of span {:?}", self.span.name),
+            None => panic!("span {:?} has no attribute {key:?}", self.span.name),
+        }
+        self
+    }
+
+    #[track_caller]
+    pub fn with_attribute_present(self, key: &str) -> Self {
+        assert!(self.attribute(key).is_some(), "span {:?} has no attribute {key:?}", self.span.name);
+        self
+    }
+
+    #[track_caller]
+    pub fn with_error_status(self) -> Self {
+        assert!(
+            matches!(self.span.status, Status::Error { .. }),
+            "span {:?} has status {:?}, expected an error",
+            self.span.name,
+            self.span.status
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn without_error_status(self) -> Self {
+        assert!(
+            !matches!(self.span.status, Status::Error { .. }),
+            "span {:?} has an error status",
+            self.span.name
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn without_parent(self) -> Self {
+        if let Some(parent) = self.spans.parent_of(self.span) {
+            panic!("span {:?} is a child of {:?}", self.span.name, parent.name);
+        }
+        self
+    }
+
+    #[track_caller]
+    pub fn child_of(self, parent: &str) -> Self {
+        match self.spans.parent_of(self.span) {
+            Some(actual) if actual.name == parent => self,
+            Some(actual) => panic!("span {:?} is a child of {:?}, not {parent:?}", self.span.name, actual.name),
+            None => panic!("span {:?} has no recorded parent, expected {parent:?}", self.span.name),
+        }
+    }
+
+    // Continues with the named child of this span
+    #[track_caller]
+    pub fn has_child(self, name: &str) -> SpanAssert<'a> {
+        match self.spans.children_of(self.span).find(|child| child.name == name) {
+            Some(span) => SpanAssert { spans: self.spans, span },
+            None => panic!("span {:?} has no child named {name:?}", self.span.name),
+        }
+    }
+}
+
+// Compares `actual` with `src/snapshots/<name>.txt`; UPDATE_SNAPSHOTS=1 writes them
+// instead, missing ones included, for review before they are committed
+#[track_caller]
+pub fn assert_snapshot(name: &str, actual: &str) {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/snapshots").join(format!("{name}.txt"));
+
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    match std::fs::read_to_string(&path) {
+        Ok(expected) => assert_eq!(actual, expected, "span tree differs from {}, rerun with UPDATE_SNAPSHOTS=1 if intended", path.display()),
+        Err(e) => panic!("no snapshot at {} ({e}), rerun with UPDATE_SNAPSHOTS=1 and review it:\n{actual}", path.display()),
+    }
+}
+
+// A Redis of PUBLISH and SUBSCRIBE only, every subscriber on every channel
+pub async fn fake_redis() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (published, _) = tokio::sync::broadcast::channel::<(Vec<u8>, Vec<u8>)>(16);
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let published = published.clone();
+            tokio::spawn(async move {
+                let mut connection = tokio::io::BufStream::new(stream);
+                while let Ok(Reply::Array(command)) = crate::redis::read(&mut connection).await {
+                    match command.as_slice() {
+                        [Reply::Bulk(Some(name)), Reply::Bulk(Some(channel)), Reply::Bulk(Some(message))] if name == b"PUBLISH" => {
+                            let receivers = published.send((channel.clone(), message.clone())).unwrap_or(0);
+                            connection.write_all(format!(":{receivers}\r\n").as_bytes()).await.unwrap();
+                            connection.flush().await.unwrap();
+                        }
+                        [Reply::Bulk(Some(name)), Reply::Bulk(Some(channel))] if name == b"SUBSCRIBE" => {
+                            let mut receiving = published.subscribe();
+                            crate::redis::send(&mut connection, &[b"subscribe", channel, b"1"]).await.unwrap();
+                            while let Ok((channel, message)) = receiving.recv().await {
+                                crate::redis::send(&mut connection, &[b"message", &channel, &message]).await.unwrap();
+                            }
+                        }
+                        _ => return,
+                    }
+                }
+            });
+        }
+    });
+    format!("redis://{address}")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn asserts_nesting_and_attributes() {
+        let telemetry = init();
+
+        tracing::info_span!("outer", answer = 42).in_scope(|| {
+            tracing::info_span!("inner", otel.status_code = "error").in_scope(|| {});
+        });
+
+        let spans = telemetry.spans();
+        spans
+            .assert_span_exists("outer")
+            .without_parent()
+            .with_attribute("answer", 42_i64)
+            .has_child("inner")
+            .with_error_status();
+        spans.assert_span_exists("inner").child_of("outer");
+        spans.assert_no_span("missing");
+        assert_eq!(spans.tree(), "outer\n  inner [error]\n");
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "no span named")]
+    async fn missing_span_fails() {
+        let telemetry = init();
+        telemetry.spans().assert_span_exists("fetch row");
+    }
+}
@@ -0,0 +1,77 @@
+// Work on other threads, traced like the async code handing it over. A new thread has
+// neither the caller's span nor its subscriber, so what it traces would land in a trace of
+// its own, or nowhere; `ThreadContext` carries the three across: the tracing span, the
+// subscriber, and the OTel context with its baggage. `blocking::run_blocking` hands its
+// work over in one.
+//
+// With rayon, capture once and run each item in it, the workers being shared:
+//
+//     let context = threads::ThreadContext::capture();
+//     images.par_iter().map(|image| context.run(|| resize(image))).collect()
+
+// Where the caller was, to be re-entered on another thread
+#[derive(Clone)]
+pub struct ThreadContext {
+    span: tracing::Span,
+    dispatch: tracing::Dispatch,
+    cx: opentelemetry::Context,
+}
+
+impl ThreadContext {
+    pub fn capture() -> Self {
+        Self {
+            span: tracing::Span::current(),
+            dispatch: tracing::dispatcher::get_default(Clone::clone),
+            cx: opentelemetry::Context::current(),
+        }
+    }
+
+    // Runs `work` as if where `capture` was called, its spans children of that span
+    pub fn run<T>(&self, work: impl FnOnce() -> T) -> T {
+        tracing::dispatcher::with_default(&self.dispatch, || {
+            let _cx = self.cx.clone().attach();
+            self.span.in_scope(work)
+        })
+    }
+
+    // `work`, to be run in this context wherever it's called
+    pub fn wrap<T>(self, work: impl FnOnce() -> T) -> impl FnOnce() -> T {
+        // The span dropped under the subscriber too, for the registry to release its parent
+        move || {
+            let dispatch = self.dispatch.clone();
+            tracing::dispatcher::with_default(&dispatch, move || self.run(work))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threads_continue_the_trace() {
+        let telemetry = crate::test_support::init();
+
+        let context = tracing::info_span!("request").in_scope(ThreadContext::capture);
+        std::thread::spawn(context.wrap(|| tracing::info_span!("encode").in_scope(|| ()))).join().unwrap();
+
+        telemetry.spans().assert_span_exists("encode").child_of("request");
+    }
+
+    #[test]
+    fn rayon_workers_continue_the_trace() {
+        use rayon::prelude::*;
+
+        let telemetry = crate::test_support::init();
+        tracing::info_span!("request").in_scope(|| {
+            let context = ThreadContext::capture();
+            (0..16).into_par_iter().for_each(|_| context.run(|| tracing::info_span!("thumbnail").in_scope(|| ())));
+        });
+
+        let spans = telemetry.spans();
+        let thumbnails: Vec<_> = spans.all().iter().filter(|span| span.name == "thumbnail").collect();
+        let request = spans.find("request").unwrap();
+        assert_eq!(thumbnails.len(), 16);
+        assert!(thumbnails.iter().all(|span| span.parent_span_id == request.span_context.span_id()));
+    }
+}
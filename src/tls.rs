@@ -0,0 +1,150 @@
+// HTTPS on the main listener, from PEM files. With `reload_secs` the files are checked that
+// often and a renewed pair is used from the next connection on, so a certificate is replaced
+// without a restart; a pair which fails to load leaves the one in use. The connection's span
+// gets the negotiated protocol version and cipher, and how long the handshake took.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::Instrument;
+
+use crate::config::TlsSettings;
+
+// A client which doesn't finish its handshake by then is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Certificates {
+    settings: TlsSettings,
+    http2: bool,
+    // With the files' modification time when they were loaded
+    config: RwLock<(Arc<rustls::ServerConfig>, Option<SystemTime>)>,
+}
+
+// The later of the two files' modification times
+fn modified(settings: &TlsSettings) -> Option<SystemTime> {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    modified(&settings.cert_path).max(modified(&settings.key_path))
+}
+
+// Offering h2 in ALPN with `http2`
+fn load(settings: &TlsSettings, http2: bool) -> Result<rustls::ServerConfig, String> {
+    let open = |path: &str| {
+        std::fs::File::open(path)
+            .map(std::io::BufReader::new)
+            .map_err(|e| format!("failed to open {path}: {e}"))
+    };
+
+    let chain = rustls_pemfile::certs(&mut open(&settings.cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("failed to read {}: {e}", settings.cert_path))?;
+    if chain.is_empty() {
+        return Err(format!("no certificate in {}", settings.cert_path));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&settings.key_path)?)
+        .map_err(|e| format!("failed to read {}: {e}", settings.key_path))?
+        .ok_or_else(|| format!("no private key in {}", settings.key_path))?;
+
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(|e| format!("invalid certificate or key: {e}"))?;
+    config.alpn_protocols = match http2 {
+        true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        false => vec![b"http/1.1".to_vec()],
+    };
+    Ok(config)
+}
+
+fn protocol_version(version: rustls::ProtocolVersion) -> &'static str {
+    match version {
+        rustls::ProtocolVersion::TLSv1_2 => "1.2",
+        rustls::ProtocolVersion::TLSv1_3 => "1.3",
+        _ => "unknown",
+    }
+}
+
+impl Certificates {
+    pub fn load(settings: &TlsSettings, http2: bool) -> Result<Self, String> {
+        let config = load(settings, http2)?;
+        Ok(Self {
+            settings: settings.clone(),
+            http2,
+            config: RwLock::new((Arc::new(config), modified(settings))),
+        })
+    }
+
+    fn reload(&self) {
+        let modified = modified(&self.settings);
+        if modified == self.config.read().unwrap().1 {
+            return;
+        }
+
+        match load(&self.settings, self.http2) {
+            Ok(config) => {
+                *self.config.write().unwrap() = (Arc::new(config), modified);
+                tracing::info!(tls.cert_path = self.settings.cert_path, "Reloaded TLS certificate");
+            }
+            // Not retried until the files change again
+            Err(e) => {
+                self.config.write().unwrap().1 = modified;
+                tracing::warn!(error = %e, "Failed to reload TLS certificate, keeping the one in use");
+            }
+        }
+    }
+
+    // Checks the files every `reload_secs` until the process exits
+    pub fn spawn_reload(self: &Arc<Self>) {
+        if self.settings.reload_secs == 0 {
+            return;
+        }
+
+        let certificates = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(certificates.settings.reload_secs));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                certificates.reload();
+            }
+        });
+    }
+
+    // The handshake of an accepted connection, recorded on the connection's span; a failed
+    // handshake is an event on it
+    pub async fn handshake(
+        &self,
+        stream: tokio::net::TcpStream,
+        span: &tracing::Span,
+    ) -> Option<tokio_rustls::server::TlsStream<tokio::net::TcpStream>> {
+        let acceptor = tokio_rustls::TlsAcceptor::from(self.config.read().unwrap().0.clone());
+        let started = Instant::now();
+        let accepted = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+            .instrument(span.clone())
+            .await
+            .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "no handshake in time")));
+        span.record("tls.handshake.duration_ms", started.elapsed().as_secs_f64() * 1000.0);
+
+        match accepted {
+            Ok(stream) => {
+                let (_, connection) = stream.get_ref();
+                if let Some(version) = connection.protocol_version() {
+                    span.record("tls.protocol.version", protocol_version(version));
+                }
+                if let Some(suite) = connection.negotiated_cipher_suite() {
+                    span.record("tls.cipher", format!("{:?}", suite.suite()));
+                }
+                if let Some(protocol) = connection.alpn_protocol() {
+                    span.record("tls.next_protocol", String::from_utf8_lossy(protocol).as_ref());
+                }
+                Some(stream)
+            }
+            Err(e) => {
+                span.record("otel.status_code", "error");
+                span.in_scope(|| tracing::warn!(error = %e, "TLS handshake failed"));
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// Transactions which start over when MySQL picks them as a deadlock's victim or they time
+// out waiting for a lock, per `[transaction_retry]`. Each attempt is a "transaction" span
+// with `db.transaction.attempt`, a failed one having its error as an event; the caller's
+// span gets `db.transaction.attempts` and `db.transaction.outcome`: committed, failed, or
+// exhausted when it still conflicted after the last retry.
+
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use rand::Rng;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::TransactionRetrySettings;
+use crate::result_ext::ResultExt;
+
+// ER_LOCK_DEADLOCK and ER_LOCK_WAIT_TIMEOUT
+const DEADLOCK: u16 = 1213;
+const LOCK_WAIT_TIMEOUT: u16 = 1205;
+
+// What made the transaction worth another attempt, if anything
+fn conflict(e: &sqlx::Error) -> Option<&'static str> {
+    let e = e.as_database_error()?.try_downcast_ref::<sqlx::mysql::MySqlDatabaseError>()?;
+    conflict_of(e.number())
+}
+
+fn conflict_of(number: u16) -> Option<&'static str> {
+    match number {
+        DEADLOCK => Some("deadlock"),
+        LOCK_WAIT_TIMEOUT => Some("lock_wait_timeout"),
+        _ => None,
+    }
+}
+
+pub struct TransactionRetry {
+    settings: TransactionRetrySettings,
+}
+
+impl TransactionRetry {
+    pub fn new(settings: TransactionRetrySettings) -> Self {
+        Self { settings }
+    }
+
+    // A random wait of up to the doubled backoff, so the transactions which conflicted
+    // don't conflict again
+    fn backoff(&self, retry: u32) -> Duration {
+        let cap = self.settings.backoff_ms.saturating_mul(2u64.saturating_pow(retry - 1)).min(self.settings.max_backoff_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=cap))
+    }
+
+    // Runs `body` in a transaction of its own, committed once it returns, as often as
+    // conflicts allow; a `body` which fails rolls it back
+    pub async fn run<T, F>(&self, pool: &sqlx::MySqlPool, mut body: F) -> Result<T, sqlx::Error>
+    where
+        F: for<'c> FnMut(&'c mut sqlx::MySqlConnection) -> BoxFuture<'c, Result<T, sqlx::Error>>,
+    {
+        let mut attempts = Attempts::new(self);
+        loop {
+            let attempt_span = attempts.start();
+            let result = async {
+                let mut transaction = pool.begin().await?;
+                let value = body(&mut transaction).await?;
+                transaction.commit().await?;
+                Ok(value)
+            }
+            .instrument(attempt_span.clone())
+            .await;
+
+            let conflicted = result.as_ref().err().and_then(conflict);
+            if let Some(result) = attempts.end(&attempt_span, result, conflicted).await {
+                return result;
+            }
+        }
+    }
+}
+
+// The attempts of one transaction, each a span of its own, and what's recorded of them on
+// the caller's
+struct Attempts<'a> {
+    retry: &'a TransactionRetry,
+    span: tracing::Span,
+    attempt: u32,
+}
+
+impl<'a> Attempts<'a> {
+    fn new(retry: &'a TransactionRetry) -> Self {
+        Self { retry, span: tracing::Span::current(), attempt: 0 }
+    }
+
+    // The next attempt's span
+    fn start(&mut self) -> tracing::Span {
+        self.attempt += 1;
+        tracing::info_span!(
+            "transaction",
+            db.system = "mysql",
+            db.transaction.attempt = self.attempt,
+            "error.type" = tracing::field::Empty,
+        )
+    }
+
+    // The transaction's result, or None to start over once the backoff has passed
+    async fn end<T, E: std::error::Error + 'static>(
+        &mut self,
+        attempt_span: &tracing::Span,
+        result: Result<T, E>,
+        conflicted: Option<&'static str>,
+    ) -> Option<Result<T, E>> {
+        if let Some(conflict) = conflicted {
+            attempt_span.record("error.type", conflict);
+        }
+        let result = attempt_span.in_scope(|| result.trace_err_with(tracing::Level::WARN));
+        let outcome = match (&result, conflicted) {
+            (Ok(_), _) => "committed",
+            (Err(_), Some(_)) if self.attempt <= self.retry.settings.retries => {
+                let backoff = self.retry.backoff(self.attempt);
+                tracing::info!(retry.attempt = self.attempt, retry.backoff_ms = backoff.as_millis() as u64, "Retrying transaction");
+                tokio::time::sleep(backoff).await;
+                return None;
+            }
+            (Err(_), Some(_)) => "exhausted",
+            (Err(_), None) => "failed",
+        };
+        self.span.set_attribute("db.transaction.attempts", i64::from(self.attempt));
+        self.span.set_attribute("db.transaction.outcome", outcome);
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_with_jitter_up_to_the_cap() {
+        let retry = TransactionRetry::new(TransactionRetrySettings { retries: 5, backoff_ms: 20, max_backoff_ms: 50 });
+        for _ in 0..100 {
+            assert!(retry.backoff(1) <= Duration::from_millis(20));
+            assert!(retry.backoff(5) <= Duration::from_millis(50));
+        }
+        assert!(conflict(&sqlx::Error::PoolTimedOut).is_none());
+    }
+
+    // A database error by its number
+    #[derive(Debug)]
+    struct Error(u16);
+
+    impl std::fmt::Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "error {}", self.0)
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    // The attempts `run` makes, with these results in turn
+    async fn attempts(retries: u32, mut results: Vec<Result<(), Error>>) -> Result<(), Error> {
+        let retry = TransactionRetry::new(TransactionRetrySettings { retries, backoff_ms: 1, max_backoff_ms: 1 });
+        results.reverse();
+        let mut attempts = Attempts::new(&retry);
+        loop {
+            let attempt_span = attempts.start();
+            let result = attempt_span.in_scope(|| results.pop().unwrap());
+            let conflicted = result.as_ref().err().and_then(|e| conflict_of(e.0));
+            if let Some(result) = attempts.end(&attempt_span, result, conflicted).await {
+                return result;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn starts_over_after_deadlocks_and_lock_wait_timeouts() {
+        let telemetry = crate::test_support::init();
+
+        let committed = attempts(3, vec![Err(Error(DEADLOCK)), Err(Error(LOCK_WAIT_TIMEOUT)), Ok(())]).instrument(tracing::info_span!("committed"));
+        assert!(committed.await.is_ok());
+        let exhausted = attempts(1, vec![Err(Error(DEADLOCK)), Err(Error(DEADLOCK))]).instrument(tracing::info_span!("exhausted"));
+        assert!(exhausted.await.is_err());
+        // a duplicate key say, which another attempt won't change
+        let failed = attempts(3, vec![Err(Error(1062))]).instrument(tracing::info_span!("failed"));
+        assert!(failed.await.is_err());
+
+        let spans = telemetry.spans();
+        spans.assert_span_exists("committed").with_attribute("db.transaction.attempts", 3).with_attribute("db.transaction.outcome", "committed");
+        spans.assert_span_exists("exhausted").with_attribute("db.transaction.attempts", 2).with_attribute("db.transaction.outcome", "exhausted");
+        spans.assert_span_exists("failed").with_attribute("db.transaction.attempts", 1).with_attribute("db.transaction.outcome", "failed");
+
+        let committed = spans.find("committed").unwrap();
+        let tried: Vec<_> = spans
+            .children_of(committed)
+            .map(|span| {
+                let attribute = |key: &str| span.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| kv.value.to_string());
+                (attribute("db.transaction.attempt"), attribute("error.type"))
+            })
+            .collect();
+        let expected = [(Some("1"), Some("deadlock")), (Some("2"), Some("lock_wait_timeout")), (Some("3"), None)];
+        assert_eq!(tried, expected.map(|(attempt, error)| (attempt.map(String::from), error.map(String::from))));
+    }
+}
@@ -0,0 +1,143 @@
+// CPU time and heap allocations of each request, with the `resource-usage` feature, so an
+// endpoint which is expensive rather than slow stands out: `request.cpu_time_ms`,
+// `request.allocated_bytes` and `request.allocations` on the request span. Counted per poll
+// of the request's future, on whichever worker polls it, so what runs elsewhere isn't in
+// them: spawned tasks, the blocking pool, and the body once it's streamed. Approximate, the
+// allocator's counting and the clock reads cost a little on every request.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+// The allocator underneath, jemalloc with that feature
+#[cfg(feature = "jemalloc")]
+use tikv_jemallocator::Jemalloc as Inner;
+#[cfg(not(feature = "jemalloc"))]
+use std::alloc::System as Inner;
+
+// `Inner`, counting what each thread allocates
+struct Counting;
+
+thread_local! {
+    static ALLOCATED: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+// SAFETY: every call goes to `Inner` unchanged; the counter is a const-initialized thread
+// local with no destructor, which doesn't allocate
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        Inner.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        Inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size.saturating_sub(layout.size()));
+        Inner.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Inner.dealloc(ptr, layout)
+    }
+}
+
+fn count(bytes: usize) {
+    // Gone while the thread exits
+    let _ = ALLOCATED.try_with(|allocated| {
+        let (bytes_so_far, allocations) = allocated.get();
+        allocated.set((bytes_so_far + bytes as u64, allocations + 1));
+    });
+}
+
+// CPU time of the calling thread, in nanoseconds
+fn thread_cpu_ns() -> u64 {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `time` is a valid timespec for the call to write to
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) } != 0 {
+        return 0;
+    }
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Usage {
+    cpu_ns: u64,
+    bytes: u64,
+    allocations: u64,
+}
+
+impl Usage {
+    fn now() -> Self {
+        let (bytes, allocations) = ALLOCATED.with(Cell::get);
+        Self { cpu_ns: thread_cpu_ns(), bytes, allocations }
+    }
+
+    fn add_since(&mut self, before: Self) {
+        let after = Self::now();
+        self.cpu_ns += after.cpu_ns.saturating_sub(before.cpu_ns);
+        self.bytes += after.bytes.saturating_sub(before.bytes);
+        self.allocations += after.allocations.saturating_sub(before.allocations);
+    }
+}
+
+// `inner`, with what its polls used added up
+struct Measured<F> {
+    inner: Pin<Box<F>>,
+    usage: Usage,
+}
+
+impl<F: Future> Future for Measured<F> {
+    type Output = (F::Output, Usage);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let before = Usage::now();
+        let poll = self.inner.as_mut().poll(cx);
+        self.usage.add_since(before);
+        poll.map(|output| (output, self.usage))
+    }
+}
+
+fn measure<F: Future>(future: F) -> Measured<F> {
+    Measured { inner: Box::pin(future), usage: Usage::default() }
+}
+
+// Just inside the request span, so every layer's share is in it
+pub async fn layer(request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let (response, usage) = measure(next.run(request)).await;
+
+    let span = tracing::Span::current();
+    span.set_attribute("request.cpu_time_ms", usage.cpu_ns as f64 / 1_000_000.0);
+    span.set_attribute("request.allocated_bytes", usage.bytes as i64);
+    span.set_attribute("request.allocations", usage.allocations as i64);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_what_the_future_allocates_and_spins() {
+        let (_, usage) = measure(async {
+            let buffer = std::hint::black_box(vec![1u8; 1 << 20]);
+            tokio::task::yield_now().await;
+            (0..2_000_000u64).fold(u64::from(buffer[0]), |sum, n| std::hint::black_box(sum ^ n))
+        })
+        .await;
+
+        assert!(usage.bytes >= 1 << 20, "{usage:?}");
+        assert!(usage.allocations >= 1);
+        assert!(usage.cpu_ns > 0);
+    }
+}
@@ -0,0 +1,89 @@
+// Presets for exporting straight to a vendor's OTLP endpoint, without a collector: from
+// `telemetry.vendor` and an API key come the endpoint, the header carrying the key, the
+// protocol and the compression. OTLP/gRPC where it is built in and the vendor takes it,
+// gzipped; OTLP/HTTP otherwise, uncompressed as that exporter can't compress.
+
+use base64::Engine;
+
+use crate::config::Vendor;
+
+// Takes precedence over `telemetry.api_key`, to keep the key out of the config file
+pub const API_KEY_ENV: &str = "TELEMETRY_API_KEY";
+
+// Where and how spans and metrics are exported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportTarget {
+    pub protocol: String,
+    // OTLP/HTTP appends the signal's path
+    pub endpoint: String,
+    pub headers: Vec<(String, String)>,
+    pub gzip: bool,
+}
+
+fn by_protocol(grpc_endpoint: String, http_endpoint: String, header: (&str, String)) -> ExportTarget {
+    let grpc = cfg!(feature = "otlp-grpc");
+    ExportTarget {
+        protocol: if grpc { "grpc" } else { "http/protobuf" }.to_string(),
+        endpoint: if grpc { grpc_endpoint } else { http_endpoint },
+        headers: vec![(header.0.to_string(), header.1)],
+        gzip: grpc,
+    }
+}
+
+// `region` is "us" (the default) or "eu" for Honeycomb and New Relic, and the stack's zone,
+// say "prod-us-east-0", for Grafana Cloud
+pub fn preset(vendor: Vendor, region: Option<&str>, api_key: &str) -> Result<ExportTarget, String> {
+    let unknown_region = |region: &str| format!("unknown {vendor:?} region {region:?}, expected \"us\" or \"eu\"");
+
+    match vendor {
+        Vendor::Honeycomb => {
+            let host = match region.unwrap_or("us") {
+                "us" => "api.honeycomb.io",
+                "eu" => "api.eu1.honeycomb.io",
+                other => return Err(unknown_region(other)),
+            };
+            Ok(by_protocol(format!("https://{host}:443"), format!("https://{host}"), ("x-honeycomb-team", api_key.to_string())))
+        }
+        Vendor::NewRelic => {
+            let host = match region.unwrap_or("us") {
+                "us" => "otlp.nr-data.net",
+                "eu" => "otlp.eu01.nr-data.net",
+                other => return Err(unknown_region(other)),
+            };
+            Ok(by_protocol(format!("https://{host}:4317"), format!("https://{host}:4318"), ("api-key", api_key.to_string())))
+        }
+        // Only takes OTLP/HTTP, with the instance id and token as basic auth
+        Vendor::GrafanaCloud => {
+            let zone = region.ok_or("Grafana Cloud needs telemetry.vendor_region, the zone of the stack")?;
+            if !api_key.contains(':') {
+                return Err("the Grafana Cloud API key is `instance id:token`".to_string());
+            }
+            let credentials = base64::engine::general_purpose::STANDARD.encode(api_key);
+            Ok(ExportTarget {
+                protocol: "http/protobuf".to_string(),
+                endpoint: format!("https://otlp-gateway-{zone}.grafana.net/otlp"),
+                headers: vec![("authorization".to_string(), format!("Basic {credentials}"))],
+                gzip: false,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets() {
+        let honeycomb = preset(Vendor::Honeycomb, Some("eu"), "key").unwrap();
+        assert!(honeycomb.endpoint.starts_with("https://api.eu1.honeycomb.io"));
+        assert_eq!(honeycomb.headers, [("x-honeycomb-team".to_string(), "key".to_string())]);
+        assert!(preset(Vendor::NewRelic, Some("apac"), "key").is_err());
+
+        let grafana = preset(Vendor::GrafanaCloud, Some("prod-eu-west-2"), "123456:glc_token").unwrap();
+        assert_eq!(grafana.protocol, "http/protobuf");
+        assert_eq!(grafana.endpoint, "https://otlp-gateway-prod-eu-west-2.grafana.net/otlp");
+        assert_eq!(grafana.headers[0].1, "Basic MTIzNDU2OmdsY190b2tlbg==");
+        assert!(preset(Vendor::GrafanaCloud, None, "123456:glc_token").is_err());
+    }
+}
@@ -0,0 +1,177 @@
+// End to end: MySQL and an OpenTelemetry collector in containers, the real server binary
+// in between, and the spans the collector received checked at the end. Needs Docker:
+//
+//     cargo test --test e2e -- --ignored
+
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use testcontainers::core::{IntoContainerPort, Mount, WaitFor};
+use testcontainers::runners::AsyncRunner;
+use testcontainers::{GenericImage, ImageExt};
+
+const COLLECTOR_CONFIG: &str = r#"
+receivers:
+  otlp:
+    protocols:
+      grpc:
+        endpoint: 0.0.0.0:4317
+exporters:
+  file:
+    path: /out/traces.json
+    flush_interval: 100ms
+service:
+  pipelines:
+    traces:
+      receivers: [otlp]
+      exporters: [file]
+"#;
+
+// Kills the server if the test fails before stopping it
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+    }
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn scratch_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust-trace-minimum-e2e-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // The collector image doesn't run as root and writes its output here
+    let mut permissions = std::fs::metadata(&dir).unwrap().permissions();
+    std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, 0o777);
+    std::fs::set_permissions(&dir, permissions).unwrap();
+    dir
+}
+
+async fn wait_until_started(client: &reqwest::Client, base: &str) {
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while Instant::now() < deadline {
+        if let Ok(response) = client.get(format!("{base}/healthz/startup")).send().await {
+            if response.status().is_success() {
+                return;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    panic!("server did not start within 60s");
+}
+
+// Every span the collector wrote, as (name, status code) pairs; status 2 is an error
+fn received_spans(path: &Path) -> Vec<(String, i64)> {
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+
+    let mut spans = Vec::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let request: serde_json::Value = serde_json::from_str(line).unwrap();
+        for resource in request["resourceSpans"].as_array().into_iter().flatten() {
+            for scope in resource["scopeSpans"].as_array().into_iter().flatten() {
+                for span in scope["spans"].as_array().into_iter().flatten() {
+                    let name = span["name"].as_str().unwrap_or_default().to_string();
+                    let status = span["status"]["code"].as_i64().unwrap_or(0);
+                    spans.push((name, status));
+                }
+            }
+        }
+    }
+    spans
+}
+
+async fn wait_for_spans(path: &Path, expected: &[&str]) -> Vec<(String, i64)> {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        let spans = received_spans(path);
+        if expected.iter().all(|name| spans.iter().any(|(received, _)| received == name)) {
+            return spans;
+        }
+        if Instant::now() > deadline {
+            panic!("expected spans {expected:?}, the collector received {spans:?}");
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+fn status_of<'a>(spans: &'a [(String, i64)], name: &str) -> impl Iterator<Item = i64> + 'a {
+    let name = name.to_string();
+    spans.iter().filter(move |(received, _)| *received == name).map(|(_, status)| *status)
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn traces_reach_the_collector() {
+    let dir = scratch_dir();
+
+    let mysql = testcontainers_modules::mysql::Mysql::default().start().await.unwrap();
+    let collector = GenericImage::new("otel/opentelemetry-collector-contrib", "0.111.0")
+        .with_exposed_port(4317.tcp())
+        .with_wait_for(WaitFor::message_on_stderr("Everything is ready"))
+        .with_mount(Mount::bind_mount(dir.to_string_lossy(), "/out"))
+        .with_copy_to("/etc/otelcol-contrib/config.yaml", COLLECTOR_CONFIG.as_bytes().to_vec())
+        .start()
+        .await
+        .unwrap();
+
+    let (port, admin_port) = (free_port(), free_port());
+    let settings = format!(
+        r#"
+[server]
+bind = "127.0.0.1:{port}"
+shutdown_drain_secs = 5
+
+[admin]
+bind = "127.0.0.1:{admin_port}"
+
+[database]
+port = {mysql_port}
+username = "root"
+password = ""
+database = "test"
+
+[telemetry]
+resource_detectors = []
+"#,
+        mysql_port = mysql.get_host_port_ipv4(3306).await.unwrap(),
+    );
+    let config = dir.join("settings.toml");
+    std::fs::write(&config, settings).unwrap();
+
+    let collector_port = collector.get_host_port_ipv4(4317).await.unwrap();
+    let mut server = Server(
+        Command::new(env!("CARGO_BIN_EXE_rust-trace-minimum"))
+            .env("APP_CONFIG", &config)
+            .env("OTEL_EXPORTER_OTLP_ENDPOINT", format!("http://127.0.0.1:{collector_port}"))
+            .spawn()
+            .unwrap(),
+    );
+
+    let client = reqwest::Client::new();
+    let base = format!("http://127.0.0.1:{port}");
+    wait_until_started(&client, &format!("http://127.0.0.1:{admin_port}")).await;
+
+    assert!(client.get(format!("{base}/v1")).send().await.unwrap().status().is_success());
+    client.get(format!("{base}/v1/cause_error")).send().await.unwrap();
+
+    // SIGTERM drains and flushes the batch processor before exiting
+    Command::new("kill").arg("-TERM").arg(server.0.id().to_string()).status().unwrap();
+    assert!(server.0.wait().unwrap().success());
+
+    let spans = wait_for_spans(
+        &dir.join("traces.json"),
+        &["GET /v1", "root", "some process", "fetch row", "GET /v1/cause_error", "cause_error"],
+    )
+    .await;
+
+    assert!(status_of(&spans, "GET /v1").all(|status| status != 2), "{spans:?}");
+    assert!(status_of(&spans, "cause_error").any(|status| status == 2), "{spans:?}");
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,61 @@
+// `#[traced_handler]`, the `#[tracing::instrument]` of axum handlers. Extractors are left
+// out of the span, they are mostly state and the pool's `Debug` is no use in a trace; the
+// span gets the handler's name and module, and its response status once it returns. The
+// handler then returns `axum::response::Response`, whatever it was written to return.
+//
+//     #[traced_handler(export.rows = params.rows)]
+//     async fn export(axum::extract::Query(params): axum::extract::Query<Params>) -> impl IntoResponse
+//
+// Anything between the parentheses is added to the span's fields, as in `instrument`.
+
+use proc_macro::TokenStream;
+use quote::quote;
+
+#[proc_macro_attribute]
+pub fn traced_handler(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut handler = syn::parse_macro_input!(item as syn::ItemFn);
+    if handler.sig.asyncness.is_none() {
+        return syn::Error::new_spanned(handler.sig.fn_token, "#[traced_handler] is for async handlers")
+            .to_compile_error()
+            .into();
+    }
+
+    let name = handler.sig.ident.to_string();
+    let extra = proc_macro2::TokenStream::from(attr);
+    let extra = (!extra.is_empty()).then(|| quote!(, #extra));
+    let body = &handler.block;
+    // The type the body was written for, so `?` in it knows what to convert to; which
+    // can't be said of `impl IntoResponse`
+    let output = match &handler.sig.output {
+        syn::ReturnType::Type(_, ty) if !matches!(**ty, syn::Type::ImplTrait(_)) => quote!(: #ty),
+        _ => quote!(),
+    };
+
+    handler.sig.output = syn::parse_quote!(-> axum::response::Response);
+    handler.block = syn::parse_quote!({
+        let output #output = async move #body.await;
+        let response = axum::response::IntoResponse::into_response(output);
+        let span = tracing::Span::current();
+        span.record("http.response.status_code", response.status().as_u16());
+        if response.status().is_server_error() {
+            span.record("otel.status_code", "error");
+        }
+        response
+    });
+
+    quote!(
+        #[tracing::instrument(
+            name = #name,
+            skip_all,
+            fields(
+                code.function = #name,
+                code.namespace = module_path!(),
+                http.response.status_code = tracing::field::Empty,
+                otel.status_code = tracing::field::Empty
+                #extra
+            )
+        )]
+        #handler
+    )
+    .into()
+}